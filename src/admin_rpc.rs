@@ -0,0 +1,24 @@
+use {crate::RpcMeta, jsonrpc_core::Result, jsonrpc_derive::rpc};
+
+#[rpc]
+pub trait AdminRpc {
+    type Metadata;
+
+    #[rpc(meta, name = "f")]
+    fn f(&self, meta: Self::Metadata, a: u8, b: u8) -> Result<u8>;
+}
+
+pub struct AdminRpcImpl;
+impl AdminRpc for AdminRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn f(&self, meta: RpcMeta, a: u8, b: u8) -> Result<u8> {
+        if let Some(caller) = &meta.caller {
+            log::debug!(
+                "f({a}, {b}) called by {caller}, request_id={}",
+                meta.request_id
+            );
+        }
+        Ok(a.saturating_mul(10).saturating_add(b).saturating_add(2))
+    }
+}
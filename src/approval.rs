@@ -0,0 +1,499 @@
+//! Two-person approval of calls to dangerous methods.
+//!
+//! A call to one of the [`ApprovalConfig::methods`] is not executed the first time.  It is
+//! rejected with [`Reason::ApprovalRequired`], and the error data carries an `approval_id`.  The
+//! call then waits for someone else to approve it with `admin_approve`, and is executed when
+//! the caller repeats it, with the same parameters and the id in the [`APPROVAL_ID_HEADER`].
+//! Approved calls are executed once, and both the approval and the retry have to happen within
+//! [`ApprovalConfig::timeout`] of the first call.
+//!
+//! Callers are told apart by user name, for sessions started by a user login.  All other admin
+//! credentials, that is the admin token, sessions started with it, emergency tokens and request
+//! signatures, count as one caller, so that holders of the admin token can not approve their own
+//! calls by switching between them.  Calls made with `X-Run-As` count as calls by whoever sent
+//! them, not by the user named.
+//!
+//! Approvals tell credentials apart, not people.  Whoever may call `user_add`, including every
+//! holder of the admin token, can add a second admin user, log in as them, and approve their
+//! own calls.  So a second person is only needed when the callers of these methods can not
+//! manage users, such as users whose sessions do not allow `user_add`, and the admin token is
+//! kept from them.
+//!
+//! Calls waiting for approval are listed by `admin_pending_approvals`, with secrets in the
+//! parameters redacted, and can be refused with `admin_deny`.
+
+use {
+    crate::{
+        rejection::{Reason, Rejection},
+        request_log::redact,
+        state::Role,
+        RpcMeta,
+    },
+    futures_util::future::Either,
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall},
+            response::{Output, Response},
+        },
+        Error as JsonRpcError, Result as RpcResult,
+    },
+    jsonrpc_derive::rpc,
+    rand::Rng,
+    serde::Serialize,
+    serde_json::Value,
+    sha2::{Digest, Sha256},
+    std::{
+        collections::{HashMap, HashSet},
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const APPROVAL_ID_HEADER: &str = "X-Approval-Id";
+
+/// Calls waiting for approval, beyond this many, are rejected.
+pub const MAX_PENDING: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct ApprovalConfig {
+    /// Methods that are only executed once approved.
+    pub methods: HashSet<String>,
+    /// How long a call waits for approval, and for the retry once approved.
+    pub timeout: Duration,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        Self {
+            methods: HashSet::new(),
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A call waiting for approval, as listed by `admin_pending_approvals`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingApproval {
+    pub id: String,
+    pub method: String,
+    /// The parameters, as JSON, with secrets redacted.
+    pub params: String,
+    /// Who made the call, as shown in [`crate::state::Caller`]'s `Display`.
+    pub requested_by: String,
+    pub request_id: String,
+    /// Milliseconds since the Unix epoch.
+    pub requested_at: u64,
+    /// Who approved the call, if anyone did yet.
+    pub approved_by: Option<String>,
+}
+
+struct Pending {
+    listing: PendingApproval,
+    /// SHA-256 of the parameters, so that only the call that was approved is executed.
+    params_hash: [u8; 32],
+    requester: String,
+    expires: Instant,
+}
+
+/// Calls waiting for approval, shared by every clone.
+#[derive(Clone, Default)]
+pub struct Approvals {
+    config: Arc<ApprovalConfig>,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl Approvals {
+    pub fn new(config: ApprovalConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            pending: Arc::default(),
+        }
+    }
+
+    pub fn pending(&self) -> Vec<PendingApproval> {
+        let mut pending = self.pending.lock().unwrap();
+        purge_expired(&mut pending);
+        let mut listing = pending
+            .values()
+            .map(|pending| pending.listing.clone())
+            .collect::<Vec<_>>();
+        listing.sort_by_key(|pending| pending.requested_at);
+        listing
+    }
+
+    /// Approves call `id` on behalf of the caller with `meta`.
+    pub fn approve(&self, id: &str, meta: &RpcMeta) -> Result<String, &'static str> {
+        let mut pending = self.pending.lock().unwrap();
+        purge_expired(&mut pending);
+        let pending = pending.get_mut(id).ok_or(NOT_PENDING)?;
+        if pending.requester == requester(meta) {
+            return Err("Calls can not be approved by whoever made them");
+        }
+        pending.listing.approved_by = Some(caller(meta));
+        Ok(pending.listing.method.clone())
+    }
+
+    /// Drops call `id`, so it can not be approved any more.
+    pub fn deny(&self, id: &str) -> Result<String, &'static str> {
+        let mut pending = self.pending.lock().unwrap();
+        purge_expired(&mut pending);
+        let pending = pending.remove(id).ok_or(NOT_PENDING)?;
+        Ok(pending.listing.method)
+    }
+
+    /// Registers a call waiting for approval, and returns its id.
+    fn request(&self, method: &str, params: &Value, meta: &RpcMeta) -> Result<String, Rejection> {
+        let mut pending = self.pending.lock().unwrap();
+        purge_expired(&mut pending);
+        if pending.len() >= MAX_PENDING {
+            return Err(Rejection::new(
+                Reason::NotApproved,
+                "Too many calls are waiting for approval",
+            ));
+        }
+
+        let id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let mut redacted = params.clone();
+        redact(&mut redacted);
+        pending.insert(
+            id.clone(),
+            Pending {
+                listing: PendingApproval {
+                    id: id.clone(),
+                    method: method.to_owned(),
+                    params: redacted.to_string(),
+                    requested_by: caller(meta),
+                    request_id: meta.request_id.clone(),
+                    requested_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_millis() as u64),
+                    approved_by: None,
+                },
+                params_hash: hash(params),
+                requester: requester(meta),
+                expires: Instant::now() + self.config.timeout,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Takes the approval `id`, if it approves this exact call by the caller with `meta`.
+    fn take(
+        &self,
+        id: &str,
+        method: &str,
+        params: &Value,
+        meta: &RpcMeta,
+    ) -> Result<(), Rejection> {
+        let not_approved = |message| Err(Rejection::new(Reason::NotApproved, message));
+
+        let mut pending = self.pending.lock().unwrap();
+        purge_expired(&mut pending);
+        let Some(call) = pending.get(id) else {
+            return not_approved(NOT_PENDING);
+        };
+        if call.listing.method != method
+            || call.params_hash != hash(params)
+            || call.requester != requester(meta)
+        {
+            return not_approved("The approval is for a different call");
+        }
+        if call.listing.approved_by.is_none() {
+            return not_approved("The call is not approved yet");
+        }
+        pending.remove(id);
+        Ok(())
+    }
+}
+
+const NOT_PENDING: &str = "No call with this approval id is waiting for approval";
+
+fn purge_expired(pending: &mut HashMap<String, Pending>) {
+    let now = Instant::now();
+    pending.retain(|_, pending| pending.expires > now);
+}
+
+fn hash(params: &Value) -> [u8; 32] {
+    Sha256::digest(params.to_string().as_bytes()).into()
+}
+
+/// Tells callers apart by who is behind the credentials, see the module documentation.
+fn requester(meta: &RpcMeta) -> String {
+    let Some(caller) = &meta.caller else {
+        return "anonymous".to_owned();
+    };
+    let caller = caller.run_by.as_deref().unwrap_or(caller);
+    match (&caller.user, caller.role) {
+        (Some(user), _) => format!("user {user}"),
+        (None, Role::Admin) => "admin credentials".to_owned(),
+        (None, Role::Anonymous) => "anonymous".to_owned(),
+    }
+}
+
+fn caller(meta: &RpcMeta) -> String {
+    meta.caller
+        .as_ref()
+        .map_or_else(|| "-".to_owned(), |caller| caller.to_string())
+}
+
+/// Holds back calls to the methods that need approval.  Has to run after the protection
+/// middleware, which sets [`RpcMeta::caller`].
+#[derive(Clone)]
+pub struct ApprovalMiddleware {
+    approvals: Approvals,
+}
+
+impl ApprovalMiddleware {
+    pub fn new(approvals: Approvals) -> Self {
+        Self { approvals }
+    }
+}
+
+impl Middleware<RpcMeta> for ApprovalMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let methods = &self.approvals.config.methods;
+        let (jsonrpc, method, params, id) = match &call {
+            Call::MethodCall(MethodCall {
+                jsonrpc,
+                method,
+                params,
+                id,
+            }) if methods.contains(method) => (jsonrpc, method, params, id),
+            // Notifications can not be given an approval id, so they are dropped.
+            Call::Notification(notification) if methods.contains(&notification.method) => {
+                return Either::Left(Box::pin(async { None }));
+            }
+            _ => return Either::Right(next(call, meta)),
+        };
+        let params = serde_json::to_value(params).expect("Params always serialize");
+
+        let approval_id = meta.approval_id.as_deref();
+        let rejection = match approval_id {
+            Some(approval_id) => match self.approvals.take(approval_id, method, &params, &meta) {
+                Ok(()) => {
+                    log::info!(
+                        "Executing approved call {approval_id} to {method}, request_id={}",
+                        meta.request_id,
+                    );
+                    return Either::Right(next(call, meta));
+                }
+                Err(rejection) => rejection,
+            },
+            None => match self.approvals.request(method, &params, &meta) {
+                Ok(approval_id) => {
+                    log::info!(
+                        "Call {approval_id} to {method} by {} waits for approval, request_id={}",
+                        caller(&meta),
+                        meta.request_id,
+                    );
+                    Rejection::new(
+                        Reason::ApprovalRequired,
+                        "The call has to be approved by someone else, then repeated with the \
+                         X-Approval-Id header",
+                    )
+                    .approval_id(approval_id)
+                }
+                Err(rejection) => rejection,
+            },
+        };
+
+        let output = Output::from(Err(rejection.to_error(&meta)), id.clone(), *jsonrpc);
+        Either::Left(Box::pin(async move { Some(output) }))
+    }
+}
+
+#[rpc(server)]
+pub trait ApprovalRpc {
+    type Metadata;
+
+    /// Calls waiting for approval, oldest first.
+    #[rpc(name = "admin_pending_approvals")]
+    fn pending(&self) -> RpcResult<Vec<PendingApproval>>;
+
+    /// Approves call `id`.  The caller has to repeat it to have it executed.
+    #[rpc(meta, name = "admin_approve")]
+    fn approve(&self, meta: Self::Metadata, id: String) -> RpcResult<()>;
+
+    /// Refuses call `id`.
+    #[rpc(meta, name = "admin_deny")]
+    fn deny(&self, meta: Self::Metadata, id: String) -> RpcResult<()>;
+}
+
+#[derive(Clone)]
+pub struct ApprovalRpcImpl {
+    approvals: Approvals,
+}
+
+impl ApprovalRpcImpl {
+    pub fn new(approvals: Approvals) -> Self {
+        Self { approvals }
+    }
+}
+
+impl ApprovalRpc for ApprovalRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn pending(&self) -> RpcResult<Vec<PendingApproval>> {
+        Ok(self.approvals.pending())
+    }
+
+    fn approve(&self, meta: RpcMeta, id: String) -> RpcResult<()> {
+        let method = self
+            .approvals
+            .approve(&id, &meta)
+            .map_err(JsonRpcError::invalid_params)?;
+        log::warn!(
+            "Call {id} to {method} approved by {}, request_id={}",
+            caller(&meta),
+            meta.request_id,
+        );
+        Ok(())
+    }
+
+    fn deny(&self, meta: RpcMeta, id: String) -> RpcResult<()> {
+        let method = self
+            .approvals
+            .deny(&id)
+            .map_err(JsonRpcError::invalid_params)?;
+        log::warn!(
+            "Call {id} to {method} denied by {}, request_id={}",
+            caller(&meta),
+            meta.request_id,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::state::{tests::*, ProtectionState},
+        serde_json::json,
+    };
+
+    const METHOD: &str = "f";
+
+    fn approvals() -> Approvals {
+        Approvals::new(ApprovalConfig {
+            methods: [METHOD.to_owned()].into(),
+            ..Default::default()
+        })
+    }
+
+    /// `meta`, with the caller set as the protection middleware does.
+    fn identified(state: &ProtectionState, mut meta: RpcMeta) -> RpcMeta {
+        meta.caller = Some(state.caller(&meta));
+        meta
+    }
+
+    /// Metadata of a call made with a new [`session`] of `user`.
+    fn session_of(state: &ProtectionState, user: Option<&str>) -> RpcMeta {
+        identified(state, meta(Some(&session(state, user))))
+    }
+
+    /// [`state`], with user `alice`.
+    fn with_user() -> ProtectionState {
+        let state = state();
+        state.users.add("alice", "secret", Role::Admin).unwrap();
+        state
+    }
+
+    #[test]
+    fn admin_credentials_can_not_approve_their_own_calls() {
+        let state = with_user();
+        let approvals = approvals();
+        let params = json!([1]);
+        let admin = identified(&state, meta(Some("root")));
+        let id = approvals.request(METHOD, &params, &admin).unwrap();
+
+        let mut run_as = meta(Some("root"));
+        run_as.run_as = Some(Ok("alice".to_owned()));
+        let mut signed = meta(None);
+        signed.request_signature = Some(Ok(()));
+        for approver in [
+            admin.clone(),
+            session_of(&state, None),
+            identified(&state, run_as),
+            identified(&state, signed),
+        ] {
+            assert_eq!(
+                approvals.approve(&id, &approver),
+                Err("Calls can not be approved by whoever made them"),
+            );
+        }
+        assert!(approvals.take(&id, METHOD, &params, &admin).is_err());
+
+        approvals
+            .approve(&id, &session_of(&state, Some("alice")))
+            .unwrap();
+        // Retried with another credential of the same admin.
+        approvals
+            .take(&id, METHOD, &params, &session_of(&state, None))
+            .unwrap();
+        assert!(approvals.take(&id, METHOD, &params, &admin).is_err());
+    }
+
+    #[test]
+    fn users_can_not_approve_their_own_calls() {
+        let state = with_user();
+        let approvals = approvals();
+        let params = json!({"amount": 1});
+        let alice = session_of(&state, Some("alice"));
+        let id = approvals.request(METHOD, &params, &alice).unwrap();
+
+        assert!(approvals
+            .approve(&id, &session_of(&state, Some("alice")))
+            .is_err());
+        // The admin acting as alice is still the admin.
+        let mut run_as = meta(Some("root"));
+        run_as.run_as = Some(Ok("alice".to_owned()));
+        approvals.approve(&id, &identified(&state, run_as)).unwrap();
+
+        assert!(approvals
+            .take(&id, METHOD, &json!({"amount": 2}), &alice)
+            .is_err());
+        assert!(approvals.take(&id, "g", &params, &alice).is_err());
+        approvals.take(&id, METHOD, &params, &alice).unwrap();
+        assert_eq!(
+            approvals
+                .take(&id, METHOD, &params, &alice)
+                .unwrap_err()
+                .reason,
+            Reason::NotApproved,
+        );
+    }
+
+    #[test]
+    fn approvals_expire_and_can_be_denied() {
+        let state = state();
+        let expiring = Approvals::new(ApprovalConfig {
+            methods: [METHOD.to_owned()].into(),
+            timeout: Duration::ZERO,
+        });
+        let admin = identified(&state, meta(Some("root")));
+        let id = expiring.request(METHOD, &json!([]), &admin).unwrap();
+        assert_eq!(
+            expiring.approve(&id, &session_of(&state, Some("bob"))),
+            Err(NOT_PENDING)
+        );
+        assert!(expiring.pending().is_empty());
+
+        let approvals = approvals();
+        let id = approvals.request(METHOD, &json!([]), &admin).unwrap();
+        assert_eq!(approvals.pending().len(), 1);
+        assert_eq!(approvals.deny(&id), Ok(METHOD.to_owned()));
+        assert_eq!(approvals.deny(&id), Err(NOT_PENDING));
+        assert!(approvals.pending().is_empty());
+    }
+}
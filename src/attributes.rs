@@ -0,0 +1,81 @@
+//! Caller attributes, such as a tenant or an organization, taken from request headers.
+//!
+//! Operators list the headers to take with [`AttributeHeader`]s, and the values end up in
+//! [`RpcMeta::attributes`], where handlers can read them.  The request log shows them after the
+//! caller identity.
+//!
+//! The headers are not authenticated in any way.  They can only be trusted when a proxy in
+//! front of the server sets them, replacing whatever the client sent.
+//!
+//! [`RpcMeta::attributes`]: crate::RpcMeta::attributes
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// Attribute named `attribute`, with the value of the `header` request header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeHeader {
+    pub attribute: String,
+    pub header: String,
+}
+
+impl FromStr for AttributeHeader {
+    type Err = String;
+
+    /// Parses `ATTRIBUTE=HEADER`, such as `tenant_id=X-Tenant-Id`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (attribute, header) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected ATTRIBUTE=HEADER, got \"{s}\""))?;
+        // Both end up in log lines as `attribute=value`, and `header` must be a token.
+        if attribute.is_empty()
+            || !attribute
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        {
+            return Err(format!(
+                "attribute \"{attribute}\" must be made of letters, digits and `_`"
+            ));
+        }
+        if header.is_empty()
+            || !header
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(format!("\"{header}\" is not a valid header name"));
+        }
+        Ok(AttributeHeader {
+            attribute: attribute.to_owned(),
+            header: header.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for AttributeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.attribute, self.header)
+    }
+}
+
+/// Values of the `headers` the request has.  `header` returns the raw value of the named header,
+/// as for [`RpcMeta::from_headers`].
+///
+/// Values end up in logs, so, as with request ids, only visible ASCII characters are accepted,
+/// and other values are ignored.
+///
+/// [`RpcMeta::from_headers`]: crate::RpcMeta::from_headers
+pub fn extract<'a>(
+    headers: &[AttributeHeader],
+    header: impl Fn(&str) -> Option<&'a [u8]>,
+) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|attribute| {
+            let value = header(&attribute.header)?;
+            if value.is_empty() || !value.iter().all(u8::is_ascii_graphic) {
+                return None;
+            }
+            let value = String::from_utf8(value.to_vec()).expect("ASCII is valid UTF-8");
+            Some((attribute.attribute.clone(), value))
+        })
+        .collect()
+}
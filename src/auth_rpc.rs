@@ -0,0 +1,398 @@
+//! Methods for exchanging long-term credentials for [`session`](crate::session) tokens.
+//!
+//! Neither method is protected, as they check the credentials they are given themselves:
+//! `auth_login` takes a username and password, or the admin credentials a protected method
+//! would need, while `auth_refresh` takes a refresh token.
+
+use {
+    crate::{
+        rejection::{Reason, Rejection},
+        secret::Secret,
+        session::{Grant, Issued, RefreshError, DEFAULT_SESSION_TTL, REFRESH_TOKEN_TTL},
+        state::{ProtectionHandle, Role},
+        users::{self, UserStore},
+        RpcMeta,
+    },
+    jsonrpc_core::{BoxFuture, Params, Result},
+    jsonrpc_derive::rpc,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    },
+};
+
+/// Parameters of `auth_login`, all optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginParams {
+    /// Log in as this user, rather than with the admin credentials.  Requires `password`.
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    /// Lifetime of the session in seconds.
+    pub ttl: Option<u64>,
+    /// Protected methods the session may call.  All of them when omitted.
+    pub methods: Option<HashSet<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshParams {
+    pub refresh_token: Secret<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Login {
+    /// To be sent in the `X-Admin-Auth` header.
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    pub token: Secret<String>,
+    /// Seconds until the session expires.
+    pub expires_in: u64,
+    /// To be passed to `auth_refresh`, once.
+    #[serde(serialize_with = "Secret::serialize_exposed")]
+    pub refresh_token: Secret<String>,
+    /// Seconds until the refresh token expires.
+    pub refresh_expires_in: u64,
+}
+
+impl From<Issued> for Login {
+    fn from(issued: Issued) -> Self {
+        Self {
+            token: issued.token,
+            expires_in: whole_seconds_until(issued.session.expires_at),
+            refresh_token: issued.refresh_token,
+            refresh_expires_in: REFRESH_TOKEN_TTL.as_secs(),
+        }
+    }
+}
+
+/// Rounded to the nearest second, as the deadline was computed moments ago.
+fn whole_seconds_until(deadline: Instant) -> u64 {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    (remaining + Duration::from_millis(500)).as_secs()
+}
+
+#[rpc(server)]
+pub trait AuthRpc {
+    type Metadata;
+
+    /// Starts a session, for a user, or for the holder of the admin token or a request
+    /// signing key.  Sessions can not be used to start more sessions.
+    #[rpc(meta, name = "auth_login", params = "raw")]
+    fn login(&self, meta: Self::Metadata, params: Params) -> BoxFuture<Result<Login>>;
+
+    /// Replaces the session a refresh token was issued with by a new one.
+    #[rpc(meta, name = "auth_refresh", params = "raw")]
+    fn refresh(&self, meta: Self::Metadata, params: Params) -> Result<Login>;
+}
+
+pub struct AuthRpcImpl {
+    state: ProtectionHandle,
+    users: UserStore,
+}
+
+impl AuthRpcImpl {
+    pub fn new(state: ProtectionHandle, users: UserStore) -> Self {
+        Self { state, users }
+    }
+}
+
+impl AuthRpc for AuthRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn login(&self, meta: Self::Metadata, params: Params) -> BoxFuture<Result<Login>> {
+        let state = self.state.clone();
+        let users = self.users.clone();
+        Box::pin(login(state, users, meta, params))
+    }
+
+    fn refresh(&self, meta: Self::Metadata, params: Params) -> Result<Login> {
+        let params = params.parse::<RefreshParams>()?;
+
+        let state = self.state.load();
+        match state.sessions.refresh(params.refresh_token.expose()) {
+            Ok(issued) => {
+                // Users that were removed, or given another role, have to log in again.
+                if let Some(user) = &issued.session.grant.user {
+                    if self.users.role(user) != Some(issued.session.grant.role) {
+                        state.sessions.revoke(issued.token.expose());
+                        return Err(users::invalid_login().to_error(&meta));
+                    }
+                }
+                Ok(issued.into())
+            }
+            Err(RefreshError::Unknown) => Err(Rejection::new(
+                Reason::InvalidToken,
+                "Refresh token is not valid",
+            )
+            .to_error(&meta)),
+            Err(RefreshError::Reused) => {
+                log::warn!(
+                    "Refresh token reused by peer={} request_id={}, ending all sessions from \
+                     the same login",
+                    meta.peer_addr
+                        .map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+                    meta.request_id,
+                );
+                Err(Rejection::new(
+                    Reason::InvalidToken,
+                    "Refresh token was already used, all sessions from the same login are ended",
+                )
+                .to_error(&meta))
+            }
+        }
+    }
+}
+
+/// `auth_login`.  Passwords are checked on a blocking thread, as argon2 takes a while on
+/// purpose, and anyone may call this.
+async fn login(
+    handle: ProtectionHandle,
+    users: UserStore,
+    meta: RpcMeta,
+    params: Params,
+) -> Result<Login> {
+    let params = match params {
+        Params::None => LoginParams::default(),
+        params => params.parse()?,
+    };
+
+    let state = handle.load_full();
+    let (role, user) = match (params.username, params.password) {
+        (Some(username), Some(password)) => {
+            let result = tokio::task::spawn_blocking({
+                let username = username.clone();
+                move || users.login(&username, password.expose())
+            })
+            .await
+            .map_err(|_| jsonrpc_core::Error::internal_error())?;
+            match result {
+                Ok(role) => (role, Some(username)),
+                Err(error) => {
+                    log::info!(
+                        "Failed login as {username} from peer={} request_id={}",
+                        meta.peer_addr
+                            .map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+                        meta.request_id,
+                    );
+                    return Err(Rejection::from(error).to_error(&meta));
+                }
+            }
+        }
+        (None, None) => {
+            // Sessions would outlive the emergency token they were started with.
+            if state.session(&meta).is_some() || state.is_emergency(&meta) {
+                return Err(Rejection::new(
+                    Reason::OutOfScope,
+                    "auth_login requires the admin token or a request signature",
+                )
+                .to_error(&meta));
+            }
+            state
+                .authorize(&meta)
+                .map_err(|rejection| rejection.to_error(&meta))?;
+            (Role::Admin, None)
+        }
+        _ => {
+            return Err(jsonrpc_core::Error::invalid_params(
+                "username and password must be given together",
+            ))
+        }
+    };
+
+    if let Some(user) = &user {
+        log::info!("User {user} logged in, request_id={}", meta.request_id);
+    }
+
+    let ttl = params.ttl.map_or(DEFAULT_SESSION_TTL, Duration::from_secs);
+    let grant = Grant {
+        role,
+        user,
+        methods: params.methods,
+    };
+    Ok(state.sessions.issue(ttl, grant).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::state::tests::{call, meta, state, with_emergency_token},
+        jsonrpc_core::{MetaIoHandler, Value},
+        serde_json::json,
+    };
+
+    fn io(state: &ProtectionHandle) -> MetaIoHandler<RpcMeta> {
+        let mut io = MetaIoHandler::default();
+        let users = state.load().users.clone();
+        io.extend_with(AuthRpcImpl::new(state.clone(), users).to_delegate());
+        io
+    }
+
+    /// Calls `method` and returns the response.
+    fn handle(
+        io: &MetaIoHandler<RpcMeta>,
+        method: &str,
+        params: Value,
+        auth: Option<&str>,
+    ) -> Value {
+        let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+        let response = io
+            .handle_request_sync(&request.to_string(), meta(auth))
+            .unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    /// Token of a new session, started with the admin token.
+    fn login(io: &MetaIoHandler<RpcMeta>, params: Value) -> String {
+        let response = handle(io, "auth_login", params, Some("root"));
+        response["result"]["token"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn admin_credentials_start_a_session() {
+        let state = ProtectionHandle::new(state());
+        let io = io(&state);
+
+        let response = handle(&io, "auth_login", json!({}), Some("root"));
+        assert_eq!(
+            response["result"]["expires_in"],
+            DEFAULT_SESSION_TTL.as_secs()
+        );
+        let token = response["result"]["token"].as_str().unwrap();
+        assert_ne!(token, "root");
+        assert!(state
+            .load()
+            .check_call(&call("f"), &meta(Some(token)))
+            .is_ok());
+
+        // Parameters may be omitted altogether.
+        let request = json!({"jsonrpc": "2.0", "method": "auth_login", "id": 1});
+        let response = io
+            .handle_request_sync(&request.to_string(), meta(Some("root")))
+            .unwrap();
+        assert!(response.contains("\"token\""));
+    }
+
+    #[test]
+    fn sessions_need_admin_credentials() {
+        let io = io(&ProtectionHandle::new(state()));
+        let reason =
+            |auth| handle(&io, "auth_login", json!({}), auth)["error"]["data"]["reason"].clone();
+        assert_eq!(reason(None), "credentials_required");
+        assert_eq!(reason(Some("wrong")), "invalid_token");
+
+        let token = login(&io, json!({}));
+        assert_eq!(reason(Some(&token)), "out_of_scope");
+    }
+
+    /// A session would let the holder of an emergency token keep admin access, by refreshing it,
+    /// long after the token expired.
+    #[test]
+    fn emergency_tokens_do_not_start_sessions() {
+        let (state, _) = with_emergency_token(Duration::from_secs(60));
+        let io = io(&ProtectionHandle::new(state));
+        let response = handle(&io, "auth_login", json!({}), Some("break"));
+        assert_eq!(response["error"]["data"]["reason"], "out_of_scope");
+    }
+
+    #[test]
+    fn sessions_are_limited_to_their_methods_and_lifetime() {
+        let state = ProtectionHandle::new(state());
+        let io = io(&state);
+        state.update(|state| {
+            state.protected.insert("g".to_owned());
+        });
+
+        let token = login(&io, json!({"methods": ["g"]}));
+        let state = state.load();
+        assert!(state.check_call(&call("g"), &meta(Some(&token))).is_ok());
+        let rejection = state
+            .check_call(&call("f"), &meta(Some(&token)))
+            .unwrap_err();
+        assert_eq!(rejection.reason, Reason::OutOfScope);
+
+        let response = handle(&io, "auth_login", json!({"ttl": 1_000_000}), Some("root"));
+        assert_eq!(
+            response["result"]["expires_in"],
+            crate::session::MAX_SESSION_TTL.as_secs()
+        );
+    }
+
+    #[test]
+    fn malformed_logins_are_rejected() {
+        let io = io(&ProtectionHandle::new(state()));
+        for params in [
+            json!({"username": "alice"}),
+            json!({"password": "secret"}),
+            json!({"ttl": "long"}),
+            json!({"scope": ["f"]}),
+        ] {
+            let response = handle(&io, "auth_login", params, Some("root"));
+            assert_eq!(response["error"]["code"], -32602);
+        }
+    }
+
+    #[test]
+    fn refresh_tokens_rotate_sessions() {
+        let state = ProtectionHandle::new(state());
+        let io = io(&state);
+        let login = handle(&io, "auth_login", json!({}), Some("root"))["result"].clone();
+
+        let refresh = |refresh_token: &Value| {
+            handle(
+                &io,
+                "auth_refresh",
+                json!({"refresh_token": refresh_token}),
+                None,
+            )
+        };
+        let refreshed = refresh(&login["refresh_token"])["result"].clone();
+        let token = refreshed["token"].as_str().unwrap();
+        let state = state.load();
+        assert!(state.check_call(&call("f"), &meta(Some(token))).is_ok());
+        let old_token = login["token"].as_str().unwrap();
+        assert!(state
+            .check_call(&call("f"), &meta(Some(old_token)))
+            .is_err());
+
+        let reused = refresh(&login["refresh_token"]);
+        assert_eq!(reused["error"]["data"]["reason"], "invalid_token");
+        assert!(state.check_call(&call("f"), &meta(Some(token))).is_err());
+
+        let unknown = refresh(&json!("unknown"));
+        assert_eq!(unknown["error"]["data"]["reason"], "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn users_start_sessions_with_their_password() {
+        let state = ProtectionHandle::new(state());
+        state
+            .load()
+            .users
+            .add("alice", "secret", Role::Admin)
+            .unwrap();
+        let io = io(&state);
+
+        let login = |password: &str| {
+            let request = json!({
+                "jsonrpc": "2.0",
+                "method": "auth_login",
+                "params": {"username": "alice", "password": password},
+                "id": 1,
+            });
+            let response = io.handle_request(&request.to_string(), meta(None));
+            async move { serde_json::from_str::<Value>(&response.await.unwrap()).unwrap() }
+        };
+
+        let response = login("wrong").await;
+        assert_eq!(response["error"]["data"]["reason"], "invalid_token");
+
+        let response = login("secret").await;
+        let token = response["result"]["token"].as_str().unwrap();
+        let state = state.load();
+        let caller = state.caller(&meta(Some(token)));
+        assert_eq!(caller.role, Role::Admin);
+        assert_eq!(caller.user.as_deref(), Some("alice"));
+    }
+}
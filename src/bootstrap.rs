@@ -0,0 +1,106 @@
+//! Provisioning of the admin token on a fresh install, without shipping a default one.
+//!
+//! While [`TrustOnFirstUse`] is open, the first `X-Admin-Auth` value any caller presents
+//! becomes the admin token, and is saved so that it survives restarts.  Whoever reaches the
+//! server first wins, so the window should be short and the server not yet exposed.
+
+use {
+    crate::{state::ProtectionHandle, strength::StrengthPolicy, RpcMeta},
+    std::{
+        fs::OpenOptions,
+        io::{self, Write},
+        path::PathBuf,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+pub const TARGET: &str = "jsonrpc_protection::bootstrap";
+
+pub struct TrustOnFirstUse {
+    path: PathBuf,
+    until: Instant,
+    claimed: Mutex<bool>,
+    strength: Option<StrengthPolicy>,
+}
+
+impl TrustOnFirstUse {
+    /// Accepts the first admin token presented within `window` from now, and saves it to
+    /// `path`, which must not exist yet.
+    pub fn new(path: PathBuf, window: Duration) -> Self {
+        Self {
+            path,
+            until: Instant::now() + window,
+            claimed: Mutex::new(false),
+            strength: None,
+        }
+    }
+
+    /// Ignore tokens that do not satisfy `policy`, waiting for a stronger one.
+    pub fn strength_policy(mut self, policy: StrengthPolicy) -> Self {
+        self.strength = Some(policy);
+        self
+    }
+
+    /// Makes the `X-Admin-Auth` value in `meta` the admin token in `state`, if no token has
+    /// been accepted yet, and the window is still open.
+    pub fn offer(&self, meta: &RpcMeta, state: &ProtectionHandle) {
+        let Some(Ok(token)) = &meta.auth else {
+            return;
+        };
+        if token.expose().is_empty() || Instant::now() >= self.until {
+            return;
+        }
+
+        let mut claimed = self.claimed.lock().unwrap();
+        if *claimed {
+            return;
+        }
+
+        if let Some(Err(weak)) = self.strength.map(|policy| policy.check(token.expose())) {
+            log::warn!(
+                target: TARGET,
+                "Ignoring the admin token presented by peer={} request_id={}: it {weak}",
+                meta.peer_addr
+                    .map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+                meta.request_id,
+            );
+            return;
+        }
+
+        if let Err(err) = self.save(token.expose()) {
+            log::error!(
+                target: TARGET,
+                "Failed to save the first admin token to {}: {err}",
+                self.path.display(),
+            );
+            return;
+        }
+        *claimed = true;
+
+        state.update(|state| state.admin_token = token.clone());
+        log::warn!(
+            target: TARGET,
+            "TRUST ON FIRST USE: the admin token was set by peer={} request_id={}, and saved \
+             to {}",
+            meta.peer_addr
+                .map_or_else(|| "-".to_owned(), |addr| addr.to_string()),
+            meta.request_id,
+            self.path.display(),
+        );
+    }
+
+    fn save(&self, token: &str) -> io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&self.path)?;
+        writeln!(file, "{token}")?;
+        file.sync_all()
+    }
+}
@@ -0,0 +1,191 @@
+//! Emergency tokens, for when the admin token is lost or the usual way of logging in is broken.
+//!
+//! Emergency tokens are provisioned ahead of time, and the server only knows their SHA-256
+//! hashes, so the file listing them does not need to be kept secret.  Each token is presented in
+//! the `X-Admin-Auth` header, like the admin token.  The first call to a protected method
+//! activates it, and it then allows calls to every protected method for [`BreakGlass::new`]'s
+//! `duration`, after which it is rejected.  It cannot be used to start a session, which would
+//! outlive it.  Activations are only remembered until the server restarts, so used tokens
+//! should be removed from the file.
+//!
+//! Activations are logged under the [`TARGET`] target, and passed to
+//! [`BreakGlass::on_activation`], for alerting.  The request log shows every call made with an
+//! emergency token, with `identity=emergency`.
+
+use {
+    crate::RpcMeta,
+    serde::Serialize,
+    sha2::{Digest, Sha256},
+    std::{
+        collections::HashMap,
+        fmt, fs, io,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const TARGET: &str = "jsonrpc_protection::break_glass";
+
+/// Emergency tokens stay active this long by default.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Sent to [`BreakGlass::on_activation`] when an emergency token is used for the first time.
+#[derive(Clone, Debug, Serialize)]
+pub struct Activation {
+    /// First 8 hex digits of the hash of the token, which tell tokens apart without revealing
+    /// them.
+    pub token: String,
+    /// When the token stops working.  Seconds since the Unix epoch.
+    pub expires_at: u64,
+    pub peer: Option<String>,
+    pub request_id: String,
+}
+
+/// Whether a token presented is an emergency token, and whether it is still active.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    NotEmergencyToken,
+    Active,
+    Expired,
+}
+
+/// Emergency tokens, shared by every clone.
+#[derive(Clone, Default)]
+pub struct BreakGlass {
+    /// Hashes of the tokens, and when each one was activated.
+    tokens: Arc<Mutex<HashMap<[u8; 32], Option<Instant>>>>,
+    duration: Duration,
+    on_activation: Option<Arc<dyn Fn(Activation) + Send + Sync>>,
+}
+
+impl BreakGlass {
+    /// Accepts tokens with the given SHA-256 `hashes`, for `duration` from their first use.
+    pub fn new(hashes: impl IntoIterator<Item = [u8; 32]>, duration: Duration) -> Self {
+        Self {
+            tokens: Arc::new(Mutex::new(
+                hashes.into_iter().map(|hash| (hash, None)).collect(),
+            )),
+            duration,
+            on_activation: None,
+        }
+    }
+
+    /// Reads hex encoded SHA-256 hashes from `path`, one per line, as produced by
+    /// `printf %s "$TOKEN" | sha256sum`.  Anything after the hash on a line is ignored, as are
+    /// empty lines and lines starting with `#`.
+    pub fn load(path: &Path, duration: Duration) -> io::Result<Self> {
+        let hashes = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let hash = line.split_whitespace().next().unwrap_or_default();
+                let mut bytes = [0; 32];
+                hex::decode_to_slice(hash, &mut bytes).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("\"{hash}\" is not a hex encoded SHA-256 hash"),
+                    )
+                })?;
+                Ok(bytes)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self::new(hashes, duration))
+    }
+
+    /// Calls `f` every time an emergency token is activated.
+    pub fn on_activation<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Activation) + Send + Sync + 'static,
+    {
+        self.on_activation = Some(Arc::new(f));
+        self
+    }
+
+    /// Whether there are no emergency tokens at all.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.lock().unwrap().is_empty()
+    }
+
+    /// Checks `token`, sent with `meta`, activating it if it is an emergency token used for the
+    /// first time.
+    pub fn check(&self, token: &str, meta: &RpcMeta) -> Check {
+        let hash = {
+            let mut tokens = self.tokens.lock().unwrap();
+            if tokens.is_empty() {
+                return Check::NotEmergencyToken;
+            }
+            let hash = hash(token);
+            match tokens.get_mut(&hash) {
+                None => return Check::NotEmergencyToken,
+                Some(Some(activated)) => return self.status(*activated),
+                Some(activated @ None) => *activated = Some(Instant::now()),
+            }
+            hash
+        };
+
+        // Outside of the lock, so that a slow `on_activation` does not hold up other checks.
+        self.activate(&hash, meta);
+        Check::Active
+    }
+
+    /// Same as [`Self::check`], without activating `token`.  Emergency tokens that were not
+    /// used yet are reported as active.
+    pub fn peek(&self, token: &str) -> Check {
+        let tokens = self.tokens.lock().unwrap();
+        if tokens.is_empty() {
+            return Check::NotEmergencyToken;
+        }
+        match tokens.get(&hash(token)) {
+            None => Check::NotEmergencyToken,
+            Some(None) => Check::Active,
+            Some(Some(activated)) => self.status(*activated),
+        }
+    }
+
+    fn status(&self, activated: Instant) -> Check {
+        if activated.elapsed() < self.duration {
+            Check::Active
+        } else {
+            Check::Expired
+        }
+    }
+
+    fn activate(&self, hash: &[u8; 32], meta: &RpcMeta) {
+        let activation = Activation {
+            token: hex::encode(&hash[..4]),
+            expires_at: SystemTime::now()
+                .checked_add(self.duration)
+                .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                .map_or(u64::MAX, |at| at.as_secs()),
+            peer: meta.peer_addr.map(|addr| addr.to_string()),
+            request_id: meta.request_id.clone(),
+        };
+        log::warn!(
+            target: TARGET,
+            "Emergency token {} activated for {:?}, peer={}, request_id={}",
+            activation.token,
+            self.duration,
+            activation.peer.as_deref().unwrap_or("-"),
+            activation.request_id,
+        );
+        if let Some(on_activation) = &self.on_activation {
+            on_activation(activation);
+        }
+    }
+}
+
+/// Looking the hash up, rather than the token, does not reveal how much of a guess was right.
+fn hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+impl fmt::Debug for BreakGlass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BreakGlass")
+            .field("tokens", &self.tokens.lock().unwrap().len())
+            .field("duration", &self.duration)
+            .finish()
+    }
+}
@@ -0,0 +1,353 @@
+//! Capture of whole calls and their responses, for debugging a handful of methods.
+//!
+//! Capturing is off until an admin starts it with `admin_capture_start`, naming the methods to
+//! capture.  Captured calls are kept in a ring buffer of [`CaptureConfig::capacity`] entries,
+//! the oldest dropped first, and read with `admin_captured`.  Both the call and the response
+//! have secrets redacted, see [`redact_params`], and are cut at [`CaptureConfig::max_body_size`]
+//! bytes, so the buffer never holds more than about `2 * capacity * max_body_size` bytes.
+
+use {
+    crate::{
+        request_log::{redact, redact_params},
+        RpcMeta,
+    },
+    futures_util::{future::Either, FutureExt},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall, Notification},
+            response::{Output, Response},
+        },
+        Result as RpcResult,
+    },
+    jsonrpc_derive::rpc,
+    serde::Serialize,
+    serde_json::Value,
+    std::{
+        collections::{HashSet, VecDeque},
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureConfig {
+    /// Number of calls kept.
+    pub capacity: usize,
+    /// Longer calls and responses are cut to this many bytes.
+    pub max_body_size: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            max_body_size: 64 * 1024,
+        }
+    }
+}
+
+/// A captured call.
+#[derive(Clone, Debug, Serialize)]
+pub struct Captured {
+    /// Milliseconds since the Unix epoch, when the response was ready.
+    pub time: u64,
+    pub method: String,
+    pub request_id: String,
+    pub peer: Option<String>,
+    /// The call, as JSON.
+    pub request: String,
+    /// The response, as JSON.  `None` for notifications.
+    pub response: Option<String>,
+    /// Whether `request` or `response` were cut.
+    pub truncated: bool,
+}
+
+#[derive(Default)]
+struct Buffer {
+    /// Capturing is off when empty.
+    methods: HashSet<String>,
+    captured: VecDeque<Captured>,
+}
+
+/// Captured calls, shared by every clone.
+#[derive(Clone, Default)]
+pub struct Capture {
+    config: CaptureConfig,
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+impl Capture {
+    pub fn new(config: CaptureConfig) -> Self {
+        Self {
+            config,
+            buffer: Arc::default(),
+        }
+    }
+
+    /// Captures calls to `methods` from now on, instead of whichever were captured before.
+    pub fn start(&self, methods: HashSet<String>) {
+        self.buffer.lock().unwrap().methods = methods;
+    }
+
+    pub fn stop(&self) {
+        self.buffer.lock().unwrap().methods.clear();
+    }
+
+    pub fn methods(&self) -> HashSet<String> {
+        self.buffer.lock().unwrap().methods.clone()
+    }
+
+    fn is_captured(&self, method: &str) -> bool {
+        self.buffer.lock().unwrap().methods.contains(method)
+    }
+
+    /// Captured calls, oldest first.  When `clear`, they are removed from the buffer.
+    pub fn captured(&self, clear: bool) -> Vec<Captured> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if clear {
+            buffer.captured.drain(..).collect()
+        } else {
+            buffer.captured.iter().cloned().collect()
+        }
+    }
+
+    fn push(&self, captured: Captured) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let mut buffer = self.buffer.lock().unwrap();
+        while buffer.captured.len() >= self.config.capacity {
+            buffer.captured.pop_front();
+        }
+        buffer.captured.push_back(captured);
+    }
+
+    /// `value` as redacted JSON, cut to `max_body_size`.  The flag tells whether it was cut.
+    fn body(&self, value: &impl Serialize) -> (String, bool) {
+        let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+        redact(&mut value);
+        let mut body = value.to_string();
+        if body.len() <= self.config.max_body_size {
+            return (body, false);
+        }
+        let mut end = self.config.max_body_size;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+        (body, true)
+    }
+}
+
+/// Feeds calls to the methods being captured into a [`Capture`].
+#[derive(Clone)]
+pub struct CaptureMiddleware {
+    capture: Capture,
+}
+
+impl CaptureMiddleware {
+    pub fn new(capture: Capture) -> Self {
+        Self { capture }
+    }
+}
+
+impl Middleware<RpcMeta> for CaptureMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let method = match &call {
+            Call::MethodCall(MethodCall { method, .. })
+            | Call::Notification(Notification { method, .. }) => method.clone(),
+            Call::Invalid { .. } => return Either::Right(next(call, meta)),
+        };
+        if !self.capture.is_captured(&method) {
+            return Either::Right(next(call, meta));
+        }
+
+        let mut request = serde_json::to_value(&call).unwrap_or(Value::Null);
+        if let Some(params) = request.get_mut("params") {
+            redact_params(&method, params);
+        }
+        let (request, request_truncated) = self.capture.body(&request);
+        let request_id = meta.request_id.clone();
+        let peer = meta.peer_addr.map(|addr| addr.to_string());
+        let capture = self.capture.clone();
+        Either::Left(Box::pin(next(call, meta).map(move |output| {
+            let (response, response_truncated) = match &output {
+                Some(output) => {
+                    let (response, truncated) = capture.body(output);
+                    (Some(response), truncated)
+                }
+                None => (None, false),
+            };
+            capture.push(Captured {
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+                method,
+                request_id,
+                peer,
+                request,
+                response,
+                truncated: request_truncated || response_truncated,
+            });
+            output
+        })))
+    }
+}
+
+#[rpc(server)]
+pub trait CaptureRpc {
+    type Metadata;
+
+    /// Starts capturing calls to `methods`, in place of the methods captured so far.
+    #[rpc(meta, name = "admin_capture_start")]
+    fn start(&self, meta: Self::Metadata, methods: Vec<String>) -> RpcResult<()>;
+
+    #[rpc(meta, name = "admin_capture_stop")]
+    fn stop(&self, meta: Self::Metadata) -> RpcResult<()>;
+
+    /// Captured calls, oldest first.  When `clear` is `true`, they are removed from the buffer.
+    #[rpc(name = "admin_captured")]
+    fn captured(&self, clear: Option<bool>) -> RpcResult<Vec<Captured>>;
+}
+
+#[derive(Clone)]
+pub struct CaptureRpcImpl {
+    capture: Capture,
+}
+
+impl CaptureRpcImpl {
+    pub fn new(capture: Capture) -> Self {
+        Self { capture }
+    }
+}
+
+impl CaptureRpc for CaptureRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn start(&self, meta: RpcMeta, methods: Vec<String>) -> RpcResult<()> {
+        if methods.is_empty() {
+            return Err(jsonrpc_core::Error::invalid_params(
+                "at least one method must be captured",
+            ));
+        }
+        log::warn!(
+            "Capturing calls to {}, request_id={}",
+            methods.join(", "),
+            meta.request_id,
+        );
+        self.capture.start(methods.into_iter().collect());
+        Ok(())
+    }
+
+    fn stop(&self, meta: RpcMeta) -> RpcResult<()> {
+        if !self.capture.methods().is_empty() {
+            log::warn!("Capturing stopped, request_id={}", meta.request_id);
+        }
+        self.capture.stop();
+        Ok(())
+    }
+
+    fn captured(&self, clear: Option<bool>) -> RpcResult<Vec<Captured>> {
+        Ok(self.capture.captured(clear.unwrap_or(false)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{request_log::REDACTED, state::tests::meta},
+        jsonrpc_core::{MetaIoHandler, Params},
+        serde_json::json,
+    };
+
+    /// Captures calls to every method of `io`, all of which return `{"token": "t"}`.
+    fn io(config: CaptureConfig) -> (MetaIoHandler<RpcMeta, CaptureMiddleware>, Capture) {
+        let capture = Capture::new(config);
+        let mut io = MetaIoHandler::with_middleware(CaptureMiddleware::new(capture.clone()));
+        let methods = ["auth_login", "user_add", "f"];
+        for method in methods {
+            io.add_method_with_meta(method, |_: Params, _: RpcMeta| async {
+                Ok(json!({"token": "t", "value": 1}))
+            });
+        }
+        capture.start(methods.iter().map(|method| method.to_string()).collect());
+        (io, capture)
+    }
+
+    fn call(io: &MetaIoHandler<RpcMeta, CaptureMiddleware>, method: &str, params: Value) {
+        let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+        io.handle_request_sync(&request.to_string(), meta(None))
+            .unwrap();
+    }
+
+    #[test]
+    fn secrets_are_not_captured() {
+        let (io, capture) = io(CaptureConfig::default());
+        call(&io, "auth_login", json!(["alice", "hunter2"]));
+        call(&io, "user_add", json!(["bob", "hunter2", "admin"]));
+        call(
+            &io,
+            "auth_login",
+            json!({"username": "alice", "password": "hunter2"}),
+        );
+        call(&io, "f", json!(["hunter2", {"secret": "hunter2"}]));
+
+        let captured = capture.captured(false);
+        assert_eq!(captured.len(), 4);
+        for captured in &captured[..3] {
+            assert!(
+                !captured.request.contains("hunter2"),
+                "{}",
+                captured.request
+            );
+        }
+        for captured in &captured {
+            let response = captured.response.as_deref().unwrap();
+            assert!(!response.contains("\"t\""), "{response}");
+            assert!(response.contains(REDACTED));
+        }
+        // Only methods that may take secrets lose their positional params.
+        assert!(captured[2].request.contains("alice"));
+        assert!(captured[3].request.contains("[\"hunter2\","));
+        assert!(!captured[3].request.contains("{\"secret\":\"hunter2\"}"));
+    }
+
+    #[test]
+    fn only_the_configured_calls_are_kept() {
+        let (io, capture) = io(CaptureConfig {
+            capacity: 2,
+            max_body_size: 80,
+        });
+        for value in 0..3 {
+            call(&io, "f", json!([value]));
+        }
+        let captured = capture.captured(false);
+        assert_eq!(captured.len(), 2);
+        assert!(captured[0].request.contains("[1]"));
+        assert!(captured[1].request.contains("[2]"));
+        assert!(!captured[1].truncated);
+
+        call(&io, "f", json!(["é".repeat(80)]));
+        let captured = capture.captured(true);
+        assert!(captured[1].truncated);
+        assert!(captured[1].request.len() <= 80);
+        assert!(capture.captured(false).is_empty());
+
+        capture.start(["g".to_owned()].into());
+        call(&io, "f", json!([]));
+        capture.stop();
+        call(&io, "f", json!([]));
+        assert!(capture.captured(false).is_empty());
+    }
+}
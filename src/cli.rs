@@ -0,0 +1,132 @@
+use {
+    clap::{Parser, Subcommand},
+    jsonrpc_protection::{client::Auth, strength::StrengthPolicy},
+    std::{fs, path::Path},
+};
+
+pub mod bench;
+pub mod call;
+pub mod check;
+pub mod config;
+pub mod config_file;
+pub mod connection;
+pub mod daemon;
+pub mod doctor;
+#[cfg(unix)]
+pub mod journald;
+pub mod policy;
+pub mod serve;
+pub mod systemd;
+pub mod user;
+
+#[derive(Parser)]
+#[command(about = "JSON-RPC server with protected admin methods")]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Server arguments, used when no subcommand is given.
+    #[command(flatten)]
+    pub serve: serve::Args,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the JSON-RPC server.  This is the default when no subcommand is given.
+    Serve(Box<serve::Args>),
+    /// Send a mix of protected and unprotected calls to a server and report latencies.
+    Bench(Box<bench::Args>),
+    /// Send a single call to a server and print the response.
+    Call(call::Args),
+    /// Ask a server how it would decide a call, and by which rules, without making it.
+    Check(check::Args),
+    /// Inspect the configuration of the server.
+    Config(config::Args),
+    /// Check that the environment is fit for running the server, and print a report.
+    Doctor(doctor::Args),
+    /// Test policy files before deploying them.
+    Policy(policy::Args),
+    /// Add, remove and list users in a users file.
+    User(user::Args),
+}
+
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    /// The systemd journal, with the fields of request logs kept separate.
+    Journald,
+}
+
+/// Sends log records to `output`, filtered according to `RUST_LOG`.
+pub fn init_logging(output: LogOutput) {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn,jsonrpc_protection=info"),
+    );
+
+    match output {
+        LogOutput::Stderr => builder.init(),
+        #[cfg(unix)]
+        LogOutput::Journald => match journald::JournaldLogger::new(builder.build()) {
+            Ok(logger) => logger.init().expect("The logger is only set once"),
+            Err(err) => {
+                builder.init();
+                log::warn!("Failed to connect to journald, logging to stderr: {err}");
+            }
+        },
+        #[cfg(not(unix))]
+        LogOutput::Journald => {
+            builder.init();
+            log::warn!("journald is only available on Unix, logging to stderr");
+        }
+    }
+}
+
+/// Minimum strength of passwords and tokens set through the server or the CLI.
+#[derive(clap::Args)]
+pub struct StrengthArgs {
+    /// Minimum length of new passwords and of a trusted-on-first-use admin token.
+    #[arg(long, default_value_t = StrengthPolicy::default().min_length)]
+    min_secret_length: usize,
+
+    /// Minimum estimated entropy, in bits, of new passwords and of a trusted-on-first-use
+    /// admin token.  `0` disables the check.
+    #[arg(long, default_value_t = StrengthPolicy::default().min_entropy_bits)]
+    min_secret_entropy: f64,
+}
+
+impl StrengthArgs {
+    pub fn policy(&self) -> StrengthPolicy {
+        StrengthPolicy {
+            min_length: self.min_secret_length,
+            min_entropy_bits: self.min_secret_entropy,
+        }
+    }
+}
+
+/// Reads a key file, reporting failures to the user.
+pub fn read_key(path: &Path) -> Option<Vec<u8>> {
+    match fs::read(path) {
+        Ok(key) => Some(key.trim_ascii().to_vec()),
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Credentials for calling protected methods, from an admin token file, reporting failures to
+/// the user.  No credentials without a file.
+pub fn read_admin_token(path: Option<&Path>) -> Option<Auth> {
+    let Some(path) = path else {
+        return Some(Auth::None);
+    };
+    match fs::read_to_string(path) {
+        Ok(token) => Some(Auth::AdminToken(token.trim().to_owned())),
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", path.display());
+            None
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Load generator used to measure the overhead of the protection middleware.
+
+use {
+    clap::Parser,
+    futures_util::future::join_all,
+    hyper::{StatusCode, Uri},
+    jsonrpc_protection::client::{Auth, Client, ClientError},
+    serde_json::Value,
+    std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    },
+    tokio::runtime,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Server to send requests to.
+    #[arg(long, default_value = "http://127.0.0.1:33481/")]
+    url: Uri,
+
+    /// Number of requests kept in flight at the same time.
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// How long to run, in seconds.
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+
+    /// Value for the `X-Admin-Auth` header.  The header is not sent when omitted.
+    #[arg(long)]
+    auth: Option<String>,
+
+    /// Percentage of calls that go to the protected method.
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+    protected_percent: u8,
+
+    /// Method that requires admin credentials.
+    #[arg(long, default_value = "f")]
+    protected_method: String,
+
+    /// Method that is available to everyone.
+    #[arg(long, default_value = "g")]
+    unprotected_method: String,
+
+    /// JSON array of parameters sent with every call.
+    #[arg(long, default_value = "[3, 4]", value_parser = parse_params)]
+    params: Value,
+}
+
+fn parse_params(params: &str) -> Result<Value, String> {
+    match serde_json::from_str(params) {
+        Ok(params @ Value::Array(_)) => Ok(params),
+        Ok(_) => Err("parameters must be a JSON array".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// How a single call ended.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Success,
+    RpcError { code: i64, message: String },
+    HttpStatus(StatusCode),
+    InvalidResponse,
+    Transport,
+}
+
+#[derive(Default)]
+struct WorkerReport {
+    latencies: Vec<Duration>,
+    outcomes: BTreeMap<Outcome, u64>,
+}
+
+pub fn run(args: Args) {
+    let rt = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let started = Instant::now();
+    let auth = match &args.auth {
+        Some(auth) => Auth::AdminToken(auth.clone()),
+        None => Auth::None,
+    };
+    let client = Client::new(args.url.clone()).with_auth(auth);
+
+    let reports = rt.block_on(async {
+        let deadline = started + Duration::from_secs(args.duration);
+
+        join_all((0..args.concurrency).map(|_| worker_loop(&client, &args, deadline))).await
+    });
+    let elapsed = started.elapsed();
+
+    let mut latencies = vec![];
+    let mut outcomes = BTreeMap::<Outcome, u64>::new();
+    for report in reports {
+        latencies.extend(report.latencies);
+        for (outcome, count) in report.outcomes {
+            *outcomes.entry(outcome).or_default() += count;
+        }
+    }
+    latencies.sort_unstable();
+
+    print_report(&latencies, &outcomes, elapsed);
+}
+
+async fn worker_loop(client: &Client, args: &Args, deadline: Instant) -> WorkerReport {
+    let mut report = WorkerReport::default();
+    let params = match &args.params {
+        Value::Array(params) => params.clone(),
+        _ => unreachable!("`parse_params` only accepts arrays"),
+    };
+
+    for call in 0u64.. {
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        let method = if is_protected_call(call, args.protected_percent) {
+            &args.protected_method
+        } else {
+            &args.unprotected_method
+        };
+
+        let started = Instant::now();
+        let outcome = match client.call(method, params.clone()).await {
+            Ok(_) => Outcome::Success,
+            Err(err) => Outcome::from(err),
+        };
+        report.latencies.push(started.elapsed());
+        *report.outcomes.entry(outcome).or_default() += 1;
+    }
+
+    report
+}
+
+/// Whether the `call`th call of a worker goes to the protected method.  Protected calls are
+/// spread evenly over the sequence, so that every worker sends the requested mix even during
+/// short runs.
+fn is_protected_call(call: u64, protected_percent: u8) -> bool {
+    let percent = u64::from(protected_percent);
+    (call + 1) * percent / 100 != call * percent / 100
+}
+
+impl From<ClientError> for Outcome {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::HttpStatus(status) => Outcome::HttpStatus(status),
+            ClientError::InvalidResponse(_) => Outcome::InvalidResponse,
+            ClientError::Transport(_)
+            | ClientError::InvalidRequestParts(_)
+            | ClientError::DeadlineExceeded => Outcome::Transport,
+            err => {
+                let error = err
+                    .rpc_error()
+                    .expect("All other variants carry an RPC error");
+                Outcome::RpcError {
+                    code: error.code.code(),
+                    message: error.message.clone(),
+                }
+            }
+        }
+    }
+}
+
+fn print_report(latencies: &[Duration], outcomes: &BTreeMap<Outcome, u64>, elapsed: Duration) {
+    let total = latencies.len();
+
+    println!(
+        "Calls: {total} in {:.2}s ({:.1} calls/s)",
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+
+    if total > 0 {
+        let percentile = |p: usize| latencies[(total - 1) * p / 100];
+
+        println!("Latency:");
+        println!("  p50: {:?}", percentile(50));
+        println!("  p90: {:?}", percentile(90));
+        println!("  p99: {:?}", percentile(99));
+        println!("  max: {:?}", latencies[total - 1]);
+    }
+
+    println!("Outcomes:");
+    for (outcome, count) in outcomes {
+        let outcome = match outcome {
+            Outcome::Success => "success".to_owned(),
+            Outcome::RpcError { code, message } => format!("RPC error {code}: {message}"),
+            Outcome::HttpStatus(status) => format!("HTTP {status}"),
+            Outcome::InvalidResponse => "invalid response".to_owned(),
+            Outcome::Transport => "transport error".to_owned(),
+        };
+        println!("  {outcome}: {count}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_calls_make_up_the_requested_percentage() {
+        for percent in [0, 1, 33, 50, 99, 100] {
+            let protected = (0..100)
+                .filter(|&call| is_protected_call(call, percent))
+                .count();
+            assert_eq!(protected, usize::from(percent));
+        }
+        // Spread out, rather than bunched up at the start.
+        let first_half = (0..50).filter(|&call| is_protected_call(call, 10)).count();
+        assert_eq!(first_half, 5);
+    }
+
+    #[test]
+    fn params_must_be_an_array() {
+        assert_eq!(
+            parse_params("[1, \"a\"]").unwrap(),
+            serde_json::json!([1, "a"])
+        );
+        assert!(parse_params("{\"a\": 1}").is_err());
+        assert!(parse_params("[1,").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! One-off calls for testing a running server by hand.
+
+use {
+    super::read_key,
+    clap::Parser,
+    hyper::Uri,
+    jsonrpc_protection::{
+        client::{Auth, Client},
+        signing::{RequestSigner, ResponseSigner},
+    },
+    serde_json::Value,
+    std::{path::PathBuf, process::ExitCode},
+    tokio::runtime,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Server to send the request to.
+    #[arg(long, default_value = "http://127.0.0.1:33481/")]
+    url: Uri,
+
+    /// Admin credentials, sent in the `X-Admin-Auth` header.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// File holding the key used to sign the request, instead of sending `--token`.
+    #[arg(long, conflicts_with = "token")]
+    signing_key_file: Option<PathBuf>,
+
+    /// Id of the `--signing-key-file` key, for servers that accept several keys.
+    #[arg(long, value_name = "ID", requires = "signing_key_file")]
+    signing_key_id: Option<String>,
+
+    /// File holding the key used by the server to sign responses.  When given, responses
+    /// without a valid signature are rejected.
+    #[arg(long)]
+    verify_key_file: Option<PathBuf>,
+
+    /// Method to call.
+    method: String,
+
+    /// Method parameters.  Each one is parsed as JSON, and is sent as a string if that fails.
+    params: Vec<String>,
+}
+
+/// A parameter given on the command line, as JSON, or as a string if it is not valid JSON.
+fn parse_param(param: &str) -> Value {
+    serde_json::from_str(param).unwrap_or_else(|_| Value::from(param))
+}
+
+pub fn run(args: Args) -> ExitCode {
+    let params = args
+        .params
+        .iter()
+        .map(|param| parse_param(param))
+        .collect::<Vec<_>>();
+
+    let auth = match (args.token, &args.signing_key_file) {
+        (Some(token), _) => Auth::AdminToken(token),
+        (None, Some(path)) => {
+            let Some(key) = read_key(path) else {
+                return ExitCode::FAILURE;
+            };
+            let mut signer = RequestSigner::new(&key);
+            if let Some(id) = args.signing_key_id {
+                signer = signer.key_id(id);
+            }
+            Auth::Signature(signer)
+        }
+        (None, None) => Auth::None,
+    };
+    let mut client = Client::new(args.url).with_auth(auth);
+
+    if let Some(path) = &args.verify_key_file {
+        let Some(key) = read_key(path) else {
+            return ExitCode::FAILURE;
+        };
+        client = client.with_response_verification(ResponseSigner::new(&key));
+    }
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    match rt.block_on(client.call(&args.method, params)) {
+        Ok(result) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&result).expect("Values always serialize")
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            if let Some(data) = err.rpc_error().and_then(|error| error.data.as_ref()) {
+                eprintln!(
+                    "{}",
+                    serde_json::to_string_pretty(data).expect("Values always serialize")
+                );
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[test]
+    fn params_are_json_or_strings() {
+        assert_eq!(parse_param("3"), json!(3));
+        assert_eq!(parse_param("\"3\""), json!("3"));
+        assert_eq!(parse_param("[1, {\"a\": null}]"), json!([1, {"a": null}]));
+        assert_eq!(parse_param("alice"), json!("alice"));
+        assert_eq!(parse_param("{"), json!("{"));
+    }
+}
@@ -0,0 +1,123 @@
+//! Asking a running server how it would decide a call, without making it.
+
+use {
+    super::read_admin_token,
+    clap::Parser,
+    hyper::Uri,
+    jsonrpc_protection::{client::Client, state::Decision},
+    serde_json::{json, Map, Value},
+    std::{net::IpAddr, path::PathBuf, process::ExitCode},
+    tokio::runtime,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Server to ask, with `admin_check`.
+    #[arg(long, default_value = "http://127.0.0.1:33481/")]
+    url: Uri,
+
+    /// File holding the admin token `admin_check` is called with.
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// Method of the call.
+    #[arg(long)]
+    method: String,
+
+    /// `X-Admin-Auth` value the call is made with.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// User the call is made as, with the `X-Run-As` header.
+    #[arg(long, value_name = "USER")]
+    run_as: Option<String>,
+
+    /// Address the call comes from.
+    #[arg(long)]
+    ip: Option<IpAddr>,
+
+    /// Caller attribute, as `NAME=VALUE`.  Can be given multiple times.
+    #[arg(long = "attribute", value_name = "NAME=VALUE", value_parser = parse_attribute)]
+    attributes: Vec<(String, String)>,
+
+    /// Parameters of the call.  Each one is parsed as JSON, and is sent as a string if that
+    /// fails.
+    params: Vec<String>,
+}
+
+fn parse_attribute(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(format!("expected NAME=VALUE, got \"{s}\"")),
+    }
+}
+
+pub fn run(args: Args) -> ExitCode {
+    let Some(auth) = read_admin_token(args.admin_token_file.as_deref()) else {
+        return ExitCode::FAILURE;
+    };
+    let client = Client::new(args.url).with_auth(auth);
+
+    let params = args
+        .params
+        .iter()
+        .map(|param| serde_json::from_str(param).unwrap_or_else(|_| Value::from(param.as_str())))
+        .collect::<Vec<_>>();
+    let mut request = json!({ "method": args.method, "params": params });
+    if let Some(token) = args.token {
+        request["token"] = token.into();
+    }
+    if let Some(user) = args.run_as {
+        request["run_as"] = user.into();
+    }
+    if let Some(ip) = args.ip {
+        request["ip"] = ip.to_string().into();
+    }
+    request["attributes"] = Value::Object(
+        args.attributes
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect::<Map<_, _>>(),
+    );
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let decision = match rt.block_on(client.call("admin_check", vec![request])) {
+        Ok(decision) => decision,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let decision = match serde_json::from_value::<Decision>(decision) {
+        Ok(decision) => decision,
+        Err(err) => {
+            eprintln!("Unexpected response from the server: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "{} {} as {}, by the {} rules",
+        if decision.allowed { "ALLOW" } else { "DENY " },
+        args.method,
+        decision.caller,
+        decision.rule_set,
+    );
+    println!("  access: {}", decision.access);
+    for rule in &decision.rules {
+        println!("  rule: {rule}");
+    }
+    if let Some(reason) = decision.reason {
+        let reason = serde_json::to_value(reason).expect("Reasons always serialize");
+        println!(
+            "  reason: {}: {}",
+            reason.as_str().unwrap_or_default(),
+            decision.message.unwrap_or_default(),
+        );
+    }
+    ExitCode::SUCCESS
+}
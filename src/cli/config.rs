@@ -0,0 +1,154 @@
+//! Inspecting the configuration of the server.
+//!
+//! The server is configured with command line arguments, configuration files, see
+//! [`config_file`](super::config_file), and `RUST_LOG`.  `config show` merges these with the
+//! defaults.
+
+use {
+    super::{config_file, serve},
+    clap::{parser::ValueSource, ArgAction, CommandFactory, Parser, Subcommand},
+    std::{
+        env,
+        ffi::OsString,
+        io::{self, Read},
+        process::ExitCode,
+    },
+};
+
+/// Arguments whose values may carry credentials, such as the user part of a URL.
+const SECRET_ARGS: [&str; 2] = ["slo_webhook", "break_glass_webhook"];
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the configuration `serve` would run with, given the same arguments, and where
+    /// every value comes from.  Secrets are masked.
+    Show {
+        /// Arguments to `serve`.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    /// Encrypt a value for a configuration file, with the key in the
+    /// `JSONRPC_PROTECTION_CONFIG_KEY` environment variable.  The value is read from standard
+    /// input, without a trailing newline.
+    Encrypt,
+}
+
+pub fn run(args: Args) -> ExitCode {
+    match args.command {
+        Command::Show { args } => show(args),
+        Command::Encrypt => encrypt(),
+    }
+}
+
+fn show(args: Vec<OsString>) -> ExitCode {
+    let expanded = match config_file::expand(args) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let command = serve::Args::command().no_binary_name(true);
+    let matches = match command.clone().try_get_matches_from(expanded.args) {
+        Ok(matches) => matches,
+        Err(err) => err.exit(),
+    };
+
+    for arg in command.get_arguments() {
+        if matches!(arg.get_action(), ArgAction::Help | ArgAction::Version) {
+            continue;
+        }
+        let id = arg.get_id().as_str();
+        let name = arg
+            .get_long()
+            .map_or_else(|| id.to_owned(), |long| format!("--{long}"));
+
+        let (value, source) = match (matches.get_raw(id), matches.value_source(id)) {
+            (Some(values), Some(source)) => {
+                let values = values
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .map(|value| {
+                        if expanded.encrypted.contains(id) {
+                            "****".to_owned()
+                        } else if SECRET_ARGS.contains(&id) {
+                            mask_url(&value)
+                        } else {
+                            value
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let source = match expanded.sources.get(id) {
+                    Some(path) => path.display().to_string(),
+                    None => source_name(source).to_owned(),
+                };
+                (values.join(" "), source)
+            }
+            _ => ("-".to_owned(), "unset".to_owned()),
+        };
+        println!("{name} {value}  # {source}");
+    }
+
+    let admin_token = match matches.value_source("admin_token_file") {
+        Some(ValueSource::CommandLine) => "from --admin-token-file",
+        _ => "built-in default, set one with --admin-token-file",
+    };
+    println!("# admin token: {admin_token}");
+
+    println!(
+        "RUST_LOG {}  # {}",
+        env::var("RUST_LOG").unwrap_or_else(|_| "warn,jsonrpc_protection=info".to_owned()),
+        if env::var_os("RUST_LOG").is_some() {
+            "environment"
+        } else {
+            "default"
+        },
+    );
+
+    ExitCode::SUCCESS
+}
+
+fn encrypt() -> ExitCode {
+    let Ok(key) = env::var(config_file::CONFIG_KEY_VAR) else {
+        eprintln!("{} is not set", config_file::CONFIG_KEY_VAR);
+        return ExitCode::FAILURE;
+    };
+    let mut value = String::new();
+    if let Err(err) = io::stdin().read_to_string(&mut value) {
+        eprintln!("Failed to read the value: {err}");
+        return ExitCode::FAILURE;
+    }
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    println!("{}", config_file::encrypt(&key, value));
+    ExitCode::SUCCESS
+}
+
+fn source_name(source: ValueSource) -> &'static str {
+    match source {
+        ValueSource::CommandLine => "command line",
+        ValueSource::EnvVariable => "environment",
+        _ => "default",
+    }
+}
+
+/// Replaces the user information and the query of `url`, which may hold credentials.
+fn mask_url(url: &str) -> String {
+    let (url, query) = match url.split_once('?') {
+        Some((url, _)) => (url, "?****"),
+        None => (url, ""),
+    };
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return format!("{url}{query}");
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let authority = match authority.rsplit_once('@') {
+        Some((_, host)) => format!("****@{host}"),
+        None => authority.to_owned(),
+    };
+    format!("{scheme}://{authority}{path}{query}")
+}
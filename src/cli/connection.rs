@@ -0,0 +1,539 @@
+//! Limits on the lifetime of HTTP connections, see [`ConnectionLimits`].
+//!
+//! hyper keeps a connection open for as long as the client does.  Long-polling clients rely on
+//! that, but connections left open by clients that went away hold on to a file descriptor and
+//! some memory each, and clients that never reconnect do not spread over instances added behind
+//! a load balancer.  So connections can be closed once they were inactive for a while, or
+//! served enough requests.
+//!
+//! The number of connections from one address can be limited as well, see
+//! [`Incoming::per_ip_limit`], so that a single client can not use up the file descriptors of
+//! the server by opening connections and leaving them open.
+
+use {
+    hyper::{
+        header::{self, HeaderValue},
+        server::{
+            accept::Accept,
+            conn::{AddrIncoming, AddrStream},
+        },
+        service::Service,
+        Body, Request, Response,
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        convert::Infallible,
+        future::Future,
+        io::{self, IoSlice},
+        net::{IpAddr, SocketAddr},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{ready, Context, Poll},
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        time::{Instant, Sleep},
+    },
+};
+
+/// When connections are closed.  Unlimited by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionLimits {
+    /// How long a connection may go without sending or receiving anything while no request is
+    /// handled on it, that is, while it waits for its next request.
+    pub keep_alive_timeout: Option<Duration>,
+    /// How long a connection may go without sending or receiving anything, even while a
+    /// request is handled on it.
+    pub idle_timeout: Option<Duration>,
+    /// Number of requests served on a connection, after which it is closed.  The last response
+    /// has a `Connection: close` header.
+    pub max_requests: Option<u64>,
+}
+
+/// Connections accepted by an [`AddrIncoming`], closed according to the limits.
+pub struct Incoming {
+    incoming: AddrIncoming,
+    limits: ConnectionLimits,
+    per_ip_limit: Option<PerIpLimit>,
+}
+
+impl Incoming {
+    pub fn new(incoming: AddrIncoming, limits: ConnectionLimits) -> Self {
+        Self {
+            incoming,
+            limits,
+            per_ip_limit: None,
+        }
+    }
+
+    /// Close new connections from addresses that already have `max` connections open, except
+    /// from `trusted_proxies`.  Proxies, such as load balancers, forward the requests of many
+    /// clients over their connections.
+    pub fn per_ip_limit(mut self, max: usize, trusted_proxies: HashSet<IpAddr>) -> Self {
+        self.per_ip_limit = Some(PerIpLimit {
+            max,
+            trusted_proxies: trusted_proxies.iter().map(IpAddr::to_canonical).collect(),
+            open: Default::default(),
+        });
+        self
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            let stream = match ready!(Pin::new(&mut self.incoming).poll_accept(cx)) {
+                Some(Ok(stream)) => stream,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            };
+            // IPv4 clients of dual-stack listeners connect from IPv4-mapped IPv6 addresses.
+            let ip = stream.remote_addr().ip().to_canonical();
+            let slot = match &self.per_ip_limit {
+                Some(limit) if !limit.trusted_proxies.contains(&ip) => match limit.take_slot(ip) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        log::debug!("Closing a connection from {ip}, it has too many open already");
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+            return Poll::Ready(Some(Ok(Connection::new(stream, self.limits, slot))));
+        }
+    }
+}
+
+/// See [`Incoming::per_ip_limit`].
+struct PerIpLimit {
+    max: usize,
+    trusted_proxies: HashSet<IpAddr>,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl PerIpLimit {
+    /// `None` if `ip` has the maximum number of connections open.
+    fn take_slot(&self, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            ip,
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// Counts a connection against the limit of its address until dropped.
+struct IpSlot {
+    ip: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// A connection that fails with [`io::ErrorKind::TimedOut`] once it was inactive for too long,
+/// which makes hyper close it.
+pub struct Connection {
+    stream: AddrStream,
+    limits: ConnectionLimits,
+    /// Requests handled on the connection at the moment, see [`ConnectionService`].
+    in_flight: Arc<AtomicUsize>,
+    last_active: Instant,
+    timer: Option<Pin<Box<Sleep>>>,
+    _slot: Option<IpSlot>,
+}
+
+impl Connection {
+    fn new(stream: AddrStream, limits: ConnectionLimits, slot: Option<IpSlot>) -> Self {
+        Self {
+            stream,
+            limits,
+            in_flight: Default::default(),
+            last_active: Instant::now(),
+            timer: None,
+            _slot: slot,
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.stream.remote_addr()
+    }
+
+    /// Wraps `service` to handle the requests received over this connection.
+    pub fn service<S>(&self, service: S) -> ConnectionService<S> {
+        ConnectionService {
+            service,
+            in_flight: self.in_flight.clone(),
+            max_requests: self.limits.max_requests,
+            served: 0,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let keep_alive_timeout = self
+            .limits
+            .keep_alive_timeout
+            .filter(|_| self.in_flight.load(Ordering::Relaxed) == 0);
+        [keep_alive_timeout, self.limits.idle_timeout]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|timeout| self.last_active + timeout)
+    }
+
+    /// Called whenever the stream is not ready, so the deadline follows requests starting and
+    /// finishing.
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let Some(deadline) = self.deadline() else {
+            return Poll::Pending;
+        };
+        let timer = self
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+        if timer.deadline() != deadline {
+            timer.as_mut().reset(deadline);
+        }
+        ready!(timer.as_mut().poll(cx));
+        Poll::Ready(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection was inactive for too long",
+        ))
+    }
+
+    fn record_progress(&mut self, result: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        match result {
+            Poll::Ready(Ok(written)) if written > 0 => self.last_active = Instant::now(),
+            _ => (),
+        }
+        result
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    self.last_active = Instant::now();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => self.poll_timeout(cx).map(Err),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.stream).poll_write(cx, buf) {
+            Poll::Pending => self.poll_timeout(cx).map(Err),
+            result => self.record_progress(result),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.stream).poll_write_vectored(cx, bufs) {
+            Poll::Pending => self.poll_timeout(cx).map(Err),
+            result => self.record_progress(result),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Counts the requests handled on a [`Connection`], and closes it after the last one allowed.
+pub struct ConnectionService<S> {
+    service: S,
+    in_flight: Arc<AtomicUsize>,
+    max_requests: Option<u64>,
+    served: u64,
+}
+
+impl<S> Service<Request<Body>> for ConnectionService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.served += 1;
+        let last = self.max_requests.is_some_and(|max| self.served >= max);
+        let in_flight = InFlight::new(self.in_flight.clone());
+        let response = self.service.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            drop(in_flight);
+            if last {
+                response
+                    .headers_mut()
+                    .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Counts a request as handled until dropped, including when the client goes away first.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        hyper::{
+            service::{make_service_fn, service_fn},
+            Server,
+        },
+        std::net::Ipv4Addr,
+        tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        },
+    };
+
+    /// Serves `ok` on all paths, after a while on `/slow`.
+    async fn serve(limits: ConnectionLimits, per_ip: Option<(usize, &[IpAddr])>) -> SocketAddr {
+        let incoming = AddrIncoming::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = incoming.local_addr();
+        let mut incoming = Incoming::new(incoming, limits);
+        if let Some((max, trusted_proxies)) = per_ip {
+            incoming = incoming.per_ip_limit(max, trusted_proxies.iter().copied().collect());
+        }
+        let make_service = make_service_fn(|conn: &Connection| {
+            let service = conn.service(service_fn(|request: Request<Body>| async move {
+                if request.uri().path() == "/slow" {
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                }
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            }));
+            async move { Ok::<_, Infallible>(service) }
+        });
+        tokio::spawn(Server::builder(incoming).serve(make_service));
+        addr
+    }
+
+    /// The response, or what was received before the connection was closed.
+    async fn get(stream: &mut TcpStream, path: &str) -> String {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n");
+        if stream.write_all(request.as_bytes()).await.is_err() {
+            return String::new();
+        }
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.ends_with(b"\r\n\r\nok") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => response.extend_from_slice(&buf[..len]),
+            }
+        }
+        String::from_utf8(response).unwrap().to_lowercase()
+    }
+
+    fn served(response: &str) -> bool {
+        response.starts_with("http/1.1 200 ok") && response.ends_with("ok")
+    }
+
+    async fn closed(stream: &mut TcpStream) -> bool {
+        let mut buf = [0; 16];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+        matches!(read.await, Ok(Ok(0) | Err(_)))
+    }
+
+    #[test]
+    fn slots_are_released_on_drop() {
+        let limit = PerIpLimit {
+            max: 2,
+            trusted_proxies: HashSet::new(),
+            open: Default::default(),
+        };
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let first = limit.take_slot(client).unwrap();
+        let second = limit.take_slot(client).unwrap();
+        assert!(limit.take_slot(client).is_none());
+        let other = limit.take_slot(IpAddr::from([10, 0, 0, 2])).unwrap();
+
+        drop(first);
+        let third = limit.take_slot(client).unwrap();
+        assert!(limit.take_slot(client).is_none());
+
+        drop((second, third, other));
+        assert!(limit.open.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_per_ip_are_limited() {
+        let addr = serve(ConnectionLimits::default(), Some((2, &[]))).await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut first, "/").await));
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut second, "/").await));
+
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut third).await);
+        assert!(served(&get(&mut second, "/").await));
+
+        // The slot is free once the server noticed the connection is gone.
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut next = TcpStream::connect(addr).await.unwrap();
+            if served(&get(&mut next, "/").await) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "the slot was not released");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn trusted_proxies_are_not_limited() {
+        // Trusted proxies can be given as IPv4-mapped addresses too.
+        let proxy = IpAddr::from(Ipv4Addr::LOCALHOST.to_ipv6_mapped());
+        let addr = serve(ConnectionLimits::default(), Some((1, &[proxy]))).await;
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            assert!(served(&get(&mut stream, "/").await));
+            streams.push(stream);
+        }
+
+        let addr = serve(
+            ConnectionLimits::default(),
+            Some((1, &[[10, 0, 0, 1].into()])),
+        )
+        .await;
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut first, "/").await));
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut second).await);
+    }
+
+    #[tokio::test]
+    async fn inactive_connections_are_closed() {
+        let timeout = Duration::from_millis(200);
+        let limits = ConnectionLimits {
+            keep_alive_timeout: Some(timeout),
+            ..Default::default()
+        };
+        let addr = serve(limits, None).await;
+
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        assert!(closed(&mut silent).await);
+        assert!(start.elapsed() >= timeout);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut stream, "/").await));
+        let start = Instant::now();
+        assert!(closed(&mut stream).await);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn keep_alive_timeout_spares_requests_in_flight() {
+        let limits = ConnectionLimits {
+            keep_alive_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let addr = serve(limits, None).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut stream, "/slow").await));
+        assert!(served(&get(&mut stream, "/").await));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_cuts_requests_in_flight() {
+        let limits = ConnectionLimits {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let addr = serve(limits, None).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(served(&get(&mut stream, "/").await));
+        assert_eq!(get(&mut stream, "/slow").await, "");
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_after_max_requests() {
+        let limits = ConnectionLimits {
+            max_requests: Some(2),
+            ..Default::default()
+        };
+        let addr = serve(limits, None).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let first = get(&mut stream, "/").await;
+        assert!(served(&first));
+        assert!(!first.contains("connection: close"));
+        let last = get(&mut stream, "/").await;
+        assert!(served(&last));
+        assert!(last.contains("connection: close"));
+        assert!(closed(&mut stream).await);
+    }
+}
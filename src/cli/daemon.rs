@@ -0,0 +1,106 @@
+//! Running in the background, for hosts without a service manager.
+
+use std::{fs, io, path::Path, process};
+
+/// Makes sure no other server is using `path`, removing it if it was left behind by a server
+/// that is no longer running.
+pub fn check_pidfile(path: &Path) -> io::Result<()> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if let Ok(pid) = content.trim().parse::<u32>() {
+        if is_running(pid) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("the server is already running with PID {pid}"),
+            ));
+        }
+    }
+
+    log::warn!("Removing stale pidfile {}", path.display());
+    fs::remove_file(path)
+}
+
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists.
+    let result = unsafe { libc::kill(pid, 0) };
+    // `EPERM` means the process exists, but belongs to someone else.
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    // There is no cheap way to tell, so the pidfile is assumed to be stale.
+    false
+}
+
+/// Detaches from the terminal, and continues in a grandchild process, with standard input
+/// read from `/dev/null`, and output written to `log_file`, or discarded.  The original
+/// process exits.
+///
+/// Must be called before any threads are started, as only the calling thread survives.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    use std::{fs::OpenOptions, os::unix::io::AsRawFd};
+
+    // Opened before forking, so that errors are still seen by the user.
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => null.try_clone()?,
+    };
+
+    fork_and_exit_parent()?;
+    // SAFETY: no preconditions.  Becomes the leader of a new session, without a terminal.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // The session leader exits, so that the server can never get a terminal again.
+    fork_and_exit_parent()?;
+
+    for (source, target) in [
+        (&null, libc::STDIN_FILENO),
+        (&output, libc::STDOUT_FILENO),
+        (&output, libc::STDERR_FILENO),
+    ] {
+        // SAFETY: both descriptors are open, and the standard ones are owned by the process.
+        if unsafe { libc::dup2(source.as_raw_fd(), target) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: the process is single threaded, see `daemonize()`.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // SAFETY: `_exit` skips destructors and atexit handlers, which belong to the child now.
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "running in the background is only supported on Unix",
+    ))
+}
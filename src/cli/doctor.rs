@@ -0,0 +1,157 @@
+//! Checks of the environment the server is about to run in.
+
+use {
+    clap::Parser,
+    hyper::{body, header, Body, Method, Request, Uri},
+    jsonrpc_protection::signing::DEFAULT_MAX_CLOCK_SKEW,
+    std::{
+        fmt::Display,
+        fs,
+        net::{SocketAddr, TcpListener},
+        path::PathBuf,
+        process::ExitCode,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::runtime,
+};
+
+/// Clocks showing an earlier time are certainly wrong: this is 2024-01-01.
+const EARLIEST_SANE_TIME: Duration = Duration::from_secs(1_704_067_200);
+
+#[derive(Parser)]
+pub struct Args {
+    /// Address the server is going to accept connections on.
+    #[arg(long, default_value = "0.0.0.0:33481")]
+    listen: SocketAddr,
+
+    /// Address the server is going to accept WebSocket connections on.
+    #[arg(long)]
+    ws_listen: Option<SocketAddr>,
+
+    /// Key file the server is going to read, see `serve --request-signing-key-file` and
+    /// `serve --response-signing-key-file`.  Can be given multiple times.
+    #[arg(long = "key-file", value_name = "PATH")]
+    key_files: Vec<PathBuf>,
+
+    /// A running server to check.  Its clock is compared to the local one, as signed requests
+    /// are rejected when the two disagree.
+    #[arg(long)]
+    url: Option<Uri>,
+}
+
+/// Prints the results of checks as they are made, and counts the failures.
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn check(&mut self, what: impl Display, result: Result<String, String>) {
+        match result {
+            Ok(details) => println!("PASS  {what}: {details}"),
+            Err(details) => {
+                println!("FAIL  {what}: {details}");
+                self.failures += 1;
+            }
+        }
+    }
+}
+
+pub fn run(args: Args) -> ExitCode {
+    let mut report = Report { failures: 0 };
+
+    report.check(format!("--listen {}", args.listen), bindable(args.listen));
+    if let Some(addr) = args.ws_listen {
+        report.check(format!("--ws-listen {addr}"), bindable(addr));
+    }
+
+    for path in &args.key_files {
+        let result = match fs::read(path) {
+            Ok(key) if key.trim_ascii().is_empty() => Err("the file is empty".to_owned()),
+            Ok(_) => Ok("readable".to_owned()),
+            Err(err) => Err(err.to_string()),
+        };
+        report.check(format!("key file {}", path.display()), result);
+    }
+
+    let now = SystemTime::now();
+    report.check("local clock", local_clock(now));
+
+    if let Some(url) = args.url {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        match rt.block_on(server_date(&url)) {
+            Ok(date) => {
+                report.check(format!("server {url}"), Ok("responding".to_owned()));
+                report.check(format!("clock of {url}"), clock_skew(now, date));
+            }
+            Err(err) => report.check(format!("server {url}"), Err(err)),
+        }
+    }
+
+    if report.failures == 0 {
+        println!("All checks passed");
+        ExitCode::SUCCESS
+    } else {
+        println!("{} checks failed", report.failures);
+        ExitCode::FAILURE
+    }
+}
+
+fn bindable(addr: SocketAddr) -> Result<String, String> {
+    // The listener is closed right away, so this does not get in the way of the server.
+    TcpListener::bind(addr)
+        .map(|_| "can be bound".to_owned())
+        .map_err(|err| err.to_string())
+}
+
+fn local_clock(now: SystemTime) -> Result<String, String> {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if since_epoch < EARLIEST_SANE_TIME {
+        return Err(format!(
+            "shows {}, which is in the past",
+            httpdate::fmt_http_date(now)
+        ));
+    }
+    Ok(httpdate::fmt_http_date(now))
+}
+
+/// Sends a request to `url`, and returns the `Date` of the response.  Any HTTP response will
+/// do, as the request is not a valid call.
+async fn server_date(url: &Uri) -> Result<SystemTime, String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{}"))
+        .map_err(|err| err.to_string())?;
+    let response = hyper::Client::new()
+        .request(request)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let date = response
+        .headers()
+        .get(header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok())
+        .ok_or_else(|| "response has no valid Date header".to_owned());
+    // Not interested in the content, only in finishing the exchange cleanly.
+    let _ = body::to_bytes(response.into_body()).await;
+    date
+}
+
+fn clock_skew(local: SystemTime, server: SystemTime) -> Result<String, String> {
+    // `Date` only has a resolution of a second.
+    let skew = match server.duration_since(local) {
+        Ok(ahead) => ahead,
+        Err(behind) => behind.duration(),
+    };
+    let details = format!("differs from the local one by {}s", skew.as_secs());
+    if skew > DEFAULT_MAX_CLOCK_SKEW {
+        Err(format!("{details}, signed requests would be rejected"))
+    } else {
+        Ok(details)
+    }
+}
@@ -0,0 +1,125 @@
+//! Logging straight into the systemd journal, keeping the key-values of every record as
+//! separate fields, so that `journalctl METHOD=admin_stats` finds the calls to a method.
+//!
+//! Records are sent over the native protocol, see `systemd-journald.service(8)`.  Records that
+//! cannot be sent, because journald is not running, or because they are too large for a single
+//! datagram, are written to stderr instead.
+
+use {
+    log::{
+        kv::{self, Key, Value, VisitSource},
+        Level, Log, Metadata, Record,
+    },
+    std::{
+        io::{self, Write},
+        os::unix::net::UnixDatagram,
+    },
+};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "jsonrpc-protection";
+
+pub struct JournaldLogger {
+    socket: UnixDatagram,
+    /// Decides which records are logged, according to `RUST_LOG`.
+    filter: env_logger::Logger,
+}
+
+impl JournaldLogger {
+    pub fn new(filter: env_logger::Logger) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+        Ok(Self { socket, filter })
+    }
+
+    /// Installs the logger for the `log` crate.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.filter.filter());
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let mut entry = vec![];
+        add_field(&mut entry, "MESSAGE", &record.args().to_string());
+        add_field(&mut entry, "PRIORITY", priority(record.level()));
+        add_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        add_field(&mut entry, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            add_field(&mut entry, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            add_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        let _ = record.key_values().visit(&mut Fields(&mut entry));
+
+        if self.socket.send(&entry).is_err() {
+            let _ = writeln!(
+                io::stderr(),
+                "[{} {}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        if let Some(name) = field_name(key.as_str()) {
+            add_field(self.0, &name, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Field names may only contain uppercase letters, digits and underscores, and may not start
+/// with an underscore or a digit, which are reserved for journald.  `None` if nothing is left.
+fn field_name(key: &str) -> Option<String> {
+    let mut name = String::with_capacity(key.len());
+    for c in key.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => name.push(c.to_ascii_uppercase()),
+            _ => name.push('_'),
+        }
+    }
+    let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+/// Values containing a newline are length-prefixed, everything else is `NAME=value`.
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Syslog priority of `level`, see `syslog(3)`.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
@@ -0,0 +1,379 @@
+//! Checking policy files, as served at `serve --policy-url`, before deploying them, and
+//! exporting the policy of a running server.
+
+use {
+    super::read_admin_token,
+    clap::{Parser, Subcommand},
+    hyper::Uri,
+    jsonrpc_core::{
+        types::request::{Call, MethodCall},
+        Id, Params, Version,
+    },
+    jsonrpc_protection::{
+        admin_rpc::{AdminRpc, AdminRpcImpl},
+        client::Client,
+        diagnostic,
+        main_rpc::{MainRpc, MainRpcImpl},
+        openrpc,
+        policy::Policy,
+        session::Grant,
+        state::{ProtectionState, Role},
+        RpcMeta,
+    },
+    serde::Deserialize,
+    serde_json::Value,
+    std::{
+        collections::{BTreeMap, HashSet},
+        fs,
+        net::IpAddr,
+        path::{Path, PathBuf},
+        process::ExitCode,
+        time::Duration,
+    },
+    tokio::runtime,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the rules a running server applies, and the methods it protects, as JSON, with
+    /// `admin_policy`.
+    Export {
+        /// Server to ask.
+        #[arg(long, default_value = "http://127.0.0.1:33481/")]
+        url: Uri,
+
+        /// File holding the admin token `admin_policy` is called with.
+        #[arg(long)]
+        admin_token_file: Option<PathBuf>,
+    },
+    /// Print a policy with the parameter constraints of an OpenRPC document, see
+    /// `jsonrpc_protection::openrpc`.  Fails if the document and the server disagree on which
+    /// methods exist or are protected.
+    ImportOpenrpc {
+        /// The OpenRPC document, as JSON.
+        spec: PathBuf,
+    },
+    /// Print the problems of a policy, which keep the server from applying it, and the rules
+    /// that have no effect, or keep a method from ever being called.  Exits with a failure if
+    /// there are any.
+    Lint {
+        /// The policy, as JSON.
+        policy: PathBuf,
+    },
+    /// Check the calls described in a scenarios file against a policy, and print which of
+    /// them are not allowed or denied as expected.  Exits with a failure if any are not.
+    Test {
+        /// The policy, as JSON.
+        #[arg(long)]
+        policy: PathBuf,
+
+        /// JSON array of scenarios, each with the `method` called, the `params` passed, and
+        /// whether the call is expected to be `"allow"`ed or `"deny"`ed.  Optionally, the
+        /// `role` and `user` of the caller, the caller `ip`, the caller `attributes`, and
+        /// `"canary": true` to check against the canary of the policy.
+        scenarios: PathBuf,
+    },
+}
+
+/// A call checked by `policy test`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    /// Shown in the report.  The method is shown when omitted.
+    name: Option<String>,
+    #[serde(default = "anonymous")]
+    role: Role,
+    /// User the caller logged in as, for ownership rules.
+    user: Option<String>,
+    /// Address of the caller, for loopback methods.
+    ip: Option<IpAddr>,
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+    method: String,
+    params: Option<Value>,
+    /// Whether the rules of the canary are applied, rather than the main ones.
+    #[serde(default)]
+    canary: bool,
+    expect: Decision,
+}
+
+fn anonymous() -> Role {
+    Role::Anonymous
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Decision {
+    Allow,
+    Deny,
+}
+
+pub fn run(args: Args) -> ExitCode {
+    match args.command {
+        Command::Export {
+            url,
+            admin_token_file,
+        } => export(url, admin_token_file.as_deref()),
+        Command::ImportOpenrpc { spec } => import_openrpc(&spec),
+        Command::Lint { policy } => lint(&policy),
+        Command::Test { policy, scenarios } => test(&policy, &scenarios),
+    }
+}
+
+/// All methods of the server, and the protected ones, that policies refer to.
+fn methods() -> (HashSet<String>, HashSet<String>) {
+    let methods = MainRpcImpl
+        .to_delegate()
+        .into_iter()
+        .chain(AdminRpcImpl.to_delegate())
+        .map(|(name, _)| name)
+        .collect();
+    let protected = AdminRpcImpl
+        .to_delegate()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    (methods, protected)
+}
+
+fn export(url: Uri, admin_token_file: Option<&Path>) -> ExitCode {
+    let Some(auth) = read_admin_token(admin_token_file) else {
+        return ExitCode::FAILURE;
+    };
+    let client = Client::new(url).with_auth(auth);
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    match rt.block_on(client.call("admin_policy", Vec::new())) {
+        Ok(policy) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&policy).expect("Values always serialize")
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn import_openrpc(spec: &Path) -> ExitCode {
+    let json = match fs::read_to_string(spec) {
+        Ok(json) => json,
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", spec.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let rules = match openrpc::import(&json) {
+        Ok(rules) => rules,
+        Err(err) => {
+            eprintln!("{}: {err}", spec.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (methods, protected) = methods();
+    let mut problems = rules.problems;
+    for method in rules
+        .methods
+        .iter()
+        .filter(|method| !methods.contains(*method))
+    {
+        problems.push(format!("{method}: there is no such method"));
+    }
+    for method in &methods {
+        let in_spec = rules.methods.contains(method);
+        match (protected.contains(method), rules.protected.contains(method)) {
+            (true, false) if in_spec => problems.push(format!(
+                "{method}: the server protects it, the spec does not"
+            )),
+            (false, true) => problems.push(format!(
+                "{method}: the spec protects it, the server does not"
+            )),
+            _ => (),
+        }
+    }
+    if !problems.is_empty() {
+        for problem in problems {
+            eprintln!("{}: {problem}", spec.display());
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let policy = Policy {
+        param_constraints: rules.constraints,
+        ..Policy::default()
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&policy).expect("Policies always serialize")
+    );
+    ExitCode::SUCCESS
+}
+
+fn lint(policy: &Path) -> ExitCode {
+    let Some(policy) = read_json::<Policy>(policy) else {
+        return ExitCode::FAILURE;
+    };
+    let (methods, protected) = methods();
+    let problems = policy.problems(&methods, &protected);
+    let lints = policy.lints(&protected);
+    for problem in &problems {
+        println!("error: {problem}");
+    }
+    for lint in &lints {
+        println!("warning: {lint}");
+    }
+    if problems.is_empty() && lints.is_empty() {
+        println!("Policy is valid");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn test(policy: &Path, scenarios: &Path) -> ExitCode {
+    let Some(policy) = read_json::<Policy>(policy) else {
+        return ExitCode::FAILURE;
+    };
+    let Some(scenarios) = read_json::<Vec<Scenario>>(scenarios) else {
+        return ExitCode::FAILURE;
+    };
+
+    let (methods, protected) = methods();
+    let problems = policy.problems(&methods, &protected);
+    if !problems.is_empty() {
+        for problem in problems {
+            eprintln!("Policy: {problem}");
+        }
+        return ExitCode::FAILURE;
+    }
+
+    let mut failures = 0;
+    for scenario in &scenarios {
+        let name = scenario.name.as_deref().unwrap_or(&scenario.method);
+        let result = if methods.contains(&scenario.method) {
+            check(&policy, &protected, scenario)
+        } else {
+            Err("there is no such method".to_owned())
+        };
+        match result {
+            Ok(details) => println!("PASS  {name}: {details}"),
+            Err(details) => {
+                println!("FAIL  {name}: {details}");
+                failures += 1;
+            }
+        }
+    }
+    println!(
+        "{} of {} scenarios passed",
+        scenarios.len() - failures,
+        scenarios.len()
+    );
+
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Whether `policy` decides as the `scenario` expects.
+fn check(
+    policy: &Policy,
+    protected: &HashSet<String>,
+    scenario: &Scenario,
+) -> Result<String, String> {
+    let admin_token = "policy-test".to_owned();
+    let mut state = ProtectionState {
+        protected: protected.clone(),
+        admin_token: admin_token.clone().into(),
+        loopback_methods: Default::default(),
+        sessions: Default::default(),
+        users: Default::default(),
+        break_glass: Default::default(),
+        constraints: Default::default(),
+        ownership: Default::default(),
+        canary: None,
+    };
+    match (&policy.canary, scenario.canary) {
+        (Some(canary), true) => canary.policy.apply(&mut state),
+        (None, true) => return Err("the policy has no canary".to_owned()),
+        (_, false) => Policy {
+            canary: None,
+            ..policy.clone()
+        }
+        .apply(&mut state),
+    }
+
+    let mut meta = RpcMeta::from_headers(|_| None);
+    meta.peer_addr = scenario.ip.map(|ip| (ip, 0).into());
+    meta.attributes = scenario.attributes.clone();
+    meta.auth = match (&scenario.user, scenario.role) {
+        (Some(user), role) => Some(Ok(state
+            .sessions
+            .issue(
+                Duration::from_secs(60),
+                Grant {
+                    role,
+                    user: Some(user.clone()),
+                    methods: None,
+                },
+            )
+            .token)),
+        (None, Role::Admin) => Some(Ok(admin_token.into())),
+        (None, Role::Anonymous) => None,
+    };
+
+    let params = match &scenario.params {
+        None => Params::None,
+        Some(params) => serde_json::from_value(params.clone())
+            .map_err(|_| "params has to be an array or an object".to_owned())?,
+    };
+    let call = Call::MethodCall(MethodCall {
+        jsonrpc: Some(Version::V2),
+        method: scenario.method.clone(),
+        params,
+        id: Id::Num(1),
+    });
+
+    match (state.check_call(&call, &meta), scenario.expect) {
+        (Ok(_), Decision::Allow) => Ok("allowed".to_owned()),
+        (Err(rejection), Decision::Deny) => Ok(format!("denied, {}", rejection.message)),
+        (Ok(_), Decision::Deny) => Err("expected to be denied, but allowed".to_owned()),
+        (Err(rejection), Decision::Allow) => Err(format!(
+            "expected to be allowed, but denied, {}",
+            rejection.message
+        )),
+    }
+}
+
+/// Reads and parses a JSON file, reporting failures to the user.
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", path.display());
+            return None;
+        }
+    };
+    match diagnostic::from_json(&json) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            eprintln!("{}: {err}", path.display());
+            None
+        }
+    }
+}
@@ -0,0 +1,1861 @@
+//! The JSON-RPC server itself.
+
+use {
+    super::{
+        connection::{Connection, ConnectionLimits, Incoming},
+        daemon, read_key, systemd, LogOutput, StrengthArgs,
+    },
+    clap::Parser,
+    hyper::{
+        client::HttpConnector, server::conn::AddrIncoming, service::make_service_fn, Body, Client,
+        Server, Uri,
+    },
+    jsonrpc_core::{IoHandlerExtension, MetaIoHandler},
+    jsonrpc_protection::{
+        admin_rpc::{AdminRpc, AdminRpcImpl},
+        approval::{ApprovalConfig, ApprovalMiddleware, ApprovalRpc, ApprovalRpcImpl, Approvals},
+        attributes::AttributeHeader,
+        auth_rpc::{AuthRpc, AuthRpcImpl},
+        bootstrap::TrustOnFirstUse,
+        break_glass::{self, BreakGlass},
+        capture::{Capture, CaptureConfig, CaptureMiddleware, CaptureRpc, CaptureRpcImpl},
+        client::Auth,
+        constraints::{OwnershipRule, ParamConstraint, USER_ATTRIBUTE},
+        deadline::DeadlineMiddleware,
+        deprecation::{Deprecation, DeprecationMiddleware},
+        federation::{ClusterStatsRpc, ClusterStatsRpcImpl},
+        http::{
+            access_log::{AccessLog, AccessLogFormat},
+            cors::Cors,
+            profile,
+            router::PathRouter,
+            RpcHttpHandler,
+        },
+        idempotency::{IdempotencyConfig, IdempotencyMiddleware},
+        load::{LoadConfig, LoadMonitor},
+        main_rpc::{MainRpc, MainRpcImpl, MainRpcV2, MainRpcV2Impl},
+        memory::MemoryBudget,
+        messages::MessageCatalog,
+        middleware::ProtectRpcMiddleware,
+        panic_guard::PanicGuardMiddleware,
+        policy::{self, Policy, PolicyHistory, PolicyRpc, PolicyRpcImpl, PolicySource},
+        priority::{PriorityConfig, PriorityScheduler},
+        pubsub::{
+            self, DenialsPubSub, DenialsPubSubImpl, Event, EventsPubSub, EventsPubSubImpl, Feed,
+            SubscriptionLimits,
+        },
+        rate_limit::{Algorithm, KeyPart, KeyedQuota, RateLimiter},
+        request_log::{
+            LogLevelsRpc, LogLevelsRpcImpl, MethodLogLevels, Outcome, RequestLogMiddleware,
+            SampleRates,
+        },
+        result_filter::{ResultFilter, ResultFilterMiddleware},
+        signing::{self, RequestVerifier, ResponseSigner},
+        slo::{self, Alert, SloMonitor},
+        state::{ProtectionHandle, ProtectionState, Role},
+        stats::{Stats, StatsMiddleware, StatsRpc, StatsRpcImpl},
+        users::{LockoutPolicy, UserStore, UsersRpc, UsersRpcImpl},
+        versioning::{ApiVersion, VersionRpc, VersionRpcImpl},
+    },
+    rand::Rng,
+    std::{
+        collections::{HashMap, HashSet},
+        convert::Infallible,
+        fs,
+        hash::Hash,
+        io,
+        net::{IpAddr, SocketAddr, TcpListener},
+        path::PathBuf,
+        process::ExitCode,
+        str::FromStr,
+        sync::Arc,
+        time::Duration,
+    },
+    tokio::runtime,
+};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Address to accept connections on.  Ignored when systemd passes a listening socket, see
+    /// `systemd.socket(5)`.
+    #[arg(long, default_value = "0.0.0.0:33481")]
+    listen: SocketAddr,
+
+    /// Let other processes listen on `--listen` at the same time, using `SO_REUSEPORT`.  To
+    /// upgrade without refusing connections, start the new server with this flag, then send
+    /// `SIGTERM` to the old one, which stops accepting connections and exits once its requests
+    /// in flight are answered.
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// How long, in seconds, a connection is kept open while it waits for its next request.
+    /// `0` closes connections after every response.  Unlimited when omitted.
+    #[arg(long, value_name = "SECONDS")]
+    keep_alive_timeout: Option<u64>,
+
+    /// How long, in seconds, a connection may neither send nor receive anything, even while a
+    /// request is handled on it.  Has to be longer than the calls clients wait for, such as
+    /// long polls.  Unlimited when omitted.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// How long, in seconds, a client may take to send the headers of a request once it
+    /// started sending them.  Unlimited when omitted.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    header_read_timeout: Option<u64>,
+
+    /// Number of requests served on a connection before it is closed, so that clients
+    /// reconnect, possibly to another instance.  Unlimited when omitted.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_requests_per_connection: Option<u64>,
+
+    /// Maximum number of HTTP connections open at the same time from one IP address.  Further
+    /// connections are closed as soon as they are accepted.  Unlimited when omitted.
+    ///
+    /// Does not apply to `--ws-listen`: the WebSocket server does not tell where connections
+    /// come from.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections_per_ip: Option<u64>,
+
+    /// Address of a proxy, such as a load balancer, that connects on behalf of many clients,
+    /// and so is not subject to `--max-connections-per-ip`.  Can be given multiple times.
+    #[arg(
+        long = "trusted-proxy",
+        value_name = "IP",
+        requires = "max_connections_per_ip"
+    )]
+    trusted_proxies: Vec<IpAddr>,
+
+    /// Address to accept WebSocket connections on.  Subscriptions are only available over
+    /// WebSocket.  Pass the admin token as the `x-admin-auth.<token>` subprotocol.
+    #[cfg(feature = "ws")]
+    #[arg(long)]
+    ws_listen: Option<SocketAddr>,
+
+    /// File holding the admin token, the `X-Admin-Auth` value that allows calls to protected
+    /// methods.  Leading and trailing whitespace is ignored.  The token is `root` when omitted.
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// When `--admin-token-file` does not exist, the first `X-Admin-Auth` value received within
+    /// this many seconds of startup becomes the admin token, and is written to the file.  Until
+    /// then, protected methods may only be called with a request signature.
+    #[arg(long, value_name = "SECONDS", requires = "admin_token_file")]
+    bootstrap_window: Option<u64>,
+
+    /// File listing the SHA-256 hashes of emergency tokens, one per line, as printed by
+    /// `printf %s "$TOKEN" | sha256sum`.  An emergency token is used like the admin token, and
+    /// works for `--break-glass-duration` seconds from its first use.  Activations are logged
+    /// under the `jsonrpc_protection::break_glass` target.
+    #[arg(long)]
+    break_glass_file: Option<PathBuf>,
+
+    /// How long, in seconds, emergency tokens work once activated.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60 * 60, requires = "break_glass_file")]
+    break_glass_duration: u64,
+
+    /// URL that every emergency token activation is `POST`ed to as JSON.  Only `http` URLs are
+    /// supported.
+    #[arg(long, value_name = "URL", requires = "break_glass_file")]
+    break_glass_webhook: Option<Uri>,
+
+    /// File holding user accounts, see the `user` subcommand.  Users log in with `auth_login`.
+    /// Users added with the `user_add` method are lost on restart when omitted.
+    #[arg(long)]
+    users_file: Option<PathBuf>,
+
+    /// Lock a user account after this many failed logins in a row.  Accounts are never locked
+    /// when omitted.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_failed_logins: Option<u32>,
+
+    /// How long, in seconds, locked accounts stay locked, unless unlocked with `user_unlock`.
+    #[arg(long, value_name = "SECONDS", default_value_t = 15 * 60, requires = "max_failed_logins")]
+    lockout_duration: u64,
+
+    #[command(flatten)]
+    strength: StrengthArgs,
+
+    /// File holding the key clients use to sign requests.  Signed requests may call protected
+    /// methods without the admin token.  Request signatures are ignored when omitted.  Leading
+    /// and trailing whitespace is ignored.
+    #[arg(long)]
+    request_signing_key_file: Option<PathBuf>,
+
+    /// JSON file listing additional request signing keys, each with an id that signatures name
+    /// in the `X-Signature-Key-Id` header, and an optional validity window, so keys can be
+    /// rotated.  See the `signing` module documentation for the format.
+    #[arg(long)]
+    request_signing_keys_file: Option<PathBuf>,
+
+    /// File holding the key used to sign response bodies.  Responses are not signed when
+    /// omitted.  Leading and trailing whitespace is ignored.
+    #[arg(long)]
+    response_signing_key_file: Option<PathBuf>,
+
+    /// Protected method that may be called without credentials over HTTP connections from
+    /// 127.0.0.1 or ::1.  Can be given multiple times.
+    #[arg(long = "loopback-method", value_name = "METHOD")]
+    loopback_methods: Vec<String>,
+
+    /// Copy the value of request header `HEADER` into caller attribute `ATTRIBUTE`, which
+    /// handlers can read, and which the request log shows.  Headers are not authenticated, so
+    /// only use this behind a proxy that sets them.  Can be given multiple times.
+    #[arg(long = "attribute-header", value_name = "ATTRIBUTE=HEADER")]
+    attribute_headers: Vec<AttributeHeader>,
+
+    /// Only let callers with role `ROLE` call `METHOD` when parameter `PARAM`, a position or a
+    /// name, compares to the JSON `VALUE` as `OP` says: one of `==`, `!=`, `<`, `<=`, `>` and
+    /// `>=`.  For example `admin:f:0<=10`.  Can be given multiple times, and all constraints
+    /// for a role and a method have to hold.
+    #[arg(long = "param-constraint", value_name = "ROLE:METHOD:PARAM OP VALUE")]
+    param_constraints: Vec<ParamConstraint>,
+
+    /// Only let callers pass their own `ATTRIBUTE`, from `--attribute-header`, as parameter
+    /// `PARAM` of `METHOD`, a position or a name.  Attribute `user` is the name of the user that
+    /// logged in.  For example `transfer:account=account_id`.  Can be given multiple times.
+    #[arg(long = "owned-param", value_name = "METHOD:PARAM=ATTRIBUTE")]
+    owned_params: Vec<OwnershipRule>,
+
+    /// Give callers with role `ROLE` the result of `METHOD` with the field at `PATH`, a JSON
+    /// pointer, removed or masked: `ACTION` is `remove` or `mask`.  A `*` segment matches every
+    /// array element or object member.  For example `anonymous:status:/peers/*/address=mask`.
+    /// Can be given multiple times.
+    #[arg(long = "result-filter", value_name = "ROLE:METHOD:PATH=ACTION")]
+    result_filters: Vec<ResultFilter>,
+
+    /// Mark `METHOD` as deprecated.  Calls to it are still made, but responses carry a
+    /// `Deprecation` header and the `NOTICE`, and `admin_stats` counts them by caller.  For
+    /// example `g=use g on /v2 instead`.  Can be given multiple times.
+    #[arg(long = "deprecated-method", value_name = "METHOD[=NOTICE]")]
+    deprecated_methods: Vec<Deprecation>,
+
+    /// URL of another instance whose `admin_stats` are added to the ones of this instance by
+    /// `admin_cluster_stats`.  Can be given multiple times.  Only `http` URLs are supported.
+    #[arg(
+        long = "stats-peer",
+        value_name = "URL",
+        requires = "stats_peer_token_file"
+    )]
+    stats_peers: Vec<Uri>,
+
+    /// File holding the admin token of the `--stats-peer` instances.
+    #[arg(long, requires = "stats_peers")]
+    stats_peer_token_file: Option<PathBuf>,
+
+    /// URL of a JSON policy holding the loopback methods, parameter constraints and owned
+    /// parameters, in place of the flags setting them.  The server does not start unless it
+    /// can fetch the policy, and then polls the URL, applying changes as a whole.  Point every
+    /// instance at the same URL to keep their rules in sync.  Only `http` URLs are supported, as
+    /// there is no TLS support, so use `--policy-signing-key-file` unless the network between
+    /// the server and the URL is trusted.
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["loopback_methods", "param_constraints", "owned_params"]
+    )]
+    policy_url: Option<Uri>,
+
+    /// How often, in seconds, the `--policy-url` is checked for changes.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 30,
+        requires = "policy_url"
+    )]
+    policy_poll_interval: u64,
+
+    /// How long, in seconds, fetching the `--policy-url` may take, including reading the
+    /// policy.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "policy_url"
+    )]
+    policy_fetch_timeout: u64,
+
+    /// Largest policy accepted from the `--policy-url`, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024, requires = "policy_url")]
+    policy_max_size: usize,
+
+    /// File holding the key policies from the `--policy-url` are signed with.  Policies are
+    /// then only applied with an `X-Policy-Signature` header holding the hex encoded
+    /// HMAC-SHA256 of the policy, as printed by `openssl dgst -sha256 -hmac "$KEY"`.  Leading
+    /// and trailing whitespace is ignored.  Signed policies need a `version`, greater than the
+    /// one of the policy fetched before.
+    #[arg(long, requires = "policy_url")]
+    policy_signing_key_file: Option<PathBuf>,
+
+    /// Number of applied policies kept for `admin_policy_diff` and `admin_policy_rollback`.
+    #[arg(long, default_value_t = 20)]
+    policy_history_size: usize,
+
+    /// Serve the admin methods only on this path, such as `/admin`, with a handler of their
+    /// own, and the other methods on every other path, including the paths of API versions.
+    /// The WebSocket server serves all methods either way.
+    #[arg(long, value_name = "PATH", value_parser = parse_path)]
+    admin_path: Option<String>,
+
+    /// JSON file with named protection profiles, for `--path-profile`, see
+    /// `jsonrpc_protection::http::profile`.
+    #[arg(long, value_name = "FILE")]
+    profiles: Option<PathBuf>,
+
+    /// Serve PATH with the rate limits, credentials and CORS settings of a profile from
+    /// `--profiles`.  The `--admin-path` serves the admin methods, any other path the other
+    /// methods.  Paths without a profile, including paths not listed anywhere, are not served
+    /// when they would serve methods of a path with a profile, so that the profile can not be
+    /// bypassed.  Can be given multiple times.
+    #[arg(
+        long = "path-profile",
+        value_name = "PATH=PROFILE",
+        value_parser = parse_path_profile,
+        requires = "profiles",
+    )]
+    path_profiles: Vec<(String, String)>,
+
+    /// Protected method that is only executed once someone other than the caller approves the
+    /// call with `admin_approve`, and the caller repeats it with the `X-Approval-Id` header
+    /// from the first response.  Callers that can add users can approve their own calls
+    /// through a user of their own, so keep `user_add` and the admin token from them.  Can be
+    /// given multiple times.
+    #[arg(long = "approval-method", value_name = "METHOD")]
+    approval_methods: Vec<String>,
+
+    /// How long, in seconds, calls wait for approval, and for the caller to repeat them once
+    /// approved.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5 * 60, requires = "approval_methods")]
+    approval_timeout: u64,
+
+    /// Method for which retries carrying the same `Idempotency-Key` header get the result of
+    /// the first call.  Can be given multiple times.
+    #[arg(long = "idempotent-method", value_name = "METHOD")]
+    idempotent_methods: Vec<String>,
+
+    /// How long, in seconds, results of idempotent calls are kept.
+    #[arg(long, default_value_t = 24 * 60 * 60)]
+    idempotency_ttl: u64,
+
+    /// Maximum number of results of idempotent calls that are kept.
+    #[arg(long, default_value_t = 10_000)]
+    idempotency_cache_size: usize,
+
+    /// Also accept JSON-RPC 1.0 requests, which have no `jsonrpc` field.  They are answered in
+    /// the 1.0 format.
+    #[arg(long)]
+    jsonrpc1: bool,
+
+    /// Reject calls that do not follow the JSON-RPC 2.0 specification exactly, with an error
+    /// describing the problem.
+    #[arg(long)]
+    strict: bool,
+
+    /// Maximum rate of calls for each caller with the given role, as `ROLE=COUNT/SECONDS`.
+    /// Callers may make bursts of up to `COUNT` calls.  Admins are limited per token, everyone
+    /// else per IP address, unless `@KEY+...` says otherwise: every combination of `ip`,
+    /// `credential` and caller attributes from `--attribute-header` gets its own limit, as in
+    /// `anonymous=100/60@ip+tenant`.  Can be given once for every role.  Unlimited when omitted.
+    #[arg(
+        long,
+        value_name = "ROLE=COUNT/SECONDS[@KEY+...]",
+        value_parser = parse_role_limit::<KeyedQuota>
+    )]
+    rate_limit: Vec<(Role, KeyedQuota)>,
+
+    /// How calls are counted against `--rate-limit` quotas.  `token-bucket` lets callers that
+    /// were idle make a burst of a whole quota at once.  `sliding-window` allows about a quota
+    /// of calls in any period.
+    #[arg(long, default_value_t, requires = "rate_limit")]
+    rate_limit_algorithm: Algorithm,
+
+    /// Maximum number of requests handled at the same time.  Further requests wait, and are
+    /// let through admins first.  Unlimited when omitted.
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+
+    /// Maximum number of requests waiting for `--max-in-flight`.  When it is reached, waiting
+    /// anonymous requests are rejected first.
+    #[arg(long, default_value_t = 1000, requires = "max_in_flight")]
+    max_queued: usize,
+
+    /// How long, in milliseconds, a request may wait for `--max-in-flight` before it is
+    /// rejected because the server is overloaded.  Unlimited when omitted.
+    #[arg(long, value_name = "MILLISECONDS", requires = "max_in_flight")]
+    max_queue_time: Option<u64>,
+
+    /// Reject requests from everyone but admins while the host is saturated, and divide their
+    /// `--rate-limit` quotas by `--load-rate-limit-divisor`.
+    #[arg(long)]
+    shed_load: bool,
+
+    /// CPU usage, as a percentage of all cores, above which the host is saturated.
+    #[arg(long, default_value_t = 90.0, requires = "shed_load")]
+    max_cpu_usage: f64,
+
+    /// How late, in milliseconds, timers may fire before the host is saturated.
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = 100,
+        requires = "shed_load"
+    )]
+    max_event_loop_lag: u64,
+
+    /// What to divide quotas of everyone but admins by while the host is saturated.
+    #[arg(long, default_value_t = 4, requires = "shed_load")]
+    load_rate_limit_divisor: u32,
+
+    /// Approximate memory, in MiB, that request bodies, the idempotency cache, the nonce table
+    /// and rate limit buckets may use together.  Once it is reached, cached results are
+    /// dropped, and requests whose bodies do not fit are rejected.  Unlimited when omitted.
+    #[arg(long, value_name = "MIB")]
+    memory_limit: Option<usize>,
+
+    /// JSON file with translations of error messages, keyed by locale and then by error
+    /// reason.  The locale is chosen according to the `Accept-Language` request header.
+    #[arg(long)]
+    messages_file: Option<PathBuf>,
+
+    /// Maximum number of concurrent subscriptions for each caller with the given role, as
+    /// `ROLE=COUNT`.  Can be given once for every role.  Unlimited when omitted.
+    #[arg(long, value_name = "ROLE=COUNT", value_parser = parse_role_limit::<usize>)]
+    max_subscriptions: Vec<(Role, usize)>,
+
+    /// Maximum number of notifications per second for each caller with the given role, as
+    /// `ROLE=RATE`.  Subscriptions that go over it are terminated.  Can be given once for every
+    /// role.  Unlimited when omitted.
+    #[arg(long, value_name = "ROLE=RATE", value_parser = parse_role_limit::<u32>)]
+    max_notification_rate: Vec<(Role, u32)>,
+
+    /// Fraction of calls with the given outcome to log, as `OUTCOME=RATE`, where `OUTCOME` is
+    /// `success`, `denied` or `error`, and `RATE` is between 0 and 1.  Defaults to 0.01 for
+    /// successful calls, and to 1 otherwise.  Calls are logged under the
+    /// `jsonrpc_protection::request` target, see `RUST_LOG`.
+    #[arg(long, value_name = "OUTCOME=RATE", value_parser = parse_sample_rate)]
+    log_sample_rate: Vec<(Outcome, f64)>,
+
+    /// Log every call that takes longer than this many milliseconds, with a warning, under the
+    /// `jsonrpc_protection::slow_call` target.
+    #[arg(long, value_name = "MILLISECONDS")]
+    slow_call_threshold: Option<u64>,
+
+    /// Number of calls kept while capturing them with `admin_capture_start`.
+    #[arg(long, default_value_t = CaptureConfig::default().capacity)]
+    capture_buffer_size: usize,
+
+    /// Captured calls and responses are cut to this many bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = CaptureConfig::default().max_body_size)]
+    capture_max_body_size: usize,
+
+    /// File to append a line to for every HTTP request, in the format given by
+    /// `--access-log-format`, followed by the JSON-RPC methods called.
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+
+    /// `common` or `combined`, as used by web servers.
+    #[arg(long, default_value = "common", requires = "access_log")]
+    access_log_format: AccessLogFormat,
+
+    /// JSON file with service level objectives for groups of methods.  Alerts are logged under
+    /// the `jsonrpc_protection::slo` target when the error budget of an objective is spent too
+    /// fast.
+    #[arg(long)]
+    slo_file: Option<PathBuf>,
+
+    /// URL that every SLO alert, and its resolution, is `POST`ed to as JSON.  Only `http` URLs
+    /// are supported.
+    #[arg(long, value_name = "URL", requires = "slo_file")]
+    slo_webhook: Option<Uri>,
+
+    /// Where log records go.  Filtered according to `RUST_LOG` either way.
+    #[arg(long, value_enum, default_value_t)]
+    pub log_output: LogOutput,
+
+    /// Detach from the terminal and run in the background.  Paths given in other arguments are
+    /// still relative to the current directory.
+    #[arg(long)]
+    daemon: bool,
+
+    /// File to write the server PID to.  The server refuses to start if the file names a
+    /// running process.
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// File to append the output of a `--daemon` server to.  Discarded when omitted.
+    #[arg(long, requires = "daemon")]
+    log_file: Option<PathBuf>,
+
+    /// TOML file to read arguments from, keyed by their long names, such as
+    /// `listen = "0.0.0.0:8545"`.  Arguments given on the command line take precedence.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Also read `NAME.toml`, from the directory of `--config`, whose values replace those of
+    /// `--config`.
+    #[arg(long, value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+
+    /// Check the configuration, print every problem found, and exit, without listening.  Exits
+    /// with a failure if there are problems.
+    #[arg(long)]
+    check_config: bool,
+}
+
+pub fn run(args: Args) -> ExitCode {
+    if let Some(path) = &args.config {
+        match &args.profile {
+            Some(profile) => log::info!("Using {} with profile {profile}", path.display()),
+            None => log::info!("Using {}", path.display()),
+        }
+    }
+
+    if args.check_config {
+        return check_config(&args);
+    }
+
+    if let Some(path) = &args.pidfile {
+        if let Err(err) = daemon::check_pidfile(path) {
+            eprintln!("{}: {err}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // The runtime starts threads, so this has to happen first.
+    if args.daemon {
+        if let Err(err) = daemon::daemonize(args.log_file.as_deref()) {
+            eprintln!("Failed to run in the background: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(path) = &args.pidfile {
+        if let Err(err) = daemon::write_pidfile(path) {
+            eprintln!("{}: {err}", path.display());
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let rt = runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let (admin_token, bootstrap) = match &args.admin_token_file {
+        None => ("root".to_owned(), None),
+        Some(path) => match fs::read_to_string(path) {
+            Ok(token) if token.trim().is_empty() => {
+                eprintln!("{} is empty", path.display());
+                return ExitCode::FAILURE;
+            }
+            Ok(token) => (token.trim().to_owned(), None),
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound && args.bootstrap_window.is_some() =>
+            {
+                let window = Duration::from_secs(args.bootstrap_window.unwrap());
+                log::warn!(
+                    "{} does not exist, the first admin token received in the next {}s will be \
+                     trusted",
+                    path.display(),
+                    window.as_secs(),
+                );
+                // Nobody can guess it, so protected methods are closed until the window is used.
+                let placeholder = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+                let bootstrap = TrustOnFirstUse::new(path.clone(), window)
+                    .strength_policy(args.strength.policy());
+                (placeholder, Some(bootstrap))
+            }
+            Err(err) => {
+                eprintln!("Failed to read {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let users = match &args.users_file {
+        Some(path) => match UserStore::load(path) {
+            Ok(users) => users,
+            Err(err) => {
+                eprintln!("Failed to load users from {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => UserStore::default(),
+    }
+    .strength_policy(args.strength.policy());
+    let users = match args.max_failed_logins {
+        Some(max_failures) => users.lockout(LockoutPolicy {
+            max_failures,
+            duration: Duration::from_secs(args.lockout_duration),
+        }),
+        None => users,
+    };
+    let break_glass = match &args.break_glass_file {
+        Some(path) => {
+            match BreakGlass::load(path, Duration::from_secs(args.break_glass_duration)) {
+                Ok(break_glass) => break_glass,
+                Err(err) => {
+                    eprintln!("{}: {err}", path.display());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => BreakGlass::default(),
+    };
+    let break_glass = match args.break_glass_webhook.clone() {
+        Some(url) => {
+            let client = Client::new();
+            break_glass.on_activation(move |activation| {
+                let body = serde_json::to_vec(&activation).expect("Activations always serialize");
+                tokio::spawn(post_json(
+                    client.clone(),
+                    url.clone(),
+                    body,
+                    break_glass::TARGET,
+                ));
+            })
+        }
+        None => break_glass,
+    };
+
+    let protection = ProtectionHandle::new(ProtectionState {
+        protected: AdminRpcImpl
+            .to_delegate()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        admin_token: admin_token.into(),
+        loopback_methods: args.loopback_methods.iter().cloned().collect(),
+        sessions: Default::default(),
+        users: users.clone(),
+        break_glass,
+        constraints: args.param_constraints.clone(),
+        ownership: args.owned_params.clone(),
+        canary: None,
+    });
+
+    let limits = subscription_limits(&args);
+    let denial_feed = Arc::new(Feed::new(Role::Admin, limits.clone()));
+    let event_feed = Arc::new(Feed::new(Role::Anonymous, limits));
+    let denials_pubsub = DenialsPubSubImpl::new(denial_feed.clone(), protection.clone());
+    let events_pubsub = EventsPubSubImpl::new(event_feed.clone(), protection.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            denials_pubsub
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+    pubsub::watch_state(&protection, denial_feed.clone(), event_feed.clone());
+
+    let auth_rpc = AuthRpcImpl::new(protection.clone(), users.clone());
+    let users_rpc = UsersRpcImpl::new(users, protection.load().sessions.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            users_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+
+    let memory_budget = args
+        .memory_limit
+        .map(|mib| MemoryBudget::new(mib.saturating_mul(1024 * 1024)));
+
+    let load_monitor = args.shed_load.then(|| {
+        let monitor = LoadMonitor::new(LoadConfig {
+            max_cpu_usage: args.max_cpu_usage / 100.0,
+            max_lag: Duration::from_millis(args.max_event_loop_lag),
+        });
+        rt.spawn(monitor.clone().run());
+        monitor
+    });
+
+    let new_rate_limiter = |limits: &[(Role, KeyedQuota)]| {
+        if limits.is_empty() {
+            return None;
+        }
+        let limiter = RateLimiter::new(
+            protection.clone(),
+            limits
+                .iter()
+                .map(|(role, limit)| (*role, limit.quota))
+                .collect(),
+        )
+        .algorithm(args.rate_limit_algorithm);
+        let limiter = limits
+            .iter()
+            .filter_map(|(role, limit)| Some((*role, limit.key.clone()?)))
+            .fold(limiter, |limiter, (role, key)| limiter.key_by(role, key));
+        let limiter = match &load_monitor {
+            Some(monitor) => {
+                limiter.tighten_under_load(monitor.clone(), args.load_rate_limit_divisor)
+            }
+            None => limiter,
+        };
+        Some(match &memory_budget {
+            Some(budget) => limiter.memory_budget(budget.clone()),
+            None => limiter,
+        })
+    };
+    let rate_limiter = new_rate_limiter(&args.rate_limit);
+
+    let scheduler = (args.max_in_flight.is_some() || load_monitor.is_some()).then(|| {
+        let scheduler = PriorityScheduler::new(
+            protection.clone(),
+            PriorityConfig {
+                max_in_flight: args.max_in_flight.unwrap_or(usize::MAX),
+                max_queued: args.max_queued,
+                max_queue_time: args.max_queue_time.map(Duration::from_millis),
+            },
+        );
+        match &load_monitor {
+            Some(monitor) => scheduler.shed_under_load(monitor.clone()),
+            None => scheduler,
+        }
+    });
+
+    let stats = Stats::new();
+    let mut stats_rpc = StatsRpcImpl::new(stats.clone(), protection.clone());
+    if let Some(limiter) = &rate_limiter {
+        stats_rpc = stats_rpc.rate_limiter(limiter.clone());
+    }
+    if let Some(scheduler) = &scheduler {
+        stats_rpc = stats_rpc.priority_scheduler(scheduler.clone());
+    }
+    if let Some(budget) = &memory_budget {
+        stats_rpc = stats_rpc.memory_budget(budget.clone());
+    }
+    protection.update(|state| {
+        state.protected.extend(
+            stats_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+
+    let cluster_stats_rpc = match &args.stats_peer_token_file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(token) => Some(ClusterStatsRpcImpl::new(
+                stats_rpc.clone(),
+                args.stats_peers.clone(),
+                Auth::AdminToken(token.trim().to_owned()),
+            )),
+            Err(err) => {
+                eprintln!("Failed to read {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    if let Some(cluster_stats_rpc) = &cluster_stats_rpc {
+        protection.update(|state| {
+            state.protected.extend(
+                cluster_stats_rpc
+                    .clone()
+                    .to_delegate()
+                    .into_iter()
+                    .map(|(name, _)| name),
+            )
+        });
+    }
+
+    let mut protect_middleware =
+        ProtectRpcMiddleware::new(protection.clone()).on_denial(move |denial| {
+            denial_feed.publish(&denial, Role::Admin);
+            event_feed.publish(&Event::Denial(denial), Role::Admin);
+        });
+
+    if let Some(bootstrap) = bootstrap {
+        protect_middleware = protect_middleware.trust_on_first_use(bootstrap);
+    }
+
+    if let Some(path) = &args.messages_file {
+        match MessageCatalog::load(path) {
+            Ok(messages) => {
+                protect_middleware = protect_middleware.message_catalog(Arc::new(messages))
+            }
+            Err(err) => {
+                eprintln!("{}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let approvals = Approvals::new(ApprovalConfig {
+        methods: args.approval_methods.iter().cloned().collect(),
+        timeout: Duration::from_secs(args.approval_timeout),
+    });
+    let approval_rpc = ApprovalRpcImpl::new(approvals.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            approval_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+
+    let mut idempotency_middleware = IdempotencyMiddleware::new(IdempotencyConfig {
+        methods: args.idempotent_methods.iter().cloned().collect(),
+        ttl: Duration::from_secs(args.idempotency_ttl),
+        capacity: args.idempotency_cache_size,
+    });
+    if let Some(budget) = &memory_budget {
+        idempotency_middleware = idempotency_middleware.memory_budget(budget.clone());
+    }
+
+    let mut sample_rates = SampleRates::default();
+    for &(outcome, rate) in &args.log_sample_rate {
+        sample_rates.set(outcome, rate);
+    }
+    let method_log_levels = MethodLogLevels::default();
+    let log_levels_rpc = LogLevelsRpcImpl::new(method_log_levels.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            log_levels_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+    let capture = Capture::new(CaptureConfig {
+        capacity: args.capture_buffer_size,
+        max_body_size: args.capture_max_body_size,
+    });
+    let capture_rpc = CaptureRpcImpl::new(capture.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            capture_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+
+    let history = PolicyHistory::new(args.policy_history_size);
+    let policy_rpc = PolicyRpcImpl::new(history.clone(), protection.clone());
+    protection.update(|state| {
+        state.protected.extend(
+            policy_rpc
+                .clone()
+                .to_delegate()
+                .into_iter()
+                .map(|(name, _)| name),
+        )
+    });
+
+    let mut request_log_middleware = RequestLogMiddleware::new(sample_rates)
+        .identities(protection.clone())
+        .method_levels(method_log_levels);
+    if let Some(threshold) = args.slow_call_threshold {
+        request_log_middleware =
+            request_log_middleware.slow_calls(Duration::from_millis(threshold), protection.clone());
+    }
+
+    let mut stats_middleware = StatsMiddleware::new(stats.clone(), protection.clone());
+    if let Some(path) = &args.slo_file {
+        let slos = match slo::load(path) {
+            Ok(slos) => slos,
+            Err(err) => {
+                eprintln!("{}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        let mut monitor = SloMonitor::new(slos);
+        if let Some(url) = args.slo_webhook.clone() {
+            let client = Client::new();
+            monitor = monitor.on_alert(move |alert| {
+                tokio::spawn(post_alert(client.clone(), url.clone(), alert));
+            });
+        }
+        rt.spawn(monitor.clone().run());
+        stats_middleware = stats_middleware.slo_monitor(monitor);
+    }
+
+    // Credentials are checked first, so that cached results are only returned to callers that
+    // are allowed to call the method.
+    let middleware = (
+        stats_middleware,
+        request_log_middleware,
+        CaptureMiddleware::new(capture),
+        (
+            PanicGuardMiddleware::new(),
+            (
+                DeadlineMiddleware::new(),
+                (
+                    protect_middleware,
+                    (
+                        DeprecationMiddleware::new(args.deprecated_methods.clone())
+                            .stats(stats.clone()),
+                        ResultFilterMiddleware::new(args.result_filters.clone()),
+                    ),
+                    ApprovalMiddleware::new(approvals),
+                    idempotency_middleware,
+                ),
+            ),
+        ),
+    );
+    let versions = API_VERSIONS.map(ApiVersion::new).to_vec();
+    let version_rpc = VersionRpcImpl::new(versions.clone());
+
+    let mut io = MetaIoHandler::with_middleware(middleware.clone());
+    io.extend_with(events_pubsub.to_delegate());
+    io.extend_with(auth_rpc.to_delegate());
+    io.extend_with(version_rpc.to_delegate());
+    let mut v2_io = io.clone();
+
+    let main_rpc = MainRpcImpl;
+    io.extend_with(main_rpc.to_delegate());
+    v2_io.extend_with(MainRpcV2Impl.to_delegate());
+
+    let mut admin_io = MetaIoHandler::default();
+    let admin_rpc = AdminRpcImpl;
+    admin_io.extend_with(admin_rpc.to_delegate());
+    admin_io.extend_with(denials_pubsub.to_delegate());
+    admin_io.extend_with(users_rpc.to_delegate());
+    admin_io.extend_with(stats_rpc.to_delegate());
+    if let Some(cluster_stats_rpc) = cluster_stats_rpc {
+        admin_io.extend_with(cluster_stats_rpc.to_delegate());
+    }
+    admin_io.extend_with(log_levels_rpc.to_delegate());
+    admin_io.extend_with(capture_rpc.to_delegate());
+    admin_io.extend_with(approval_rpc.to_delegate());
+    admin_io.extend_with(policy_rpc.to_delegate());
+
+    // Served over WebSocket, whatever the HTTP paths.
+    let mut all_io = io.clone();
+    admin_io.clone().augment(&mut all_io);
+
+    // Version 1 is also served on paths without a version, as it was before versioning.
+    let mut http_paths = vec![];
+    let http_io = match &args.admin_path {
+        Some(path) => {
+            let mut separate = MetaIoHandler::with_middleware(middleware);
+            admin_io.augment(&mut separate);
+            http_paths.push((path.clone(), separate));
+            io
+        }
+        None => {
+            admin_io.augment(&mut v2_io);
+            all_io.clone()
+        }
+    };
+    for (version, version_io) in versions.iter().zip([http_io.clone(), v2_io.clone()]) {
+        http_paths.push((version.path.clone(), version_io));
+    }
+
+    if let Some(url) = args.policy_url.clone() {
+        let methods = all_io
+            .iter()
+            .chain(v2_io.iter())
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut source = match PolicySource::new(url.clone()) {
+            Ok(source) => source
+                .timeout(Duration::from_secs(args.policy_fetch_timeout))
+                .max_size(args.policy_max_size),
+            Err(err) => {
+                eprintln!("--policy-url {url}: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Some(path) = &args.policy_signing_key_file {
+            let Some(key) = read_key(path) else {
+                return ExitCode::FAILURE;
+            };
+            source = source.signing_key(key);
+        }
+        let policy = match rt.block_on(source.fetch()) {
+            Ok(policy) => policy.expect("The first fetch is never conditional"),
+            Err(err) => {
+                eprintln!("Failed to fetch the policy from {}: {err}", source.url());
+                return ExitCode::FAILURE;
+            }
+        };
+        if let Err(problems) = apply_policy(&protection, &methods, &policy, &history, source.url())
+        {
+            for problem in problems {
+                eprintln!("Policy at {}: {problem}", source.url());
+            }
+            return ExitCode::FAILURE;
+        }
+        rt.spawn(watch_policy(
+            source,
+            Duration::from_secs(args.policy_poll_interval),
+            protection.clone(),
+            methods,
+            history,
+        ));
+    } else {
+        history.record(Policy::of(&protection.load()), "command line");
+    }
+
+    #[cfg(feature = "ws")]
+    let _ws_server = match args.ws_listen {
+        Some(addr) => match jsonrpc_protection::ws::start(&addr, all_io, rt.handle().clone()) {
+            Ok(server) => Some(server),
+            Err(err) => {
+                eprintln!("WebSocket server failed: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    let mut verifier = None;
+    if let Some(path) = &args.request_signing_key_file {
+        let Some(key) = read_key(path) else {
+            return ExitCode::FAILURE;
+        };
+        verifier = Some(RequestVerifier::new(&key));
+    }
+    if let Some(path) = &args.request_signing_keys_file {
+        let keys = match signing::load_keys(path) {
+            Ok(keys) => keys,
+            Err(err) => {
+                eprintln!("{}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        verifier = Some(match verifier {
+            Some(verifier) => verifier.keys(keys),
+            None => RequestVerifier::from_keys(keys),
+        });
+    }
+    if let Some(budget) = &memory_budget {
+        verifier = verifier.map(|verifier| verifier.memory_budget(budget.clone()));
+    }
+
+    let mut response_signer = None;
+    if let Some(path) = &args.response_signing_key_file {
+        let Some(key) = read_key(path) else {
+            return ExitCode::FAILURE;
+        };
+        response_signer = Some(ResponseSigner::new(&key));
+    }
+
+    let mut access_log = None;
+    if let Some(path) = &args.access_log {
+        match AccessLog::open(args.access_log_format, path) {
+            Ok(log) => access_log = Some(log),
+            Err(err) => {
+                eprintln!("Failed to open {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let defaults = PathProtection {
+        rate_limiter: rate_limiter.clone(),
+        verifier,
+        accept_tokens: true,
+        cors: None,
+    };
+    let mut profiles = HashMap::new();
+    if let Some(path) = &args.profiles {
+        let loaded = match profile::load(path) {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                eprintln!("{}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        for (name, profile) in loaded {
+            let mut protection = defaults.clone();
+            if let Some(limits) = &profile.rate_limit {
+                let limits = limits
+                    .iter()
+                    .map(|(role, limit)| (*role, limit.clone()))
+                    .collect::<Vec<_>>();
+                protection.rate_limiter = new_rate_limiter(&limits);
+            }
+            if let Some(path) = &profile.request_signing_keys_file {
+                let keys = match signing::load_keys(path) {
+                    Ok(keys) => keys,
+                    Err(err) => {
+                        eprintln!("Profile {name}: {}: {err}", path.display());
+                        return ExitCode::FAILURE;
+                    }
+                };
+                let verifier = RequestVerifier::from_keys(keys);
+                protection.verifier = Some(match &memory_budget {
+                    Some(budget) => verifier.memory_budget(budget.clone()),
+                    None => verifier,
+                });
+            }
+            protection.accept_tokens = profile.accept_tokens;
+            if !profile.cors_origins.is_empty() {
+                protection.cors = Some(Cors::new(profile.cors_origins));
+            }
+            profiles.insert(name, protection);
+        }
+    }
+
+    // Handlers on different paths share the scheduler, the response signer and the access log,
+    // and, unless their profiles say otherwise, the limits and the nonces of signed requests.
+    let handler = |io, protection: &PathProtection| {
+        let mut handler = RpcHttpHandler::new(io)
+            .jsonrpc1(args.jsonrpc1)
+            .strict(args.strict)
+            .attribute_headers(args.attribute_headers.clone())
+            .stats(stats.clone())
+            .accept_tokens(protection.accept_tokens);
+        if let Some(limiter) = &protection.rate_limiter {
+            handler = handler.rate_limiter(limiter.clone());
+        }
+        if let Some(scheduler) = &scheduler {
+            handler = handler.priority_scheduler(scheduler.clone());
+        }
+        if let Some(verifier) = &protection.verifier {
+            handler = handler.request_verifier(verifier.clone());
+        }
+        if let Some(cors) = &protection.cors {
+            handler = handler.cors(cors.clone());
+        }
+        if let Some(signer) = &response_signer {
+            handler = handler.response_signer(signer.clone());
+        }
+        if let Some(budget) = &memory_budget {
+            handler = handler.memory_budget(budget.clone());
+        }
+        if let Some(log) = &access_log {
+            handler = handler.access_log(log.clone());
+        }
+        handler
+    };
+
+    // Methods of paths with a profile are not served without it on other paths, which would
+    // bypass the profile.
+    let path_io = |path: &String| {
+        http_paths
+            .iter()
+            .find(|(served, _)| served == path)
+            .map_or(&http_io, |(_, io)| io)
+    };
+    let method_names = |io: &MetaIoHandler<_, _>| {
+        io.iter()
+            .map(|(name, _)| name.clone())
+            .collect::<HashSet<_>>()
+    };
+    let profiled_methods = args
+        .path_profiles
+        .iter()
+        .flat_map(|(path, _)| method_names(path_io(path)))
+        .collect::<HashSet<_>>();
+
+    let mut router = PathRouter::new();
+    if method_names(&http_io).is_disjoint(&profiled_methods) {
+        router = router.fallback(handler(http_io.clone(), &defaults));
+    } else {
+        log::warn!(
+            "Paths without a --path-profile are not served, as they would serve the methods of \
+             paths with one without their profile"
+        );
+    }
+    for (path, io) in &http_paths {
+        if args
+            .path_profiles
+            .iter()
+            .any(|(profiled, _)| profiled == path)
+        {
+            continue;
+        }
+        if method_names(io).is_disjoint(&profiled_methods) {
+            router = router.route(path, handler(io.clone(), &defaults));
+        } else {
+            log::warn!(
+                "{path} is not served, as it would serve the methods of paths with a \
+                 --path-profile without their profile, give it a --path-profile to serve it"
+            );
+        }
+    }
+    for (path, name) in &args.path_profiles {
+        let Some(protection) = profiles.get(name) else {
+            eprintln!("--path-profile {path}={name}: there is no such profile");
+            return ExitCode::FAILURE;
+        };
+        router = router.route(path, handler(path_io(path).clone(), protection));
+    }
+    let service = router.into_service();
+
+    let mut listeners = match systemd::listeners() {
+        Ok(listeners) => listeners.into_iter(),
+        Err(err) => {
+            eprintln!("Failed to use sockets passed by systemd: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut listener = listeners.next();
+    if listeners.len() > 0 {
+        log::warn!("systemd passed more than one socket, only the first one is used");
+    }
+
+    if listener.is_none() && args.reuse_port {
+        match bind_reusing_port(args.listen) {
+            Ok(reused) => listener = Some(reused),
+            Err(err) => {
+                eprintln!("Failed to listen on {}: {err}", args.listen);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let result = rt.block_on(async {
+        let make_service = make_service_fn(move |conn: &Connection| {
+            let service = conn.service(service.with_peer_addr(conn.remote_addr()));
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let incoming = match listener {
+            Some(listener) => {
+                AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)?
+            }
+            None => AddrIncoming::bind(&args.listen)?,
+        };
+        let limits = ConnectionLimits {
+            keep_alive_timeout: args
+                .keep_alive_timeout
+                .filter(|&timeout| timeout > 0)
+                .map(Duration::from_secs),
+            idle_timeout: args.idle_timeout.map(Duration::from_secs),
+            max_requests: args.max_requests_per_connection,
+        };
+        let mut incoming = Incoming::new(incoming, limits);
+        if let Some(max) = args.max_connections_per_ip {
+            let trusted_proxies = args.trusted_proxies.iter().copied().collect();
+            incoming = incoming.per_ip_limit(max as usize, trusted_proxies);
+        }
+        let mut server =
+            Server::builder(incoming).http1_keepalive(args.keep_alive_timeout != Some(0));
+        if let Some(timeout) = args.header_read_timeout {
+            server = server.http1_header_read_timeout(Duration::from_secs(timeout));
+        }
+
+        if let Err(err) = systemd::notify("READY=1") {
+            log::warn!("Failed to notify systemd that the server is ready: {err}");
+        }
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(systemd::watchdog(interval));
+        }
+
+        server
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown_requested())
+            .await?;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    });
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Server failed: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn post_alert(client: Client<HttpConnector>, url: Uri, alert: Alert) {
+    let body = serde_json::to_vec(&alert).expect("Alerts always serialize");
+    post_json(client, url, body, slo::TARGET).await
+}
+
+/// Applies `policy` from `url`, unless it refers to methods the server does not have, and
+/// records it in `history`.  Rules that have no effect are logged.
+fn apply_policy(
+    protection: &ProtectionHandle,
+    methods: &HashSet<String>,
+    policy: &Policy,
+    history: &PolicyHistory,
+    url: &Uri,
+) -> Result<(), Vec<String>> {
+    let problems = policy.problems(methods, &protection.load().protected);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    protection.update(|state| policy.apply(state));
+    history.record(policy.clone(), url.to_string());
+    for lint in policy.lints(&protection.load().protected) {
+        log::warn!(target: policy::TARGET, "Policy at {url}: {lint}");
+    }
+    Ok(())
+}
+
+/// Applies changes to the policy at `source` every `interval`.  Policies that can not be
+/// fetched or applied are logged, and the rules in place are kept.
+async fn watch_policy(
+    mut source: PolicySource,
+    interval: Duration,
+    protection: ProtectionHandle,
+    methods: HashSet<String>,
+    history: PolicyHistory,
+) {
+    use policy::TARGET;
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let policy = match source.fetch().await {
+            Ok(Some(policy)) => policy,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!(target: TARGET, "Failed to fetch the policy from {}: {err}", source.url());
+                continue;
+            }
+        };
+        let rules = Policy {
+            version: None,
+            ..policy.clone()
+        };
+        // `Policy::of` has no version, only rules.
+        if rules == Policy::of(&protection.load()) {
+            continue;
+        }
+        match apply_policy(&protection, &methods, &policy, &history, source.url()) {
+            Ok(()) => log::info!(target: TARGET, "Applied the policy from {}", source.url()),
+            Err(problems) => log::warn!(
+                target: TARGET,
+                "Ignoring the policy from {}: {}",
+                source.url(),
+                problems.join(", "),
+            ),
+        }
+    }
+}
+
+/// `POST`s `body` to the webhook at `url`, logging failures under `target`.
+async fn post_json(client: Client<HttpConnector>, url: Uri, body: Vec<u8>, target: &str) {
+    let request = hyper::Request::post(&url)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap();
+    match client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => log::warn!(
+            target: target,
+            "Webhook {url} responded with HTTP {}",
+            response.status(),
+        ),
+        Err(err) => log::warn!(target: target, "Webhook {url} failed: {err}"),
+    }
+}
+
+/// A listener on `addr`, that other processes may listen on at the same time, so that a new
+/// server can start before the old one stops.
+#[cfg(unix)]
+fn bind_reusing_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reusing_port(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--reuse-port is only supported on Unix",
+    ))
+}
+
+/// Resolves on `SIGTERM` or `SIGINT`, after which the server stops accepting connections, and
+/// exits once the requests it already has are answered.
+async fn shutdown_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => (),
+                    _ = tokio::signal::ctrl_c() => (),
+                }
+            }
+            Err(err) => {
+                log::warn!("Failed to handle SIGTERM: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+
+    log::info!("Shutting down, waiting for requests in flight");
+    if let Err(err) = systemd::notify("STOPPING=1") {
+        log::warn!("Failed to notify systemd that the server is stopping: {err}");
+    }
+}
+
+/// Versions of the API, oldest first, see [`jsonrpc_protection::versioning`].  Each is served on
+/// its own path, and version 1 on every other path too.
+const API_VERSIONS: [&str; 2] = ["v1", "v2"];
+
+/// How the handler on a path is protected, as set on the command line, or by a profile.
+#[derive(Clone)]
+struct PathProtection {
+    rate_limiter: Option<RateLimiter>,
+    verifier: Option<RequestVerifier>,
+    accept_tokens: bool,
+    cors: Option<Cors>,
+}
+
+fn check_config(args: &Args) -> ExitCode {
+    let problems = config_problems(args);
+    if problems.is_empty() {
+        println!("Configuration is valid");
+        return ExitCode::SUCCESS;
+    }
+
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    ExitCode::FAILURE
+}
+
+/// Everything in `args` that would make the server fail to start, or that is most likely not
+/// what was meant.  Problems clap can not see, as they involve several arguments or files.
+fn config_problems(args: &Args) -> Vec<String> {
+    let mut problems = vec![];
+
+    #[cfg(feature = "ws")]
+    if args.ws_listen == Some(args.listen) {
+        problems.push(format!(
+            "--ws-listen {} is the same as --listen",
+            args.listen
+        ));
+    }
+
+    if let (Some(idle), Some(keep_alive @ 1..)) = (args.idle_timeout, args.keep_alive_timeout) {
+        if idle <= keep_alive {
+            problems.push(format!(
+                "--idle-timeout {idle} is not longer than --keep-alive-timeout {keep_alive}, \
+                 which then has no effect"
+            ));
+        }
+    }
+
+    for (option, path) in [
+        ("--request-signing-key-file", &args.request_signing_key_file),
+        (
+            "--response-signing-key-file",
+            &args.response_signing_key_file,
+        ),
+        ("--policy-signing-key-file", &args.policy_signing_key_file),
+    ] {
+        let Some(path) = path else {
+            continue;
+        };
+        match fs::read(path) {
+            Ok(key) if key.trim_ascii().is_empty() => {
+                problems.push(format!("{option} {} is empty", path.display()))
+            }
+            Ok(_) => (),
+            Err(err) => problems.push(format!("{option} {}: {err}", path.display())),
+        }
+    }
+
+    if let Some(url) = &args.policy_url {
+        if let Err(err) = PolicySource::new(url.clone()) {
+            problems.push(format!("--policy-url {url}: {err}"));
+        }
+    }
+
+    if let Some(path) = &args.admin_token_file {
+        match fs::read_to_string(path) {
+            Ok(token) if token.trim().is_empty() => {
+                problems.push(format!("--admin-token-file {} is empty", path.display()))
+            }
+            Ok(_) => {
+                if args.bootstrap_window.is_some() {
+                    problems.push(format!(
+                        "--admin-token-file {} exists, so --bootstrap-window has no effect",
+                        path.display()
+                    ));
+                }
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound && args.bootstrap_window.is_some() => {}
+            Err(err) => problems.push(format!("--admin-token-file {}: {err}", path.display())),
+        }
+    }
+
+    if let Some(path) = &args.request_signing_keys_file {
+        if let Err(err) = signing::load_keys(path) {
+            problems.push(format!(
+                "--request-signing-keys-file {}: {err}",
+                path.display()
+            ));
+        }
+    }
+
+    if let Some(path) = &args.profiles {
+        match profile::load(path) {
+            Ok(profiles) => {
+                for (name, profile) in &profiles {
+                    if let Some(keys) = &profile.request_signing_keys_file {
+                        if let Err(err) = signing::load_keys(keys) {
+                            problems.push(format!(
+                                "--profiles {}: profile {name}: {}: {err}",
+                                path.display(),
+                                keys.display()
+                            ));
+                        }
+                    }
+                    for (role, limit) in profile.rate_limit.iter().flatten() {
+                        for part in limit.key.iter().flatten() {
+                            let KeyPart::Attribute(attribute) = part else {
+                                continue;
+                            };
+                            if !args
+                                .attribute_headers
+                                .iter()
+                                .any(|header| header.attribute == *attribute)
+                            {
+                                problems.push(format!(
+                                    "--profiles {}: profile {name}: rate limit for {role}: no \
+                                     --attribute-header sets attribute {attribute}",
+                                    path.display()
+                                ));
+                            }
+                        }
+                    }
+                }
+                for (path, name) in &args.path_profiles {
+                    if !profiles.contains_key(name) {
+                        problems.push(format!(
+                            "--path-profile {path}={name}: there is no such profile"
+                        ));
+                    }
+                }
+            }
+            Err(err) => problems.push(format!("--profiles {}: {err}", path.display())),
+        }
+    }
+    if let Some(path) = &args.admin_path {
+        if API_VERSIONS
+            .map(ApiVersion::new)
+            .iter()
+            .any(|version| version.path == *path)
+        {
+            problems.push(format!(
+                "--admin-path {path} is where an API version is served"
+            ));
+        }
+    }
+    for path in repeated(args.path_profiles.iter().map(|(path, _)| path)) {
+        problems.push(format!("--path-profile is given more than once for {path}"));
+    }
+
+    if let Some(path) = &args.break_glass_file {
+        match BreakGlass::load(path, Duration::from_secs(args.break_glass_duration)) {
+            Ok(break_glass) if break_glass.is_empty() => problems.push(format!(
+                "--break-glass-file {} lists no tokens",
+                path.display()
+            )),
+            Ok(_) => (),
+            Err(err) => problems.push(format!("--break-glass-file {}: {err}", path.display())),
+        }
+    }
+
+    if let Some(path) = &args.messages_file {
+        if let Err(err) = MessageCatalog::load(path) {
+            problems.push(format!("--messages-file {}: {err}", path.display()));
+        }
+    }
+
+    let methods = MainRpcImpl
+        .to_delegate()
+        .into_iter()
+        .chain(AdminRpcImpl.to_delegate())
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    for method in &args.idempotent_methods {
+        if !methods.contains(method) {
+            problems.push(format!(
+                "--idempotent-method {method}: there is no such method"
+            ));
+        }
+    }
+    for constraint in &args.param_constraints {
+        if !methods.contains(&constraint.method) {
+            problems.push(format!(
+                "--param-constraint {constraint}: there is no such method"
+            ));
+        }
+    }
+    for rule in &args.owned_params {
+        if !methods.contains(&rule.method) {
+            problems.push(format!("--owned-param {rule}: there is no such method"));
+        }
+        let from_header = args
+            .attribute_headers
+            .iter()
+            .any(|header| header.attribute == rule.attribute);
+        if rule.attribute != USER_ATTRIBUTE && !from_header {
+            problems.push(format!(
+                "--owned-param {rule}: no --attribute-header sets attribute {}",
+                rule.attribute
+            ));
+        }
+    }
+    for (role, limit) in &args.rate_limit {
+        for part in limit.key.iter().flatten() {
+            let KeyPart::Attribute(attribute) = part else {
+                continue;
+            };
+            if !args
+                .attribute_headers
+                .iter()
+                .any(|header| header.attribute == *attribute)
+            {
+                problems.push(format!(
+                    "--rate-limit for {role}: no --attribute-header sets attribute {attribute}"
+                ));
+            }
+        }
+    }
+    for filter in &args.result_filters {
+        if !methods.contains(&filter.method) {
+            problems.push(format!("--result-filter {filter}: there is no such method"));
+        }
+    }
+    for deprecation in &args.deprecated_methods {
+        if !methods.contains(&deprecation.method) {
+            problems.push(format!(
+                "--deprecated-method {deprecation}: there is no such method"
+            ));
+        }
+    }
+    for method in repeated(args.deprecated_methods.iter().map(|d| &d.method)) {
+        problems.push(format!(
+            "--deprecated-method is given more than once for {method}"
+        ));
+    }
+    for header in &args.attribute_headers {
+        if header.attribute == USER_ATTRIBUTE && !args.owned_params.is_empty() {
+            problems.push(format!(
+                "--attribute-header {header}: --owned-param takes attribute \
+                 {USER_ATTRIBUTE} to be the user name, not the header"
+            ));
+        }
+    }
+    if let Some(path) = &args.slo_file {
+        match slo::load(path) {
+            Ok(slos) => {
+                for slo in slos {
+                    for method in slo.methods.difference(&methods) {
+                        problems.push(format!(
+                            "--slo-file {}: objective {:?} covers {method}, there is no such \
+                             method",
+                            path.display(),
+                            slo.name,
+                        ));
+                    }
+                }
+            }
+            Err(err) => problems.push(format!("--slo-file {}: {err}", path.display())),
+        }
+    }
+    let protected = AdminRpcImpl
+        .to_delegate()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<HashSet<_>>();
+    for method in &args.loopback_methods {
+        if !protected.contains(method) {
+            problems.push(format!(
+                "--loopback-method {method}: there is no such protected method"
+            ));
+        }
+    }
+    for method in &args.approval_methods {
+        if !protected.contains(method) {
+            problems.push(format!(
+                "--approval-method {method}: there is no such protected method"
+            ));
+        }
+    }
+    let policy = Policy {
+        version: None,
+        loopback_methods: args.loopback_methods.iter().cloned().collect(),
+        param_constraints: args.param_constraints.clone(),
+        owned_params: args.owned_params.clone(),
+        canary: None,
+    };
+    for lint in policy.lints(&protected) {
+        // Written with the keys of a policy file, the flags set the same rules.
+        let lint = lint
+            .replacen("param_constraints: ", "--param-constraint ", 1)
+            .replacen("owned_params: ", "--owned-param ", 1);
+        problems.push(lint);
+    }
+    if !args.idempotent_methods.is_empty() && args.idempotency_cache_size == 0 {
+        problems
+            .push("--idempotency-cache-size is 0, so --idempotent-method has no effect".to_owned());
+    }
+
+    // Later values silently replace earlier ones, which is likely a mistake.
+    let repeated_roles = [
+        (
+            "--rate-limit",
+            repeated(args.rate_limit.iter().map(|(role, _)| role)),
+        ),
+        (
+            "--max-subscriptions",
+            repeated(args.max_subscriptions.iter().map(|(role, _)| role)),
+        ),
+        (
+            "--max-notification-rate",
+            repeated(args.max_notification_rate.iter().map(|(role, _)| role)),
+        ),
+    ];
+    for (option, roles) in repeated_roles {
+        for role in roles {
+            problems.push(format!("{option} is given more than once for {role}"));
+        }
+    }
+    for outcome in repeated(args.log_sample_rate.iter().map(|(outcome, _)| outcome)) {
+        problems.push(format!(
+            "--log-sample-rate is given more than once for {outcome}"
+        ));
+    }
+
+    if args.max_in_flight == Some(0) {
+        problems.push("--max-in-flight is 0, so no request would ever be handled".to_owned());
+    }
+    if !(args.max_cpu_usage > 0.0 && args.max_cpu_usage <= 100.0) {
+        problems.push(format!(
+            "--max-cpu-usage {} is not a percentage between 0 and 100",
+            args.max_cpu_usage
+        ));
+    }
+    if args.load_rate_limit_divisor == 0 {
+        problems.push("--load-rate-limit-divisor must not be 0".to_owned());
+    }
+    if args.memory_limit == Some(0) {
+        problems.push("--memory-limit is 0, so every request would be rejected".to_owned());
+    }
+
+    problems
+}
+
+/// Items that occur in `items` more than once.
+fn repeated<T: Eq + Hash>(items: impl IntoIterator<Item = T>) -> Vec<T> {
+    let mut seen = HashSet::new();
+    let mut repeated = vec![];
+    for item in items {
+        if seen.contains(&item) {
+            if !repeated.contains(&item) {
+                repeated.push(item);
+            }
+        } else {
+            seen.insert(item);
+        }
+    }
+    repeated
+}
+
+fn parse_role_limit<T>(s: &str) -> Result<(Role, T), String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let (role, limit) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ROLE=LIMIT, got \"{s}\""))?;
+    let limit = limit
+        .parse()
+        .map_err(|err| format!("invalid limit: {err}"))?;
+    Ok((role.parse()?, limit))
+}
+
+fn parse_path(s: &str) -> Result<String, String> {
+    if s.starts_with('/') {
+        Ok(s.to_owned())
+    } else {
+        Err(format!("expected a path starting with /, got \"{s}\""))
+    }
+}
+
+fn parse_path_profile(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((path, name)) if !name.is_empty() => Ok((parse_path(path)?, name.to_owned())),
+        _ => Err(format!("expected PATH=PROFILE, got \"{s}\"")),
+    }
+}
+
+fn parse_sample_rate(s: &str) -> Result<(Outcome, f64), String> {
+    let (outcome, rate) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected OUTCOME=RATE, got \"{s}\""))?;
+    let rate = rate
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+        .ok_or_else(|| format!("invalid rate \"{rate}\", expected a number between 0 and 1"))?;
+    Ok((outcome.parse()?, rate))
+}
+
+fn subscription_limits(args: &Args) -> HashMap<Role, SubscriptionLimits> {
+    let mut limits = HashMap::<Role, SubscriptionLimits>::new();
+    for &(role, max) in &args.max_subscriptions {
+        limits.entry(role).or_default().max_subscriptions = max;
+    }
+    for &(role, max) in &args.max_notification_rate {
+        limits.entry(role).or_default().max_notification_rate = max;
+    }
+    limits
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::cli::Cli};
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Cli::try_parse_from(["jsonrpc-protection"].iter().chain(args)).map(|cli| cli.serve)
+    }
+
+    #[test]
+    fn connections_per_ip_are_at_least_one() {
+        assert!(parse(&["--max-connections-per-ip", "0"]).is_err());
+        assert!(parse(&["--max-connections-per-ip", "-1"]).is_err());
+        let args = parse(&["--max-connections-per-ip", "1"]).unwrap();
+        assert_eq!(args.max_connections_per_ip, Some(1));
+    }
+
+    #[test]
+    fn trusted_proxies_need_a_per_ip_limit() {
+        assert!(parse(&["--trusted-proxy", "10.0.0.1"]).is_err());
+        let args = parse(&[
+            "--max-connections-per-ip",
+            "4",
+            "--trusted-proxy",
+            "10.0.0.1",
+            "--trusted-proxy",
+            "::1",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.trusted_proxies,
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+    }
+}
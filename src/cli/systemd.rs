@@ -0,0 +1,101 @@
+//! Integration with systemd.
+//!
+//! Socket activation, see `sd_listen_fds(3)`: when the unit has a matching `.socket` unit,
+//! systemd opens the listening socket, and passes it to the server, which can then be restarted
+//! without refusing connections, and bind privileged ports without running as root.
+//!
+//! Readiness and watchdog notifications, see `sd_notify(3)`: units with `Type=notify` are only
+//! considered started once the server listens, and units with `WatchdogSec=` are restarted
+//! when the runtime stops responding to timers.
+
+use std::{env, io, net::TcpListener, time::Duration};
+
+/// Listening sockets passed by systemd, in the order of the `.socket` unit.  Empty if the
+/// server was not socket activated.
+#[cfg(unix)]
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    use std::{os::unix::io::FromRawFd, process};
+
+    /// The first passed file descriptor.  The rest follow it.
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    // The variables are meant for this process only, not for anything it starts.
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+        return Ok(vec![]);
+    }
+    let Some(count) = count.and_then(|count| count.parse::<i32>().ok()) else {
+        return Ok(vec![]);
+    };
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count))
+        .map(|fd| {
+            // SAFETY: systemd passes these descriptors to this process, and nothing else in the
+            // process knows about them.  `LISTEN_FDS` is removed above, so this happens once.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(vec![])
+}
+
+/// Sends `state` to the service manager, if it asked for notifications.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+
+    // Names starting with `@` are in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        let addr = SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// How often the service manager expects watchdog notifications, if at all.
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(std::process::id())) {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Notifies the watchdog twice per `interval`, as `sd_watchdog_enabled(3)` suggests.  Meant to
+/// run on the runtime that handles requests, so that notifications stop when it is stuck.
+pub async fn watchdog(interval: Duration) {
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        if let Err(err) = notify("WATCHDOG=1") {
+            log::warn!("Failed to notify the systemd watchdog: {err}");
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Managing the users file while the server is not running.
+
+use {
+    super::StrengthArgs,
+    clap::{Parser, Subcommand},
+    jsonrpc_protection::{state::Role, users::UserStore},
+    std::{io, path::PathBuf, process::ExitCode},
+};
+
+#[derive(Parser)]
+pub struct Args {
+    /// File holding user accounts, as passed to `serve --users-file`.
+    #[arg(long)]
+    users_file: PathBuf,
+
+    #[command(flatten)]
+    strength: StrengthArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add a user.  The password is read from the first line of standard input.
+    Add {
+        username: String,
+        #[arg(long, default_value = "admin")]
+        role: Role,
+    },
+    /// Remove a user.
+    Remove { username: String },
+    /// Change the password of a user.  The new password is read from the first line of
+    /// standard input.
+    SetPassword { username: String },
+    /// Change the role of a user.
+    SetRole { username: String, role: Role },
+    /// Print all users and their roles.
+    List,
+}
+
+pub fn run(args: Args) -> ExitCode {
+    let users = match UserStore::load(&args.users_file) {
+        Ok(users) => users.strength_policy(args.strength.policy()),
+        Err(err) => {
+            eprintln!(
+                "Failed to load users from {}: {err}",
+                args.users_file.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match args.command {
+        Command::Add { username, role } => read_password().and_then(|password| {
+            users
+                .add(&username, &password, role)
+                .map_err(|err| err.to_string())
+        }),
+        Command::Remove { username } => users.remove(&username).map_err(|err| err.to_string()),
+        Command::SetPassword { username } => read_password().and_then(|password| {
+            users
+                .set_password(&username, &password)
+                .map_err(|err| err.to_string())
+        }),
+        Command::SetRole { username, role } => users
+            .set_role(&username, role)
+            .map_err(|err| err.to_string()),
+        Command::List => {
+            for (username, role) in users.list() {
+                println!("{username}\t{role}");
+            }
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|err| format!("Failed to read the password: {err}"))?;
+
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("The password is empty".to_owned());
+    }
+    Ok(password.to_owned())
+}
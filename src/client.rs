@@ -0,0 +1,360 @@
+//! HTTP client for servers protected by [`ProtectRpcMiddleware`].
+//!
+//! The client attaches the configured credentials to every call and turns JSON-RPC errors into
+//! [`ClientError`] variants, so callers do not need to inspect error codes by hand.
+//!
+//! [`ProtectRpcMiddleware`]: crate::middleware::ProtectRpcMiddleware
+
+use {
+    crate::{
+        deadline::{self, DEADLINE_HEADER},
+        signing::{
+            RequestSigner, ResponseSigner, KEY_ID_HEADER, NONCE_HEADER, RESPONSE_SIGNATURE_HEADER,
+            SIGNATURE_HEADER, TIMESTAMP_HEADER,
+        },
+    },
+    hyper::{body, client::HttpConnector, header, Body, Method, Request, StatusCode, Uri},
+    jsonrpc_core::{
+        types::{
+            error::{Error as JsonRpcError, ErrorCode},
+            response::Output,
+        },
+        Value,
+    },
+    serde_json::json,
+    std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Instant,
+    },
+    thiserror::Error,
+};
+
+/// Credentials attached to every call.
+#[derive(Clone, Default)]
+pub enum Auth {
+    /// Only unprotected methods can be called.
+    #[default]
+    None,
+    /// Sent in the `X-Admin-Auth` header.
+    AdminToken(String),
+    /// Every request is signed, see [`crate::signing`].
+    Signature(RequestSigner),
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("request failed: {0}")]
+    Transport(#[from] hyper::Error),
+
+    /// The deadline passed before the request was sent.
+    #[error("deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("request could not be built: {0}")]
+    InvalidRequestParts(#[from] hyper::http::Error),
+
+    #[error("server responded with HTTP {0}")]
+    HttpStatus(StatusCode),
+
+    /// Response verification is enabled, but the response signature is missing or does not
+    /// match the body.
+    #[error("response signature is missing or invalid")]
+    InvalidSignature,
+
+    #[error("server response is not a valid JSON-RPC response: {0}")]
+    InvalidResponse(serde_json::Error),
+
+    /// The server could not parse the request.
+    #[error("parse error: {}", .0.message)]
+    Parse(JsonRpcError),
+
+    /// The request was rejected before reaching the method.  This is also how the server
+    /// reports missing or incorrect credentials for protected methods.
+    #[error("invalid request: {}", .0.message)]
+    InvalidRequest(JsonRpcError),
+
+    #[error("method not found: {}", .0.message)]
+    MethodNotFound(JsonRpcError),
+
+    #[error("invalid params: {}", .0.message)]
+    InvalidParams(JsonRpcError),
+
+    #[error("internal error: {}", .0.message)]
+    Internal(JsonRpcError),
+
+    /// An error reported by the method itself.
+    #[error("server error {}: {}", .0.code.code(), .0.message)]
+    Server(JsonRpcError),
+}
+
+impl From<JsonRpcError> for ClientError {
+    fn from(error: JsonRpcError) -> Self {
+        match error.code {
+            ErrorCode::ParseError => Self::Parse(error),
+            ErrorCode::InvalidRequest => Self::InvalidRequest(error),
+            ErrorCode::MethodNotFound => Self::MethodNotFound(error),
+            ErrorCode::InvalidParams => Self::InvalidParams(error),
+            ErrorCode::InternalError => Self::Internal(error),
+            ErrorCode::ServerError(_) => Self::Server(error),
+        }
+    }
+}
+
+impl ClientError {
+    /// Returns the JSON-RPC error sent by the server, if there was one.
+    pub fn rpc_error(&self) -> Option<&JsonRpcError> {
+        match self {
+            Self::Transport(_)
+            | Self::InvalidRequestParts(_)
+            | Self::DeadlineExceeded
+            | Self::HttpStatus(_)
+            | Self::InvalidSignature
+            | Self::InvalidResponse(_) => None,
+            Self::Parse(error)
+            | Self::InvalidRequest(error)
+            | Self::MethodNotFound(error)
+            | Self::InvalidParams(error)
+            | Self::Internal(error)
+            | Self::Server(error) => Some(error),
+        }
+    }
+}
+
+pub struct Client {
+    http: hyper::Client<HttpConnector>,
+    url: Uri,
+    auth: Auth,
+    response_verifier: Option<ResponseSigner>,
+    next_id: AtomicU64,
+}
+
+impl Client {
+    pub fn new(url: Uri) -> Self {
+        Self {
+            http: hyper::Client::new(),
+            url,
+            auth: Auth::None,
+            response_verifier: None,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Reject responses that are not signed with `verifier`'s key.
+    pub fn with_response_verification(mut self, verifier: ResponseSigner) -> Self {
+        self.response_verifier = Some(verifier);
+        self
+    }
+
+    /// Calls `method` with positional `params` and returns the call result.
+    pub async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, ClientError> {
+        self.send(method, params, None).await
+    }
+
+    /// Same as [`Self::call`], but tells the server how much time is left until `deadline`, so
+    /// that it can give up on the call once the result is no longer needed.
+    pub async fn call_with_deadline(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        deadline: Instant,
+    ) -> Result<Value, ClientError> {
+        self.send(method, params, Some(deadline)).await
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        deadline: Option<Instant>,
+    ) -> Result<Value, ClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        let body = body.to_string();
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json");
+        match &self.auth {
+            Auth::None => (),
+            Auth::AdminToken(token) => request = request.header("X-Admin-Auth", token),
+            Auth::Signature(signer) => {
+                let headers = signer.signature_headers(self.url.path(), body.as_bytes());
+                request = request
+                    .header(TIMESTAMP_HEADER, headers.timestamp)
+                    .header(NONCE_HEADER, headers.nonce)
+                    .header(SIGNATURE_HEADER, headers.signature);
+                if let Some(key_id) = headers.key_id {
+                    request = request.header(KEY_ID_HEADER, key_id);
+                }
+            }
+        }
+        if let Some(deadline) = deadline {
+            let budget =
+                deadline::remaining_budget(deadline).ok_or(ClientError::DeadlineExceeded)?;
+            request = request.header(DEADLINE_HEADER, budget);
+        }
+        let request = request.body(Body::from(body))?;
+
+        let response = self.http.request(request).await?;
+        let status = response.status();
+        if status != StatusCode::OK {
+            return Err(ClientError::HttpStatus(status));
+        }
+
+        let signature = response
+            .headers()
+            .get(RESPONSE_SIGNATURE_HEADER)
+            .and_then(|signature| signature.to_str().ok())
+            .map(str::to_owned);
+        let body = body::to_bytes(response.into_body()).await?;
+
+        if let Some(verifier) = &self.response_verifier {
+            match signature {
+                Some(signature) if verifier.verify(&body, &signature) => (),
+                _ => return Err(ClientError::InvalidSignature),
+            }
+        }
+
+        let output =
+            serde_json::from_slice::<Output>(&body).map_err(ClientError::InvalidResponse)?;
+
+        match output {
+            Output::Success(success) => Ok(success.result),
+            Output::Failure(failure) => Err(failure.error.into()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            http::RpcHttpHandler,
+            middleware::ProtectRpcMiddleware,
+            state::{tests::state, ProtectionHandle},
+            RpcMeta,
+        },
+        hyper::{server::Server, service::make_service_fn},
+        jsonrpc_core::{MetaIoHandler, Params},
+        std::{convert::Infallible, net::SocketAddr},
+    };
+
+    /// Serves `f`, which is protected, and `g`, on a random port.
+    fn serve(
+        configure: impl FnOnce(
+            RpcHttpHandler<ProtectRpcMiddleware>,
+        ) -> RpcHttpHandler<ProtectRpcMiddleware>,
+    ) -> Uri {
+        let mut io = MetaIoHandler::with_middleware(ProtectRpcMiddleware::new(
+            ProtectionHandle::new(state()),
+        ));
+        io.add_method_with_meta("f", |_: Params, _: RpcMeta| async { Ok(Value::from(1)) });
+        io.add_method_with_meta("g", |params: Params, _: RpcMeta| async move {
+            let [a, b] = params.parse::<[u16; 2]>()?;
+            Ok(Value::from(a + b))
+        });
+        let service = configure(RpcHttpHandler::new(io)).into_service();
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(service) }
+            },
+        ));
+        let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn credentials_are_sent_with_every_call() {
+        let url = serve(|handler| handler);
+
+        let anonymous = Client::new(url.clone());
+        assert_eq!(
+            anonymous.call("g", vec![1.into(), 2.into()]).await.unwrap(),
+            3
+        );
+        let err = anonymous.call("f", vec![]).await.unwrap_err();
+        assert!(matches!(err, ClientError::InvalidRequest(_)), "{err}");
+        assert_eq!(
+            err.rpc_error().unwrap().data.as_ref().unwrap()["reason"],
+            "credentials_required"
+        );
+
+        let admin = Client::new(url.clone()).with_auth(Auth::AdminToken("root".to_owned()));
+        assert_eq!(admin.call("f", vec![]).await.unwrap(), 1);
+        let wrong = Client::new(url).with_auth(Auth::AdminToken("wrong".to_owned()));
+        let err = wrong.call("f", vec![]).await.unwrap_err();
+        assert_eq!(
+            err.rpc_error().unwrap().data.as_ref().unwrap()["reason"],
+            "invalid_token"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_typed_by_code() {
+        let client = Client::new(serve(|handler| handler));
+        assert!(matches!(
+            client.call("h", vec![]).await,
+            Err(ClientError::MethodNotFound(_))
+        ));
+        assert!(matches!(
+            client.call("g", vec!["a".into()]).await,
+            Err(ClientError::InvalidParams(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_errors_are_reported_with_the_status() {
+        let client = Client::new(serve(|handler| handler.max_request_body_size(10)));
+        assert!(matches!(
+            client.call("g", vec![1.into(), 2.into()]).await,
+            Err(ClientError::HttpStatus(StatusCode::PAYLOAD_TOO_LARGE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deadlines_that_passed_are_not_sent() {
+        let client = Client::new(serve(|handler| handler));
+        assert!(matches!(
+            client.call_with_deadline("g", vec![], Instant::now()).await,
+            Err(ClientError::DeadlineExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn responses_are_checked_against_the_signing_key() {
+        let url = serve(|handler| handler.response_signer(ResponseSigner::new(b"key")));
+
+        let client =
+            Client::new(url.clone()).with_response_verification(ResponseSigner::new(b"key"));
+        assert_eq!(client.call("g", vec![1.into(), 2.into()]).await.unwrap(), 3);
+
+        let client = Client::new(url).with_response_verification(ResponseSigner::new(b"other"));
+        assert!(matches!(
+            client.call("g", vec![1.into(), 2.into()]).await,
+            Err(ClientError::InvalidSignature)
+        ));
+
+        let unsigned = Client::new(serve(|handler| handler))
+            .with_response_verification(ResponseSigner::new(b"key"));
+        assert!(matches!(
+            unsigned.call("g", vec![1.into(), 2.into()]).await,
+            Err(ClientError::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,640 @@
+//! Limits on the parameters callers with a given role may pass to a method.
+//!
+//! A [`ParamConstraint`] such as `admin:f:0<=10` only lets admins call `f` when the first
+//! parameter is at most 10.  Calls with other parameters are rejected with
+//! [`Reason::ParamsNotAllowed`] before the method is executed.  Every constraint for the role and
+//! the method has to hold, and callers with other roles are not affected.
+//!
+//! An [`OwnershipRule`] such as `transfer:account=account_id` only lets callers pass their own
+//! `account_id` attribute as the `account` parameter of `transfer`, for "users may only act on
+//! their own resources" policies.  Calls with a different value, or by callers without the
+//! attribute, are rejected with [`Reason::NotOwner`].  The attribute [`USER_ATTRIBUTE`] is the
+//! name of the user that logged in.  Other attributes come from
+//! [`RpcMeta::attributes`], so they can only be trusted when a proxy sets the headers.
+//!
+//! For calls made as another user, with the `X-Run-As` header, the role and the name of that
+//! user count.
+//!
+//! [`RpcMeta::attributes`]: crate::RpcMeta::attributes
+
+use {
+    crate::{
+        rejection::{Reason, Rejection},
+        state::Role,
+    },
+    jsonrpc_core::Params,
+    serde_json::Value,
+    std::{cmp::Ordering, collections::BTreeMap, fmt, str::FromStr},
+};
+
+/// Attribute of [`OwnershipRule`]s that stands for the name of the user that logged in.
+pub const USER_ATTRIBUTE: &str = "user";
+
+/// `method` may only be called by callers with `role` when `param` compares to `value` as
+/// `op` says.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamConstraint {
+    pub role: Role,
+    pub method: String,
+    pub param: Param,
+    pub op: Op,
+    pub value: Value,
+}
+
+/// `method` may only be called with the caller's own `attribute` as `param`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnershipRule {
+    pub method: String,
+    pub param: Param,
+    pub attribute: String,
+}
+
+/// Which parameter a constraint is about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Param {
+    /// Zero based position, for parameters passed as an array.
+    Position(usize),
+    /// Name, for parameters passed as an object.
+    Name(String),
+}
+
+impl Param {
+    /// Value of the parameter in `params`, if it is there.
+    pub fn find<'a>(&self, params: &'a Params) -> Option<&'a Value> {
+        match (self, params) {
+            (Param::Position(i), Params::Array(values)) => values.get(*i),
+            (Param::Name(name), Params::Map(values)) => values.get(name),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Param {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(position) => Ok(Param::Position(position)),
+            Err(_) if !s.is_empty() => Ok(Param::Name(s.to_owned())),
+            Err(_) => Err("parameter is empty".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    /// Longer operators first, so that `<=` is not taken for `<`.
+    const ALL: [(&'static str, Op); 6] = [
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        ("<=", Op::Le),
+        (">=", Op::Ge),
+        ("<", Op::Lt),
+        (">", Op::Gt),
+    ];
+
+    fn as_str(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, op)| *op == self)
+            .map(|(s, _)| *s)
+            .expect("Every operator is listed")
+    }
+
+    /// `==` and `!=` compare any values.  The others only hold for two numbers, or two strings.
+    fn holds(self, actual: &Value, expected: &Value) -> bool {
+        let ordering = compare(actual, expected);
+        match self {
+            Op::Eq => actual == expected || ordering == Some(Ordering::Equal),
+            Op::Ne => actual != expected && ordering != Some(Ordering::Equal),
+            Op::Lt => ordering == Some(Ordering::Less),
+            Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Op::Gt => ordering == Some(Ordering::Greater),
+            Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Order of two numbers, or of two strings.  Other values are not ordered.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl ParamConstraint {
+    /// Whether `params` of a call to [`Self::method`] satisfy the constraint.  A missing
+    /// parameter does not.
+    pub fn holds(&self, params: &Params) -> bool {
+        self.param
+            .find(params)
+            .is_some_and(|actual| self.op.holds(actual, &self.value))
+    }
+
+    /// Whether every call that satisfies `self` satisfies `other` too, so that `other` adds
+    /// nothing.  Only tells for constraints on the same parameter, method and role.
+    pub fn implies(&self, other: &ParamConstraint) -> bool {
+        if !self.same_param(other) {
+            return false;
+        }
+        let (a, b) = (&self.value, &other.value);
+        match (self.op, other.op) {
+            (Op::Eq, _) => other.op.holds(a, b),
+            (Op::Ne, Op::Ne) => Op::Eq.holds(a, b),
+            (Op::Lt | Op::Le, Op::Lt | Op::Le) => match compare(a, b) {
+                Some(Ordering::Less) => true,
+                Some(Ordering::Equal) => self.op == Op::Lt || other.op == Op::Le,
+                _ => false,
+            },
+            (Op::Gt | Op::Ge, Op::Gt | Op::Ge) => match compare(a, b) {
+                Some(Ordering::Greater) => true,
+                Some(Ordering::Equal) => self.op == Op::Gt || other.op == Op::Ge,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether no call can satisfy both `self` and `other`.  Only tells for constraints on the
+    /// same parameter, method and role.
+    pub fn excludes(&self, other: &ParamConstraint) -> bool {
+        if !self.same_param(other) {
+            return false;
+        }
+        let (a, b) = (&self.value, &other.value);
+        match (self.op, other.op) {
+            (Op::Eq, _) => !other.op.holds(a, b),
+            (_, Op::Eq) => !self.op.holds(b, a),
+            // Bounds of different types: no value is ordered against both.
+            (Op::Lt | Op::Le, Op::Gt | Op::Ge) => match compare(a, b) {
+                Some(Ordering::Less) | None => true,
+                Some(Ordering::Equal) => self.op == Op::Lt || other.op == Op::Gt,
+                Some(Ordering::Greater) => false,
+            },
+            (Op::Gt | Op::Ge, Op::Lt | Op::Le) => other.excludes(self),
+            _ => false,
+        }
+    }
+
+    fn same_param(&self, other: &ParamConstraint) -> bool {
+        self.role == other.role && self.method == other.method && self.param == other.param
+    }
+}
+
+impl OwnershipRule {
+    /// Whether `params` of a call to [`Self::method`] carry `owner` as the parameter.  Both
+    /// strings and numbers are compared with the text of the attribute.
+    pub fn holds(&self, params: &Params, owner: Option<&str>) -> bool {
+        match (self.param.find(params), owner) {
+            (Some(Value::String(actual)), Some(owner)) => actual == owner,
+            (Some(Value::Number(actual)), Some(owner)) => actual.to_string() == owner,
+            _ => false,
+        }
+    }
+}
+
+/// Checks `params` of a call to `method`, by a caller with `role`, against all `constraints`.
+pub fn check(
+    constraints: &[ParamConstraint],
+    role: Role,
+    method: &str,
+    params: &Params,
+) -> Result<(), Rejection> {
+    match constraints
+        .iter()
+        .filter(|constraint| constraint.role == role && constraint.method == method)
+        .find(|constraint| !constraint.holds(params))
+    {
+        Some(violated) => Err(Rejection::new(
+            Reason::ParamsNotAllowed,
+            format!(
+                "Callers with role {role} may only call {method} with parameter {} {} {}",
+                violated.param, violated.op, violated.value
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `params` of a call to `method`, by a caller named `user` with `attributes`,
+/// only refer to what the caller owns, according to all `rules`.
+pub fn check_ownership(
+    rules: &[OwnershipRule],
+    user: Option<&str>,
+    attributes: &BTreeMap<String, String>,
+    method: &str,
+    params: &Params,
+) -> Result<(), Rejection> {
+    let owner = |attribute: &str| match attribute {
+        USER_ATTRIBUTE => user,
+        _ => attributes.get(attribute).map(String::as_str),
+    };
+    match rules
+        .iter()
+        .filter(|rule| rule.method == method)
+        .find(|rule| !rule.holds(params, owner(&rule.attribute)))
+    {
+        Some(violated) => Err(Rejection::new(
+            Reason::NotOwner,
+            format!(
+                "Parameter {} of {method} has to be the caller's own {}",
+                violated.param, violated.attribute
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+impl FromStr for ParamConstraint {
+    type Err = String;
+
+    /// Parses `ROLE:METHOD:PARAM OP VALUE`, such as `admin:f:0<=10` or
+    /// `anonymous:g:name=="x"`.  `PARAM` is a position or a name, `OP` one of `==`, `!=`,
+    /// `<`, `<=`, `>` and `>=`, and `VALUE` JSON.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected ROLE:METHOD:PARAM OP VALUE, got \"{s}\"");
+        let (role, rest) = s.split_once(':').ok_or_else(expected)?;
+        let (method, predicate) = rest.split_once(':').ok_or_else(expected)?;
+        if method.is_empty() {
+            return Err(expected());
+        }
+
+        let (at, op) = predicate
+            .char_indices()
+            .find_map(|(at, _)| {
+                Op::ALL
+                    .iter()
+                    .find(|(op, _)| predicate[at..].starts_with(op))
+                    .map(|(_, op)| (at, *op))
+            })
+            .ok_or_else(expected)?;
+        let param = predicate[..at].trim();
+        let value = predicate[at + op.as_str().len()..].trim();
+
+        let param = param.parse().map_err(|_| expected())?;
+        let value = serde_json::from_str(value)
+            .map_err(|err| format!("\"{value}\" is not a JSON value: {err}"))?;
+
+        Ok(ParamConstraint {
+            role: role.parse()?,
+            method: method.to_owned(),
+            param,
+            op,
+            value,
+        })
+    }
+}
+
+impl fmt::Display for ParamConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}{}{}",
+            self.role, self.method, self.param, self.op, self.value
+        )
+    }
+}
+
+impl FromStr for OwnershipRule {
+    type Err = String;
+
+    /// Parses `METHOD:PARAM=ATTRIBUTE`, such as `transfer:account=account_id`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected METHOD:PARAM=ATTRIBUTE, got \"{s}\"");
+        let (method, rest) = s.split_once(':').ok_or_else(expected)?;
+        let (param, attribute) = rest.rsplit_once('=').ok_or_else(expected)?;
+        if method.is_empty() || attribute.is_empty() {
+            return Err(expected());
+        }
+        Ok(OwnershipRule {
+            method: method.to_owned(),
+            param: param.parse().map_err(|_| expected())?,
+            attribute: attribute.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for OwnershipRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}={}", self.method, self.param, self.attribute)
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Param::Position(position) => write!(f, "{position}"),
+            Param::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            state::tests::{meta, state},
+            RpcMeta,
+        },
+        jsonrpc_core::{Call, Id, MethodCall, Version},
+        serde_json::json,
+    };
+
+    fn constraint(s: &str) -> ParamConstraint {
+        s.parse().unwrap()
+    }
+
+    fn params(value: Value) -> Params {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn call(method: &str, params: Value) -> Call {
+        Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: method.to_owned(),
+            params: self::params(params),
+            id: Id::Num(1),
+        })
+    }
+
+    fn reason<T>(result: Result<T, Rejection>) -> Option<Reason> {
+        result.err().map(|rejection| rejection.reason)
+    }
+
+    #[test]
+    fn constraints_parse() {
+        let parsed = constraint("admin:f:0<=10");
+        assert_eq!(parsed.role, Role::Admin);
+        assert_eq!(parsed.method, "f");
+        assert_eq!(parsed.param, Param::Position(0));
+        assert_eq!(parsed.op, Op::Le);
+        assert_eq!(parsed.value, json!(10));
+        assert_eq!(parsed.to_string(), "admin:f:0<=10");
+
+        let parsed = constraint(r#"anonymous:g: name != "x" "#);
+        assert_eq!(parsed.param, Param::Name("name".to_owned()));
+        assert_eq!(parsed.op, Op::Ne);
+        assert_eq!(parsed.to_string(), r#"anonymous:g:name!="x""#);
+
+        for invalid in [
+            "admin:f",
+            "admin::0<=10",
+            "admin:f:<=10",
+            "admin:f:0~10",
+            "admin:f:0<=ten",
+            "root:f:0<=10",
+        ] {
+            assert!(invalid.parse::<ParamConstraint>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn operators_compare_numbers_and_strings() {
+        let holds = |s: &str, value| constraint(s).holds(&params(json!([value])));
+        assert!(holds("admin:f:0<=10", json!(10)));
+        assert!(holds("admin:f:0<=10", json!(-1.5)));
+        assert!(!holds("admin:f:0<10", json!(10)));
+        assert!(holds("admin:f:0>=10", json!(10.0)));
+        assert!(!holds("admin:f:0>10", json!(10)));
+        assert!(holds(r#"admin:f:0<"b""#, json!("a")));
+        assert!(holds("admin:f:0==1", json!(1.0)));
+        assert!(!holds("admin:f:0!=1", json!(1.0)));
+        assert!(holds("admin:f:0==[1,2]", json!([1, 2])));
+
+        // Values of other types are not ordered, so bounds never hold for them.
+        assert!(!holds("admin:f:0<=10", json!("5")));
+        assert!(!holds("admin:f:0>=10", json!(null)));
+        assert!(!holds("admin:f:0<=10", json!([5])));
+        assert!(holds("admin:f:0!=10", json!("10")));
+    }
+
+    #[test]
+    fn missing_parameters_violate_constraints() {
+        let by_position = constraint("admin:f:1<=10");
+        assert!(!by_position.holds(&params(json!([1]))));
+        assert!(!by_position.holds(&params(json!({"1": 1}))));
+        assert!(!by_position.holds(&Params::None));
+
+        let by_name = constraint("admin:f:amount<=10");
+        assert!(by_name.holds(&params(json!({"amount": 1}))));
+        assert!(!by_name.holds(&params(json!([1]))));
+        assert!(!by_name.holds(&params(json!({}))));
+    }
+
+    #[test]
+    fn every_constraint_of_the_role_and_method_has_to_hold() {
+        let constraints = [
+            constraint("admin:f:0>=1"),
+            constraint("admin:f:0<=10"),
+            constraint("anonymous:f:0==0"),
+            constraint("admin:g:0==0"),
+        ];
+        let check = |role, method, value| {
+            reason(check(&constraints, role, method, &params(json!([value]))))
+        };
+        assert_eq!(check(Role::Admin, "f", 5), None);
+        assert_eq!(check(Role::Admin, "f", 0), Some(Reason::ParamsNotAllowed));
+        assert_eq!(check(Role::Admin, "f", 11), Some(Reason::ParamsNotAllowed));
+        assert_eq!(check(Role::Anonymous, "f", 0), None);
+        assert_eq!(
+            check(Role::Anonymous, "f", 5),
+            Some(Reason::ParamsNotAllowed)
+        );
+        assert_eq!(check(Role::Admin, "h", 100), None);
+
+        let rejection =
+            super::check(&constraints, Role::Admin, "f", &params(json!([0]))).unwrap_err();
+        assert_eq!(
+            rejection.message,
+            "Callers with role admin may only call f with parameter 0 >= 1"
+        );
+    }
+
+    #[test]
+    fn implied_and_excluded_constraints() {
+        let implies = |a: &str, b: &str| constraint(a).implies(&constraint(b));
+        assert!(implies("admin:f:0<5", "admin:f:0<=5"));
+        assert!(implies("admin:f:0<=5", "admin:f:0<=5"));
+        assert!(!implies("admin:f:0<=5", "admin:f:0<5"));
+        assert!(implies("admin:f:0==3", "admin:f:0<5"));
+        assert!(!implies("admin:f:0<5", "admin:f:1<5"));
+        assert!(!implies("admin:f:0<5", "anonymous:f:0<5"));
+
+        let excludes = |a: &str, b: &str| constraint(a).excludes(&constraint(b));
+        assert!(excludes("admin:f:0<5", "admin:f:0>5"));
+        assert!(excludes("admin:f:0<5", "admin:f:0>=5"));
+        assert!(!excludes("admin:f:0<=5", "admin:f:0>=5"));
+        assert!(excludes("admin:f:0>=5", "admin:f:0<5"));
+        assert!(excludes("admin:f:0==1", "admin:f:0==2"));
+        assert!(excludes("admin:f:0<=5", "admin:f:0==6"));
+        assert!(excludes(r#"admin:f:0<="a""#, "admin:f:0>=1"));
+        assert!(!excludes("admin:f:0<5", "admin:f:1>5"));
+    }
+
+    #[test]
+    fn calls_are_checked_with_the_role_of_the_caller() {
+        let mut state = state();
+        state.protected.clear();
+        state.constraints = vec![constraint("admin:f:0<=10"), constraint("anonymous:f:0<=1")];
+        let admin = meta(Some("root"));
+        let anonymous = meta(None);
+        let check = |value, meta| reason(state.check_call(&call("f", json!([value])), meta));
+        assert_eq!(check(10, &admin), None);
+        assert_eq!(check(11, &admin), Some(Reason::ParamsNotAllowed));
+        assert_eq!(check(1, &anonymous), None);
+        assert_eq!(check(2, &anonymous), Some(Reason::ParamsNotAllowed));
+        assert_eq!(
+            check(2, &meta(Some("wrong"))),
+            Some(Reason::ParamsNotAllowed)
+        );
+
+        // Acting as a user with another role, the constraints of that role apply.
+        state.users.add("guest", "secret", Role::Anonymous).unwrap();
+        let mut run_as = meta(Some("root"));
+        run_as.run_as = Some(Ok("guest".to_owned()));
+        assert_eq!(
+            reason(state.check_call(&call("f", json!([2])), &run_as)),
+            Some(Reason::ParamsNotAllowed),
+        );
+    }
+
+    fn rule(s: &str) -> OwnershipRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ownership_rules_parse() {
+        let parsed = rule("transfer:account=account_id");
+        assert_eq!(parsed.method, "transfer");
+        assert_eq!(parsed.param, Param::Name("account".to_owned()));
+        assert_eq!(parsed.attribute, "account_id");
+        assert_eq!(parsed.to_string(), "transfer:account=account_id");
+        assert_eq!(rule("f:0=user").param, Param::Position(0));
+
+        for invalid in [
+            "transfer",
+            "transfer:account",
+            ":account=id",
+            "f:0=",
+            "f:=user",
+        ] {
+            assert!(invalid.parse::<OwnershipRule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn callers_may_only_pass_what_they_own() {
+        let rules = [rule("transfer:account=account_id"), rule("transfer:0=user")];
+        let attributes = BTreeMap::from([("account_id".to_owned(), "42".to_owned())]);
+        let check = |user, attributes, params| {
+            reason(check_ownership(
+                &rules[..1],
+                user,
+                attributes,
+                "transfer",
+                &params,
+            ))
+        };
+        assert_eq!(
+            check(None, &attributes, self::params(json!({"account": "42"}))),
+            None
+        );
+        assert_eq!(
+            check(None, &attributes, self::params(json!({"account": 42}))),
+            None
+        );
+        assert_eq!(
+            check(None, &attributes, self::params(json!({"account": "43"}))),
+            Some(Reason::NotOwner),
+        );
+        assert_eq!(
+            check(None, &attributes, self::params(json!({"account": 42.0}))),
+            Some(Reason::NotOwner),
+        );
+        assert_eq!(
+            check(None, &attributes, self::params(json!({"account": ["42"]}))),
+            Some(Reason::NotOwner),
+        );
+        assert_eq!(
+            check(None, &attributes, self::params(json!({}))),
+            Some(Reason::NotOwner),
+        );
+        assert_eq!(
+            check(
+                Some("42"),
+                &BTreeMap::new(),
+                self::params(json!({"account": "42"}))
+            ),
+            Some(Reason::NotOwner),
+        );
+
+        let user = |user, params| {
+            reason(check_ownership(
+                &rules[1..],
+                user,
+                &attributes,
+                "transfer",
+                &params,
+            ))
+        };
+        assert_eq!(user(Some("alice"), self::params(json!(["alice"]))), None);
+        assert_eq!(
+            user(Some("alice"), self::params(json!(["bob"]))),
+            Some(Reason::NotOwner)
+        );
+        assert_eq!(
+            user(None, self::params(json!(["alice"]))),
+            Some(Reason::NotOwner)
+        );
+
+        let other_method =
+            check_ownership(&rules, None, &BTreeMap::new(), "balance", &Params::None);
+        assert_eq!(reason(other_method), None);
+    }
+
+    #[test]
+    fn ownership_follows_the_user_acted_as() {
+        let mut state = state();
+        state.protected.clear();
+        state.ownership = vec![rule("transfer:0=user")];
+        state.users.add("alice", "secret", Role::Admin).unwrap();
+        let check = |meta: &RpcMeta, user: &str| {
+            reason(state.check_call(&call("transfer", json!([user])), meta))
+        };
+
+        // The admin token has no user.
+        assert_eq!(check(&meta(Some("root")), "alice"), Some(Reason::NotOwner));
+        let mut run_as = meta(Some("root"));
+        run_as.run_as = Some(Ok("alice".to_owned()));
+        assert_eq!(check(&run_as, "alice"), None);
+        assert_eq!(check(&run_as, "bob"), Some(Reason::NotOwner));
+
+        // Attributes can not name the user.
+        let mut spoofed = meta(None);
+        spoofed
+            .attributes
+            .insert(USER_ATTRIBUTE.to_owned(), "alice".to_owned());
+        assert_eq!(check(&spoofed, "alice"), Some(Reason::NotOwner));
+    }
+}
@@ -0,0 +1,100 @@
+//! Client supplied deadlines.
+//!
+//! Clients put the number of milliseconds they are willing to wait for into the
+//! [`DEADLINE_HEADER`].  Calls that are still running when it runs out are cancelled, and
+//! answered with a [`DEADLINE_EXCEEDED`] error, as the client has likely given up on them.
+//! Only asynchronous work can be cancelled: a handler that blocks runs to completion, and its
+//! result is still returned.
+//!
+//! The budget is relative, rather than a point in time, so that clocks of the client and the
+//! server do not need to agree.
+
+use {
+    crate::RpcMeta,
+    futures_util::future::Either,
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            error::{Error as JsonRpcError, ErrorCode},
+            request::{Call, MethodCall},
+            response::{Output, Response},
+        },
+    },
+    serde_json::json,
+    std::{
+        future::Future,
+        pin::Pin,
+        time::{Duration, Instant},
+    },
+};
+
+pub const DEADLINE_HEADER: &str = "X-Request-Timeout";
+
+/// Error code for calls that did not finish before their deadline.
+pub const DEADLINE_EXCEEDED: ErrorCode = ErrorCode::ServerError(-32001);
+
+/// Deadline for a request that arrived just now, with the given [`DEADLINE_HEADER`] value.
+/// Values that are not a number of milliseconds are ignored.
+pub fn parse_deadline(value: &[u8]) -> Option<Instant> {
+    let millis = std::str::from_utf8(value).ok()?.trim().parse().ok()?;
+    Instant::now().checked_add(Duration::from_millis(millis))
+}
+
+/// Value of the [`DEADLINE_HEADER`] to forward with a request made on behalf of a caller with
+/// the given `deadline`, or `None` if there is no time left.
+pub fn remaining_budget(deadline: Instant) -> Option<String> {
+    let remaining = deadline.checked_duration_since(Instant::now())?;
+    (!remaining.is_zero()).then(|| remaining.as_millis().to_string())
+}
+
+/// Cancels calls that exceed [`RpcMeta::deadline`].
+#[derive(Clone, Debug, Default)]
+pub struct DeadlineMiddleware;
+
+impl DeadlineMiddleware {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Middleware<RpcMeta> for DeadlineMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let Some(deadline) = meta.deadline else {
+            return Either::Right(next(call, meta));
+        };
+
+        let response_to = match &call {
+            Call::MethodCall(MethodCall { jsonrpc, id, .. }) => Some((id.clone(), *jsonrpc)),
+            Call::Notification(_) | Call::Invalid { .. } => None,
+        };
+        let error = JsonRpcError {
+            code: DEADLINE_EXCEEDED,
+            message: "Deadline exceeded".to_owned(),
+            data: Some(json!({ "request_id": meta.request_id })),
+        };
+        let exceeded = move || {
+            let (id, jsonrpc) = response_to?;
+            Some(Output::from(Err(error), id, jsonrpc))
+        };
+
+        // Not even started, if the time is already up.
+        if deadline <= Instant::now() {
+            return Either::Left(Box::pin(async move { exceeded() }));
+        }
+
+        let output = next(call, meta);
+        Either::Left(Box::pin(async move {
+            match tokio::time::timeout_at(deadline.into(), output).await {
+                Ok(output) => output,
+                Err(_) => exceeded(),
+            }
+        }))
+    }
+}
@@ -0,0 +1,188 @@
+//! Telling callers that methods they use are going away, and finding out who still uses them.
+//!
+//! A [`Deprecation`] such as `g=use g on /v2 instead` marks a method as deprecated.  Calls to
+//! it are still made, but [`DeprecationMiddleware`]:
+//!
+//! - adds a `deprecation` member with the notice to the `data` of errors,
+//! - collects the notices into [`RpcMeta::deprecations`], so that the transport can send them
+//!   in the [`DEPRECATION_HEADER`] and [`DEPRECATION_NOTICE_HEADER`] of the response, as the
+//!   results of successful calls have no room for them,
+//! - counts the calls by caller into [`Stats`], reported by `admin_stats`.
+
+use {
+    crate::{stats::Stats, RpcMeta},
+    futures_util::{future::Either, FutureExt},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall, Notification},
+            response::{Output, Response},
+        },
+    },
+    serde::Serialize,
+    serde_json::{Map, Value},
+    std::{
+        collections::HashMap,
+        fmt,
+        future::Future,
+        pin::Pin,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// Set to `true` in responses to requests that called deprecated methods.
+pub const DEPRECATION_HEADER: &str = "Deprecation";
+
+/// Names a deprecated method called in the request, followed by its notice, if it has one.
+/// Repeated for every such method.
+pub const DEPRECATION_NOTICE_HEADER: &str = "X-Deprecation-Notice";
+
+/// Calls to `method` are answered with `notice`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+    pub method: String,
+    /// What to use instead, or when the method goes away.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notice: Option<String>,
+}
+
+impl Deprecation {
+    /// Value of the [`DEPRECATION_NOTICE_HEADER`].
+    pub fn header_value(&self) -> String {
+        match &self.notice {
+            Some(notice) => format!("{}: {notice}", self.method),
+            None => self.method.clone(),
+        }
+    }
+}
+
+impl FromStr for Deprecation {
+    type Err = String;
+
+    /// Parses `METHOD[=NOTICE]`, such as `g=use g on /v2 instead`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (method, notice) = match s.split_once('=') {
+            Some((method, notice)) => (method, Some(notice)),
+            None => (s, None),
+        };
+        if method.is_empty() {
+            return Err(format!("expected METHOD[=NOTICE], got \"{s}\""));
+        }
+        // Notices are sent in response headers.
+        if let Some(notice) =
+            notice.filter(|notice| !notice.chars().all(|c| c == ' ' || c.is_ascii_graphic()))
+        {
+            return Err(format!(
+                "notice \"{notice}\" must contain only printable ASCII characters"
+            ));
+        }
+        Ok(Deprecation {
+            method: method.to_owned(),
+            notice: notice.map(str::to_owned),
+        })
+    }
+}
+
+impl fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.method)?;
+        if let Some(notice) = &self.notice {
+            write!(f, "={notice}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Deprecations of the methods called in a request, collected while it is handled.  Clones
+/// share them.
+#[derive(Clone, Debug, Default)]
+pub struct DeprecationNotices(Arc<Mutex<Vec<Deprecation>>>);
+
+impl DeprecationNotices {
+    /// Every deprecated method called so far, once.
+    pub fn list(&self) -> Vec<Deprecation> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn add(&self, deprecation: &Deprecation) {
+        let mut notices = self.0.lock().unwrap();
+        if !notices.contains(deprecation) {
+            notices.push(deprecation.clone());
+        }
+    }
+}
+
+/// Adds notices to calls of deprecated methods, and counts them.  Has to run after the
+/// protection middleware, which sets [`RpcMeta::caller`], so calls that are not allowed are
+/// neither noticed nor counted.
+#[derive(Clone, Default)]
+pub struct DeprecationMiddleware {
+    deprecations: Arc<HashMap<String, Deprecation>>,
+    stats: Option<Stats>,
+}
+
+impl DeprecationMiddleware {
+    pub fn new(deprecations: Vec<Deprecation>) -> Self {
+        Self {
+            deprecations: Arc::new(
+                deprecations
+                    .into_iter()
+                    .map(|deprecation| (deprecation.method.clone(), deprecation))
+                    .collect(),
+            ),
+            stats: None,
+        }
+    }
+
+    /// Count calls to deprecated methods by caller into `stats`.
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+}
+
+impl Middleware<RpcMeta> for DeprecationMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let deprecation = match &call {
+            Call::MethodCall(MethodCall { method, .. })
+            | Call::Notification(Notification { method, .. }) => self.deprecations.get(method),
+            Call::Invalid { .. } => None,
+        };
+        let Some(deprecation) = deprecation.cloned() else {
+            return Either::Right(next(call, meta));
+        };
+
+        meta.deprecations.add(&deprecation);
+        if let Some(stats) = &self.stats {
+            let caller = meta
+                .caller
+                .as_ref()
+                .map_or_else(|| "anonymous".to_owned(), ToString::to_string);
+            stats.record_deprecated_call(&deprecation.method, &caller);
+        }
+
+        Either::Left(Box::pin(next(call, meta).map(move |mut output| {
+            if let Some(Output::Failure(failure)) = &mut output {
+                let data = failure
+                    .error
+                    .data
+                    .get_or_insert_with(|| Value::Object(Map::new()));
+                if let Value::Object(data) = data {
+                    data.insert(
+                        "deprecation".to_owned(),
+                        serde_json::to_value(&deprecation).expect("Deprecations always serialize"),
+                    );
+                }
+            }
+            output
+        })))
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors in JSON configuration files that point at the mistake.
+//!
+//! Plain `serde_json` errors give a position, but not the key being parsed, and list the
+//! expected keys without saying which one was probably meant.  [`from_json`] adds both.
+
+use {serde::de::DeserializeOwned, std::fmt, thiserror::Error};
+
+#[derive(Error, Debug)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    /// Key that failed to parse, such as `[0].latency.objective`.  Empty at the top level.
+    pub path: String,
+    pub message: String,
+    /// The expected key or value closest to the one given, for misspellings.
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)?;
+        if !self.path.is_empty() {
+            write!(f, ", at `{}`", self.path)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ", did you mean `{suggestion}`?")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, Diagnostic> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|err| {
+        // Map keys that failed to parse are shown as `?`, the map they are in says enough.
+        let path = err.path().to_string();
+        let path = path.trim_end_matches('?').trim_end_matches('.').to_owned();
+        diagnostic(path, err.into_inner())
+    })?;
+    deserializer
+        .end()
+        .map_err(|err| diagnostic(String::new(), err))?;
+    Ok(value)
+}
+
+fn diagnostic(path: String, err: serde_json::Error) -> Diagnostic {
+    // The position is reported separately.
+    let message = err.to_string();
+    let message = message
+        .rsplit_once(" at line ")
+        .map_or(message.as_str(), |(message, _)| message)
+        .to_owned();
+    Diagnostic {
+        line: err.line(),
+        column: err.column(),
+        path,
+        suggestion: suggestion(&message),
+        message,
+    }
+}
+
+/// For "unknown field `x`, expected one of `a`, `b`" and "unknown variant" messages, the
+/// expected name closest to `x`, when it is close enough to be a misspelling.
+fn suggestion(message: &str) -> Option<String> {
+    if !message.starts_with("unknown field") && !message.starts_with("unknown variant") {
+        return None;
+    }
+    let mut names = message.split('`').skip(1).step_by(2);
+    let given = names.next()?;
+    closest(given, names).map(str::to_owned)
+}
+
+/// The name in `names` closest to `given`, when it is close enough to be a misspelling.
+pub fn closest<'a>(given: &str, names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    names
+        .into_iter()
+        .map(|name| (edit_distance(given, name), name))
+        .filter(|&(distance, name)| distance <= 2.max(name.len() / 3))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name)
+}
+
+/// Levenshtein distance, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,155 @@
+//! Statistics of a whole fleet, served by the protected `admin_cluster_stats` method.
+//!
+//! Behind a load balancer, the numbers of any single instance say little.  An instance given
+//! the URLs of its peers asks each of them for their `admin_stats`, and reports the sums of the
+//! call, denial and outcome counters, along with the report of every instance.  Latency
+//! percentiles can not be added up, so they are only in the reports of the instances.
+//!
+//! Peers that do not answer within [`PEER_TIMEOUT`] are listed as unreachable, and left out of
+//! the sums.
+
+use {
+    crate::{
+        client::{Auth, Client},
+        rejection::Reason,
+        stats::{StatsReport, StatsRpc, StatsRpcImpl},
+        RpcMeta,
+    },
+    futures_util::future::join_all,
+    hyper::Uri,
+    jsonrpc_core::{BoxFuture, Error as JsonRpcError, Result},
+    jsonrpc_derive::rpc,
+    serde::Serialize,
+    std::{collections::BTreeMap, sync::Arc, time::Duration},
+};
+
+/// How long peers have to answer.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Key of the report of the instance that was asked, in [`ClusterStats::instances`].
+pub const LOCAL_INSTANCE: &str = "local";
+
+/// Result of `admin_cluster_stats`.
+#[derive(Debug, Default, Serialize)]
+pub struct ClusterStats {
+    /// Sums over all instances that answered.
+    pub calls: BTreeMap<String, u64>,
+    pub denials: BTreeMap<Reason, u64>,
+    pub outcomes: BTreeMap<String, BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rule_sets: BTreeMap<String, BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub deprecated_calls: BTreeMap<String, BTreeMap<String, u64>>,
+    pub sessions: usize,
+    /// Report of every instance that answered, by URL, and [`LOCAL_INSTANCE`].
+    pub instances: BTreeMap<String, StatsReport>,
+    /// Why peers that did not answer did not, by URL.
+    pub unreachable: BTreeMap<String, String>,
+}
+
+impl ClusterStats {
+    fn add(&mut self, instance: String, report: StatsReport) {
+        for (method, calls) in &report.calls {
+            *self.calls.entry(method.clone()).or_default() += calls;
+        }
+        for (reason, denials) in &report.denials {
+            *self.denials.entry(*reason).or_default() += denials;
+        }
+        for (outcome, identities) in &report.outcomes {
+            let sums = self.outcomes.entry(outcome.clone()).or_default();
+            for (identity, calls) in identities {
+                *sums.entry(identity.clone()).or_default() += calls;
+            }
+        }
+        for (rule_set, outcomes) in &report.rule_sets {
+            let sums = self.rule_sets.entry(rule_set.clone()).or_default();
+            for (outcome, calls) in outcomes {
+                *sums.entry(outcome.clone()).or_default() += calls;
+            }
+        }
+        for (method, callers) in &report.deprecated_calls {
+            let sums = self.deprecated_calls.entry(method.clone()).or_default();
+            for (caller, calls) in callers {
+                *sums.entry(caller.clone()).or_default() += calls;
+            }
+        }
+        self.sessions += report.sessions;
+        self.instances.insert(instance, report);
+    }
+}
+
+#[rpc(server)]
+pub trait ClusterStatsRpc {
+    type Metadata;
+
+    #[rpc(name = "admin_cluster_stats")]
+    fn cluster_stats(&self) -> BoxFuture<Result<ClusterStats>>;
+}
+
+struct Peer {
+    url: Uri,
+    client: Client,
+}
+
+#[derive(Clone)]
+pub struct ClusterStatsRpcImpl {
+    local: StatsRpcImpl,
+    peers: Arc<Vec<Peer>>,
+}
+
+impl ClusterStatsRpcImpl {
+    /// Adds the stats of `local` to those of `peers`, which are called with `auth`.
+    pub fn new(local: StatsRpcImpl, peers: Vec<Uri>, auth: Auth) -> Self {
+        let peers = peers
+            .into_iter()
+            .map(|url| Peer {
+                client: Client::new(url.clone()).with_auth(auth.clone()),
+                url,
+            })
+            .collect();
+        Self {
+            local,
+            peers: Arc::new(peers),
+        }
+    }
+}
+
+impl ClusterStatsRpc for ClusterStatsRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn cluster_stats(&self) -> BoxFuture<Result<ClusterStats>> {
+        let local = self.local.stats();
+        let peers = self.peers.clone();
+        Box::pin(async move {
+            let mut stats = ClusterStats::default();
+            stats.add(LOCAL_INSTANCE.to_owned(), local?);
+
+            let reports = join_all(peers.iter().map(|peer| async move {
+                let report = match tokio::time::timeout(
+                    PEER_TIMEOUT,
+                    peer.client.call("admin_stats", vec![]),
+                )
+                .await
+                {
+                    Ok(Ok(report)) => serde_json::from_value::<StatsReport>(report)
+                        .map_err(|err| format!("unexpected report: {err}")),
+                    Ok(Err(err)) => Err(err.to_string()),
+                    Err(_) => Err("timed out".to_owned()),
+                };
+                (peer.url.to_string(), report)
+            }))
+            .await;
+
+            for (url, report) in reports {
+                match report {
+                    Ok(report) => stats.add(url, report),
+                    Err(err) => {
+                        log::warn!("Peer {url} did not report its stats: {err}");
+                        stats.unreachable.insert(url, err);
+                    }
+                }
+            }
+            Ok::<_, JsonRpcError>(stats)
+        })
+    }
+}
@@ -0,0 +1,791 @@
+//! HTTP transport for the protected JSON-RPC handler.
+//!
+//! [`RpcService`] can be mounted inside an existing hyper server, next to other routes:
+//!
+//! ```no_run
+//! # use {
+//! #     hyper::{
+//! #         service::{service_fn, Service},
+//! #         Body, Request, Response,
+//! #     },
+//! #     jsonrpc_core::MetaIoHandler,
+//! #     jsonrpc_protection::{
+//! #         http::RpcHttpHandler,
+//! #         middleware::ProtectRpcMiddleware,
+//! #         state::{ProtectionHandle, ProtectionState},
+//! #     },
+//! #     std::convert::Infallible,
+//! # };
+//! # let state = ProtectionHandle::new(ProtectionState {
+//! #     protected: Default::default(),
+//! #     admin_token: "root".into(),
+//! #     loopback_methods: Default::default(),
+//! #     sessions: Default::default(),
+//! #     users: Default::default(),
+//! #     break_glass: Default::default(),
+//! #     constraints: Default::default(),
+//! #     ownership: Default::default(),
+//! #     canary: Default::default(),
+//! # });
+//! let io = MetaIoHandler::with_middleware(ProtectRpcMiddleware::new(state));
+//! let rpc = RpcHttpHandler::new(io).into_service();
+//!
+//! let router = service_fn(move |request: Request<Body>| {
+//!     let mut rpc = rpc.clone();
+//!     async move {
+//!         match request.uri().path() {
+//!             "/rpc" => rpc.call(request).await,
+//!             _ => Ok::<_, Infallible>(Response::new(Body::from("Hello"))),
+//!         }
+//!     }
+//! });
+//! ```
+//!
+//! To serve several handlers, with different methods or middleware, on different paths, see
+//! [`router`].
+
+use {
+    self::{access_log::AccessLog, cors::Cors},
+    crate::{
+        attributes::{self, AttributeHeader},
+        deprecation::{DEPRECATION_HEADER, DEPRECATION_NOTICE_HEADER},
+        memory::{MemoryBudget, Reservation},
+        priority::PriorityScheduler,
+        rate_limit::{
+            RateLimitStatus, RateLimiter, RATE_LIMIT_LIMIT_HEADER, RATE_LIMIT_REMAINING_HEADER,
+            RATE_LIMIT_RESET_HEADER,
+        },
+        rejection::{whole_seconds, Reason, Rejection},
+        signing::{RequestVerifier, ResponseSigner, RESPONSE_SIGNATURE_HEADER},
+        stats::Stats,
+        RpcMeta, REQUEST_ID_HEADER,
+    },
+    hyper::{
+        body::HttpBody,
+        header::{self, HeaderValue},
+        service::Service,
+        Body, Method, Request, Response, StatusCode,
+    },
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall, Request as RpcRequest},
+            response::{Output, Response as RpcResponse},
+            Id, Version,
+        },
+        MetaIoHandler,
+    },
+    serde::de::IgnoredAny,
+    std::{
+        convert::Infallible,
+        future::Future,
+        net::SocketAddr,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    },
+};
+
+pub mod access_log;
+pub mod cors;
+mod jsonrpc1;
+pub mod profile;
+pub mod router;
+mod strict;
+
+/// Requests with larger bodies are rejected without being parsed.
+pub const DEFAULT_MAX_REQUEST_BODY_SIZE: usize = 5 * 1024 * 1024;
+
+/// Turns HTTP requests into JSON-RPC calls and their results back into HTTP responses.
+pub struct RpcHttpHandler<S: Middleware<RpcMeta>> {
+    io: MetaIoHandler<RpcMeta, S>,
+    request_verifier: Option<RequestVerifier>,
+    response_signer: Option<ResponseSigner>,
+    max_request_body_size: usize,
+    jsonrpc1: bool,
+    strict: bool,
+    rate_limiter: Option<RateLimiter>,
+    priority_scheduler: Option<PriorityScheduler>,
+    memory_budget: Option<MemoryBudget>,
+    stats: Option<Stats>,
+    access_log: Option<AccessLog>,
+    attribute_headers: Vec<AttributeHeader>,
+    accept_tokens: bool,
+    cors: Option<Cors>,
+}
+
+impl<S: Middleware<RpcMeta>> RpcHttpHandler<S> {
+    pub fn new(io: MetaIoHandler<RpcMeta, S>) -> Self {
+        Self {
+            io,
+            request_verifier: None,
+            response_signer: None,
+            max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
+            jsonrpc1: false,
+            strict: false,
+            rate_limiter: None,
+            priority_scheduler: None,
+            memory_budget: None,
+            stats: None,
+            access_log: None,
+            attribute_headers: vec![],
+            accept_tokens: true,
+            cors: None,
+        }
+    }
+
+    /// Accept request signatures checked by `verifier` as admin credentials.
+    pub fn request_verifier(mut self, verifier: RequestVerifier) -> Self {
+        self.request_verifier = Some(verifier);
+        self
+    }
+
+    /// Sign every response body with `signer`.
+    pub fn response_signer(mut self, signer: ResponseSigner) -> Self {
+        self.response_signer = Some(signer);
+        self
+    }
+
+    /// Reject requests with larger bodies, [`DEFAULT_MAX_REQUEST_BODY_SIZE`] by default.
+    pub fn max_request_body_size(mut self, size: usize) -> Self {
+        self.max_request_body_size = size;
+        self
+    }
+
+    /// Also accept JSON-RPC 1.0 requests, and answer them in the 1.0 format.
+    pub fn jsonrpc1(mut self, enabled: bool) -> Self {
+        self.jsonrpc1 = enabled;
+        self
+    }
+
+    /// Copy the values of `headers` into [`RpcMeta::attributes`], see [`crate::attributes`].
+    pub fn attribute_headers(mut self, headers: Vec<AttributeHeader>) -> Self {
+        self.attribute_headers = headers;
+        self
+    }
+
+    /// Reject calls that do not follow the JSON-RPC 2.0 specification exactly, with an error
+    /// describing the problem, before any middleware sees them.  JSON-RPC 1.0 requests
+    /// accepted by [`Self::jsonrpc1`] are not affected.
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.strict = enabled;
+        self
+    }
+
+    /// Reject calls over the quota of the caller, and tell callers where they stand in the
+    /// `X-RateLimit-*` response headers.
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Limit the number of requests handled at the same time, letting callers with higher roles
+    /// go first.  Requests that can not even be queued, or that wait for too long, get a 503
+    /// response with a `Retry-After` header.
+    pub fn priority_scheduler(mut self, scheduler: PriorityScheduler) -> Self {
+        self.priority_scheduler = Some(scheduler);
+        self
+    }
+
+    /// Count request bodies against `budget`, and reject requests with a 503 response once
+    /// their bodies do not fit.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Count requests rejected before they reach the handler into `stats`.  Calls that reach it
+    /// are counted by [`StatsMiddleware`](crate::stats::StatsMiddleware).
+    pub fn stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Write a line for every request to `log`, see [`access_log`].
+    pub fn access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// Whether the `X-Admin-Auth` header is taken as credentials.  When not, it is ignored, and
+    /// only signed requests are made with more than the anonymous role.  Enabled by default.
+    pub fn accept_tokens(mut self, enabled: bool) -> Self {
+        self.accept_tokens = enabled;
+        self
+    }
+
+    /// Answer preflight requests, and let pages from the origins `cors` allows read the
+    /// responses, see [`cors`].
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Wraps the handler into a cheaply cloneable hyper service.
+    pub fn into_service(self) -> RpcService<S> {
+        RpcService {
+            handler: Arc::new(self),
+            peer_addr: None,
+        }
+    }
+
+    /// Handles a request received from `peer_addr`.
+    pub async fn handle(
+        &self,
+        request: Request<Body>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Response<Body> {
+        let mut meta =
+            RpcMeta::from_headers(|name| request.headers().get(name).map(HeaderValue::as_bytes));
+        meta.peer_addr = peer_addr;
+
+        self.handle_with_meta(request, meta).await
+    }
+
+    /// Handles a request for which the caller has already extracted `meta`.
+    ///
+    /// `meta.request_signature` is overwritten, as the signature can only be checked here.
+    pub async fn handle_with_meta(&self, request: Request<Body>, meta: RpcMeta) -> Response<Body> {
+        let cors = self
+            .cors
+            .as_ref()
+            .zip(request.headers().get(header::ORIGIN).cloned());
+
+        let mut response = match &self.access_log {
+            None => self.respond(request, meta, None).await,
+            Some(access_log) => {
+                let entry = access_log::Entry::new(&request, meta.peer_addr);
+                let mut methods = None;
+                let response = self.respond(request, meta, Some(&mut methods)).await;
+                access_log.write(&entry, &response, methods.as_deref());
+                response
+            }
+        };
+
+        if let Some((cors, origin)) = cors {
+            cors.allow(&origin, &mut response);
+        }
+        response
+    }
+
+    /// Stores the names of the methods called into `methods`, when given.
+    async fn respond(
+        &self,
+        request: Request<Body>,
+        mut meta: RpcMeta,
+        methods: Option<&mut Option<String>>,
+    ) -> Response<Body> {
+        if let Some(cors) = &self.cors {
+            if request.method() == Method::OPTIONS {
+                return cors.preflight(request.headers());
+            }
+        }
+
+        if request.method() != Method::POST {
+            return plain_text(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Used HTTP Method is not allowed. POST is required\n",
+            );
+        }
+
+        if !is_json(request.headers().get(header::CONTENT_TYPE)) {
+            return plain_text(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Supplied content type is not allowed. Content-Type: application/json is required\n",
+            );
+        }
+
+        let (parts, body) = request.into_parts();
+
+        // Held until the response is sent, as handlers may keep parts of the body until then.
+        let mut reservation = self.memory_budget.as_ref().map(MemoryBudget::request);
+
+        let body = match read_body(body, self.max_request_body_size, reservation.as_mut()).await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+
+        meta.request_signature = self
+            .request_verifier
+            .as_ref()
+            .and_then(|verifier| verifier.verify(&parts.headers, parts.uri.path(), &body));
+        if !self.accept_tokens {
+            meta.auth = None;
+        }
+
+        meta.attributes
+            .extend(attributes::extract(&self.attribute_headers, |name| {
+                parts.headers.get(name).map(HeaderValue::as_bytes)
+            }));
+
+        let Ok(body) = String::from_utf8(body) else {
+            return plain_text(
+                StatusCode::BAD_REQUEST,
+                "Request body must be valid UTF-8\n",
+            );
+        };
+
+        if let Some(methods) = methods {
+            *methods = access_log::method_names(&body);
+        }
+
+        let request_id =
+            HeaderValue::try_from(&meta.request_id).expect("Request ids are valid header values");
+
+        let rate_limit = self
+            .rate_limiter
+            .as_ref()
+            .and_then(|limiter| limiter.check(&meta, count_calls(&body)));
+
+        let deprecations = meta.deprecations.clone();
+        let mut status = StatusCode::OK;
+        let mut retry_after = None;
+        let record_denial = |reason| {
+            if let Some(stats) = &self.stats {
+                stats.record_denial(reason);
+            }
+        };
+        let response = match rate_limit {
+            Some(limit) if !limit.allowed => {
+                let mut rejection = Rejection::new(Reason::RateLimited, "Rate limit exceeded");
+                if let Some(retry_after) = limit.retry_after {
+                    rejection = rejection.retry_after(retry_after);
+                }
+                status = StatusCode::TOO_MANY_REQUESTS;
+                record_denial(rejection.reason);
+                reject_request(&body, &rejection, &meta)
+            }
+            _ => match &self.priority_scheduler {
+                Some(scheduler) => match scheduler.acquire(&meta).await {
+                    Ok(_slot) => self.handle_body(&body, meta).await,
+                    Err(rejection) => {
+                        status = StatusCode::SERVICE_UNAVAILABLE;
+                        retry_after = rejection.retry_after;
+                        record_denial(rejection.reason);
+                        reject_request(&body, &rejection, &meta)
+                    }
+                },
+                None => self.handle_body(&body, meta).await,
+            },
+        };
+
+        let content = match response {
+            Some(response) => format!("{response}\n"),
+            None => String::new(),
+        };
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        headers.insert(REQUEST_ID_HEADER, request_id);
+        if let Some(status) = &rate_limit {
+            insert_rate_limit_headers(headers, status);
+        }
+        if let Some(retry_after) = retry_after {
+            headers.insert(header::RETRY_AFTER, whole_seconds(retry_after).into());
+        }
+        let deprecations = deprecations.list();
+        if !deprecations.is_empty() {
+            headers.insert(DEPRECATION_HEADER, HeaderValue::from_static("true"));
+        }
+        for deprecation in &deprecations {
+            headers.append(
+                DEPRECATION_NOTICE_HEADER,
+                HeaderValue::try_from(deprecation.header_value())
+                    .expect("Notices are checked to be valid header values"),
+            );
+        }
+        if let Some(signer) = &self.response_signer {
+            let signature = signer.sign(content.as_bytes());
+            headers.insert(
+                RESPONSE_SIGNATURE_HEADER,
+                HeaderValue::try_from(signature).expect("Hex strings are valid header values"),
+            );
+        }
+        *response.body_mut() = Body::from(content);
+
+        response
+    }
+
+    async fn handle_body(&self, body: &str, meta: RpcMeta) -> Option<String> {
+        let jsonrpc1 = self
+            .jsonrpc1
+            .then(|| jsonrpc1::upgrade_request(body))
+            .flatten();
+
+        let rejection = (self.strict && jsonrpc1.is_none())
+            .then(|| strict::validate(body))
+            .flatten();
+
+        match (&jsonrpc1, rejection) {
+            (_, Some(rejection)) => Some(rejection),
+            (Some(request), None) => self
+                .io
+                .handle_request(request, meta)
+                .await
+                .map(|response| jsonrpc1::downgrade_response(&response)),
+            (None, None) => self.io.handle_request(body, meta).await,
+        }
+    }
+}
+
+/// A hyper [`Service`] that handles every request with an [`RpcHttpHandler`].
+pub struct RpcService<S: Middleware<RpcMeta>> {
+    handler: Arc<RpcHttpHandler<S>>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl<S: Middleware<RpcMeta>> RpcService<S> {
+    /// Returns a service for requests received over a connection from `peer_addr`.
+    pub fn with_peer_addr(&self, peer_addr: SocketAddr) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            peer_addr: Some(peer_addr),
+        }
+    }
+}
+
+impl<S: Middleware<RpcMeta>> Clone for RpcService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            peer_addr: self.peer_addr,
+        }
+    }
+}
+
+impl<S: Middleware<RpcMeta>> Service<Request<Body>> for RpcService<S> {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let handler = self.handler.clone();
+        let peer_addr = self.peer_addr;
+        Box::pin(async move { Ok(handler.handle(request, peer_addr).await) })
+    }
+}
+
+/// Reads the whole body, as long as it is not larger than `limit` bytes, and fits into
+/// `reservation`.
+pub(crate) async fn read_body(
+    mut body: Body,
+    limit: usize,
+    mut reservation: Option<&mut Reservation>,
+) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || {
+        plain_text(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "request body size exceeds allowed maximum",
+        )
+    };
+
+    if body.size_hint().lower() > limit as u64 {
+        return Err(too_large());
+    }
+
+    let mut content = vec![];
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return Err(plain_text(
+                StatusCode::BAD_REQUEST,
+                "Failed to read request body\n",
+            ));
+        };
+        if content.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        if let Some(reservation) = reservation.as_deref_mut() {
+            if !reservation.grow(chunk.len()) {
+                return Err(plain_text(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Server is low on memory\n",
+                ));
+            }
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(content)
+}
+
+/// Response rejecting every call in `request`, or `None` if there are only notifications.
+/// Requests that can not be parsed get a single error.
+pub(crate) fn reject_request(
+    request: &str,
+    rejection: &Rejection,
+    meta: &RpcMeta,
+) -> Option<String> {
+    let error = rejection.to_error(meta);
+    let output = |call: Call| match call {
+        Call::MethodCall(MethodCall { jsonrpc, id, .. }) => {
+            Some(Output::from(Err(error.clone()), id, jsonrpc))
+        }
+        Call::Notification(_) => None,
+        Call::Invalid { id } => Some(Output::from(Err(error.clone()), id, Some(Version::V2))),
+    };
+
+    let response = match serde_json::from_str::<RpcRequest>(request) {
+        Ok(RpcRequest::Single(call)) => RpcResponse::Single(output(call)?),
+        Ok(RpcRequest::Batch(calls)) => {
+            let outputs = calls.into_iter().filter_map(output).collect::<Vec<_>>();
+            if outputs.is_empty() {
+                return None;
+            }
+            RpcResponse::Batch(outputs)
+        }
+        Err(_) => RpcResponse::Single(Output::from(
+            Err(error.clone()),
+            Id::Null,
+            Some(Version::V2),
+        )),
+    };
+
+    Some(serde_json::to_string(&response).expect("Responses always serialize"))
+}
+
+/// Number of calls in `request`, for rate limiting.  Anything that is not a batch counts as one.
+pub(crate) fn count_calls(request: &str) -> u32 {
+    if !request.trim_start().starts_with('[') {
+        return 1;
+    }
+    serde_json::from_str::<Vec<IgnoredAny>>(request).map_or(1, |calls| {
+        u32::try_from(calls.len()).unwrap_or(u32::MAX).max(1)
+    })
+}
+
+pub(crate) fn insert_rate_limit_headers(headers: &mut header::HeaderMap, status: &RateLimitStatus) {
+    headers.insert(RATE_LIMIT_LIMIT_HEADER, status.limit.into());
+    headers.insert(RATE_LIMIT_REMAINING_HEADER, status.remaining.into());
+    headers.insert(RATE_LIMIT_RESET_HEADER, whole_seconds(status.reset).into());
+    // Same value as `retry_after` in the error data.
+    if let Some(retry_after) = status.retry_after {
+        headers.insert(header::RETRY_AFTER, whole_seconds(retry_after).into());
+    }
+}
+
+fn is_json(content_type: Option<&HeaderValue>) -> bool {
+    match content_type.and_then(|val| val.to_str().ok()) {
+        Some(content) => {
+            content.eq_ignore_ascii_case("application/json")
+                || content.eq_ignore_ascii_case("application/json; charset=utf-8")
+                || content.eq_ignore_ascii_case("application/json;charset=utf-8")
+        }
+        None => false,
+    }
+}
+
+fn plain_text(status: StatusCode, message: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            middleware::ProtectRpcMiddleware,
+            rate_limit::Quota,
+            signing::{RequestSigner, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+            state::{tests::state, ProtectionHandle, Role},
+        },
+        jsonrpc_core::{Params, Value},
+        std::time::Duration,
+    };
+
+    const CALL: &str = r#"{"jsonrpc":"2.0","method":"f","params":[],"id":1}"#;
+
+    /// Serves `f`, a protected method, and `g`.
+    fn handler() -> RpcHttpHandler<ProtectRpcMiddleware> {
+        let mut io = MetaIoHandler::with_middleware(ProtectRpcMiddleware::new(
+            ProtectionHandle::new(state()),
+        ));
+        for method in ["f", "g"] {
+            io.add_method(method, |_: Params| async { Ok(Value::from("ok")) });
+        }
+        RpcHttpHandler::new(io)
+    }
+
+    fn post(body: impl Into<Body>, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::post("/rpc").header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(body.into()).unwrap()
+    }
+
+    async fn respond(
+        handler: &RpcHttpHandler<ProtectRpcMiddleware>,
+        request: Request<Body>,
+    ) -> (StatusCode, header::HeaderMap, Vec<u8>) {
+        let response = handler.handle(request, None).await;
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, body.to_vec())
+    }
+
+    /// Result of the call, or the rejection reason.
+    async fn call(
+        handler: &RpcHttpHandler<ProtectRpcMiddleware>,
+        request: Request<Body>,
+    ) -> Result<Value, Value> {
+        let (status, _, body) = respond(handler, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = serde_json::from_slice::<Value>(&body).unwrap();
+        match response.get("result") {
+            Some(result) => Ok(result.clone()),
+            None => Err(response["error"]["data"]["reason"].clone()),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_json_posts_are_handled() {
+        let handler = handler();
+        let get = Request::get("/rpc").body(Body::from(CALL)).unwrap();
+        let (status, ..) = respond(&handler, get).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let untyped = Request::post("/rpc").body(Body::from(CALL)).unwrap();
+        let (status, ..) = respond(&handler, untyped).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        for (content_type, status) in [
+            ("text/plain", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            ("application/json+x", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            ("Application/JSON; charset=UTF-8", StatusCode::OK),
+        ] {
+            let mut request = post(CALL, &[]);
+            request
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            assert_eq!(respond(&handler, request).await.0, status, "{content_type}");
+        }
+
+        let invalid = post(&b"[\xff]"[..], &[]);
+        assert_eq!(respond(&handler, invalid).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let handler = handler().max_request_body_size(CALL.len());
+        let result = call(&handler, post(CALL, &[])).await;
+        assert_eq!(result, Err("credentials_required".into()));
+        let padded = format!("{CALL} ");
+        let (status, ..) = respond(&handler, post(padded.clone(), &[])).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Bodies of unknown size are checked while they are read.
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in [&padded[..10], &padded[10..]] {
+                sender.send_data(chunk.to_owned().into()).await.unwrap();
+            }
+        });
+        let (status, ..) = respond(&handler, post(body, &[])).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_tokens_or_signatures() {
+        let handler = handler();
+        let token = [("X-Admin-Auth", "root")];
+        assert_eq!(
+            call(&handler, post(CALL, &[])).await,
+            Err("credentials_required".into())
+        );
+        assert_eq!(call(&handler, post(CALL, &token)).await, Ok("ok".into()));
+
+        let without_tokens = self::handler().accept_tokens(false);
+        let result = call(&without_tokens, post(CALL, &token)).await;
+        assert_eq!(result, Err("credentials_required".into()));
+
+        let handler = self::handler().request_verifier(RequestVerifier::new(b"key"));
+        let signed = |body: &str| {
+            let headers = RequestSigner::new(b"key").signature_headers("/rpc", body.as_bytes());
+            [
+                (TIMESTAMP_HEADER, headers.timestamp),
+                (NONCE_HEADER, headers.nonce),
+                (SIGNATURE_HEADER, headers.signature),
+            ]
+        };
+        let with = |headers: &[(&str, String)]| {
+            let headers = headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect::<Vec<_>>();
+            post(CALL, &headers)
+        };
+        let signature = signed(CALL);
+        assert_eq!(call(&handler, with(&signature)).await, Ok("ok".into()));
+        let replayed = call(&handler, with(&signature)).await;
+        assert_eq!(replayed, Err("signature_replayed".into()));
+        let other_body = signed(&CALL.replace(r#""id":1"#, r#""id":2"#));
+        let result = call(&handler, with(&other_body)).await;
+        assert_eq!(result, Err("invalid_signature".into()));
+    }
+
+    #[tokio::test]
+    async fn responses_are_signed_rejections_included() {
+        let handler = handler().response_signer(ResponseSigner::new(b"key"));
+        for headers in [&[][..], &[("X-Admin-Auth", "root")]] {
+            let (_, response_headers, body) = respond(&handler, post(CALL, headers)).await;
+            let signature = response_headers[RESPONSE_SIGNATURE_HEADER]
+                .to_str()
+                .unwrap();
+            assert!(ResponseSigner::new(b"key").verify(&body, signature));
+            assert!(!ResponseSigner::new(b"other").verify(&body, signature));
+        }
+    }
+
+    #[tokio::test]
+    async fn callers_over_their_quota_get_429() {
+        let quota = Quota {
+            limit: 2,
+            period: Duration::from_secs(60),
+        };
+        let limiter = RateLimiter::new(
+            ProtectionHandle::new(state()),
+            [(Role::Anonymous, quota)].into(),
+        );
+        let handler = handler().rate_limiter(limiter);
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"g","id":1},
+            {"jsonrpc":"2.0","method":"g","id":2},
+            {"jsonrpc":"2.0","method":"g"}
+        ]"#;
+        let (status, headers, body) = respond(&handler, post(batch, &[])).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // A batch larger than the quota never fits.
+        assert!(!headers.contains_key(header::RETRY_AFTER));
+        let response = serde_json::from_slice::<Value>(&body).unwrap();
+        let reasons = response
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|output| output["error"]["data"]["reason"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, ["rate_limited", "rate_limited"]);
+
+        let single = r#"{"jsonrpc":"2.0","method":"g","id":1}"#;
+        assert_eq!(call(&handler, post(single, &[])).await, Ok("ok".into()));
+        assert_eq!(call(&handler, post(single, &[])).await, Ok("ok".into()));
+        let (status, headers, _) = respond(&handler, post(single, &[])).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[RATE_LIMIT_REMAINING_HEADER], "0");
+        assert!(headers.contains_key(header::RETRY_AFTER));
+    }
+}
@@ -0,0 +1,219 @@
+//! HTTP access log in the Common or Combined Log Format of web servers, so that tools built
+//! for them can read it.
+//!
+//! The JSON-RPC methods called are appended to every line as one more quoted field, separated
+//! by commas for batches, or `-` when the body had no method.  Tools that expect exactly the
+//! standard fields usually ignore trailing ones.
+//!
+//! ```text
+//! 127.0.0.1 - - [14/Oct/2026:11:40:21 +0000] "POST / HTTP/1.1" 200 52 "-" "curl/8.5.0" "eth_call"
+//! ```
+//!
+//! Times are in UTC.
+
+use {
+    hyper::{body::HttpBody, header, Body, HeaderMap, Request, Response},
+    serde::Deserialize,
+    std::{
+        fmt::Write as _,
+        fs::OpenOptions,
+        io::{self, Write},
+        net::SocketAddr,
+        path::Path,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host ident authuser [date] "request" status bytes`
+    #[default]
+    Common,
+    /// The common format, followed by `"referer" "user-agent"`.
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!(
+                "unknown access log format {s:?}, expected `common` or `combined`"
+            )),
+        }
+    }
+}
+
+/// Writes a line for every HTTP request.  Clones write to the same output.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, out: Box<dyn Write + Send>) -> Self {
+        Self {
+            format,
+            out: Arc::new(Mutex::new(out)),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn open(format: AccessLogFormat, path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(format, Box::new(file)))
+    }
+
+    pub(super) fn write(&self, entry: &Entry, response: &Response<Body>, methods: Option<&str>) {
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            entry
+                .peer_addr
+                .map_or_else(|| "-".to_owned(), |addr| addr.ip().to_string()),
+            clf_time(entry.received),
+            escape(&entry.request_line),
+            response.status().as_u16(),
+            match response.body().size_hint().exact() {
+                Some(0) | None => "-".to_owned(),
+                Some(size) => size.to_string(),
+            },
+        );
+        if self.format == AccessLogFormat::Combined {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\"",
+                escape(entry.referer.as_deref().unwrap_or("-")),
+                escape(entry.user_agent.as_deref().unwrap_or("-")),
+            );
+        }
+        let _ = writeln!(line, " \"{}\"", escape(methods.unwrap_or("-")));
+
+        if let Err(err) = self.out.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Failed to write to the access log: {err}");
+        }
+    }
+}
+
+/// What is logged about a request, taken before it is handled.
+pub(super) struct Entry {
+    peer_addr: Option<SocketAddr>,
+    received: SystemTime,
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    pub(super) fn new(request: &Request<Body>, peer_addr: Option<SocketAddr>) -> Self {
+        let headers = request.headers();
+        Self {
+            peer_addr,
+            received: SystemTime::now(),
+            request_line: format!(
+                "{} {} {:?}",
+                request.method(),
+                request.uri(),
+                request.version()
+            ),
+            referer: header_string(headers, header::REFERER),
+            user_agent: header_string(headers, header::USER_AGENT),
+        }
+    }
+}
+
+fn header_string(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+/// Names of the methods called in `body`, separated by commas.  `None` if there are none.
+pub(crate) fn method_names(body: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Call {
+        method: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Calls {
+        Batch(Vec<Call>),
+        Single(Call),
+    }
+
+    let calls = match serde_json::from_str::<Calls>(body).ok()? {
+        Calls::Batch(calls) => calls,
+        Calls::Single(call) => vec![call],
+    };
+    let methods = calls
+        .into_iter()
+        .filter_map(|call| call.method)
+        .collect::<Vec<_>>();
+    (!methods.is_empty()).then(|| methods.join(","))
+}
+
+/// Escapes quotes, backslashes and control characters the way Apache does, so that every field
+/// stays on one line and within its quotes.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(escaped, "\\x{byte:02x}");
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `time` as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, time_of_day) = (seconds / 86_400, seconds % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+    )
+}
+
+/// Year, month and day of the date `days` after 1970-01-01, in the proleptic Gregorian
+/// calendar.  See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
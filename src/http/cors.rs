@@ -0,0 +1,112 @@
+//! Letting web pages on other origins call the handler, see [`RpcHttpHandler::cors`].
+//!
+//! Browsers send a preflight `OPTIONS` request before calls with custom headers, such as
+//! `X-Admin-Auth`, and only let the page read responses carrying an
+//! `Access-Control-Allow-Origin` header.  Requests from other origins are still handled, it is
+//! the browser that keeps the page from seeing the response.
+//!
+//! [`RpcHttpHandler::cors`]: super::RpcHttpHandler::cors
+
+use {
+    super::plain_text,
+    crate::{
+        rate_limit::{
+            RATE_LIMIT_LIMIT_HEADER, RATE_LIMIT_REMAINING_HEADER, RATE_LIMIT_RESET_HEADER,
+        },
+        signing::RESPONSE_SIGNATURE_HEADER,
+        REQUEST_ID_HEADER,
+    },
+    hyper::{
+        header::{self, HeaderMap, HeaderValue},
+        Body, Response, StatusCode,
+    },
+    std::{collections::HashSet, time::Duration},
+};
+
+/// How long browsers may cache the outcome of a preflight request, unless set with
+/// [`Cors::max_age`].
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// Origins allowed to call the handler from a browser.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: HashSet<String>,
+    any_origin: bool,
+    max_age: Duration,
+}
+
+impl Cors {
+    /// Allows pages served from `origins`, such as `https://app.example.com`.  `*` allows any
+    /// origin.
+    pub fn new(origins: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut origins = origins.into_iter().map(Into::into).collect::<HashSet<_>>();
+        let any_origin = origins.remove("*");
+        Self {
+            origins,
+            any_origin,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        self.any_origin
+            || origin
+                .to_str()
+                .is_ok_and(|origin| self.origins.contains(origin))
+    }
+
+    /// Response to a preflight request with the given `headers`.  The origin is allowed by
+    /// [`Self::allow`], as for any other response.
+    pub(super) fn preflight(&self, headers: &HeaderMap) -> Response<Body> {
+        if !headers
+            .get(header::ORIGIN)
+            .is_some_and(|origin| self.allows(origin))
+        {
+            return plain_text(StatusCode::FORBIDDEN, "Origin is not allowed\n");
+        }
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let response_headers = response.headers_mut();
+        response_headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("POST"),
+        );
+        if let Some(requested) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            response_headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        response_headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            self.max_age.as_secs().into(),
+        );
+        response
+    }
+
+    /// Lets a page from `origin` read `response`, if the origin is allowed.
+    pub(super) fn allow(&self, origin: &HeaderValue, response: &mut Response<Body>) {
+        if !self.allows(origin) {
+            return;
+        }
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        let exposed = [
+            REQUEST_ID_HEADER,
+            RATE_LIMIT_LIMIT_HEADER,
+            RATE_LIMIT_REMAINING_HEADER,
+            RATE_LIMIT_RESET_HEADER,
+            header::RETRY_AFTER.as_str(),
+            RESPONSE_SIGNATURE_HEADER,
+        ]
+        .join(", ");
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::try_from(exposed).expect("Header names are valid header values"),
+        );
+    }
+}
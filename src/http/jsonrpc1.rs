@@ -0,0 +1,40 @@
+//! JSON-RPC 1.0 compatibility.
+//!
+//! 1.0 requests have no `jsonrpc` field, 1.0 notifications are requests with a `null` id, and
+//! 1.0 responses carry both `result` and `error`, with one of them set to `null`.
+//!
+//! 1.0 requests are upgraded to 2.0 before being handled, so they pass through the same
+//! middleware as every other call.  The response is then downgraded again.  1.0 has no
+//! batches, so only single requests are recognized.
+
+use serde_json::Value;
+
+/// Returns the 2.0 equivalent of `request`, if it is a 1.0 request.
+pub(super) fn upgrade_request(request: &str) -> Option<String> {
+    let Ok(Value::Object(mut call)) = serde_json::from_str::<Value>(request) else {
+        return None;
+    };
+    if call.contains_key("jsonrpc") {
+        return None;
+    }
+
+    call.insert("jsonrpc".to_owned(), "2.0".into());
+    if call.get("id").is_some_and(Value::is_null) {
+        call.remove("id");
+    }
+
+    Some(Value::Object(call).to_string())
+}
+
+/// Converts a 2.0 `response` to a single call into the 1.0 format.
+pub(super) fn downgrade_response(response: &str) -> String {
+    let Ok(Value::Object(mut output)) = serde_json::from_str::<Value>(response) else {
+        return response.to_owned();
+    };
+
+    output.remove("jsonrpc");
+    output.entry("result").or_insert(Value::Null);
+    output.entry("error").or_insert(Value::Null);
+
+    Value::Object(output).to_string()
+}
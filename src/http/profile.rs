@@ -0,0 +1,73 @@
+//! Named sets of protection settings for the paths of a server, so that a public path and an
+//! admin path can be tuned independently, while serving the same methods.
+//!
+//! `jsonrpc-protection serve --profiles` reads them from a JSON object, keyed by name, and
+//! `--path-profile` picks the profile of a path:
+//!
+//! ```json
+//! {
+//!   "public": {
+//!     "rate_limit": { "anonymous": "100/60@ip" },
+//!     "cors_origins": ["https://app.example.com"]
+//!   },
+//!   "admin": {
+//!     "accept_tokens": false,
+//!     "request_signing_keys_file": "/etc/jsonrpc-protection/admin-keys.json"
+//!   }
+//! }
+//! ```
+//!
+//! Settings a profile leaves out are taken from the command line.  Once a path has a profile,
+//! its methods are only served on paths with a profile, so that other paths, including the
+//! paths that are not listed, can not be used to bypass it.
+
+use {
+    crate::{
+        diagnostic::{self, Diagnostic},
+        rate_limit::KeyedQuota,
+        state::Role,
+    },
+    serde::Deserialize,
+    std::{
+        collections::{BTreeMap, HashMap},
+        fs, io,
+        path::{Path, PathBuf},
+    },
+    thiserror::Error,
+};
+
+/// Protection settings of the handler on a path.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Quotas by role, in place of the ones of the server.  Paths with the same profile share
+    /// the quotas.  An empty object turns rate limiting off.
+    pub rate_limit: Option<HashMap<Role, KeyedQuota>>,
+    /// Keys request signatures are checked with, in the format of
+    /// [`crate::signing::load_keys`], in place of the ones of the server.
+    pub request_signing_keys_file: Option<PathBuf>,
+    /// See [`super::RpcHttpHandler::accept_tokens`].
+    #[serde(default = "enabled")]
+    pub accept_tokens: bool,
+    /// Origins of pages allowed to call the path, see [`super::cors`].
+    #[serde(default)]
+    pub cors_origins: Vec<String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Error, Debug)]
+pub enum ProfilesError {
+    #[error("Failed to read the profiles: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to parse the profiles: {0}")]
+    Parse(#[from] Diagnostic),
+}
+
+/// Reads the profiles in `path`, by name.
+pub fn load(path: &Path) -> Result<BTreeMap<String, Profile>, ProfilesError> {
+    Ok(diagnostic::from_json(&fs::read_to_string(path)?)?)
+}
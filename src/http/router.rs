@@ -0,0 +1,143 @@
+//! Several [`RpcHttpHandler`]s on one listener, each serving its own path.
+//!
+//! Every handler has its own methods and middleware, so a public surface and an admin surface
+//! can be protected differently while being served side by side:
+//!
+//! ```no_run
+//! # use {
+//! #     jsonrpc_core::MetaIoHandler,
+//! #     jsonrpc_protection::{
+//! #         http::{router::PathRouter, RpcHttpHandler},
+//! #         middleware::ProtectRpcMiddleware,
+//! #         state::{ProtectionHandle, ProtectionState},
+//! #     },
+//! # };
+//! # let state = ProtectionHandle::new(ProtectionState {
+//! #     protected: Default::default(),
+//! #     admin_token: "root".into(),
+//! #     loopback_methods: Default::default(),
+//! #     sessions: Default::default(),
+//! #     users: Default::default(),
+//! #     break_glass: Default::default(),
+//! #     constraints: Default::default(),
+//! #     ownership: Default::default(),
+//! #     canary: Default::default(),
+//! # });
+//! let public = MetaIoHandler::with_middleware(ProtectRpcMiddleware::new(state.clone()));
+//! let admin = MetaIoHandler::with_middleware(ProtectRpcMiddleware::new(state));
+//! let service = PathRouter::new()
+//!     .route("/rpc", RpcHttpHandler::new(public))
+//!     .route("/admin", RpcHttpHandler::new(admin))
+//!     .into_service();
+//! ```
+//!
+//! Requests for other paths get a 404 response, unless a [`PathRouter::fallback`] is set.
+
+use {
+    super::{plain_text, RpcHttpHandler},
+    crate::RpcMeta,
+    hyper::{service::Service, Body, Request, Response, StatusCode},
+    jsonrpc_core::middleware::Middleware,
+    std::{
+        collections::HashMap,
+        convert::Infallible,
+        future::Future,
+        net::SocketAddr,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    },
+};
+
+type ResponseFuture<'a> = Pin<Box<dyn Future<Output = Response<Body>> + Send + 'a>>;
+
+/// An [`RpcHttpHandler`], whatever its middleware.
+trait Endpoint: Send + Sync {
+    fn handle(&self, request: Request<Body>, peer_addr: Option<SocketAddr>) -> ResponseFuture<'_>;
+}
+
+impl<S: Middleware<RpcMeta>> Endpoint for RpcHttpHandler<S> {
+    fn handle(&self, request: Request<Body>, peer_addr: Option<SocketAddr>) -> ResponseFuture<'_> {
+        Box::pin(RpcHttpHandler::handle(self, request, peer_addr))
+    }
+}
+
+/// Routes requests to handlers by the exact path.
+#[derive(Default)]
+pub struct PathRouter {
+    routes: HashMap<String, Arc<dyn Endpoint>>,
+    fallback: Option<Arc<dyn Endpoint>>,
+}
+
+impl PathRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve requests for `path`, such as `/admin`, with `handler`.  Replaces any handler added
+    /// for the same path before.
+    pub fn route<S: Middleware<RpcMeta>>(
+        mut self,
+        path: impl Into<String>,
+        handler: RpcHttpHandler<S>,
+    ) -> Self {
+        self.routes.insert(path.into(), Arc::new(handler));
+        self
+    }
+
+    /// Serve requests for paths without a [`Self::route`] with `handler`.
+    pub fn fallback<S: Middleware<RpcMeta>>(mut self, handler: RpcHttpHandler<S>) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    /// Wraps the router into a cheaply cloneable hyper service.
+    pub fn into_service(self) -> PathRouterService {
+        PathRouterService {
+            router: Arc::new(self),
+            peer_addr: None,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> Option<Arc<dyn Endpoint>> {
+        self.routes.get(path).or(self.fallback.as_ref()).cloned()
+    }
+}
+
+/// A hyper [`Service`] that handles requests with the handler for their path.
+#[derive(Clone)]
+pub struct PathRouterService {
+    router: Arc<PathRouter>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl PathRouterService {
+    /// Returns a service for requests received over a connection from `peer_addr`.
+    pub fn with_peer_addr(&self, peer_addr: SocketAddr) -> Self {
+        Self {
+            router: self.router.clone(),
+            peer_addr: Some(peer_addr),
+        }
+    }
+}
+
+impl Service<Request<Body>> for PathRouterService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let endpoint = self.router.endpoint(request.uri().path());
+        let peer_addr = self.peer_addr;
+        Box::pin(async move {
+            Ok(match endpoint {
+                Some(endpoint) => endpoint.handle(request, peer_addr).await,
+                None => plain_text(StatusCode::NOT_FOUND, "No JSON-RPC endpoint at this path\n"),
+            })
+        })
+    }
+}
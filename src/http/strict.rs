@@ -0,0 +1,111 @@
+//! Strict JSON-RPC 2.0 validation.
+//!
+//! `jsonrpc-core` rejects most malformed calls too, but only with a generic "Invalid request",
+//! or even "Parse error" for an id of the wrong type.  Here every call is checked against the
+//! specification before anything else sees it, and the error says what exactly is wrong.
+//!
+//! A batch containing an invalid call is rejected as a whole.
+
+use {
+    jsonrpc_core::types::{
+        error::{Error as JsonRpcError, ErrorCode},
+        response::{Output, Response},
+        Id, Version,
+    },
+    serde_json::{Map, Value},
+};
+
+const FIELDS: [&str; 4] = ["jsonrpc", "method", "params", "id"];
+
+/// Returns the response to send instead of handling `request`, if it is not valid.  Invalid
+/// calls are always answered, even if they look like notifications.
+///
+/// Requests that are not JSON at all are left to `jsonrpc-core` to report.
+pub(super) fn validate(request: &str) -> Option<String> {
+    let request = serde_json::from_str::<Value>(request).ok()?;
+
+    let response = match &request {
+        Value::Array(calls) if calls.is_empty() => {
+            Response::Single(rejection(&request, "Batch must not be empty".to_owned()))
+        }
+        Value::Array(calls) => {
+            let errors = calls.iter().map(check_call).collect::<Vec<_>>();
+            if errors.iter().all(Result::is_ok) {
+                return None;
+            }
+
+            let outputs = calls
+                .iter()
+                .zip(errors)
+                .filter_map(|(call, error)| match error {
+                    Ok(()) => call
+                        .get("id")
+                        .map(|_| rejection(call, "Batch contains invalid calls".to_owned())),
+                    Err(message) => Some(rejection(call, message)),
+                })
+                .collect();
+            Response::Batch(outputs)
+        }
+        call => {
+            let message = check_call(call).err()?;
+            Response::Single(rejection(call, message))
+        }
+    };
+
+    Some(serde_json::to_string(&response).expect("Responses always serialize"))
+}
+
+fn check_call(call: &Value) -> Result<(), String> {
+    let Value::Object(call) = call else {
+        return Err("Call must be an object".to_owned());
+    };
+
+    if let Some(field) = call.keys().find(|field| !FIELDS.contains(&field.as_str())) {
+        return Err(format!("Unknown field `{field}`"));
+    }
+
+    match call.get("jsonrpc") {
+        Some(Value::String(version)) if version == "2.0" => (),
+        Some(_) => return Err(r#"`jsonrpc` must be "2.0""#.to_owned()),
+        None => return Err("`jsonrpc` field is required".to_owned()),
+    }
+
+    match call.get("method") {
+        Some(Value::String(_)) => (),
+        Some(_) => return Err("`method` must be a string".to_owned()),
+        None => return Err("`method` field is required".to_owned()),
+    }
+
+    match call.get("params") {
+        None | Some(Value::Array(_) | Value::Object(_)) => (),
+        Some(_) => return Err("`params` must be an array or an object".to_owned()),
+    }
+
+    match call.get("id") {
+        None | Some(Value::String(_)) => (),
+        Some(Value::Number(id)) if id.is_u64() => (),
+        Some(Value::Null) => {
+            return Err("`id` must not be null, omit it to send a notification".to_owned())
+        }
+        Some(_) => return Err("`id` must be a string or a non-negative integer".to_owned()),
+    }
+
+    Ok(())
+}
+
+/// Error returned for an invalid `call`.  It carries the id of the call, if it has a usable
+/// one.
+fn rejection(call: &Value, message: String) -> Output {
+    let id = call
+        .as_object()
+        .and_then(|call: &Map<String, Value>| call.get("id"))
+        .and_then(|id| serde_json::from_value::<Id>(id.clone()).ok())
+        .unwrap_or(Id::Null);
+
+    let error = JsonRpcError {
+        code: ErrorCode::InvalidRequest,
+        message,
+        data: None,
+    };
+    Output::from(Err(error), id, Some(Version::V2))
+}
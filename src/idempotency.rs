@@ -0,0 +1,388 @@
+//! Replays the first result of a call to clients that retry it with the same `Idempotency-Key`.
+//!
+//! Only methods listed in [`IdempotencyConfig::methods`] are affected.  Calls without the header
+//! are executed as usual.  A retry that arrives while the first call is still running waits for
+//! it to finish instead of running the method a second time.
+//!
+//! Keys are scoped to the caller, so that a caller can not get the result of a call made by
+//! someone else by guessing or reusing their key.
+
+use {
+    crate::{
+        memory::{Component, MemoryBudget},
+        RpcMeta,
+    },
+    futures_util::future::{BoxFuture, Either, FutureExt, Shared},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            error::{Error as JsonRpcError, ErrorCode},
+            request::{Call, MethodCall},
+            response::{Failure, Output, Response, Success},
+        },
+        Id,
+    },
+    sha2::{Digest, Sha256},
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Clone, Debug)]
+pub struct IdempotencyConfig {
+    /// Methods for which the `Idempotency-Key` header is honored.
+    pub methods: HashSet<String>,
+    /// How long a result is replayed for.
+    pub ttl: Duration,
+    /// Maximum number of results kept.  The oldest ones are dropped first.
+    pub capacity: usize,
+}
+
+type SharedOutput = Shared<BoxFuture<'static, Option<Output>>>;
+
+struct Entry {
+    /// Serialized parameters of the first call, to detect keys reused for a different call.
+    params: String,
+    output: SharedOutput,
+    expires: Instant,
+}
+
+/// Estimated memory used by an entry, besides its key and parameters.  Results are not
+/// measured, so this includes a typical one.
+const ENTRY_OVERHEAD: usize = 512;
+
+/// The caller, see [`caller_scope()`], the method and the `Idempotency-Key`.
+type CacheKey = (String, String, String);
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<CacheKey, Entry>,
+    /// Keys in insertion order, used to evict the oldest entries.
+    order: VecDeque<CacheKey>,
+    /// Estimated memory used by `entries` and `order`.
+    bytes: usize,
+    memory_budget: Option<MemoryBudget>,
+}
+
+fn entry_size((caller, method, key): &CacheKey, params: &str) -> usize {
+    // Keys are stored twice, in `entries` and in `order`.
+    2 * (caller.len() + method.len() + key.len()) + params.len() + ENTRY_OVERHEAD
+}
+
+/// Tells callers apart: by their credentials, and the user they act as, if any, or by their
+/// address for anonymous callers.  Tokens are hashed, so that they are not kept around.
+fn caller_scope(meta: &RpcMeta) -> String {
+    let mut scope = match (&meta.auth, meta.peer_addr) {
+        (Some(Ok(token)), _) => format!(
+            "token {}",
+            hex::encode(Sha256::digest(token.expose().as_bytes()))
+        ),
+        (_, Some(peer)) => format!("peer {}", peer.ip()),
+        (_, None) => "anonymous".to_owned(),
+    };
+    if let Some(user) = meta.caller.as_ref().and_then(|caller| caller.user.as_ref()) {
+        scope.push_str(&format!(" user {user}"));
+    }
+    scope
+}
+
+impl Cache {
+    /// Drops expired entries, then the oldest ones while there are more than `capacity`, or
+    /// while the memory budget is exceeded.
+    fn evict(&mut self, now: Instant, capacity: usize) {
+        while let Some(key) = self.order.front() {
+            let expired = self
+                .entries
+                .get(key)
+                .is_none_or(|entry| entry.expires <= now);
+            let over_budget = self
+                .memory_budget
+                .as_ref()
+                .is_some_and(MemoryBudget::is_exceeded);
+            if !expired && !over_budget && self.entries.len() < capacity {
+                break;
+            }
+            let key = self.order.pop_front().expect("`front()` returned a key");
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry_size(&key, &entry.params);
+            }
+            self.report();
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, entry: Entry) {
+        self.bytes += entry_size(&key, &entry.params);
+        self.entries.insert(key.clone(), entry);
+        self.order.push_back(key);
+        self.report();
+    }
+
+    fn report(&self) {
+        if let Some(budget) = &self.memory_budget {
+            budget.set(Component::IdempotencyCache, self.bytes);
+        }
+    }
+}
+
+/// Clones share the cache, so that a retry is recognized whichever transport it arrives on.
+#[derive(Clone)]
+pub struct IdempotencyMiddleware {
+    config: IdempotencyConfig,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl IdempotencyMiddleware {
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            config,
+            cache: Arc::new(Mutex::new(Cache::default())),
+        }
+    }
+
+    /// Report the size of the cache to `budget`, and drop the oldest results while it is
+    /// exceeded.
+    pub fn memory_budget(self, budget: MemoryBudget) -> Self {
+        self.cache.lock().unwrap().memory_budget = Some(budget);
+        self
+    }
+}
+
+impl Middleware<RpcMeta> for IdempotencyMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let (
+            Call::MethodCall(MethodCall {
+                jsonrpc,
+                method,
+                params,
+                id,
+            }),
+            Some(key),
+        ) = (&call, &meta.idempotency_key)
+        else {
+            return Either::Right(next(call, meta));
+        };
+
+        if !self.config.methods.contains(method) {
+            return Either::Right(next(call, meta));
+        }
+
+        let jsonrpc = *jsonrpc;
+        let id = id.clone();
+
+        let key = match key {
+            Ok(key) => key.clone(),
+            Err(err) => {
+                let error = JsonRpcError {
+                    code: ErrorCode::InvalidRequest,
+                    message: err.to_string(),
+                    data: None,
+                };
+                return Either::Left(Box::pin(async move {
+                    Some(Output::from(Err(error), id, jsonrpc))
+                }));
+            }
+        };
+
+        let params = serde_json::to_string(params).expect("Params always serialize");
+        let cache_key = (caller_scope(&meta), method.clone(), key);
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.evict(now, self.config.capacity);
+
+        let output = match cache.entries.get(&cache_key) {
+            Some(entry) if entry.params != params => {
+                let error = JsonRpcError {
+                    code: ErrorCode::InvalidRequest,
+                    message: "Idempotency-Key was already used with different parameters"
+                        .to_owned(),
+                    data: None,
+                };
+                return Either::Left(Box::pin(async move {
+                    Some(Output::from(Err(error), id, jsonrpc))
+                }));
+            }
+            Some(entry) => entry.output.clone(),
+            None => {
+                let output = next(call, meta).boxed().shared();
+                cache.insert(
+                    cache_key,
+                    Entry {
+                        params,
+                        output: output.clone(),
+                        expires: now + self.config.ttl,
+                    },
+                );
+                output
+            }
+        };
+        drop(cache);
+
+        Either::Left(Box::pin(async move {
+            output.await.map(|output| with_id(output, id))
+        }))
+    }
+}
+
+/// Replaces the request id in a replayed `output` with the id of the retry.
+fn with_id(output: Output, id: Id) -> Output {
+    match output {
+        Output::Success(success) => Output::Success(Success { id, ..success }),
+        Output::Failure(failure) => Output::Failure(Failure { id, ..failure }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::state::tests::meta,
+        jsonrpc_core::{MetaIoHandler, Params, Value},
+        serde_json::json,
+        std::{
+            net::SocketAddr,
+            sync::atomic::{AtomicU64, Ordering},
+        },
+    };
+
+    /// `count` returns the number of times it ran.
+    fn io(config: IdempotencyConfig) -> MetaIoHandler<RpcMeta, IdempotencyMiddleware> {
+        let mut io = MetaIoHandler::with_middleware(IdempotencyMiddleware::new(config));
+        let runs = Arc::new(AtomicU64::new(0));
+        io.add_method_with_meta("count", move |_: Params, _: RpcMeta| {
+            let runs = runs.fetch_add(1, Ordering::Relaxed) + 1;
+            async move { Ok(Value::from(runs)) }
+        });
+        io
+    }
+
+    fn config() -> IdempotencyConfig {
+        IdempotencyConfig {
+            methods: ["count".to_owned()].into(),
+            ttl: Duration::from_secs(60),
+            capacity: 10,
+        }
+    }
+
+    fn keyed(auth: Option<&str>, key: &str) -> RpcMeta {
+        let mut meta = meta(auth);
+        meta.idempotency_key = Some(Ok(key.to_owned()));
+        meta
+    }
+
+    fn from_peer(peer: &str, key: &str) -> RpcMeta {
+        let mut meta = keyed(None, key);
+        meta.peer_addr = Some(peer.parse::<SocketAddr>().unwrap());
+        meta
+    }
+
+    fn handle(
+        io: &MetaIoHandler<RpcMeta, IdempotencyMiddleware>,
+        params: Value,
+        id: u64,
+        meta: RpcMeta,
+    ) -> Value {
+        let request = json!({"jsonrpc": "2.0", "method": "count", "params": params, "id": id});
+        let response = io.handle_request_sync(&request.to_string(), meta).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn retries_get_the_first_result_with_their_own_id() {
+        let io = io(config());
+        let first = handle(&io, json!([]), 1, keyed(Some("root"), "k"));
+        let retry = handle(&io, json!([]), 2, keyed(Some("root"), "k"));
+        assert_eq!(first["result"], 1);
+        assert_eq!(retry["result"], 1);
+        assert_eq!(retry["id"], 2);
+
+        assert_eq!(
+            handle(&io, json!([]), 3, keyed(Some("root"), "other"))["result"],
+            2
+        );
+        assert_eq!(handle(&io, json!([]), 4, meta(Some("root")))["result"], 3);
+    }
+
+    #[test]
+    fn keys_are_not_shared_between_callers() {
+        let io = io(config());
+        assert_eq!(
+            handle(&io, json!([]), 1, keyed(Some("root"), "k"))["result"],
+            1
+        );
+        assert_eq!(
+            handle(&io, json!([]), 1, keyed(Some("other"), "k"))["result"],
+            2
+        );
+        assert_eq!(
+            handle(&io, json!([]), 1, from_peer("10.0.0.1:1", "k"))["result"],
+            3
+        );
+        assert_eq!(
+            handle(&io, json!([]), 1, from_peer("10.0.0.2:1", "k"))["result"],
+            4
+        );
+
+        // Anonymous callers are told apart by address, not by port.
+        assert_eq!(
+            handle(&io, json!([]), 1, from_peer("10.0.0.1:2", "k"))["result"],
+            3
+        );
+    }
+
+    #[test]
+    fn keys_reused_with_other_parameters_are_rejected() {
+        let io = io(config());
+        handle(&io, json!([1]), 1, keyed(Some("root"), "k"));
+        let response = handle(&io, json!([2]), 2, keyed(Some("root"), "k"));
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(response["id"], 2);
+    }
+
+    #[test]
+    fn results_are_dropped_after_the_ttl_or_past_the_capacity() {
+        let expiring = io(IdempotencyConfig {
+            ttl: Duration::ZERO,
+            ..config()
+        });
+        assert_eq!(
+            handle(&expiring, json!([]), 1, keyed(None, "k"))["result"],
+            1
+        );
+        assert_eq!(
+            handle(&expiring, json!([]), 1, keyed(None, "k"))["result"],
+            2
+        );
+
+        let io = io(IdempotencyConfig {
+            capacity: 1,
+            ..config()
+        });
+        assert_eq!(handle(&io, json!([]), 1, keyed(None, "a"))["result"], 1);
+        assert_eq!(handle(&io, json!([]), 1, keyed(None, "b"))["result"], 2);
+        assert_eq!(handle(&io, json!([]), 1, keyed(None, "a"))["result"], 3);
+    }
+
+    #[test]
+    fn other_methods_are_not_cached() {
+        let io = io(IdempotencyConfig {
+            methods: Default::default(),
+            ..config()
+        });
+        assert_eq!(handle(&io, json!([]), 1, keyed(None, "k"))["result"], 1);
+        assert_eq!(handle(&io, json!([]), 1, keyed(None, "k"))["result"], 2);
+    }
+}
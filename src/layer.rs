@@ -0,0 +1,530 @@
+//! Protection for stacks that already have their own HTTP JSON-RPC server.
+//!
+//! [`Protection`] wraps any hyper service.  It reads the request body, applies the same rules
+//! as [`ProtectRpcMiddleware`] to every call, and only forwards the request to the inner
+//! service if all calls are allowed.  A batch with a single rejected call is rejected as a
+//! whole, so that it is never partially executed.  Requests that are not valid JSON-RPC, such
+//! as calls with unexpected members, are rejected as well, rather than forwarded: the inner
+//! service may read them differently, and execute a method the layer never checked.
+//!
+//! [`ProtectionLayer`] holds the configuration, and builds a [`Protection`] for every inner
+//! service.  Callers over their quota are rejected with [`ProtectionLayer::rate_limiter`], and
+//! rejected calls are reported to [`ProtectionLayer::on_denial`], for auditing.
+//!
+//! With [`ProtectionLayer::identity_headers`], the layer tells the inner service who the caller
+//! is in request headers.  Values of these headers sent by the client are always removed, so
+//! the inner service can trust them.
+//!
+//! [`ProtectRpcMiddleware`]: crate::middleware::ProtectRpcMiddleware
+
+use {
+    crate::{
+        http::{
+            access_log::method_names, count_calls, insert_rate_limit_headers, read_body,
+            reject_request, DEFAULT_MAX_REQUEST_BODY_SIZE,
+        },
+        messages::MessageCatalog,
+        pubsub::Denial,
+        rate_limit::RateLimiter,
+        rejection::{Reason, Rejection},
+        signing::RequestVerifier,
+        state::ProtectionHandle,
+        RpcMeta, REQUEST_ID_HEADER,
+    },
+    futures_util::future::BoxFuture,
+    hyper::{
+        header::{self, HeaderName, HeaderValue},
+        service::Service,
+        Body, Request, Response, StatusCode,
+    },
+    jsonrpc_core::types::{
+        error::Error as JsonRpcError,
+        request::{self, Call, MethodCall, Notification},
+        response::{self, Output},
+    },
+    std::{
+        sync::Arc,
+        task::{Context, Poll},
+    },
+};
+
+#[derive(Clone)]
+pub struct ProtectionLayer {
+    state: ProtectionHandle,
+    request_verifier: Option<Arc<RequestVerifier>>,
+    max_request_body_size: usize,
+    messages: Option<Arc<MessageCatalog>>,
+    identity_headers: IdentityHeaders,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    on_denial: Option<Arc<dyn Fn(Denial) + Send + Sync>>,
+}
+
+/// Headers the layer sets on requests it forwards, describing the caller, see
+/// [`ProtectionLayer::identity_headers`].
+#[derive(Clone, Debug, Default)]
+pub struct IdentityHeaders {
+    /// Receives the role of the caller, such as `admin`.
+    pub role: Option<HeaderName>,
+    /// Receives the name of the user, for sessions started by a user login.
+    pub user: Option<HeaderName>,
+}
+
+impl ProtectionLayer {
+    pub fn new(state: ProtectionHandle) -> Self {
+        Self {
+            state,
+            request_verifier: None,
+            max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
+            messages: None,
+            identity_headers: IdentityHeaders::default(),
+            rate_limiter: None,
+            on_denial: None,
+        }
+    }
+
+    /// Accept request signatures checked by `verifier` as admin credentials.
+    pub fn request_verifier(mut self, verifier: RequestVerifier) -> Self {
+        self.request_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    pub fn max_request_body_size(mut self, size: usize) -> Self {
+        self.max_request_body_size = size;
+        self
+    }
+
+    /// Translates error messages according to the languages accepted by the caller.
+    pub fn message_catalog(mut self, messages: Arc<MessageCatalog>) -> Self {
+        self.messages = Some(messages);
+        self
+    }
+
+    /// Describe the caller to the inner service in `headers`, replacing any values of these
+    /// headers sent by the client.  A header is left out when there is nothing to put in it.
+    pub fn identity_headers(mut self, headers: IdentityHeaders) -> Self {
+        self.identity_headers = headers;
+        self
+    }
+
+    /// Reject requests over the quota of the caller, with a 429 response, and tell callers
+    /// where they stand in the `X-RateLimit-*` response headers, as [`RpcHttpHandler`] does.
+    ///
+    /// [`RpcHttpHandler`]: crate::http::RpcHttpHandler
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Calls `f` for every rejected call.
+    pub fn on_denial<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Denial) + Send + Sync + 'static,
+    {
+        self.on_denial = Some(Arc::new(f));
+        self
+    }
+
+    /// Protects `inner`.
+    pub fn service<S>(&self, inner: S) -> Protection<S> {
+        Protection {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Protection<S> {
+    inner: S,
+    layer: ProtectionLayer,
+}
+
+impl<S> Service<Request<Body>> for Protection<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // The service that was polled ready is the one that has to handle the request.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let mut meta = RpcMeta::from_headers(|name| {
+                request.headers().get(name).map(HeaderValue::as_bytes)
+            });
+
+            let (mut parts, body) = request.into_parts();
+            // So that the inner service reports the same id, if it had to be generated.
+            parts.headers.insert(
+                REQUEST_ID_HEADER,
+                HeaderValue::try_from(&meta.request_id)
+                    .expect("Request ids are valid header values"),
+            );
+
+            let body = match read_body(body, layer.max_request_body_size, None).await {
+                Ok(body) => body,
+                Err(response) => return Ok(response),
+            };
+
+            if let Some(verifier) = &layer.request_verifier {
+                meta.request_signature = verifier.verify(&parts.headers, parts.uri.path(), &body);
+            }
+
+            let rate_limit = layer.rate_limiter.as_ref().and_then(|limiter| {
+                limiter.check(&meta, count_calls(&String::from_utf8_lossy(&body)))
+            });
+            if let Some(limit) = rate_limit.as_ref().filter(|limit| !limit.allowed) {
+                let mut rejection = Rejection::new(Reason::RateLimited, "Rate limit exceeded");
+                if let Some(retry_after) = limit.retry_after {
+                    rejection = rejection.retry_after(retry_after);
+                }
+                let body = String::from_utf8_lossy(&body);
+                layer.report(&rejection, &method_names(&body).unwrap_or_default(), &meta);
+                let mut response =
+                    serialized_response(reject_request(&body, &rejection, &meta), &meta);
+                *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                insert_rate_limit_headers(response.headers_mut(), limit);
+                return Ok(response);
+            }
+
+            if let Some(response) = check_request(&layer, &body, &meta) {
+                return Ok(response);
+            }
+
+            let caller = layer.state.load().caller(&meta);
+            let IdentityHeaders { role, user } = &layer.identity_headers;
+            // `insert` replaces all the values sent by the client.
+            if let Some(name) = role {
+                parts
+                    .headers
+                    .insert(name, HeaderValue::from_static(caller.role.as_str()));
+            }
+            if let Some(name) = user {
+                parts.headers.remove(name);
+                // Names that are not valid header values are left out, rather than mangled.
+                if let Some(value) = caller
+                    .user
+                    .and_then(|user| HeaderValue::try_from(user).ok())
+                {
+                    parts.headers.insert(name, value);
+                }
+            }
+
+            let mut response = inner
+                .call(Request::from_parts(parts, Body::from(body)))
+                .await?;
+            if let Some(limit) = &rate_limit {
+                insert_rate_limit_headers(response.headers_mut(), limit);
+            }
+            Ok(response)
+        })
+    }
+}
+
+impl ProtectionLayer {
+    /// Tells [`Self::on_denial`] about a call to `method` rejected with `rejection`.
+    fn report(&self, rejection: &Rejection, method: &str, meta: &RpcMeta) {
+        if let Some(on_denial) = &self.on_denial {
+            on_denial(Denial {
+                method: method.to_owned(),
+                reason: rejection.reason,
+                message: rejection.message.clone(),
+                peer_addr: meta.peer_addr,
+            });
+        }
+    }
+}
+
+/// Returns the response to send instead of forwarding a request with `body`, if it is not valid
+/// JSON-RPC, or if any of its calls are not allowed.
+fn check_request(layer: &ProtectionLayer, body: &[u8], meta: &RpcMeta) -> Option<Response<Body>> {
+    let Ok(request) = serde_json::from_slice::<request::Request>(body) else {
+        let response = response::Response::from(JsonRpcError::parse_error(), None);
+        return Some(json_response(Some(response), meta));
+    };
+    let state = layer.state.load();
+    let messages = layer.messages.as_deref();
+
+    // `Err(None)` for invalid calls.  `check_call` allows them, as jsonrpc-core never executes
+    // them, but the inner service might.
+    let check = |call: &Call| match call {
+        Call::Invalid { .. } => Err(None),
+        Call::MethodCall(MethodCall { method, .. })
+        | Call::Notification(Notification { method, .. }) => {
+            state.check_call(call, meta).map_err(|rejection| {
+                layer.report(&rejection, method, meta);
+                Some(rejection)
+            })
+        }
+    };
+    let output = |call: &Call, denied: Option<Rejection>| match (call, denied) {
+        (Call::Invalid { id }, _) => Some(Output::from(
+            Err(JsonRpcError::invalid_request()),
+            id.clone(),
+            None,
+        )),
+        (_, Some(denied)) => rejection(call, &denied, messages, meta),
+        (_, None) => {
+            let denied = Rejection::new(
+                Reason::BatchRejected,
+                "Batch contains calls that are not authorized",
+            );
+            rejection(call, &denied, messages, meta)
+        }
+    };
+
+    let response = match &request {
+        request::Request::Single(call) => {
+            let denied = check(call).err()?;
+            output(call, denied).map(response::Response::Single)
+        }
+        request::Request::Batch(calls) => {
+            let denied = calls
+                .iter()
+                .map(|call| check(call).err())
+                .collect::<Vec<_>>();
+            if denied.iter().all(Option::is_none) {
+                return None;
+            }
+
+            let outputs = calls
+                .iter()
+                .zip(denied)
+                .filter_map(|(call, denied)| output(call, denied.flatten()))
+                .collect::<Vec<_>>();
+            (!outputs.is_empty()).then_some(response::Response::Batch(outputs))
+        }
+    };
+    Some(json_response(response, meta))
+}
+
+/// `response`, or an empty body if there are only notifications to respond to.
+fn json_response(response: Option<response::Response>, meta: &RpcMeta) -> Response<Body> {
+    let response = response
+        .map(|response| serde_json::to_string(&response).expect("Responses always serialize"));
+    serialized_response(response, meta)
+}
+
+/// Same as [`json_response`], for an already serialized `response`.
+fn serialized_response(response: Option<String>, meta: &RpcMeta) -> Response<Body> {
+    let content = match response {
+        Some(response) => format!("{response}\n"),
+        None => String::new(),
+    };
+
+    let mut response = Response::new(Body::from(content));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json; charset=utf-8"),
+    );
+    headers.insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::try_from(&meta.request_id).expect("Request ids are valid header values"),
+    );
+    response
+}
+
+/// Error returned for a rejected call.  Notifications get no response.
+fn rejection(
+    call: &Call,
+    rejection: &Rejection,
+    messages: Option<&MessageCatalog>,
+    meta: &RpcMeta,
+) -> Option<Output> {
+    let Call::MethodCall(MethodCall { jsonrpc, id, .. }) = call else {
+        return None;
+    };
+
+    let error = match messages {
+        Some(messages) => messages.localize(rejection, meta).to_error(meta),
+        None => rejection.to_error(meta),
+    };
+    Some(Output::from(Err(error), id.clone(), *jsonrpc))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            rate_limit::{Quota, RATE_LIMIT_REMAINING_HEADER},
+            state::{tests::state, ProtectionHandle, Role},
+        },
+        hyper::{body, service::service_fn, StatusCode},
+        serde_json::{json, Value},
+        std::{
+            convert::Infallible,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Mutex,
+            },
+            time::Duration,
+        },
+    };
+
+    /// Sends `body` through the layer to a service that answers `forwarded`, and returns the
+    /// response, along with the number of requests the service got.
+    async fn respond(
+        layer: &ProtectionLayer,
+        auth: Option<&str>,
+        body: &str,
+    ) -> (Response<Body>, usize) {
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let mut service = layer.service(service_fn({
+            let forwarded = forwarded.clone();
+            move |_: Request<Body>| {
+                forwarded.fetch_add(1, Ordering::Relaxed);
+                async { Ok::<_, Infallible>(Response::new(Body::from("forwarded"))) }
+            }
+        }));
+
+        let mut request = Request::post("/");
+        if let Some(auth) = auth {
+            request = request.header("X-Admin-Auth", auth);
+        }
+        let response = service
+            .call(request.body(Body::from(body.to_owned())).unwrap())
+            .await
+            .unwrap();
+        (response, forwarded.load(Ordering::Relaxed))
+    }
+
+    /// Same as [`respond`], for requests that get a 200 response, returning the response body.
+    async fn send(layer: &ProtectionLayer, auth: Option<&str>, body: &str) -> (String, usize) {
+        let (response, forwarded) = respond(layer, auth, body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        (String::from_utf8(body.to_vec()).unwrap(), forwarded)
+    }
+
+    fn layer() -> ProtectionLayer {
+        ProtectionLayer::new(ProtectionHandle::new(state()))
+    }
+
+    fn error(body: &str) -> Value {
+        serde_json::from_str::<Value>(body).unwrap()["error"].clone()
+    }
+
+    #[tokio::test]
+    async fn allowed_requests_are_forwarded() {
+        let layer = layer();
+        let f = json!({"jsonrpc": "2.0", "method": "f", "params": [], "id": 1}).to_string();
+        assert_eq!(
+            send(&layer, Some("root"), &f).await,
+            ("forwarded".to_owned(), 1)
+        );
+        let g = json!({"jsonrpc": "2.0", "method": "g", "params": [], "id": 1}).to_string();
+        assert_eq!(send(&layer, None, &g).await, ("forwarded".to_owned(), 1));
+    }
+
+    #[tokio::test]
+    async fn rejected_calls_are_not_forwarded() {
+        let layer = layer();
+        let f = json!({"jsonrpc": "2.0", "method": "f", "params": [], "id": 1}).to_string();
+        let (body, forwarded) = send(&layer, None, &f).await;
+        assert_eq!(forwarded, 0);
+        assert_eq!(error(&body)["data"]["reason"], "credentials_required");
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "g", "params": [], "id": 1},
+            {"jsonrpc": "2.0", "method": "f", "params": [], "id": 2},
+        ]);
+        let (body, forwarded) = send(&layer, None, &batch.to_string()).await;
+        assert_eq!(forwarded, 0);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body[0]["error"]["data"]["reason"], "batch_rejected");
+        assert_eq!(body[1]["error"]["data"]["reason"], "credentials_required");
+    }
+
+    /// The inner service may accept requests jsonrpc-core does not, and execute `f` without the
+    /// layer having checked it.
+    #[tokio::test]
+    async fn requests_that_are_not_valid_json_rpc_are_not_forwarded() {
+        let layer = layer();
+
+        let extra_member =
+            json!({"jsonrpc": "2.0", "method": "f", "params": [], "id": 1, "extra": 1});
+        let (body, forwarded) = send(&layer, None, &extra_member.to_string()).await;
+        assert_eq!(forwarded, 0);
+        assert_eq!(error(&body)["code"], -32600);
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "g", "params": [], "id": 1},
+            {"jsonrpc": "2.0", "method": "f", "id": 2, "extra": 1},
+        ]);
+        let (body, forwarded) = send(&layer, None, &batch.to_string()).await;
+        assert_eq!(forwarded, 0);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body[0]["error"]["data"]["reason"], "batch_rejected");
+        assert_eq!(body[1]["error"]["code"], -32600);
+
+        for body in ["{\"method\": \"f\", ", "not json", ""] {
+            let (body, forwarded) = send(&layer, None, body).await;
+            assert_eq!(forwarded, 0);
+            assert_eq!(error(&body)["code"], -32700);
+        }
+    }
+
+    #[tokio::test]
+    async fn callers_over_their_quota_get_429() {
+        let quota = Quota {
+            limit: 2,
+            period: Duration::from_secs(60),
+        };
+        let limiter = RateLimiter::new(
+            ProtectionHandle::new(state()),
+            [(Role::Anonymous, quota)].into(),
+        );
+        let layer = layer().rate_limiter(limiter);
+        let g = json!({"jsonrpc": "2.0", "method": "g", "params": [], "id": 1}).to_string();
+
+        for remaining in ["1", "0"] {
+            let (response, forwarded) = respond(&layer, None, &g).await;
+            assert_eq!(forwarded, 1);
+            assert_eq!(response.headers()[RATE_LIMIT_REMAINING_HEADER], remaining);
+        }
+
+        let (response, forwarded) = respond(&layer, None, &g).await;
+        assert_eq!(forwarded, 0);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(error(&body)["data"]["reason"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn rejected_calls_are_reported() {
+        let denials = Arc::new(Mutex::new(vec![]));
+        let layer = layer().on_denial({
+            let denials = denials.clone();
+            move |denial| denials.lock().unwrap().push(denial)
+        });
+
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "g", "params": [], "id": 1},
+            {"jsonrpc": "2.0", "method": "f", "params": [], "id": 2},
+        ]);
+        send(&layer, None, &batch.to_string()).await;
+        let f = json!({"jsonrpc": "2.0", "method": "f", "params": [], "id": 1}).to_string();
+        send(&layer, Some("root"), &f).await;
+
+        let denials = denials.lock().unwrap();
+        let denials = denials
+            .iter()
+            .map(|denial| (denial.method.as_str(), denial.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(denials, [("f", Reason::CredentialsRequired)]);
+    }
+}
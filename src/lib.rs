@@ -0,0 +1,188 @@
+use {
+    crate::{
+        approval::APPROVAL_ID_HEADER, deadline::DEADLINE_HEADER, deprecation::DeprecationNotices,
+        idempotency::IDEMPOTENCY_KEY_HEADER, messages::ACCEPT_LANGUAGE_HEADER, secret::Secret,
+        state::Caller,
+    },
+    jsonrpc_core::Metadata,
+    jsonrpc_pubsub::Session,
+    rand::Rng,
+    std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Instant},
+    thiserror::Error,
+};
+
+pub mod admin_rpc;
+pub mod approval;
+pub mod attributes;
+pub mod auth_rpc;
+pub mod bootstrap;
+pub mod break_glass;
+pub mod capture;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod constraints;
+pub mod deadline;
+pub mod deprecation;
+pub mod diagnostic;
+#[cfg(feature = "client")]
+pub mod federation;
+pub mod histogram;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod idempotency;
+#[cfg(feature = "http")]
+pub mod layer;
+pub mod load;
+pub mod main_rpc;
+pub mod memory;
+pub mod messages;
+pub mod middleware;
+pub mod openrpc;
+pub mod output_hook;
+pub mod panic_guard;
+pub mod policy;
+pub mod priority;
+pub mod pubsub;
+pub mod rate_limit;
+pub mod rejection;
+pub mod request_log;
+pub mod result_filter;
+pub mod secret;
+pub mod session;
+pub mod signing;
+pub mod slo;
+pub mod state;
+pub mod stats;
+pub mod strength;
+pub mod users;
+pub mod versioning;
+#[cfg(feature = "ws")]
+pub mod ws;
+
+/// Identifies a request in error responses and logs.  Generated when the client does not send
+/// one.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Name of a user an admin makes the call as, see [`state`].
+pub const RUN_AS_HEADER: &str = "X-Run-As";
+
+#[derive(Error, Debug, Clone)]
+pub enum Error {
+    #[error("X-Admin-Auth header value must contain only visible ASCII characters")]
+    AdminAuthHeaderParserError,
+
+    #[error("Idempotency-Key header value must contain only visible ASCII characters")]
+    IdempotencyKeyHeaderParserError,
+
+    #[error("X-Run-As header value must be a username")]
+    RunAsHeaderParserError,
+
+    #[error("Request signature headers must contain only visible ASCII characters")]
+    RequestSignatureHeaderParserError,
+
+    #[error("X-Signature requires X-Signature-Timestamp and X-Signature-Nonce headers")]
+    RequestSignatureIncomplete,
+
+    #[error("Request signature timestamp is too far from the server time")]
+    RequestSignatureExpired,
+
+    #[error("Request signature nonce was already used")]
+    RequestSignatureReplayed,
+
+    #[error("Request signature is not valid")]
+    RequestSignatureInvalid,
+
+    #[error("Request signature key is not known")]
+    RequestSignatureUnknownKey,
+
+    #[error("Request signature key is not valid at the signature timestamp")]
+    RequestSignatureKeyNotValid,
+}
+
+#[derive(Clone)]
+pub struct RpcMeta {
+    pub auth: Option<Result<Secret<String>, Error>>,
+    pub idempotency_key: Option<Result<String, Error>>,
+    /// Value of the [`RUN_AS_HEADER`].
+    pub run_as: Option<Result<String, Error>>,
+    /// Value of the [`APPROVAL_ID_HEADER`], see [`approval`].
+    pub approval_id: Option<String>,
+    /// Outcome of the request signature check.  `None` if the request was not signed, or if
+    /// request signing is not enabled.
+    pub request_signature: Option<Result<(), Error>>,
+    /// Address the request came from, if the transport knows it.
+    pub peer_addr: Option<SocketAddr>,
+    /// Set by transports that support subscriptions.
+    pub session: Option<Arc<Session>>,
+    /// Value of the [`REQUEST_ID_HEADER`], or a random one.
+    pub request_id: String,
+    /// Languages the caller prefers for error messages, see [`messages`].
+    pub accept_language: Option<String>,
+    /// When the caller stops waiting for the response, see [`deadline`].
+    pub deadline: Option<Instant>,
+    /// Caller attributes, such as a tenant, taken from the headers configured with
+    /// [`attributes`].  Not authenticated.
+    pub attributes: BTreeMap<String, String>,
+    /// Who is making the call.  Set by the protection middleware for calls it lets through, so
+    /// `None` before that, and for handlers served without it.
+    pub caller: Option<Caller>,
+    /// Deprecated methods called in the request, for the transport to tell the caller about,
+    /// see [`deprecation`].  Shared by the calls of a batch.
+    pub deprecations: DeprecationNotices,
+}
+impl Metadata for RpcMeta {}
+
+impl RpcMeta {
+    /// Builds metadata from request headers.  `header` returns the raw value of the named
+    /// header, if the request has one.
+    ///
+    /// This does not depend on a particular `http` crate version, so that all transports can
+    /// share it.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a [u8]>) -> Self {
+        let text = |name, error: Error| {
+            header(name).map(|value| {
+                // Same rule as `HeaderValue::to_str()`.
+                if value.iter().all(|&b| b == b'\t' || (32..127).contains(&b)) {
+                    Ok(String::from_utf8(value.to_vec()).expect("ASCII is valid UTF-8"))
+                } else {
+                    Err(error)
+                }
+            })
+        };
+
+        RpcMeta {
+            auth: text("X-Admin-Auth", Error::AdminAuthHeaderParserError)
+                .map(|auth| auth.map(Secret::new)),
+            idempotency_key: text(
+                IDEMPOTENCY_KEY_HEADER,
+                Error::IdempotencyKeyHeaderParserError,
+            ),
+            run_as: header(RUN_AS_HEADER).map(|user| {
+                if !user.is_empty() && user.iter().all(u8::is_ascii_graphic) {
+                    Ok(String::from_utf8(user.to_vec()).expect("ASCII is valid UTF-8"))
+                } else {
+                    Err(Error::RunAsHeaderParserError)
+                }
+            }),
+            // Ids are hex digits, anything else can not match.
+            approval_id: header(APPROVAL_ID_HEADER)
+                .and_then(|id| std::str::from_utf8(id).ok())
+                .map(str::to_owned),
+            request_signature: None,
+            peer_addr: None,
+            session: None,
+            // Request ids end up in logs, so anything unusual is replaced.
+            request_id: header(REQUEST_ID_HEADER)
+                .filter(|id| !id.is_empty() && id.iter().all(u8::is_ascii_graphic))
+                .map(|id| String::from_utf8(id.to_vec()).expect("ASCII is valid UTF-8"))
+                .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 16]>())),
+            accept_language: header(ACCEPT_LANGUAGE_HEADER)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .map(str::to_owned),
+            deadline: header(DEADLINE_HEADER).and_then(deadline::parse_deadline),
+            attributes: BTreeMap::new(),
+            caller: None,
+            deprecations: DeprecationNotices::default(),
+        }
+    }
+}
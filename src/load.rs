@@ -0,0 +1,123 @@
+//! Tells whether the host is saturated, so that low priority traffic can be shed before
+//! everyone slows down.
+//!
+//! [`LoadMonitor::run`] periodically samples the CPU time used by the process, and how late the
+//! runtime wakes up timers.  The latter grows when handlers block, or when there is more work
+//! than the workers can keep up with.  The host is considered saturated once either goes over
+//! its limit, and stays so until both are comfortably below it again, so that limits do not
+//! flap.
+//!
+//! CPU usage is only available on Linux.  Elsewhere only the timer lag is used.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the load is sampled.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits scale by this much when deciding that the host is no longer saturated.
+const RECOVERY_FACTOR: f64 = 0.8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LoadConfig {
+    /// Fraction of the available CPU time, between 0 and 1.
+    pub max_cpu_usage: f64,
+    /// How late timers may fire.
+    pub max_lag: Duration,
+}
+
+#[derive(Clone)]
+pub struct LoadMonitor {
+    config: LoadConfig,
+    saturated: Arc<AtomicBool>,
+}
+
+impl LoadMonitor {
+    pub fn new(config: LoadConfig) -> Self {
+        Self {
+            config,
+            saturated: Default::default(),
+        }
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.saturated.load(Ordering::Relaxed)
+    }
+
+    /// Samples the load until dropped.  Spawn it on the runtime that executes the calls, as
+    /// that is the one whose lag matters.
+    pub async fn run(self) {
+        let parallelism = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let mut cpu_time = process_cpu_time();
+
+        loop {
+            let started = Instant::now();
+            tokio::time::sleep(INTERVAL).await;
+            let elapsed = started.elapsed();
+            let lag = elapsed.saturating_sub(INTERVAL);
+
+            let previous = cpu_time;
+            cpu_time = process_cpu_time();
+            let cpu_usage = match (previous, cpu_time) {
+                (Some(previous), Some(current)) => Some(
+                    current.saturating_sub(previous).as_secs_f64()
+                        / (elapsed.as_secs_f64() * parallelism as f64),
+                ),
+                _ => None,
+            };
+
+            self.update(lag, cpu_usage);
+        }
+    }
+
+    fn update(&self, lag: Duration, cpu_usage: Option<f64>) {
+        let LoadConfig {
+            max_cpu_usage,
+            max_lag,
+        } = self.config;
+        let cpu_usage_shown =
+            cpu_usage.map_or_else(|| "-".to_owned(), |usage| format!("{usage:.2}"));
+
+        if !self.is_saturated() {
+            if lag > max_lag || cpu_usage.is_some_and(|usage| usage > max_cpu_usage) {
+                self.saturated.store(true, Ordering::Relaxed);
+                log::warn!("Host is saturated, lag={lag:?} cpu_usage={cpu_usage_shown}");
+            }
+        } else if lag <= max_lag.mul_f64(RECOVERY_FACTOR)
+            && cpu_usage.is_none_or(|usage| usage <= max_cpu_usage * RECOVERY_FACTOR)
+        {
+            self.saturated.store(false, Ordering::Relaxed);
+            log::info!("Host is no longer saturated, lag={lag:?} cpu_usage={cpu_usage_shown}");
+        }
+    }
+}
+
+/// CPU time used by all threads of the process so far.
+#[cfg(target_os = "linux")]
+fn process_cpu_time() -> Option<Duration> {
+    // `sysconf(_SC_CLK_TCK)`, which is 100 on all supported architectures.
+    const TICKS_PER_SECOND: u64 = 100;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The process name may contain spaces, so fields are counted from the end of it.  `utime`
+    // and `stime` are fields 14 and 15, while the name is field 2.
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let user = fields.next()?.parse::<u64>().ok()?;
+    let system = fields.next()?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(
+        (user + system) * 1000 / TICKS_PER_SECOND,
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_cpu_time() -> Option<Duration> {
+    None
+}
@@ -0,0 +1,37 @@
+use {crate::RpcMeta, jsonrpc_core::Result, jsonrpc_derive::rpc};
+
+#[rpc]
+pub trait MainRpc {
+    type Metadata;
+
+    #[rpc(name = "g")]
+    fn g(&self, a: u8, b: u8) -> Result<u8>;
+}
+
+pub struct MainRpcImpl;
+impl MainRpc for MainRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn g(&self, a: u8, b: u8) -> Result<u8> {
+        Ok(a.saturating_mul(10).saturating_add(b).saturating_sub(3))
+    }
+}
+
+/// Version 2 of [`MainRpc`], served on `/v2`, see [`crate::versioning`].
+#[rpc(server)]
+pub trait MainRpcV2 {
+    type Metadata;
+
+    /// Same as [`MainRpc::g`], over a wider range, without saturating.
+    #[rpc(name = "g")]
+    fn g(&self, a: u16, b: u16) -> Result<i32>;
+}
+
+pub struct MainRpcV2Impl;
+impl MainRpcV2 for MainRpcV2Impl {
+    type Metadata = RpcMeta;
+
+    fn g(&self, a: u16, b: u16) -> Result<i32> {
+        Ok(i32::from(a) * 10 + i32::from(b) - 3)
+    }
+}
@@ -0,0 +1,119 @@
+//! A global cap on the memory used by request bodies and by the tables the server keeps.
+//!
+//! Sizes are estimates: every component reports roughly how much it holds, which is enough to
+//! notice that the process is heading for the OOM killer.  Once the total goes over the
+//! limit, the idempotency cache drops its oldest results, rate limit buckets that would be
+//! recreated identically are pruned right away, and request bodies that do not fit are
+//! rejected before being read.  Nonces of signed requests are never dropped early, as that
+//! would allow replays.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// What holds the memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    /// Bodies of requests that are being read or handled.
+    Requests,
+    IdempotencyCache,
+    Nonces,
+    RateLimits,
+}
+
+impl Component {
+    const ALL: [Component; 4] = [
+        Component::Requests,
+        Component::IdempotencyCache,
+        Component::Nonces,
+        Component::RateLimits,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Clones share the counters.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    used: [AtomicUsize; Component::ALL.len()],
+}
+
+impl MemoryBudget {
+    /// `limit` is in bytes.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit,
+                used: Default::default(),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Bytes used by all components together.
+    pub fn used(&self) -> usize {
+        Component::ALL
+            .into_iter()
+            .map(|component| self.used_by(component))
+            .sum()
+    }
+
+    pub fn used_by(&self, component: Component) -> usize {
+        self.inner.used[component.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.used() > self.inner.limit
+    }
+
+    /// Records that `component` now holds `bytes`.
+    pub fn set(&self, component: Component, bytes: usize) {
+        self.inner.used[component.index()].store(bytes, Ordering::Relaxed);
+    }
+
+    /// An empty reservation for a request body, to be grown as the body is read.
+    pub fn request(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+}
+
+/// Memory held by a request body.  Released when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Adds `bytes` to the reservation, unless the limit would be exceeded.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        if self.budget.used() + bytes > self.budget.inner.limit {
+            return false;
+        }
+        self.budget.inner.used[Component::Requests.index()].fetch_add(bytes, Ordering::Relaxed);
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.inner.used[Component::Requests.index()]
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,113 @@
+//! Translations of the messages of protection errors.
+//!
+//! A [`MessageCatalog`] maps locales to messages for every [`Reason`].  The locale is chosen
+//! from the `Accept-Language` header of the request.  Reasons without a translation for any of
+//! the accepted locales keep the built-in English message.  English translations in the catalog
+//! take precedence over the built-in ones.
+//!
+//! Catalogs are JSON objects keyed by locale, then by reason:
+//!
+//! ```json
+//! {
+//!     "de": {
+//!         "credentials_required": "Diese Methode erfordert Admin-Zugangsdaten",
+//!         "invalid_token": "Das Admin-Token ist nicht gültig"
+//!     }
+//! }
+//! ```
+
+use {
+    crate::{
+        diagnostic::{self, Diagnostic},
+        rejection::{Reason, Rejection},
+        RpcMeta,
+    },
+    std::{collections::HashMap, fs, io, path::Path},
+    thiserror::Error,
+};
+
+pub const ACCEPT_LANGUAGE_HEADER: &str = "Accept-Language";
+
+#[derive(Error, Debug)]
+pub enum CatalogError {
+    #[error("Failed to read the message catalog: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to parse the message catalog: {0}")]
+    Parse(#[from] Diagnostic),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MessageCatalog {
+    /// Locales are lower case.
+    messages: HashMap<String, HashMap<Reason, String>>,
+}
+
+impl MessageCatalog {
+    pub fn from_json(json: &str) -> Result<Self, CatalogError> {
+        let messages = diagnostic::from_json::<HashMap<String, HashMap<Reason, String>>>(json)?;
+        Ok(Self {
+            messages: messages
+                .into_iter()
+                .map(|(locale, messages)| (locale.to_ascii_lowercase(), messages))
+                .collect(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CatalogError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Returns `rejection` with the message translated into the language preferred by the
+    /// caller with the given `meta`, if the catalog has it.
+    pub fn localize(&self, rejection: &Rejection, meta: &RpcMeta) -> Rejection {
+        let mut rejection = rejection.clone();
+        if let Some(message) = meta
+            .accept_language
+            .as_deref()
+            .and_then(|accepted| self.find(accepted, rejection.reason))
+        {
+            rejection.message = message.to_owned();
+        }
+        rejection
+    }
+
+    fn find(&self, accept_language: &str, reason: Reason) -> Option<&str> {
+        for locale in accepted_locales(accept_language) {
+            // "de-AT" falls back to "de".
+            let primary = locale.split('-').next().unwrap_or(&locale);
+            let message = [locale.as_str(), primary]
+                .into_iter()
+                .find_map(|locale| self.messages.get(locale)?.get(&reason));
+            if let Some(message) = message {
+                return Some(message);
+            }
+            // Built-in messages are in English, so they are preferred to any language the
+            // caller accepts after it.
+            if primary == "en" {
+                return None;
+            }
+        }
+        None
+    }
+}
+
+/// Locales listed in an `Accept-Language` header, most preferred first, in lower case.
+fn accepted_locales(header: &str) -> Vec<String> {
+    let mut locales = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let locale = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!locale.is_empty() && locale != "*" && quality > 0.0)
+                .then(|| (locale.to_ascii_lowercase(), quality))
+        })
+        .collect::<Vec<_>>();
+
+    // Stable, so that locales of equal quality keep the order they were listed in.
+    locales.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    locales.into_iter().map(|(locale, _)| locale).collect()
+}
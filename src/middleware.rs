@@ -0,0 +1,231 @@
+use {
+    crate::{
+        bootstrap::TrustOnFirstUse, messages::MessageCatalog, pubsub::Denial,
+        state::ProtectionHandle, RpcMeta,
+    },
+    futures_util::future::Either,
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall},
+            response::{Output, Response},
+        },
+    },
+    std::{future::Future, pin::Pin, sync::Arc},
+};
+
+#[derive(Clone)]
+pub struct ProtectRpcMiddleware {
+    state: ProtectionHandle,
+    on_denial: Option<Arc<dyn Fn(Denial) + Send + Sync>>,
+    messages: Option<Arc<MessageCatalog>>,
+    trust_on_first_use: Option<Arc<TrustOnFirstUse>>,
+}
+
+impl ProtectRpcMiddleware {
+    pub fn new(state: ProtectionHandle) -> Self {
+        Self {
+            state,
+            on_denial: None,
+            messages: None,
+            trust_on_first_use: None,
+        }
+    }
+
+    /// Calls `f` for every rejected call.
+    pub fn on_denial<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Denial) + Send + Sync + 'static,
+    {
+        self.on_denial = Some(Arc::new(f));
+        self
+    }
+
+    /// Translates error messages according to the languages accepted by the caller.
+    pub fn message_catalog(mut self, messages: Arc<MessageCatalog>) -> Self {
+        self.messages = Some(messages);
+        self
+    }
+
+    /// Makes the first admin token presented the admin token, see [`TrustOnFirstUse`].
+    pub fn trust_on_first_use(mut self, bootstrap: TrustOnFirstUse) -> Self {
+        self.trust_on_first_use = Some(Arc::new(bootstrap));
+        self
+    }
+}
+
+impl Middleware<RpcMeta> for ProtectRpcMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        if let Some(bootstrap) = &self.trust_on_first_use {
+            bootstrap.offer(&meta, &self.state);
+        }
+
+        let (caller, denied) = match self.state.load().check_call(&call, &meta) {
+            Ok(caller) => (Some(caller), None),
+            Err(rejection) => (None, Some(rejection)),
+        };
+
+        if let (Some(rejection), Some(on_denial)) = (&denied, &self.on_denial) {
+            let method = match &call {
+                Call::MethodCall(call) => call.method.clone(),
+                Call::Notification(notification) => notification.method.clone(),
+                Call::Invalid { .. } => String::new(),
+            };
+            on_denial(Denial {
+                method,
+                reason: rejection.reason,
+                message: rejection.message.clone(),
+                peer_addr: meta.peer_addr,
+            });
+        }
+
+        match (denied, call) {
+            (Some(rejection), Call::MethodCall(MethodCall { jsonrpc, id, .. })) => {
+                let error = match &self.messages {
+                    Some(messages) => messages.localize(&rejection, &meta).to_error(&meta),
+                    None => rejection.to_error(&meta),
+                };
+
+                Either::Left(Box::pin(async move {
+                    Some(Output::from(Err(error), id, jsonrpc))
+                }))
+            }
+            // Notifications have no response, so a rejected one is just dropped.
+            (Some(_), _) => Either::Left(Box::pin(async { None })),
+            (None, call) => {
+                let mut meta = meta;
+                meta.caller = caller;
+                Either::Right(next(call, meta))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            rejection::Reason,
+            state::{
+                tests::{meta, state},
+                ProtectionHandle,
+            },
+        },
+        jsonrpc_core::{MetaIoHandler, Params, Value},
+        serde_json::json,
+        std::sync::Mutex,
+    };
+
+    fn io(middleware: ProtectRpcMiddleware) -> MetaIoHandler<RpcMeta, ProtectRpcMiddleware> {
+        let mut io = MetaIoHandler::with_middleware(middleware);
+        for method in ["f", "g"] {
+            io.add_method_with_meta(method, |_: Params, meta: RpcMeta| async move {
+                Ok(Value::String(
+                    meta.caller
+                        .map_or("none".to_owned(), |caller| caller.to_string()),
+                ))
+            });
+        }
+        io
+    }
+
+    fn handle(
+        io: &MetaIoHandler<RpcMeta, ProtectRpcMiddleware>,
+        request: Value,
+        auth: Option<&str>,
+    ) -> Value {
+        let response = io
+            .handle_request_sync(&request.to_string(), meta(auth))
+            .unwrap_or_else(|| "null".to_owned());
+        serde_json::from_str(&response).unwrap()
+    }
+
+    fn request(method: &str, id: u64) -> Value {
+        json!({"jsonrpc": "2.0", "method": method, "params": [], "id": id})
+    }
+
+    #[test]
+    fn protected_calls_without_credentials_are_rejected() {
+        let io = io(ProtectRpcMiddleware::new(ProtectionHandle::new(state())));
+
+        let response = handle(&io, request("f", 1), None);
+        assert_eq!(response["error"]["data"]["reason"], "credentials_required");
+        assert_eq!(response["error"]["data"]["required_role"], "admin");
+        assert_eq!(response["id"], 1);
+
+        let response = handle(&io, request("f", 1), Some("wrong"));
+        assert_eq!(response["error"]["data"]["reason"], "invalid_token");
+    }
+
+    #[test]
+    fn allowed_calls_reach_the_handler_with_the_caller() {
+        let io = io(ProtectRpcMiddleware::new(ProtectionHandle::new(state())));
+        assert_eq!(
+            handle(&io, request("f", 1), Some("root"))["result"],
+            "admin"
+        );
+        assert_eq!(handle(&io, request("g", 1), None)["result"], "anonymous");
+    }
+
+    #[test]
+    fn calls_in_a_batch_are_checked_one_by_one() {
+        let io = io(ProtectRpcMiddleware::new(ProtectionHandle::new(state())));
+        let response = handle(&io, json!([request("g", 1), request("f", 2)]), None);
+        assert_eq!(response[0]["result"], "anonymous");
+        assert_eq!(
+            response[1]["error"]["data"]["reason"],
+            "credentials_required"
+        );
+    }
+
+    #[test]
+    fn rejected_notifications_are_dropped() {
+        let denials = Arc::new(Mutex::new(vec![]));
+        let middleware = ProtectRpcMiddleware::new(ProtectionHandle::new(state())).on_denial({
+            let denials = denials.clone();
+            move |denial| denials.lock().unwrap().push(denial)
+        });
+        let io = io(middleware);
+
+        let notification = json!({"jsonrpc": "2.0", "method": "f", "params": []});
+        assert_eq!(handle(&io, notification, None), Value::Null);
+        handle(&io, request("f", 1), Some("wrong"));
+
+        let denials = denials.lock().unwrap();
+        let reasons = denials
+            .iter()
+            .map(|denial| denial.reason)
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, [Reason::CredentialsRequired, Reason::InvalidToken]);
+        assert!(denials.iter().all(|denial| denial.method == "f"));
+    }
+
+    #[test]
+    fn changes_to_the_state_apply_to_the_next_call() {
+        let state = ProtectionHandle::new(state());
+        let io = io(ProtectRpcMiddleware::new(state.clone()));
+
+        state.update(|state| {
+            state.protected.insert("g".to_owned());
+        });
+        assert_eq!(
+            handle(&io, request("g", 1), None)["error"]["data"]["reason"],
+            "credentials_required"
+        );
+
+        state.update(|state| state.admin_token = "new".into());
+        assert_eq!(
+            handle(&io, request("f", 1), Some("root"))["error"]["data"]["reason"],
+            "invalid_token"
+        );
+        assert_eq!(handle(&io, request("f", 1), Some("new"))["result"], "admin");
+    }
+}
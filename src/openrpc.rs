@@ -0,0 +1,152 @@
+//! Protection rules taken from an OpenRPC document, so that teams maintaining a spec of their
+//! API do not have to repeat the rules elsewhere.
+//!
+//! Methods carry the rules in extension fields:
+//!
+//! ```json
+//! {
+//!   "openrpc": "1.2.6",
+//!   "methods": [
+//!     {
+//!       "name": "f",
+//!       "x-protected": true,
+//!       "params": [{ "name": "a", "schema": { "type": "integer", "maximum": 10 } }]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `x-role` names the role that may call the method, `admin` for protected methods, and can be
+//! given in place of `x-protected`.  Methods with neither are not protected.  The bounds in the
+//! schemas of the parameters, `minimum`, `maximum`, `exclusiveMinimum`, `exclusiveMaximum` and
+//! `const`, become [`ParamConstraint`]s for that role.  Parameters are referred to by position,
+//! unless the method has a `paramStructure` of `by-name`.
+//!
+//! Which methods are protected is decided by the server, so [`OpenRpcRules::protected`] is for
+//! checking that the server and the spec agree, see `jsonrpc-protection policy import-openrpc`.
+
+use {
+    crate::{
+        constraints::{Op, Param, ParamConstraint},
+        diagnostic::{self, Diagnostic},
+        state::Role,
+    },
+    serde::Deserialize,
+    serde_json::Value,
+    std::collections::BTreeSet,
+};
+
+/// What an OpenRPC document says about protection.
+#[derive(Clone, Debug, Default)]
+pub struct OpenRpcRules {
+    /// Every method in the document.
+    pub methods: BTreeSet<String>,
+    /// Methods only admins may call.
+    pub protected: BTreeSet<String>,
+    pub constraints: Vec<ParamConstraint>,
+    /// Methods whose `x-protected` and `x-role` disagree.  Their `x-role` is taken.
+    pub problems: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Document {
+    methods: Vec<Method>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Method {
+    name: String,
+    #[serde(default)]
+    params: Vec<ContentDescriptor>,
+    #[serde(default)]
+    param_structure: ParamStructure,
+    #[serde(rename = "x-protected")]
+    protected: Option<bool>,
+    #[serde(rename = "x-role")]
+    role: Option<Role>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ParamStructure {
+    ByName,
+    ByPosition,
+    #[default]
+    Either,
+}
+
+/// Also matches reference objects, which have neither a name nor a schema.
+#[derive(Deserialize)]
+struct ContentDescriptor {
+    name: Option<String>,
+    #[serde(default)]
+    schema: Schema,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Schema {
+    minimum: Option<Value>,
+    maximum: Option<Value>,
+    exclusive_minimum: Option<Value>,
+    exclusive_maximum: Option<Value>,
+    #[serde(rename = "const")]
+    constant: Option<Value>,
+}
+
+impl Schema {
+    fn bounds(self) -> impl Iterator<Item = (Op, Value)> {
+        [
+            (Op::Ge, self.minimum),
+            (Op::Le, self.maximum),
+            (Op::Gt, self.exclusive_minimum),
+            (Op::Lt, self.exclusive_maximum),
+            (Op::Eq, self.constant),
+        ]
+        .into_iter()
+        // `exclusiveMinimum: true` is how older drafts of JSON Schema marked `minimum`.
+        .filter_map(|(op, value)| value.filter(|value| !value.is_boolean()).map(|v| (op, v)))
+    }
+}
+
+/// Reads the rules from the OpenRPC document in `json`.
+pub fn import(json: &str) -> Result<OpenRpcRules, Diagnostic> {
+    let document: Document = diagnostic::from_json(json)?;
+    let mut rules = OpenRpcRules::default();
+    for method in document.methods {
+        let role = match (method.role, method.protected) {
+            (Some(role), Some(protected)) if (role == Role::Admin) != protected => {
+                rules.problems.push(format!(
+                    "{}: x-protected is {protected}, but x-role is {role}",
+                    method.name
+                ));
+                role
+            }
+            (Some(role), _) => role,
+            (None, Some(true)) => Role::Admin,
+            (None, _) => Role::Anonymous,
+        };
+        if role == Role::Admin {
+            rules.protected.insert(method.name.clone());
+        }
+        for (position, param) in method.params.into_iter().enumerate() {
+            let param_ref = match (method.param_structure, param.name) {
+                (ParamStructure::ByName, Some(name)) => Param::Name(name),
+                (ParamStructure::ByName, None) => continue,
+                _ => Param::Position(position),
+            };
+            rules
+                .constraints
+                .extend(param.schema.bounds().map(|(op, value)| ParamConstraint {
+                    role,
+                    method: method.name.clone(),
+                    param: param_ref.clone(),
+                    op,
+                    value,
+                }));
+        }
+        rules.methods.insert(method.name);
+    }
+    Ok(rules)
+}
@@ -0,0 +1,112 @@
+//! Post-processing of call results, for adapting handlers that can not be changed.
+//!
+//! An [`OutputHook`] registered for a method gets the [`Output`] of every call to it, and
+//! returns the output to send instead.  It may rewrite or enrich the result, or veto it by
+//! returning an error.  Closures taking the output and the [`RpcMeta`] of the call are hooks:
+//!
+//! ```
+//! # use {
+//! #     jsonrpc_core::{types::response::Output, Error, MetaIoHandler},
+//! #     jsonrpc_protection::{output_hook::OutputHookMiddleware, RpcMeta},
+//! # };
+//! let hooks = OutputHookMiddleware::new().hook("status", |output: Output, _meta: RpcMeta| {
+//!     async move {
+//!         match output {
+//!             Output::Success(success) if success.result.is_null() => {
+//!                 Output::from(Err(Error::internal_error()), success.id, success.jsonrpc)
+//!             }
+//!             output => output,
+//!         }
+//!     }
+//! });
+//! let io = MetaIoHandler::<RpcMeta, _>::with_middleware(hooks);
+//! ```
+//!
+//! Hooks registered for the same method run one after another, in the order they were
+//! registered.  Notifications have no output, so hooks do not see them.  Placed after the
+//! protection middleware, hooks can read [`RpcMeta::caller`].
+
+use {
+    crate::RpcMeta,
+    futures_util::future::{BoxFuture, Either},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::Call,
+            response::{Output, Response},
+        },
+    },
+    std::{collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc},
+};
+
+/// Turns the output of a call into the output sent to the caller.
+pub trait OutputHook: Send + Sync + 'static {
+    fn process(&self, output: Output, meta: RpcMeta) -> BoxFuture<'static, Output>;
+}
+
+impl<F, R> OutputHook for F
+where
+    F: Fn(Output, RpcMeta) -> R + Send + Sync + 'static,
+    R: Future<Output = Output> + Send + 'static,
+{
+    fn process(&self, output: Output, meta: RpcMeta) -> BoxFuture<'static, Output> {
+        Box::pin(self(output, meta))
+    }
+}
+
+/// Runs the [`OutputHook`]s registered for the called method on its output.
+#[derive(Clone, Default)]
+pub struct OutputHookMiddleware {
+    hooks: HashMap<String, Vec<Arc<dyn OutputHook>>>,
+}
+
+impl OutputHookMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `hook` on the output of every call to `method`, after the hooks registered before.
+    pub fn hook(mut self, method: impl Into<String>, hook: impl OutputHook) -> Self {
+        self.hooks
+            .entry(method.into())
+            .or_default()
+            .push(Arc::new(hook));
+        self
+    }
+}
+
+impl fmt::Debug for OutputHookMiddleware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OutputHookMiddleware")
+            .field("methods", &self.hooks.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Middleware<RpcMeta> for OutputHookMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let hooks = match &call {
+            Call::MethodCall(call) => self.hooks.get(&call.method).cloned(),
+            Call::Notification(_) | Call::Invalid { .. } => None,
+        };
+        let Some(hooks) = hooks else {
+            return Either::Right(next(call, meta));
+        };
+
+        let output = next(call, meta.clone());
+        Either::Left(Box::pin(async move {
+            let mut output = output.await?;
+            for hook in hooks {
+                output = hook.process(output, meta.clone()).await;
+            }
+            Some(output)
+        }))
+    }
+}
@@ -0,0 +1,108 @@
+//! Turns panics in method handlers into JSON-RPC errors.
+//!
+//! Without it, a panicking handler takes down the connection that made the call, and the caller
+//! gets no response at all.
+
+use {
+    crate::RpcMeta,
+    futures_util::{future::Either, FutureExt},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            error::{Error as JsonRpcError, ErrorCode},
+            request::{Call, MethodCall},
+            response::{Output, Response},
+        },
+    },
+    serde_json::json,
+    std::{
+        any::Any,
+        future::Future,
+        panic::{self, AssertUnwindSafe},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    },
+};
+
+pub const TARGET: &str = "jsonrpc_protection::panic";
+
+/// Answers calls whose handler panicked with an internal error.  The `request_id` in the error
+/// data is logged alongside the panic message, so that the two can be matched.
+#[derive(Clone, Debug, Default)]
+pub struct PanicGuardMiddleware {
+    panics: Arc<AtomicU64>,
+}
+
+impl PanicGuardMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of panics caught so far, by this middleware and all its clones.
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+}
+
+impl Middleware<RpcMeta> for PanicGuardMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let (method, response_to) = match &call {
+            Call::MethodCall(MethodCall {
+                method,
+                jsonrpc,
+                id,
+                ..
+            }) => (method.clone(), Some((id.clone(), *jsonrpc))),
+            Call::Notification(notification) => (notification.method.clone(), None),
+            Call::Invalid { .. } => (String::new(), None),
+        };
+        let request_id = meta.request_id.clone();
+        let panics = self.panics.clone();
+
+        // Handlers do part of their work when called, and the rest when polled.
+        let output = panic::catch_unwind(AssertUnwindSafe(|| next(call, meta)));
+
+        Either::Left(Box::pin(async move {
+            let payload = match output {
+                Ok(output) => match AssertUnwindSafe(output).catch_unwind().await {
+                    Ok(output) => return output,
+                    Err(payload) => payload,
+                },
+                Err(payload) => payload,
+            };
+
+            let total = panics.fetch_add(1, Ordering::Relaxed) + 1;
+            log::error!(
+                target: TARGET,
+                "handler panicked method={method} request_id={request_id} total_panics={total}: {}",
+                panic_message(payload.as_ref()),
+            );
+
+            let (id, jsonrpc) = response_to?;
+            let error = JsonRpcError {
+                code: ErrorCode::InternalError,
+                message: "Internal error".to_owned(),
+                data: Some(json!({ "request_id": request_id })),
+            };
+            Some(Output::from(Err(error), id, jsonrpc))
+        }))
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
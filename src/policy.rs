@@ -0,0 +1,867 @@
+//! Protection rules kept in a JSON document, so that a fleet of servers can share them.
+//!
+//! A [`Policy`] holds the rules of [`ProtectionState`] that operators tune, rather than the
+//! credentials:
+//!
+//! ```json
+//! {
+//!   "loopback_methods": ["f"],
+//!   "param_constraints": ["admin:f:0<=10"],
+//!   "owned_params": ["f:1=tenant_id"]
+//! }
+//! ```
+//!
+//! Constraints and ownership rules are written as on the command line, see
+//! [`crate::constraints`].  Missing keys mean no rules of that kind.
+//!
+//! New rules can be tried on some of the callers first, by putting them into a `canary`, which
+//! is applied to `percent` of the callers instead of the rules around it:
+//!
+//! ```json
+//! {
+//!   "param_constraints": ["admin:f:0<=10"],
+//!   "canary": {
+//!     "percent": 10,
+//!     "policy": { "param_constraints": ["admin:f:0<=5"] }
+//!   }
+//! }
+//! ```
+//!
+//! See [`Canary`] for how callers are picked.  `admin_stats` counts the outcomes of calls
+//! checked against either set of rules separately, so the two can be compared.
+//!
+//! [`PolicySource`] fetches a policy from a URL, such as an object in an object store, only
+//! transferring it when it changed.  Policies can be signed, so that whoever can tamper with
+//! the store or the network between it and the servers can not change the rules.  Signed
+//! policies carry a `version`, and a server only applies a policy newer than the last one it
+//! fetched, so that older signed policies can not be replayed to roll the rules back:
+//!
+//! ```json
+//! {
+//!   "version": 7,
+//!   "loopback_methods": ["f"]
+//! }
+//! ```
+//!
+//! The version is only remembered while the server runs, so after a restart, the first policy
+//! fetched is accepted whatever its version.  Servers polling the same URL pick up changes
+//! within a poll interval of each other, and [`Policy::apply`] replaces all rules at once,
+//! through [`ProtectionHandle::update`], so no call is ever checked against a mix of old and
+//! new rules.
+//!
+//! Every policy applied is recorded in a [`PolicyHistory`].  `admin_policy_versions` lists
+//! them, `admin_policy_diff` shows what changed between two of them, and
+//! `admin_policy_rollback` applies an earlier one again.  Rollbacks are logged under the
+//! [`TARGET`] target, with the caller.  A policy rolled back to stays in place until the policy
+//! at the URL changes.
+//!
+//! `admin_policy` returns the rules in place, with the methods the server protects, as an
+//! [`EffectivePolicy`], for review and diffing.
+//!
+//! `admin_check` tells how the rules in place decide a [`CheckRequest`], without making the
+//! call, see [`ProtectionState::decide`].
+//!
+//! [`ProtectionHandle::update`]: crate::state::ProtectionHandle::update
+
+use {
+    crate::{
+        constraints::{OwnershipRule, ParamConstraint},
+        diagnostic::{self, Diagnostic},
+        secret::Secret,
+        state::{Canary, Decision, ProtectionHandle, ProtectionState, Role},
+        RpcMeta,
+    },
+    jsonrpc_core::{Error as JsonRpcError, Params, Result as RpcResult},
+    jsonrpc_derive::rpc,
+    serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+        fmt::Display,
+        net::{IpAddr, SocketAddr},
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const TARGET: &str = "jsonrpc_protection::policy";
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Required in signed policies, and increased with every change, see
+    /// [`PolicySource::signing_key`].  Not a rule, so it is not applied, and not compared by
+    /// [`Self::diff`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// See [`ProtectionState::loopback_methods`].
+    #[serde(default)]
+    pub loopback_methods: BTreeSet<String>,
+    /// See [`ProtectionState::constraints`].
+    #[serde(default, with = "as_strings")]
+    pub param_constraints: Vec<ParamConstraint>,
+    /// See [`ProtectionState::ownership`].
+    #[serde(default, with = "as_strings")]
+    pub owned_params: Vec<OwnershipRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canary: Option<CanaryPolicy>,
+}
+
+/// Rules for [`Self::percent`] of the callers.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CanaryPolicy {
+    pub percent: u8,
+    /// Can not have a canary of its own.
+    pub policy: Box<Policy>,
+}
+
+impl Policy {
+    pub fn parse(json: &str) -> Result<Self, Diagnostic> {
+        diagnostic::from_json(json)
+    }
+
+    /// The rules `state` currently applies.
+    pub fn of(state: &ProtectionState) -> Self {
+        Self {
+            version: None,
+            loopback_methods: state.loopback_methods.iter().cloned().collect(),
+            param_constraints: state.constraints.clone(),
+            owned_params: state.ownership.clone(),
+            canary: state.canary.as_ref().map(|canary| CanaryPolicy {
+                percent: canary.percent,
+                policy: Box::new(Policy {
+                    version: None,
+                    loopback_methods: canary.loopback_methods.iter().cloned().collect(),
+                    param_constraints: canary.constraints.clone(),
+                    owned_params: canary.ownership.clone(),
+                    canary: None,
+                }),
+            }),
+        }
+    }
+
+    /// Replaces the rules of `state` with these.
+    pub fn apply(&self, state: &mut ProtectionState) {
+        state.loopback_methods = self.loopback_methods.iter().cloned().collect();
+        state.constraints = self.param_constraints.clone();
+        state.ownership = self.owned_params.clone();
+        state.canary = self.canary.as_ref().map(|canary| Canary {
+            percent: canary.percent,
+            loopback_methods: canary.policy.loopback_methods.iter().cloned().collect(),
+            constraints: canary.policy.param_constraints.clone(),
+            ownership: canary.policy.owned_params.clone(),
+        });
+    }
+
+    /// Rules that refer to methods other than the `methods` the server has, loopback methods
+    /// that are not `protected`, and canaries that are not valid.
+    pub fn problems(&self, methods: &HashSet<String>, protected: &HashSet<String>) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(canary) = &self.canary {
+            if canary.percent > 100 {
+                problems.push(format!(
+                    "canary: percent is {}, more than 100",
+                    canary.percent
+                ));
+            }
+            if canary.policy.canary.is_some() {
+                problems.push("canary: a canary can not have a canary".to_owned());
+            }
+            if canary.policy.version.is_some() {
+                problems.push("canary: a canary can not have a version".to_owned());
+            }
+            problems.extend(
+                canary
+                    .policy
+                    .problems(methods, protected)
+                    .into_iter()
+                    .map(|problem| format!("canary: {problem}")),
+            );
+        }
+        for method in &self.loopback_methods {
+            if !protected.contains(method) {
+                problems.push(format!(
+                    "loopback_methods: {method} is not a protected method"
+                ));
+            }
+        }
+        for constraint in &self.param_constraints {
+            if !methods.contains(&constraint.method) {
+                problems.push(format!(
+                    "param_constraints: {constraint}: there is no such method"
+                ));
+            }
+        }
+        for rule in &self.owned_params {
+            if !methods.contains(&rule.method) {
+                problems.push(format!("owned_params: {rule}: there is no such method"));
+            }
+        }
+        problems
+    }
+
+    /// Rules that have no effect, or that keep a method from ever being called, given the
+    /// `protected` methods.  Unlike [`Self::problems`], these do not keep the policy from being
+    /// applied.
+    pub fn lints(&self, protected: &HashSet<String>) -> Vec<String> {
+        let mut lints = Vec::new();
+        if let Some(canary) = &self.canary {
+            lints.extend(
+                canary
+                    .policy
+                    .lints(protected)
+                    .into_iter()
+                    .map(|lint| format!("canary: {lint}")),
+            );
+        }
+        for (i, constraint) in self.param_constraints.iter().enumerate() {
+            if constraint.role == Role::Anonymous
+                && protected.contains(&constraint.method)
+                && !self.loopback_methods.contains(&constraint.method)
+            {
+                lints.push(format!(
+                    "param_constraints: {constraint}: anonymous callers can not call {} at \
+                     all, as it is protected",
+                    constraint.method
+                ));
+            }
+            let earlier = &self.param_constraints[..i];
+            if earlier.contains(constraint) {
+                lints.push(format!(
+                    "param_constraints: {constraint} is given more than once"
+                ));
+                continue;
+            }
+            for earlier in earlier {
+                if earlier.implies(constraint) {
+                    lints.push(format!(
+                        "param_constraints: {constraint} is shadowed by {earlier}"
+                    ));
+                } else if constraint.implies(earlier) {
+                    lints.push(format!(
+                        "param_constraints: {earlier} is shadowed by {constraint}"
+                    ));
+                } else if earlier.excludes(constraint) {
+                    lints.push(format!(
+                        "param_constraints: {earlier} and {constraint} can not both hold, so \
+                         {} callers can never call {}",
+                        constraint.role, constraint.method
+                    ));
+                }
+            }
+        }
+        for (i, rule) in self.owned_params.iter().enumerate() {
+            if self.owned_params[..i].contains(rule) {
+                lints.push(format!("owned_params: {rule} is given more than once"));
+            }
+        }
+        // Constraints given more than once are compared to the others more than once.
+        let mut seen = HashSet::new();
+        lints.retain(|lint| seen.insert(lint.clone()));
+        lints
+    }
+
+    /// What changed from `self` to `other`: rules only `self` has, prefixed with `- `, then
+    /// rules only `other` has, prefixed with `+ `.
+    pub fn diff(&self, other: &Policy) -> Vec<String> {
+        let (old, new) = (self.lines(), other.lines());
+        let removed = old
+            .iter()
+            .filter(|line| !new.contains(line))
+            .map(|line| format!("- {line}"));
+        let added = new
+            .iter()
+            .filter(|line| !old.contains(line))
+            .map(|line| format!("+ {line}"));
+        removed.chain(added).collect()
+    }
+
+    /// Every rule as a line, with the key it is under.
+    fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for method in &self.loopback_methods {
+            lines.push(format!("loopback_methods: {method}"));
+        }
+        for constraint in &self.param_constraints {
+            lines.push(format!("param_constraints: {constraint}"));
+        }
+        for rule in &self.owned_params {
+            lines.push(format!("owned_params: {rule}"));
+        }
+        if let Some(canary) = &self.canary {
+            lines.push(format!("canary.percent: {}", canary.percent));
+            lines.extend(
+                canary
+                    .policy
+                    .lines()
+                    .into_iter()
+                    .map(|line| format!("canary.{line}")),
+            );
+        }
+        lines
+    }
+}
+
+/// Rules written as strings, as on the command line.
+mod as_strings {
+    use super::*;
+
+    pub fn serialize<S, T>(values: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Display,
+    {
+        serializer.collect_seq(values.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| s.parse().map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// A policy that was applied, as listed by `admin_policy_versions`.
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyVersion {
+    /// Counts up from 1.
+    pub version: u64,
+    /// Milliseconds since the Unix epoch.
+    pub applied_at: u64,
+    /// Where the policy came from, such as its URL.
+    pub source: String,
+    pub policy: Policy,
+}
+
+/// The last few policies applied, shared by every clone.
+#[derive(Clone)]
+pub struct PolicyHistory {
+    capacity: usize,
+    versions: Arc<Mutex<VecDeque<PolicyVersion>>>,
+}
+
+impl PolicyHistory {
+    /// Keeps the last `capacity` versions, and at least the current one.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            versions: Arc::default(),
+        }
+    }
+
+    /// Records that `policy` from `source` was just applied, and returns its version.
+    pub fn record(&self, policy: Policy, source: impl Into<String>) -> u64 {
+        let mut versions = self.versions.lock().unwrap();
+        let version = versions.back().map_or(1, |last| last.version + 1);
+        if versions.len() >= self.capacity {
+            versions.pop_front();
+        }
+        versions.push_back(PolicyVersion {
+            version,
+            applied_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            source: source.into(),
+            policy,
+        });
+        version
+    }
+
+    /// Versions still kept, oldest first.
+    pub fn versions(&self) -> Vec<PolicyVersion> {
+        self.versions.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Version of the policy applied last.
+    pub fn current(&self) -> Option<u64> {
+        self.versions
+            .lock()
+            .unwrap()
+            .back()
+            .map(|last| last.version)
+    }
+
+    pub fn get(&self, version: u64) -> Option<PolicyVersion> {
+        self.versions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|kept| kept.version == version)
+            .cloned()
+    }
+}
+
+/// The rules in place, as returned by `admin_policy`.  Sets are sorted, so that the same rules
+/// always serialize the same.
+#[derive(Clone, Debug, Serialize)]
+pub struct EffectivePolicy {
+    /// Version of the policy in the [`PolicyHistory`], if any was recorded.
+    pub version: Option<u64>,
+    /// Methods that require credentials.  Decided by the server, not by the policy.
+    pub protected_methods: BTreeSet<String>,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+/// A call to check with `admin_check`, made with the credentials and from the address given.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckRequest {
+    pub method: String,
+    pub params: Option<Params>,
+    /// Value of the `X-Admin-Auth` header.
+    pub token: Option<Secret<String>>,
+    /// Value of the [`crate::RUN_AS_HEADER`].
+    pub run_as: Option<String>,
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+}
+
+#[rpc(server)]
+pub trait PolicyRpc {
+    type Metadata;
+
+    /// Policies applied, oldest first.  Only the last few are kept.
+    #[rpc(name = "admin_policy_versions")]
+    fn versions(&self) -> RpcResult<Vec<PolicyVersion>>;
+
+    /// Changes from policy version `from` to version `to`, or to the rules in place when `to`
+    /// is omitted.  See [`Policy::diff`].
+    #[rpc(name = "admin_policy_diff")]
+    fn diff(&self, from: u64, to: Option<u64>) -> RpcResult<Vec<String>>;
+
+    /// Applies policy `version` again, and returns the version it is recorded as.
+    #[rpc(meta, name = "admin_policy_rollback")]
+    fn rollback(&self, meta: Self::Metadata, version: u64) -> RpcResult<u64>;
+
+    /// The rules in place.
+    #[rpc(name = "admin_policy")]
+    fn effective(&self) -> RpcResult<EffectivePolicy>;
+
+    /// How the rules in place decide `request`.  The call is not made.
+    #[rpc(name = "admin_check")]
+    fn check(&self, request: CheckRequest) -> RpcResult<Decision>;
+}
+
+#[derive(Clone)]
+pub struct PolicyRpcImpl {
+    history: PolicyHistory,
+    protection: ProtectionHandle,
+}
+
+impl PolicyRpcImpl {
+    pub fn new(history: PolicyHistory, protection: ProtectionHandle) -> Self {
+        Self {
+            history,
+            protection,
+        }
+    }
+
+    fn get(&self, version: u64) -> RpcResult<PolicyVersion> {
+        self.history.get(version).ok_or_else(|| {
+            JsonRpcError::invalid_params(format!("Policy version {version} is not kept"))
+        })
+    }
+}
+
+impl PolicyRpc for PolicyRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn versions(&self) -> RpcResult<Vec<PolicyVersion>> {
+        Ok(self.history.versions())
+    }
+
+    fn diff(&self, from: u64, to: Option<u64>) -> RpcResult<Vec<String>> {
+        let from = self.get(from)?.policy;
+        let to = match to {
+            Some(to) => self.get(to)?.policy,
+            None => Policy::of(&self.protection.load()),
+        };
+        Ok(from.diff(&to))
+    }
+
+    fn rollback(&self, meta: RpcMeta, version: u64) -> RpcResult<u64> {
+        let policy = self.get(version)?.policy;
+        // Versions were checked against the methods of this server when they were first
+        // applied, and methods do not change while it runs.
+        self.protection.update(|state| policy.apply(state));
+        let recorded = self
+            .history
+            .record(policy, format!("rollback to version {version}"));
+        log::warn!(
+            target: TARGET,
+            "Policy rolled back to version {version}, as version {recorded}, by {}, \
+             request_id={}",
+            meta.caller
+                .as_ref()
+                .map_or_else(|| "-".to_owned(), |caller| caller.to_string()),
+            meta.request_id,
+        );
+        Ok(recorded)
+    }
+
+    fn effective(&self) -> RpcResult<EffectivePolicy> {
+        let state = self.protection.load();
+        Ok(EffectivePolicy {
+            version: self.history.current(),
+            protected_methods: state.protected.iter().cloned().collect(),
+            policy: Policy::of(&state),
+        })
+    }
+
+    fn check(&self, request: CheckRequest) -> RpcResult<Decision> {
+        let mut meta = RpcMeta::from_headers(|_| None);
+        meta.auth = request.token.map(Ok);
+        meta.run_as = request.run_as.map(Ok);
+        meta.peer_addr = request.ip.map(|ip| SocketAddr::new(ip, 0));
+        meta.attributes = request.attributes;
+        Ok(self.protection.load().decide(
+            &request.method,
+            &request.params.unwrap_or(Params::None),
+            &meta,
+        ))
+    }
+}
+
+#[cfg(feature = "client")]
+pub use source::{PolicySource, SourceError, POLICY_SIGNATURE_HEADER};
+
+#[cfg(feature = "client")]
+mod source {
+    use {
+        super::Policy,
+        crate::diagnostic::Diagnostic,
+        hmac::{Hmac, Mac},
+        hyper::{
+            body::HttpBody, client::HttpConnector, header, Body, Client, Request, StatusCode, Uri,
+        },
+        sha2::Sha256,
+        std::time::Duration,
+        thiserror::Error,
+    };
+
+    /// Header carrying the hex encoded HMAC-SHA256 of the policy, when policies are signed.
+    pub const POLICY_SIGNATURE_HEADER: &str = "X-Policy-Signature";
+
+    #[derive(Error, Debug)]
+    pub enum SourceError {
+        #[error("only http URLs are supported, this build has no TLS support")]
+        UnsupportedScheme,
+
+        #[error("request failed: {0}")]
+        Transport(#[from] hyper::Error),
+
+        #[error("no response within {}s", .0.as_secs_f64())]
+        Timeout(Duration),
+
+        #[error("server responded with HTTP {0}")]
+        HttpStatus(StatusCode),
+
+        #[error("policy is larger than {0} bytes")]
+        TooLarge(usize),
+
+        #[error("policy has no valid {POLICY_SIGNATURE_HEADER} header")]
+        Unsigned,
+
+        #[error("policy signature does not match")]
+        BadSignature,
+
+        #[error("signed policy has no version")]
+        Unversioned,
+
+        #[error("policy version {version} is not newer than {fetched}, fetched before")]
+        Stale { version: u64, fetched: u64 },
+
+        #[error("policy is not UTF-8")]
+        NotUtf8,
+
+        #[error("{0}")]
+        Parse(#[from] Diagnostic),
+    }
+
+    /// A policy published at an HTTP URL.  Remembers the `ETag` of the last policy fetched, so
+    /// that unchanged policies are not transferred again.
+    #[derive(Debug)]
+    pub struct PolicySource {
+        client: Client<HttpConnector>,
+        url: Uri,
+        etag: Option<header::HeaderValue>,
+        timeout: Duration,
+        max_size: usize,
+        signing_key: Option<Vec<u8>>,
+        /// Version of the last signed policy fetched.
+        version: Option<u64>,
+    }
+
+    impl PolicySource {
+        /// Fails for URLs other than `http` ones.
+        pub fn new(url: Uri) -> Result<Self, SourceError> {
+            if url.scheme_str() != Some("http") {
+                return Err(SourceError::UnsupportedScheme);
+            }
+            Ok(Self {
+                client: Client::new(),
+                url,
+                etag: None,
+                timeout: Duration::from_secs(10),
+                max_size: 1024 * 1024,
+                signing_key: None,
+                version: None,
+            })
+        }
+
+        /// Gives up on fetches that take longer than `timeout`, including reading the policy.
+        /// 10 seconds by default.
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// Refuses policies larger than `max_size` bytes.  1 MiB by default.
+        pub fn max_size(mut self, max_size: usize) -> Self {
+            self.max_size = max_size;
+            self
+        }
+
+        /// Only accepts policies with an HMAC-SHA256 under `key` in the
+        /// [`POLICY_SIGNATURE_HEADER`], such as printed by
+        /// `openssl dgst -sha256 -hmac "$KEY" policy.json`.  Signed policies need a
+        /// [`Policy::version`] greater than the one of the last policy fetched.
+        pub fn signing_key(mut self, key: Vec<u8>) -> Self {
+            self.signing_key = Some(key);
+            self
+        }
+
+        pub fn url(&self) -> &Uri {
+            &self.url
+        }
+
+        /// The policy, or `None` if it did not change since the last call.
+        pub async fn fetch(&mut self) -> Result<Option<Policy>, SourceError> {
+            let timeout = self.timeout;
+            tokio::time::timeout(timeout, self.fetch_now())
+                .await
+                .map_err(|_| SourceError::Timeout(timeout))?
+        }
+
+        async fn fetch_now(&mut self) -> Result<Option<Policy>, SourceError> {
+            let mut request = Request::get(&self.url);
+            if let Some(etag) = &self.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            let request = request
+                .body(Body::empty())
+                .expect("GET requests with a valid URI are valid");
+
+            let response = self.client.request(request).await?;
+            match response.status() {
+                StatusCode::NOT_MODIFIED => return Ok(None),
+                status if !status.is_success() => return Err(SourceError::HttpStatus(status)),
+                _ => (),
+            }
+            let etag = response.headers().get(header::ETAG).cloned();
+            let signature = response
+                .headers()
+                .get(POLICY_SIGNATURE_HEADER)
+                .and_then(|signature| hex::decode(signature.as_bytes()).ok());
+
+            let mut body = response.into_body();
+            let mut policy = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk?;
+                if policy.len() + chunk.len() > self.max_size {
+                    return Err(SourceError::TooLarge(self.max_size));
+                }
+                policy.extend_from_slice(&chunk);
+            }
+
+            if let Some(key) = &self.signing_key {
+                let signature = signature.ok_or(SourceError::Unsigned)?;
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+                mac.update(&policy);
+                mac.verify_slice(&signature)
+                    .map_err(|_| SourceError::BadSignature)?;
+            }
+
+            let policy =
+                Policy::parse(std::str::from_utf8(&policy).map_err(|_| SourceError::NotUtf8)?)?;
+            if self.signing_key.is_some() {
+                let version = policy.version.ok_or(SourceError::Unversioned)?;
+                match self.version {
+                    Some(fetched) if version <= fetched => {
+                        return Err(SourceError::Stale { version, fetched })
+                    }
+                    _ => self.version = Some(version),
+                }
+            }
+            self.etag = etag;
+            Ok(Some(policy))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use {
+            super::*,
+            hyper::{
+                server::Server,
+                service::{make_service_fn, service_fn},
+                Response,
+            },
+            sha2::Digest,
+            std::{convert::Infallible, net::SocketAddr},
+        };
+
+        const POLICY: &str = r#"{"loopback_methods": ["f"]}"#;
+        const VERSIONED: &str = r#"{"version": 2, "loopback_methods": ["f"]}"#;
+
+        /// Serves `policy` with `headers`, after `delay`, on a random port.  The `ETag` is
+        /// derived from `policy`, and requests for it are answered with `304 Not Modified`.
+        fn serve(policy: &'static str, headers: &[(&'static str, String)], delay: Duration) -> Uri {
+            let headers = headers.to_vec();
+            let etag = format!("\"{}\"", hex::encode(&Sha256::digest(policy)[..4]));
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+                make_service_fn(move |_| {
+                    let (headers, etag) = (headers.clone(), etag.clone());
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                            let (headers, etag) = (headers.clone(), etag.clone());
+                            async move {
+                                tokio::time::sleep(delay).await;
+                                let mut response = Response::builder().header(header::ETAG, &etag);
+                                if request.headers().get(header::IF_NONE_MATCH)
+                                    == Some(&header::HeaderValue::try_from(&etag).unwrap())
+                                {
+                                    response = response.status(StatusCode::NOT_MODIFIED);
+                                    return Ok::<_, Infallible>(
+                                        response.body(Body::empty()).unwrap(),
+                                    );
+                                }
+                                for (name, value) in headers {
+                                    response = response.header(name, value);
+                                }
+                                Ok(response.body(Body::from(policy)).unwrap())
+                            }
+                        }))
+                    }
+                }),
+            );
+            let url = format!("http://{}/policy.json", server.local_addr())
+                .parse()
+                .unwrap();
+            tokio::spawn(server);
+            url
+        }
+
+        fn signature(key: &[u8], policy: &str) -> String {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(policy.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        }
+
+        #[tokio::test]
+        async fn unchanged_policies_are_not_fetched_again() {
+            let mut source = PolicySource::new(serve(POLICY, &[], Duration::ZERO)).unwrap();
+            let policy = source.fetch().await.unwrap().unwrap();
+            assert_eq!(policy.loopback_methods, ["f".to_owned()].into());
+            assert!(source.fetch().await.unwrap().is_none());
+        }
+
+        #[test]
+        fn only_http_urls_are_supported() {
+            for url in ["https://127.0.0.1/policy.json", "/policy.json"] {
+                assert!(matches!(
+                    PolicySource::new(url.parse().unwrap()),
+                    Err(SourceError::UnsupportedScheme)
+                ));
+            }
+        }
+
+        #[tokio::test]
+        async fn fetches_are_limited_in_size_and_time() {
+            let url = serve(POLICY, &[], Duration::ZERO);
+            let mut source = PolicySource::new(url.clone())
+                .unwrap()
+                .max_size(POLICY.len() - 1);
+            assert!(matches!(
+                source.fetch().await,
+                Err(SourceError::TooLarge(_))
+            ));
+            let mut source = PolicySource::new(url).unwrap().max_size(POLICY.len());
+            assert!(source.fetch().await.unwrap().is_some());
+
+            let slow = serve(POLICY, &[], Duration::from_secs(5));
+            let mut source = PolicySource::new(slow)
+                .unwrap()
+                .timeout(Duration::from_millis(50));
+            assert!(matches!(source.fetch().await, Err(SourceError::Timeout(_))));
+        }
+
+        #[tokio::test]
+        async fn signed_policies_need_a_valid_signature() {
+            let fetch = |headers: &[(&'static str, String)]| {
+                let mut source = PolicySource::new(serve(VERSIONED, headers, Duration::ZERO))
+                    .unwrap()
+                    .signing_key(b"key".to_vec());
+                async move { source.fetch().await }
+            };
+            assert!(matches!(fetch(&[]).await, Err(SourceError::Unsigned)));
+            assert!(matches!(
+                fetch(&[(POLICY_SIGNATURE_HEADER, "not hex".to_owned())]).await,
+                Err(SourceError::Unsigned)
+            ));
+            assert!(matches!(
+                fetch(&[(POLICY_SIGNATURE_HEADER, signature(b"other key", VERSIONED))]).await,
+                Err(SourceError::BadSignature)
+            ));
+            assert!(
+                fetch(&[(POLICY_SIGNATURE_HEADER, signature(b"key", VERSIONED))])
+                    .await
+                    .unwrap()
+                    .is_some()
+            );
+        }
+
+        #[tokio::test]
+        async fn signed_policies_need_a_newer_version() {
+            let signed = |policy| {
+                serve(
+                    policy,
+                    &[(POLICY_SIGNATURE_HEADER, signature(b"key", policy))],
+                    Duration::ZERO,
+                )
+            };
+            let mut source = PolicySource::new(signed(POLICY))
+                .unwrap()
+                .signing_key(b"key".to_vec());
+            assert!(matches!(
+                source.fetch().await,
+                Err(SourceError::Unversioned)
+            ));
+
+            source.url = signed(VERSIONED);
+            assert_eq!(source.fetch().await.unwrap().unwrap().version, Some(2));
+
+            // An older policy, or other rules under the same version, as from another URL.
+            for policy in [r#"{"version": 1}"#, r#"{"version": 2}"#] {
+                source.url = signed(policy);
+                assert!(matches!(
+                    source.fetch().await,
+                    Err(SourceError::Stale { fetched: 2, .. })
+                ));
+            }
+
+            source.url = signed(r#"{"version": 3}"#);
+            assert_eq!(source.fetch().await.unwrap().unwrap().version, Some(3));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Limits the number of requests executed at the same time, queueing the rest by the role of
+//! the caller.
+//!
+//! Once [`PriorityConfig::max_in_flight`] requests are running, new requests wait for a free
+//! slot.  A freed slot goes to the longest waiting request with the highest [`Role`], so admin
+//! calls are not stuck behind anonymous traffic.  When the queue is full, the newest request
+//! with the lowest role is rejected with [`Reason::Overloaded`], provided its role is lower than
+//! the role of the arriving request.  Otherwise the arriving request is rejected.  Requests
+//! that wait longer than [`PriorityConfig::max_queue_time`] are rejected too, so that latency
+//! stays bounded under load.
+//!
+//! With [`PriorityScheduler::shed_under_load`], requests from callers below [`Role::Admin`] are
+//! rejected outright while the host is saturated.
+//!
+//! This is applied by transports, see [`RpcHttpHandler::priority_scheduler`], as middleware can
+//! not delay the start of a call.
+//!
+//! [`RpcHttpHandler::priority_scheduler`]: crate::http::RpcHttpHandler::priority_scheduler
+
+use {
+    crate::{
+        load::LoadMonitor,
+        rejection::{Reason, Rejection},
+        state::{ProtectionHandle, Role},
+        RpcMeta,
+    },
+    std::{
+        collections::{BTreeMap, VecDeque},
+        mem,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::sync::oneshot,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityConfig {
+    /// Maximum number of requests executed at the same time.
+    pub max_in_flight: usize,
+    /// Maximum number of requests waiting for a free slot, across all roles.
+    pub max_queued: usize,
+    /// How long a request may wait for a free slot.  Unlimited if `None`.
+    pub max_queue_time: Option<Duration>,
+}
+
+/// How long rejected callers are asked to wait before trying again.
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct PriorityScheduler {
+    state: ProtectionHandle,
+    config: PriorityConfig,
+    slots: Arc<Mutex<Slots>>,
+    load: Option<LoadMonitor>,
+}
+
+#[derive(Default)]
+struct Slots {
+    in_flight: usize,
+    queued: usize,
+    waiting: BTreeMap<Role, VecDeque<oneshot::Sender<Slot>>>,
+}
+
+/// Permission to execute a request.  Dropping it hands the slot to the next waiting request.
+pub struct Slot {
+    slots: Arc<Mutex<Slots>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        loop {
+            let next = {
+                let mut slots = self.slots.lock().unwrap();
+                let Some(next) = slots.pop_highest() else {
+                    slots.in_flight -= 1;
+                    return;
+                };
+                next
+            };
+
+            let slot = Slot {
+                slots: self.slots.clone(),
+            };
+            match next.send(slot) {
+                Ok(()) => return,
+                // The waiting request was cancelled.  Not dropping the slot, as it would try to
+                // hand itself over again.
+                Err(slot) => mem::forget(slot),
+            }
+        }
+    }
+}
+
+impl Slots {
+    /// Forgets requests that gave up waiting.
+    fn purge_cancelled(&mut self) {
+        for queue in self.waiting.values_mut() {
+            queue.retain(|sender| !sender.is_closed());
+        }
+        self.queued = self.waiting.values().map(VecDeque::len).sum();
+    }
+
+    fn pop_highest(&mut self) -> Option<oneshot::Sender<Slot>> {
+        let next = self
+            .waiting
+            .values_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())?;
+        self.queued -= 1;
+        Some(next)
+    }
+
+    /// Makes room in the queue for a request with `role`, by dropping the newest request with a
+    /// lower role.  Returns `false` if there is none.
+    fn shed_below(&mut self, role: Role) -> bool {
+        let Some(shed) = self
+            .waiting
+            .range_mut(..role)
+            .find_map(|(_, queue)| queue.pop_back())
+        else {
+            return false;
+        };
+        self.queued -= 1;
+        // The request it belongs to sees the channel close, and rejects itself.
+        drop(shed);
+        true
+    }
+}
+
+impl PriorityScheduler {
+    pub fn new(state: ProtectionHandle, config: PriorityConfig) -> Self {
+        Self {
+            state,
+            config,
+            slots: Default::default(),
+            load: None,
+        }
+    }
+
+    /// Rejects requests from callers below [`Role::Admin`] while `monitor` reports that the
+    /// host is saturated.
+    pub fn shed_under_load(mut self, monitor: LoadMonitor) -> Self {
+        self.load = Some(monitor);
+        self
+    }
+
+    /// Number of requests currently executed.
+    pub fn in_flight(&self) -> usize {
+        self.slots.lock().unwrap().in_flight
+    }
+
+    /// Number of requests currently waiting for a free slot.
+    pub fn queued(&self) -> usize {
+        self.slots.lock().unwrap().queued
+    }
+
+    /// Waits for a free slot for a request from a caller with the given `meta`.  The slot is
+    /// released when the returned [`Slot`] is dropped.
+    pub async fn acquire(&self, meta: &RpcMeta) -> Result<Slot, Rejection> {
+        let role = self.state.load().role(meta);
+        let overloaded = || {
+            Rejection::new(Reason::Overloaded, "Server is overloaded")
+                .retry_after(OVERLOADED_RETRY_AFTER)
+        };
+
+        if role < Role::Admin && self.load.as_ref().is_some_and(LoadMonitor::is_saturated) {
+            return Err(overloaded());
+        }
+
+        let receiver = {
+            let mut slots = self.slots.lock().unwrap();
+
+            if slots.in_flight < self.config.max_in_flight {
+                slots.in_flight += 1;
+                return Ok(Slot {
+                    slots: self.slots.clone(),
+                });
+            }
+
+            if slots.queued >= self.config.max_queued {
+                slots.purge_cancelled();
+            }
+            if slots.queued >= self.config.max_queued && !slots.shed_below(role) {
+                return Err(overloaded());
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            slots.waiting.entry(role).or_default().push_back(sender);
+            slots.queued += 1;
+            receiver
+        };
+
+        let slot = match self.config.max_queue_time {
+            Some(limit) => tokio::time::timeout(limit, receiver)
+                .await
+                .map_err(|_| overloaded())?,
+            None => receiver.await,
+        };
+        slot.map_err(|_| overloaded())
+    }
+}
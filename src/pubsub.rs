@@ -0,0 +1,627 @@
+//! Subscriptions, and keeping them in line with the protection rules.
+//!
+//! Subscribing goes through [`ProtectRpcMiddleware`] like any other call.  As a subscription
+//! outlives the call that created it, a [`Feed`] also keeps the metadata of the subscriber and
+//! checks it again every time the [`ProtectionState`] changes, and every time a session ends
+//! early.  Subscriptions whose credentials are no longer good enough for the feed, or whose
+//! session has expired, receive a final error notification and are dropped.
+//!
+//! Only the connection that made a subscription may cancel it.
+//!
+//! Every published event has an audience, and is only delivered to subscribers whose [`Role`]
+//! is at least that.  Subscribers are also held to the [`SubscriptionLimits`] of their role.
+//!
+//! [`ProtectRpcMiddleware`]: crate::middleware::ProtectRpcMiddleware
+
+use {
+    crate::{
+        rejection::{Reason, Rejection},
+        secret::Secret,
+        state::{ProtectionHandle, ProtectionState, Role},
+        RpcMeta,
+    },
+    jsonrpc_core::Result,
+    jsonrpc_derive::rpc,
+    jsonrpc_pubsub::{typed, PubSubMetadata, Session, SubscriptionId},
+    serde::Serialize,
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex, Weak},
+        time::{Duration, Instant},
+    },
+};
+
+impl PubSubMetadata for RpcMeta {
+    fn session(&self) -> Option<Arc<Session>> {
+        self.session.clone()
+    }
+}
+
+/// A call rejected by the protection middleware.
+#[derive(Clone, Debug, Serialize)]
+pub struct Denial {
+    pub method: String,
+    pub reason: Reason,
+    pub message: String,
+    pub peer_addr: Option<SocketAddr>,
+}
+
+/// Notifications of the `events` subscription.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Methods that require admin credentials have changed.
+    ProtectedMethods { methods: Vec<String> },
+    /// A call was rejected by the protection middleware.
+    Denial(Denial),
+}
+
+impl Event {
+    /// Lists the protected methods of `state`, in a stable order.
+    pub fn protected_methods(state: &ProtectionState) -> Self {
+        let mut methods = state.protected.iter().cloned().collect::<Vec<_>>();
+        methods.sort();
+        Event::ProtectedMethods { methods }
+    }
+}
+
+/// Limits on the subscriptions of a single identity.
+#[derive(Clone, Copy, Debug)]
+pub struct SubscriptionLimits {
+    /// Maximum number of concurrent subscriptions.  Further subscriptions are rejected.
+    pub max_subscriptions: usize,
+    /// Maximum number of notifications per second, over all the subscriptions.  A subscription
+    /// that would go over it is terminated, as its consumer is not going to keep up.
+    pub max_notification_rate: u32,
+}
+
+impl Default for SubscriptionLimits {
+    fn default() -> Self {
+        Self {
+            max_subscriptions: usize::MAX,
+            max_notification_rate: u32::MAX,
+        }
+    }
+}
+
+/// Who [`SubscriptionLimits`] are accounted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Identity {
+    /// Admins presenting the same token share their limits.
+    Token(Secret<String>),
+    /// Everyone else is limited per connection.  The session address is unique for as long as
+    /// the session is alive, and its subscriptions do not outlive it.
+    Connection(usize),
+}
+
+impl Identity {
+    fn new(meta: &RpcMeta, role: Role) -> Self {
+        match (&meta.auth, &meta.session) {
+            (Some(Ok(token)), _) if role == Role::Admin => Identity::Token(token.clone()),
+            (_, session) => {
+                Identity::Connection(session.as_ref().map_or(0, |s| Arc::as_ptr(s) as usize))
+            }
+        }
+    }
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+struct Usage {
+    subscriptions: usize,
+    window_start: Instant,
+    notifications: u32,
+}
+
+impl Usage {
+    /// Accounts for one more notification, unless it would go over `max_rate`.  In which case,
+    /// returns how long until the rate allows more notifications.
+    fn notification(&mut self, now: Instant, max_rate: u32) -> std::result::Result<(), Duration> {
+        if now.duration_since(self.window_start) >= RATE_WINDOW {
+            self.window_start = now;
+            self.notifications = 0;
+        }
+
+        if self.notifications >= max_rate {
+            return Err(self.window_start + RATE_WINDOW - now);
+        }
+        self.notifications += 1;
+        Ok(())
+    }
+}
+
+struct Subscription<T> {
+    sink: typed::Sink<T>,
+    meta: RpcMeta,
+    role: Role,
+    /// When the session of the subscriber expires, for subscribers with a session token.
+    expires_at: Option<Instant>,
+    /// Connection the subscription was made over.  Weak, so that the subscription does not keep
+    /// the connection alive, while its address can not be reused by another one.
+    owner: Option<Weak<Session>>,
+    identity: Identity,
+    limits: SubscriptionLimits,
+}
+
+impl<T> Subscription<T> {
+    fn is_owned_by(&self, meta: &RpcMeta) -> bool {
+        match (&self.owner, &meta.session) {
+            (Some(owner), Some(session)) => std::ptr::eq(owner.as_ptr(), Arc::as_ptr(session)),
+            _ => false,
+        }
+    }
+}
+
+impl<T: Serialize> Subscription<T> {
+    fn terminate(&self, rejection: &Rejection) {
+        let error = rejection
+            .to_error_with_message(&self.meta, format!("Subscription terminated: {rejection}"));
+        // The subscriber might be gone already, in which case there is no one to tell.
+        let _ = self.sink.notify(Err(error));
+    }
+}
+
+struct Subscriptions<T> {
+    next_id: u64,
+    active: HashMap<SubscriptionId, Subscription<T>>,
+    usage: HashMap<Identity, Usage>,
+}
+
+impl<T> Default for Subscriptions<T> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            active: HashMap::new(),
+            usage: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Subscriptions<T> {
+    fn remove(&mut self, id: &SubscriptionId) -> Option<Subscription<T>> {
+        let subscription = self.active.remove(id)?;
+        if let Some(usage) = self.usage.get_mut(&subscription.identity) {
+            usage.subscriptions -= 1;
+            if usage.subscriptions == 0 {
+                self.usage.remove(&subscription.identity);
+            }
+        }
+        Some(subscription)
+    }
+}
+
+/// Delivers events to subscribers, according to their role.
+pub struct Feed<T> {
+    min_role: Role,
+    limits: HashMap<Role, SubscriptionLimits>,
+    subscriptions: Mutex<Subscriptions<T>>,
+}
+
+impl<T> Feed<T>
+where
+    T: Serialize + Clone + Send + 'static,
+{
+    /// Only callers with at least `min_role` may subscribe.  Subscribers with a role missing
+    /// from `limits` are not limited.
+    pub fn new(min_role: Role, limits: HashMap<Role, SubscriptionLimits>) -> Self {
+        Self {
+            min_role,
+            limits,
+            subscriptions: Mutex::default(),
+        }
+    }
+
+    /// Why a caller with `meta` may not subscribe.
+    fn role_rejection(&self, state: &ProtectionState, meta: &RpcMeta) -> Rejection {
+        state.authorize(meta).err().unwrap_or_else(|| {
+            Rejection::new(
+                Reason::CredentialsRequired,
+                format!("Subscription requires the {} role", self.min_role),
+            )
+            .required_role(self.min_role)
+        })
+    }
+
+    fn subscribe(&self, meta: RpcMeta, state: &ProtectionState, subscriber: typed::Subscriber<T>) {
+        let role = state.role(&meta);
+        if role < self.min_role {
+            let _ = subscriber.reject(self.role_rejection(state, &meta).to_error(&meta));
+            return;
+        }
+
+        let identity = Identity::new(&meta, role);
+        let limits = self.limits.get(&role).copied().unwrap_or_default();
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        let subscription_count = subscriptions
+            .usage
+            .get(&identity)
+            .map_or(0, |usage| usage.subscriptions);
+        if subscription_count >= limits.max_subscriptions {
+            let rejection = Rejection::new(
+                Reason::TooManySubscriptions,
+                format!(
+                    "Too many subscriptions, at most {} are allowed",
+                    limits.max_subscriptions
+                ),
+            );
+            let _ = subscriber.reject(rejection.to_error(&meta));
+            return;
+        }
+
+        let id = SubscriptionId::Number(subscriptions.next_id);
+        subscriptions.next_id += 1;
+        let Ok(sink) = subscriber.assign_id(id.clone()) else {
+            return;
+        };
+
+        subscriptions
+            .usage
+            .entry(identity.clone())
+            .or_insert_with(|| Usage {
+                subscriptions: 0,
+                window_start: Instant::now(),
+                notifications: 0,
+            })
+            .subscriptions += 1;
+
+        // The session is not needed to check credentials, and holding on to it would keep the
+        // session alive after the connection is closed.
+        let owner = meta.session.as_ref().map(Arc::downgrade);
+        let meta = RpcMeta {
+            session: None,
+            ..meta
+        };
+
+        subscriptions.active.insert(
+            id,
+            Subscription {
+                sink,
+                expires_at: state.session(&meta).map(|session| session.expires_at),
+                meta,
+                role,
+                owner,
+                identity,
+                limits,
+            },
+        );
+    }
+
+    /// Delivers `event` to subscribers with at least the `audience` role.
+    pub fn publish(&self, event: &T, audience: Role) {
+        let now = Instant::now();
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let Subscriptions { active, usage, .. } = &mut *subscriptions;
+
+        let mut too_slow = vec![];
+        let mut expired = vec![];
+        for (id, subscription) in active.iter() {
+            if subscription.role < audience {
+                continue;
+            }
+            // Sessions do not tell when they expire, so this is checked before every delivery.
+            if subscription
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                expired.push(id.clone());
+                continue;
+            }
+
+            let usage = usage
+                .get_mut(&subscription.identity)
+                .expect("Every subscription is accounted for");
+            match usage.notification(now, subscription.limits.max_notification_rate) {
+                // Closed sessions are cleaned up through `unsubscribe()`.
+                Ok(()) => drop(subscription.sink.notify(Ok(event.clone()))),
+                Err(retry_after) => too_slow.push((id.clone(), retry_after)),
+            }
+        }
+
+        for (id, retry_after) in too_slow {
+            if let Some(subscription) = subscriptions.remove(&id) {
+                let rejection = Rejection::new(
+                    Reason::NotificationRateExceeded,
+                    "notification rate limit exceeded",
+                )
+                .retry_after(retry_after);
+                subscription.terminate(&rejection);
+            }
+        }
+
+        for id in expired {
+            if let Some(subscription) = subscriptions.remove(&id) {
+                let rejection = Rejection::new(Reason::InvalidToken, "Session has expired");
+                subscription.terminate(&rejection);
+            }
+        }
+    }
+
+    /// Updates the roles of the subscribers according to `state`, dropping subscriptions that
+    /// are no longer allowed.
+    pub fn revalidate(&self, state: &ProtectionState) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+
+        let mut revoked = vec![];
+        for (id, subscription) in subscriptions.active.iter_mut() {
+            subscription.role = state.role(&subscription.meta);
+            subscription.expires_at = state
+                .session(&subscription.meta)
+                .map(|session| session.expires_at);
+            if subscription.role < self.min_role {
+                revoked.push((id.clone(), self.role_rejection(state, &subscription.meta)));
+            }
+        }
+
+        for (id, rejection) in revoked {
+            if let Some(subscription) = subscriptions.remove(&id) {
+                subscription.terminate(&rejection);
+            }
+        }
+    }
+
+    /// Cancels subscription `id`, if it was made over the connection of `meta`.  `None` when
+    /// the connection is closed.
+    fn unsubscribe(&self, meta: Option<&RpcMeta>, id: &SubscriptionId) -> bool {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        // Subscriptions of other connections are reported as unknown, so that they can not be
+        // told apart from ids that were never used.
+        let owned = match meta {
+            Some(meta) => subscriptions
+                .active
+                .get(id)
+                .is_some_and(|subscription| subscription.is_owned_by(meta)),
+            None => true,
+        };
+        owned && subscriptions.remove(id).is_some()
+    }
+}
+
+#[rpc(server)]
+pub trait DenialsPubSub {
+    type Metadata;
+
+    /// Notifies about every call rejected by the protection middleware.
+    #[pubsub(subscription = "denials", subscribe, name = "denials_subscribe")]
+    fn subscribe(&self, meta: Self::Metadata, subscriber: typed::Subscriber<Denial>);
+
+    #[pubsub(subscription = "denials", unsubscribe, name = "denials_unsubscribe")]
+    fn unsubscribe(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool>;
+}
+
+#[derive(Clone)]
+pub struct DenialsPubSubImpl {
+    feed: Arc<Feed<Denial>>,
+    state: ProtectionHandle,
+}
+
+impl DenialsPubSubImpl {
+    pub fn new(feed: Arc<Feed<Denial>>, state: ProtectionHandle) -> Self {
+        Self { feed, state }
+    }
+}
+
+impl DenialsPubSub for DenialsPubSubImpl {
+    type Metadata = RpcMeta;
+
+    fn subscribe(&self, meta: Self::Metadata, subscriber: typed::Subscriber<Denial>) {
+        self.feed.subscribe(meta, &self.state.load(), subscriber);
+    }
+
+    fn unsubscribe(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool> {
+        Ok(self.feed.unsubscribe(meta.as_ref(), &id))
+    }
+}
+
+#[rpc(server)]
+pub trait EventsPubSub {
+    type Metadata;
+
+    /// Notifies about [`Event`]s.  Anyone may subscribe, but admin level events are only
+    /// delivered to admins.
+    #[pubsub(subscription = "events", subscribe, name = "events_subscribe")]
+    fn subscribe(&self, meta: Self::Metadata, subscriber: typed::Subscriber<Event>);
+
+    #[pubsub(subscription = "events", unsubscribe, name = "events_unsubscribe")]
+    fn unsubscribe(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool>;
+}
+
+#[derive(Clone)]
+pub struct EventsPubSubImpl {
+    feed: Arc<Feed<Event>>,
+    state: ProtectionHandle,
+}
+
+impl EventsPubSubImpl {
+    pub fn new(feed: Arc<Feed<Event>>, state: ProtectionHandle) -> Self {
+        Self { feed, state }
+    }
+}
+
+impl EventsPubSub for EventsPubSubImpl {
+    type Metadata = RpcMeta;
+
+    fn subscribe(&self, meta: Self::Metadata, subscriber: typed::Subscriber<Event>) {
+        self.feed.subscribe(meta, &self.state.load(), subscriber);
+    }
+
+    fn unsubscribe(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool> {
+        Ok(self.feed.unsubscribe(meta.as_ref(), &id))
+    }
+}
+
+/// Keeps `denials` and `events` subscribers up to date with the changes of `state`, and of its
+/// sessions.
+pub fn watch_state(state: &ProtectionHandle, denials: Arc<Feed<Denial>>, events: Arc<Feed<Event>>) {
+    state.load().sessions.watch({
+        let state = state.clone();
+        let denials = denials.clone();
+        let events = events.clone();
+        move || {
+            let state = state.load();
+            denials.revalidate(&state);
+            events.revalidate(&state);
+        }
+    });
+
+    let protected = Mutex::new(state.load().protected.clone());
+    state.watch(move |state| {
+        denials.revalidate(state);
+        events.revalidate(state);
+
+        let mut protected = protected.lock().unwrap();
+        if *protected != state.protected {
+            protected.clone_from(&state.protected);
+            events.publish(&Event::protected_methods(state), Role::Anonymous);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            session::Grant,
+            state::tests::{meta, state},
+        },
+        futures_util::FutureExt,
+        jsonrpc_core::futures::channel::mpsc,
+        serde_json::Value,
+    };
+
+    type Notifications = mpsc::UnboundedReceiver<String>;
+
+    fn connection() -> Arc<Session> {
+        Arc::new(Session::new(mpsc::unbounded().0))
+    }
+
+    /// Metadata of a request over `connection`, with `auth` in the `X-Admin-Auth` header.
+    fn over(connection: &Arc<Session>, auth: Option<&str>) -> RpcMeta {
+        RpcMeta {
+            session: Some(connection.clone()),
+            ..meta(auth)
+        }
+    }
+
+    fn subscribe<T>(
+        feed: &Feed<T>,
+        state: &ProtectionState,
+        meta: RpcMeta,
+    ) -> (SubscriptionId, Notifications)
+    where
+        T: Serialize + Clone + Send + 'static,
+    {
+        let (subscriber, id, notifications) = typed::Subscriber::new_test("notify");
+        feed.subscribe(meta, state, subscriber);
+        let id = id.now_or_never().unwrap().unwrap().unwrap();
+        (id, notifications)
+    }
+
+    /// `params` of the next notification.
+    fn next(notifications: &mut Notifications) -> Option<Value> {
+        let notification = notifications.try_next().ok()??;
+        Some(serde_json::from_str::<Value>(&notification).unwrap()["params"].take())
+    }
+
+    fn admin_session(state: &ProtectionState, ttl: Duration) -> crate::session::Issued {
+        state.sessions.issue(
+            ttl,
+            Grant {
+                role: Role::Admin,
+                user: None,
+                methods: None,
+            },
+        )
+    }
+
+    #[test]
+    fn subscriptions_are_only_cancelled_by_their_connection() {
+        let feed = Feed::<u32>::new(Role::Anonymous, HashMap::new());
+        let (ours, theirs) = (connection(), connection());
+        let (id, _notifications) = subscribe(&feed, &state(), over(&ours, None));
+
+        assert!(!feed.unsubscribe(Some(&over(&theirs, Some("root"))), &id));
+        assert!(!feed.unsubscribe(Some(&meta(None)), &id));
+        assert!(feed.unsubscribe(Some(&over(&ours, None)), &id));
+        assert!(!feed.unsubscribe(Some(&over(&ours, None)), &id));
+
+        // Connections that are closed cancel all their subscriptions.
+        let (id, _notifications) = subscribe(&feed, &state(), over(&ours, None));
+        assert!(feed.unsubscribe(None, &id));
+    }
+
+    #[test]
+    fn events_are_delivered_to_their_audience() {
+        let feed = Feed::<u32>::new(Role::Anonymous, HashMap::new());
+        let state = state();
+        let (_, mut anonymous) = subscribe(&feed, &state, over(&connection(), None));
+        let (_, mut admin) = subscribe(&feed, &state, over(&connection(), Some("root")));
+
+        feed.publish(&1, Role::Admin);
+        feed.publish(&2, Role::Anonymous);
+        assert_eq!(next(&mut admin).unwrap()["result"], 1);
+        assert_eq!(next(&mut admin).unwrap()["result"], 2);
+        assert_eq!(next(&mut anonymous).unwrap()["result"], 2);
+        assert_eq!(next(&mut anonymous), None);
+    }
+
+    #[test]
+    fn callers_below_the_role_of_the_feed_can_not_subscribe() {
+        let feed = Feed::<u32>::new(Role::Admin, HashMap::new());
+        let (subscriber, id, _) = typed::Subscriber::new_test("notify");
+        feed.subscribe(over(&connection(), Some("wrong")), &state(), subscriber);
+        let error = id.now_or_never().unwrap().unwrap().unwrap_err();
+        assert_eq!(error.data.unwrap()["reason"], "invalid_token");
+    }
+
+    #[test]
+    fn sessions_ended_early_terminate_their_subscriptions() {
+        let handle = ProtectionHandle::new(state());
+        let state = handle.load_full();
+        let denials = Arc::new(Feed::new(Role::Admin, HashMap::new()));
+        let events = Arc::new(Feed::new(Role::Anonymous, HashMap::new()));
+        watch_state(&handle, denials.clone(), events.clone());
+
+        let revoked = admin_session(&state, Duration::from_secs(60));
+        let refreshed = admin_session(&state, Duration::from_secs(60));
+        let meta = |token: &Secret<String>| over(&connection(), Some(token.expose()));
+        let (_, mut revoked_denials) = subscribe(&denials, &state, meta(&revoked.token));
+        let (_, mut refreshed_denials) = subscribe(&denials, &state, meta(&refreshed.token));
+
+        assert!(state.sessions.revoke(revoked.token.expose()));
+        let params = next(&mut revoked_denials).unwrap();
+        assert_eq!(params["error"]["data"]["reason"], "invalid_token");
+        assert_eq!(next(&mut refreshed_denials), None);
+
+        state
+            .sessions
+            .refresh(refreshed.refresh_token.expose())
+            .unwrap();
+        let params = next(&mut refreshed_denials).unwrap();
+        assert_eq!(params["error"]["data"]["reason"], "invalid_token");
+    }
+
+    #[test]
+    fn expired_sessions_get_no_more_events() {
+        let state = state();
+        let feed = Feed::<u32>::new(Role::Admin, HashMap::new());
+        let session = admin_session(&state, Duration::from_millis(20));
+        let (_, mut notifications) = subscribe(
+            &feed,
+            &state,
+            over(&connection(), Some(session.token.expose())),
+        );
+
+        feed.publish(&1, Role::Admin);
+        assert_eq!(next(&mut notifications).unwrap()["result"], 1);
+
+        std::thread::sleep(Duration::from_millis(30));
+        feed.publish(&2, Role::Admin);
+        let params = next(&mut notifications).unwrap();
+        assert_eq!(params["error"]["data"]["reason"], "invalid_token");
+        assert_eq!(next(&mut notifications), None);
+    }
+}
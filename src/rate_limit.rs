@@ -0,0 +1,737 @@
+//! Limits on how often a caller may make calls.
+//!
+//! Every caller gets a token bucket, sized according to the [`Quota`] of its [`Role`].  Each
+//! call takes one token, so a batch takes as many tokens as it has calls.  Callers are told
+//! where they stand via [`RateLimitStatus`], so that they can slow down before being rejected.
+//!
+//! A token bucket lets a caller that was idle make a burst of a whole quota, and then keep
+//! going at the refill rate.  With [`Algorithm::SlidingWindow`], callers may instead make at
+//! most a quota of calls in any period, give or take, which is what per-minute quotas
+//! usually mean.
+//!
+//! By default, admins are limited per token, and callers without credentials per IP address.
+//! [`RateLimiter::key_by`] picks what callers with a role sharing a bucket have in common
+//! instead: any combination of [`KeyPart`]s, such as the IP address and a tenant attribute,
+//! for callers behind a proxy.
+//!
+//! With [`RateLimiter::tighten_under_load`], quotas of everyone but admins shrink while the host
+//! is saturated.
+
+use {
+    crate::{
+        load::LoadMonitor,
+        memory::{Component, MemoryBudget},
+        secret::Secret,
+        state::{ProtectionHandle, Role},
+        RpcMeta,
+    },
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        fmt,
+        net::IpAddr,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+pub const RATE_LIMIT_LIMIT_HEADER: &str = "X-RateLimit-Limit";
+pub const RATE_LIMIT_REMAINING_HEADER: &str = "X-RateLimit-Remaining";
+pub const RATE_LIMIT_RESET_HEADER: &str = "X-RateLimit-Reset";
+
+/// `limit` calls per `period`, with bursts of up to `limit` calls.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Tokens added to a bucket per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.limit) / self.period.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.limit, self.period.as_secs())
+    }
+}
+
+/// Parses `COUNT/SECONDS`.
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (limit, period) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected COUNT/SECONDS, got \"{s}\""))?;
+        let limit = limit
+            .parse()
+            .map_err(|err| format!("invalid count \"{limit}\": {err}"))?;
+        let period = period
+            .parse::<u64>()
+            .map_err(|err| format!("invalid period \"{period}\": {err}"))?;
+        if limit == 0 || period == 0 {
+            return Err("count and period must not be zero".to_owned());
+        }
+        Ok(Quota {
+            limit,
+            period: Duration::from_secs(period),
+        })
+    }
+}
+
+/// How calls are counted against a [`Quota`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Calls take tokens from a bucket holding up to `limit` tokens, which refills at `limit`
+    /// tokens per `period`.
+    #[default]
+    TokenBucket,
+    /// Calls made in the last `period` are counted, assuming that calls made in the period
+    /// before the current one were spread evenly over it.
+    SlidingWindow,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            "sliding-window" => Ok(Algorithm::SlidingWindow),
+            _ => Err(format!(
+                "unknown algorithm \"{s}\", expected \"token-bucket\" or \"sliding-window\""
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::TokenBucket => f.write_str("token-bucket"),
+            Algorithm::SlidingWindow => f.write_str("sliding-window"),
+        }
+    }
+}
+
+/// Part of what callers sharing a bucket have in common.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum KeyPart {
+    /// The IP address the call came from.
+    Ip,
+    /// The admin token, session token, or request signing key.  Callers without valid
+    /// credentials share one bucket, so that they can not get more calls by making up tokens.
+    Credential,
+    /// The value of a caller attribute, see [`crate::attributes`].  Callers without it share
+    /// one bucket.
+    Attribute(String),
+}
+
+impl FromStr for KeyPart {
+    type Err = String;
+
+    /// `ip`, `credential`, or the name of a caller attribute.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("key part must not be empty".to_owned()),
+            "ip" => Ok(KeyPart::Ip),
+            "credential" => Ok(KeyPart::Credential),
+            _ => Ok(KeyPart::Attribute(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for KeyPart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyPart::Ip => f.write_str("ip"),
+            KeyPart::Credential => f.write_str("credential"),
+            KeyPart::Attribute(attribute) => f.write_str(attribute),
+        }
+    }
+}
+
+/// A [`Quota`], and optionally what callers sharing a bucket have in common, as given on the
+/// command line.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyedQuota {
+    pub quota: Quota,
+    /// `None` for the default, see [`RateLimiter::key_by`].
+    pub key: Option<Vec<KeyPart>>,
+}
+
+/// Parses `COUNT/SECONDS`, optionally followed by `@KEY+KEY...`, such as `100/60@ip+tenant`.
+impl FromStr for KeyedQuota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (quota, key) = match s.split_once('@') {
+            Some((quota, key)) => (quota, Some(key)),
+            None => (s, None),
+        };
+        Ok(KeyedQuota {
+            quota: quota.parse()?,
+            key: key
+                .map(|key| key.split('+').map(str::parse).collect())
+                .transpose()?,
+        })
+    }
+}
+
+/// Parses the same format as [`FromStr`], for configuration files.
+impl TryFrom<String> for KeyedQuota {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Where a caller stands against its quota, after a call.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    /// Calls that can be made right away.
+    pub remaining: u32,
+    /// How long until the quota is available in full again.
+    pub reset: Duration,
+    pub allowed: bool,
+    /// How long until a rejected call would be allowed.  `None` if the call is allowed, or if
+    /// it is larger than the whole quota.
+    pub retry_after: Option<Duration>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Token(Secret<String>),
+    Signature,
+    Peer(IpAddr),
+    Attribute(String),
+    /// Callers lacking what the key is made of, such as an address the transport does not
+    /// know, share one bucket.
+    Unknown,
+}
+
+impl Key {
+    /// Key for the default keying: credentials for admins, the IP address for everyone else.
+    fn new(meta: &RpcMeta, role: Role) -> Self {
+        if role == Role::Admin {
+            let credential = Self::part(&KeyPart::Credential, meta, role);
+            if credential != Key::Unknown {
+                return credential;
+            }
+        }
+        Self::part(&KeyPart::Ip, meta, role)
+    }
+
+    fn part(part: &KeyPart, meta: &RpcMeta, role: Role) -> Self {
+        match part {
+            KeyPart::Ip => match meta.peer_addr {
+                Some(addr) => Key::Peer(addr.ip()),
+                None => Key::Unknown,
+            },
+            // Credentials of other roles were not accepted.
+            KeyPart::Credential if role == Role::Admin => {
+                if let Some(Ok(())) = meta.request_signature {
+                    return Key::Signature;
+                }
+                match &meta.auth {
+                    Some(Ok(token)) => Key::Token(token.clone()),
+                    _ => Key::Unknown,
+                }
+            }
+            KeyPart::Credential => Key::Unknown,
+            KeyPart::Attribute(attribute) => match meta.attributes.get(attribute) {
+                Some(value) => Key::Attribute(value.clone()),
+                None => Key::Unknown,
+            },
+        }
+    }
+}
+
+/// Buckets of callers with different roles are kept apart, as they have different quotas.
+type BucketKey = (Role, Vec<Key>);
+
+enum Bucket {
+    Tokens {
+        tokens: f64,
+        updated: Instant,
+    },
+    /// Calls made in the window starting at `start`, and in the one before it.
+    Window {
+        start: Instant,
+        current: f64,
+        previous: f64,
+    },
+}
+
+impl Bucket {
+    fn new(algorithm: Algorithm, quota: Quota, now: Instant) -> Self {
+        match algorithm {
+            Algorithm::TokenBucket => Bucket::Tokens {
+                tokens: f64::from(quota.limit),
+                updated: now,
+            },
+            Algorithm::SlidingWindow => Bucket::Window {
+                start: now,
+                current: 0.0,
+                previous: 0.0,
+            },
+        }
+    }
+
+    /// Counts `calls` against `quota`, if they fit.
+    fn take(&mut self, quota: Quota, calls: u32, now: Instant) -> RateLimitStatus {
+        let limit = f64::from(quota.limit);
+        let cost = f64::from(calls);
+        match self {
+            Bucket::Tokens { tokens, updated } => {
+                let rate = quota.rate();
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(limit);
+                *updated = now;
+
+                let allowed = *tokens >= cost;
+                if allowed {
+                    *tokens -= cost;
+                }
+                let retry_after = (!allowed && cost <= limit)
+                    .then(|| Duration::from_secs_f64((cost - *tokens) / rate));
+
+                RateLimitStatus {
+                    limit: quota.limit,
+                    remaining: *tokens as u32,
+                    reset: Duration::from_secs_f64((limit - *tokens) / rate),
+                    allowed,
+                    retry_after,
+                }
+            }
+            Bucket::Window {
+                start,
+                current,
+                previous,
+            } => {
+                let period = quota.period.as_secs_f64();
+                let elapsed = now.duration_since(*start).as_secs_f64();
+                if elapsed >= period {
+                    let windows = (elapsed / period).floor();
+                    *previous = if windows < 2.0 { *current } else { 0.0 };
+                    *current = 0.0;
+                    *start += Duration::from_secs_f64(windows * period);
+                }
+                let into = now.duration_since(*start).as_secs_f64();
+                let counted_previous = *previous * (1.0 - into / period);
+
+                let allowed = counted_previous + *current + cost <= limit;
+                if allowed {
+                    *current += cost;
+                }
+                let retry_after = (!allowed && cost <= limit).then(|| {
+                    let wait = if *current + cost <= limit {
+                        // Until enough of the previous window slides out.
+                        (1.0 - (limit - *current - cost) / *previous) * period - into
+                    } else {
+                        // Until the current window becomes the previous one, and enough of it
+                        // slides out.
+                        period - into + (1.0 - (limit - cost) / *current) * period
+                    };
+                    Duration::from_secs_f64(wait.max(0.0))
+                });
+                let reset = if *current > 0.0 {
+                    2.0 * period - into
+                } else if *previous > 0.0 {
+                    period - into
+                } else {
+                    0.0
+                };
+
+                RateLimitStatus {
+                    limit: quota.limit,
+                    remaining: (limit - counted_previous - *current).max(0.0) as u32,
+                    reset: Duration::from_secs_f64(reset),
+                    allowed,
+                    retry_after,
+                }
+            }
+        }
+    }
+
+    /// Whether the bucket is the same as a new one.
+    fn is_full(&self, quota: Quota, now: Instant) -> bool {
+        match self {
+            Bucket::Tokens { tokens, updated } => {
+                let elapsed = now.duration_since(*updated).as_secs_f64();
+                tokens + elapsed * quota.rate() >= f64::from(quota.limit)
+            }
+            Bucket::Window {
+                start,
+                current,
+                previous,
+            } => {
+                let elapsed = now.duration_since(*start);
+                elapsed >= 2 * quota.period
+                    || (*current == 0.0 && (elapsed >= quota.period || *previous == 0.0))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<BucketKey, (Bucket, Quota)>,
+    pruned: Option<Instant>,
+}
+
+/// Estimated memory used by a bucket, including its key.
+const BUCKET_SIZE: usize = std::mem::size_of::<(BucketKey, (Bucket, Quota))>() + 64;
+
+/// Clones share the buckets.
+#[derive(Clone)]
+pub struct RateLimiter {
+    state: ProtectionHandle,
+    quotas: HashMap<Role, Quota>,
+    keys: HashMap<Role, Vec<KeyPart>>,
+    algorithm: Algorithm,
+    buckets: Arc<Mutex<Buckets>>,
+    load: Option<(LoadMonitor, u32)>,
+    memory_budget: Option<MemoryBudget>,
+}
+
+impl RateLimiter {
+    /// Callers with a role missing from `quotas` are not limited.
+    pub fn new(state: ProtectionHandle, quotas: HashMap<Role, Quota>) -> Self {
+        Self {
+            state,
+            quotas,
+            keys: HashMap::new(),
+            algorithm: Algorithm::default(),
+            buckets: Arc::default(),
+            load: None,
+            memory_budget: None,
+        }
+    }
+
+    /// Gives callers with `role` a bucket for every combination of the `key` parts, rather than
+    /// one per credential for admins, and one per IP address for everyone else.
+    pub fn key_by(mut self, role: Role, key: Vec<KeyPart>) -> Self {
+        self.keys.insert(role, key);
+        self
+    }
+
+    /// Counts calls with `algorithm`, rather than with token buckets.
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Report the size of the bucket table to `budget`, and prune it without waiting while the
+    /// budget is exceeded.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Divides the quotas of callers below [`Role::Admin`] by `divisor` while `monitor` reports
+    /// that the host is saturated.
+    pub fn tighten_under_load(mut self, monitor: LoadMonitor, divisor: u32) -> Self {
+        self.load = Some((monitor, divisor.max(1)));
+        self
+    }
+
+    /// Number of callers with a bucket, including ones whose buckets have refilled, but were
+    /// not pruned yet.
+    pub fn buckets(&self) -> usize {
+        self.buckets.lock().unwrap().buckets.len()
+    }
+
+    /// Counts `calls` against the quota of the caller with the given `meta`, if they fit.
+    /// Returns `None` if the caller is not limited.
+    pub fn check(&self, meta: &RpcMeta, calls: u32) -> Option<RateLimitStatus> {
+        let role = self.state.load().role(meta);
+        let mut quota = *self.quotas.get(&role)?;
+        if let Some((monitor, divisor)) = &self.load {
+            if role < Role::Admin && monitor.is_saturated() {
+                quota.limit = (quota.limit / divisor).max(1);
+            }
+        }
+        let key = match self.keys.get(&role) {
+            Some(parts) => parts
+                .iter()
+                .map(|part| Key::part(part, meta, role))
+                .collect(),
+            None => vec![Key::new(meta, role)],
+        };
+        let key = (role, key);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let over_budget = self
+            .memory_budget
+            .as_ref()
+            .is_some_and(MemoryBudget::is_exceeded);
+        buckets.prune(now, over_budget);
+
+        if let Some(budget) = &self.memory_budget {
+            let new = usize::from(!buckets.buckets.contains_key(&key));
+            budget.set(
+                Component::RateLimits,
+                (buckets.buckets.len() + new) * BUCKET_SIZE,
+            );
+        }
+
+        let (bucket, _) = buckets
+            .buckets
+            .entry(key)
+            .or_insert_with(|| (Bucket::new(self.algorithm, quota, now), quota));
+        Some(bucket.take(quota, calls, now))
+    }
+}
+
+impl Buckets {
+    /// Drops buckets that have refilled completely, as they are the same as new ones.  Only
+    /// does so once in a while, unless `force` is set.
+    fn prune(&mut self, now: Instant, force: bool) {
+        const INTERVAL: Duration = Duration::from_secs(60);
+
+        if !force
+            && self
+                .pruned
+                .is_some_and(|pruned| now.duration_since(pruned) < INTERVAL)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+
+        self.buckets
+            .retain(|_, (bucket, quota)| !bucket.is_full(*quota, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::state::tests::{from, meta, session, state},
+    };
+
+    fn quota(limit: u32) -> Quota {
+        Quota {
+            limit,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    /// Limits admins and anonymous callers to one call each.
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(
+            ProtectionHandle::new(state()),
+            [(Role::Admin, quota(1)), (Role::Anonymous, quota(1))].into(),
+        )
+    }
+
+    fn with_tenant(mut meta: RpcMeta, tenant: &str) -> RpcMeta {
+        meta.attributes
+            .insert("tenant".to_owned(), tenant.to_owned());
+        meta
+    }
+
+    /// Whether a call with `meta` is allowed.
+    fn allowed(limiter: &RateLimiter, meta: &RpcMeta) -> bool {
+        limiter.check(meta, 1).unwrap().allowed
+    }
+
+    #[test]
+    fn keyed_quotas_parse() {
+        let parsed = "100/60@ip+tenant".parse::<KeyedQuota>().unwrap();
+        assert_eq!(parsed.quota.limit, 100);
+        assert_eq!(parsed.quota.period, Duration::from_secs(60));
+        assert_eq!(
+            parsed.key,
+            Some(vec![KeyPart::Ip, KeyPart::Attribute("tenant".to_owned())])
+        );
+        assert_eq!("5/1".parse::<KeyedQuota>().unwrap().key, None);
+        assert_eq!(
+            "5/1@credential".parse::<KeyedQuota>().unwrap().key,
+            Some(vec![KeyPart::Credential])
+        );
+
+        for invalid in [
+            "100",
+            "0/60",
+            "100/0",
+            "x/60",
+            "100/60@",
+            "100/60@ip+",
+            "-1/60",
+        ] {
+            assert!(invalid.parse::<KeyedQuota>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn admins_are_limited_per_credential() {
+        let limiter = limiter();
+        let session = session(&limiter.state.load(), None);
+        assert!(allowed(&limiter, &from(1, Some("root"))));
+        assert!(!allowed(&limiter, &from(2, Some("root"))));
+        assert!(allowed(&limiter, &from(1, Some(&session))));
+
+        // Signed requests share a bucket, whatever the address.
+        let signed = |ip| {
+            let mut meta = from(ip, None);
+            meta.request_signature = Some(Ok(()));
+            meta
+        };
+        assert!(allowed(&limiter, &signed(1)));
+        assert!(!allowed(&limiter, &signed(2)));
+    }
+
+    #[test]
+    fn anonymous_callers_are_limited_per_address() {
+        let limiter = limiter();
+        assert!(allowed(&limiter, &from(1, None)));
+        // Made up tokens do not get a bucket of their own.
+        assert!(!allowed(&limiter, &from(1, Some("made up"))));
+        assert!(allowed(&limiter, &from(2, Some("made up"))));
+        // Neither do admins and anonymous callers share one.
+        assert!(allowed(&limiter, &from(3, Some("root"))));
+        assert!(allowed(&limiter, &from(3, None)));
+
+        // Callers whose address is unknown share a bucket.
+        assert!(allowed(&limiter, &meta(None)));
+        assert!(!allowed(&limiter, &meta(Some("other"))));
+        assert_eq!(limiter.buckets(), 5);
+    }
+
+    #[test]
+    fn callers_can_be_keyed_by_attributes() {
+        let limiter = limiter().key_by(
+            Role::Anonymous,
+            vec![KeyPart::Ip, KeyPart::Attribute("tenant".to_owned())],
+        );
+        assert!(allowed(&limiter, &with_tenant(from(1, None), "a")));
+        assert!(!allowed(&limiter, &with_tenant(from(1, None), "a")));
+        assert!(allowed(&limiter, &with_tenant(from(1, None), "b")));
+        assert!(allowed(&limiter, &with_tenant(from(2, None), "a")));
+
+        // Callers without the attribute share a bucket per address.
+        assert!(allowed(&limiter, &from(1, None)));
+        assert!(!allowed(&limiter, &from(1, None)));
+
+        // Credentials of anonymous callers are not accepted, so they all share a bucket.
+        let limiter = self::limiter().key_by(Role::Anonymous, vec![KeyPart::Credential]);
+        assert!(allowed(&limiter, &from(1, Some("a"))));
+        assert!(!allowed(&limiter, &from(2, Some("b"))));
+
+        // Admins keep their default keying.
+        assert!(allowed(&limiter, &from(1, Some("root"))));
+    }
+
+    #[test]
+    fn roles_without_a_quota_are_not_limited() {
+        let limiter = RateLimiter::new(
+            ProtectionHandle::new(state()),
+            [(Role::Anonymous, quota(1))].into(),
+        );
+        assert!(limiter.check(&from(1, Some("root")), 100).is_none());
+        assert!(limiter.check(&from(1, None), 1).unwrap().allowed);
+        assert!(!limiter.check(&from(1, None), 1).unwrap().allowed);
+    }
+
+    /// 10 calls per 10 seconds.
+    const QUOTA: Quota = Quota {
+        limit: 10,
+        period: Duration::from_secs(10),
+    };
+
+    /// Takes `calls` from `bucket`, `at` seconds after `start`.
+    fn take(bucket: &mut Bucket, start: Instant, at: f64, calls: u32) -> RateLimitStatus {
+        bucket.take(QUOTA, calls, start + Duration::from_secs_f64(at))
+    }
+
+    fn assert_secs(duration: Option<Duration>, secs: f64) {
+        let duration = duration.unwrap().as_secs_f64();
+        assert!((duration - secs).abs() < 1e-6, "{duration} != {secs}");
+    }
+
+    #[test]
+    fn algorithms_parse() {
+        for algorithm in [Algorithm::TokenBucket, Algorithm::SlidingWindow] {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+        }
+        assert!("leaky-bucket".parse::<Algorithm>().is_err());
+    }
+
+    #[test]
+    fn token_buckets_allow_bursts_and_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(Algorithm::TokenBucket, QUOTA, start);
+        assert_eq!(take(&mut bucket, start, 0.0, 10).remaining, 0);
+        let rejected = take(&mut bucket, start, 0.0, 1);
+        assert!(!rejected.allowed);
+        assert_secs(rejected.retry_after, 1.0);
+        assert_secs(Some(rejected.reset), 10.0);
+
+        let refilled = take(&mut bucket, start, 5.0, 5);
+        assert!(refilled.allowed);
+        assert_eq!(refilled.remaining, 0);
+        assert!(!take(&mut bucket, start, 5.0, 1).allowed);
+        assert!(!bucket.is_full(QUOTA, start + Duration::from_secs(5)));
+        assert!(bucket.is_full(QUOTA, start + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn sliding_windows_count_the_previous_window_in_part() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(Algorithm::SlidingWindow, QUOTA, start);
+        for remaining in (0..10).rev() {
+            let status = take(&mut bucket, start, 0.0, 1);
+            assert!(status.allowed);
+            assert_eq!(status.remaining, remaining);
+        }
+        let rejected = take(&mut bucket, start, 0.0, 1);
+        assert!(!rejected.allowed);
+        // The calls move to the previous window, and one tenth of it has to slide out.
+        assert_secs(rejected.retry_after, 11.0);
+        assert_secs(Some(rejected.reset), 20.0);
+
+        // A token bucket would allow a whole burst again here.
+        let rejected = take(&mut bucket, start, 10.0, 1);
+        assert!(!rejected.allowed);
+        assert_secs(rejected.retry_after, 1.0);
+
+        // Half of the previous window still counts.
+        assert!(take(&mut bucket, start, 15.0, 5).allowed);
+        let rejected = take(&mut bucket, start, 15.0, 1);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.remaining, 0);
+        assert_secs(rejected.retry_after, 1.0);
+        assert!(take(&mut bucket, start, 16.5, 1).allowed);
+    }
+
+    #[test]
+    fn sliding_windows_forget_calls_after_two_periods() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(Algorithm::SlidingWindow, QUOTA, start);
+        assert!(bucket.is_full(QUOTA, start));
+        assert!(take(&mut bucket, start, 1.0, 10).allowed);
+        assert!(!bucket.is_full(QUOTA, start + Duration::from_secs(15)));
+        assert!(bucket.is_full(QUOTA, start + Duration::from_secs(20)));
+
+        let status = take(&mut bucket, start, 25.0, 10);
+        assert!(status.allowed);
+        assert_eq!(status.remaining, 0);
+
+        // Batches larger than the quota are never allowed.
+        let rejected = take(&mut bucket, start, 60.0, 11);
+        assert!(!rejected.allowed);
+        assert_eq!(rejected.retry_after, None);
+        assert_eq!(rejected.remaining, 10);
+        assert_eq!(rejected.reset, Duration::ZERO);
+    }
+}
@@ -0,0 +1,178 @@
+//! Errors returned for calls that are not allowed.
+//!
+//! Besides the human readable message, every rejection puts an [`ErrorData`] object into the
+//! `data` field of the JSON-RPC error, so that clients can react without parsing the message.
+
+use {
+    crate::{state::Role, Error, RpcMeta},
+    jsonrpc_core::types::error::{Error as JsonRpcError, ErrorCode},
+    serde::{Deserialize, Serialize},
+    std::{fmt, time::Duration},
+};
+
+/// Why a call was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The method requires credentials, and none were presented.
+    CredentialsRequired,
+    /// Credentials were presented, but the headers carrying them could not be parsed.
+    MalformedCredentials,
+    /// The admin token was not accepted.
+    InvalidToken,
+    /// The session token does not allow calling the method.
+    OutOfScope,
+    /// The caller's role may not call the method with these parameters, see
+    /// [`crate::constraints`].
+    ParamsNotAllowed,
+    /// A parameter refers to something the caller does not own, see [`crate::constraints`].
+    NotOwner,
+    /// The caller may not act as the user named in the `X-Run-As` header.
+    RunAsDenied,
+    /// Too many logins as the user failed, so the account is locked for a while.
+    AccountLocked,
+    /// The request signature does not match the request.
+    InvalidSignature,
+    /// The request signature timestamp is too far from the server time.
+    SignatureExpired,
+    /// The request signature nonce was already used.
+    SignatureReplayed,
+    /// The call is fine, but another call in the same batch was rejected.
+    BatchRejected,
+    /// The caller already has as many subscriptions as it may.
+    TooManySubscriptions,
+    /// Notifications were produced faster than the caller may receive them.
+    NotificationRateExceeded,
+    /// The caller made more calls than its quota allows.
+    RateLimited,
+    /// The server is busy with calls from callers with the same or a higher role.
+    Overloaded,
+    /// The method has to be approved by someone else first, see [`crate::approval`].
+    ApprovalRequired,
+    /// The `X-Approval-Id` header does not name an approval of this call.
+    NotApproved,
+}
+
+impl Reason {
+    /// JSON-RPC error code of rejections with this reason.
+    pub fn code(self) -> ErrorCode {
+        match self {
+            Reason::Overloaded => SERVER_OVERLOADED,
+            _ => ErrorCode::InvalidRequest,
+        }
+    }
+}
+
+/// Error code for calls rejected because the server is busy, rather than because of anything
+/// the caller did.
+pub const SERVER_OVERLOADED: ErrorCode = ErrorCode::ServerError(-32002);
+
+/// A rejected call.
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    pub reason: Reason,
+    pub message: String,
+    /// Role that would have been allowed to make the call.
+    pub required_role: Option<Role>,
+    /// How long the caller should wait before trying again.
+    pub retry_after: Option<Duration>,
+    /// Id to repeat the call with once it is approved, see [`crate::approval`].
+    pub approval_id: Option<String>,
+}
+
+/// Content of the `data` field of errors produced for a [`Rejection`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorData {
+    pub reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_role: Option<Role>,
+    /// In seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Identifies the request in the server logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approval_id: Option<String>,
+}
+
+impl Rejection {
+    pub fn new(reason: Reason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+            required_role: None,
+            retry_after: None,
+            approval_id: None,
+        }
+    }
+
+    pub fn required_role(mut self, role: Role) -> Self {
+        self.required_role = Some(role);
+        self
+    }
+
+    pub fn retry_after(mut self, delay: Duration) -> Self {
+        self.retry_after = Some(delay);
+        self
+    }
+
+    pub fn approval_id(mut self, id: String) -> Self {
+        self.approval_id = Some(id);
+        self
+    }
+
+    pub fn data(&self, request_id: Option<String>) -> ErrorData {
+        ErrorData {
+            reason: self.reason,
+            required_role: self.required_role,
+            retry_after: self.retry_after.map(whole_seconds),
+            request_id,
+            approval_id: self.approval_id.clone(),
+        }
+    }
+
+    /// The JSON-RPC error to send to a caller with the given `meta`.
+    pub fn to_error(&self, meta: &RpcMeta) -> JsonRpcError {
+        self.to_error_with_message(meta, self.message.clone())
+    }
+
+    /// Same as [`Self::to_error`], with a different message.
+    pub fn to_error_with_message(&self, meta: &RpcMeta, message: String) -> JsonRpcError {
+        let data = self.data(Some(meta.request_id.clone()));
+        JsonRpcError {
+            code: self.reason.code(),
+            message,
+            data: Some(serde_json::to_value(data).expect("ErrorData always serializes")),
+        }
+    }
+}
+
+/// `delay` in seconds, rounded up, so that waiting for that long is never too short.
+pub fn whole_seconds(delay: Duration) -> u64 {
+    delay.as_secs() + u64::from(delay.subsec_nanos() > 0)
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<&Error> for Rejection {
+    fn from(error: &Error) -> Self {
+        let reason = match error {
+            Error::AdminAuthHeaderParserError
+            | Error::IdempotencyKeyHeaderParserError
+            | Error::RunAsHeaderParserError
+            | Error::RequestSignatureHeaderParserError
+            | Error::RequestSignatureIncomplete => Reason::MalformedCredentials,
+            Error::RequestSignatureExpired => Reason::SignatureExpired,
+            Error::RequestSignatureReplayed => Reason::SignatureReplayed,
+            Error::RequestSignatureInvalid
+            | Error::RequestSignatureUnknownKey
+            | Error::RequestSignatureKeyNotValid => Reason::InvalidSignature,
+        };
+        Rejection::new(reason, error.to_string())
+    }
+}
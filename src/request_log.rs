@@ -0,0 +1,456 @@
+//! Logging of handled calls, with a sampling rate for every [`Outcome`].
+//!
+//! Busy servers handle far more successful calls than anything else, so logging only a
+//! fraction of them keeps the log readable, while denials and errors can still be logged in
+//! full.  Every line carries the sampling rate it was logged with, so that counts can be
+//! extrapolated.
+//!
+//! Lines are logged with the `info` level, and the [`TARGET`] target.  The method, the
+//! [`Outcome`] as `decision`, the request id, and, when known, the [`Identity`] of the caller
+//! are also attached as key-values, for loggers that keep structured fields.
+//!
+//! Calls that take longer than a threshold are always logged, with the `warn` level, and the
+//! [`SLOW_CALL_TARGET`] target.
+//!
+//! [`MethodLogLevels`] override all of the above for individual methods, and can be changed
+//! while the server runs with the protected `admin_set_method_log_level` method.  Calls to a
+//! method with a level of `info` or more verbose are all logged.  At `debug`, their params are
+//! included, and at `trace`, their responses too, with secrets redacted, see [`redact`].  At
+//! `error` and `off`, the method is not logged at all, and at `warn`, only when it is slow.
+
+use {
+    crate::{state::ProtectionHandle, stats::Identity, RpcMeta},
+    futures_util::future::Either,
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall, Notification},
+            response::{Output, Response},
+            Id,
+        },
+        Result as RpcResult,
+    },
+    jsonrpc_derive::rpc,
+    log::LevelFilter,
+    serde_json::Value,
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt,
+        future::Future,
+        pin::Pin,
+        str::FromStr,
+        sync::{Arc, RwLock},
+        time::{Duration, Instant},
+    },
+};
+
+pub const TARGET: &str = "jsonrpc_protection::request";
+pub const SLOW_CALL_TARGET: &str = "jsonrpc_protection::slow_call";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The call returned a result.  Notifications always count as successful, as there is no
+    /// way to tell.
+    Success,
+    /// The call was rejected by the protection rules.
+    Denied,
+    /// The call returned any other error.
+    Error,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Success => "success",
+            Outcome::Denied => "denied",
+            Outcome::Error => "error",
+        })
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(Outcome::Success),
+            "denied" => Ok(Outcome::Denied),
+            "error" => Ok(Outcome::Error),
+            _ => Err(format!(
+                "unknown outcome \"{s}\", expected \"success\", \"denied\" or \"error\""
+            )),
+        }
+    }
+}
+
+impl Outcome {
+    pub(crate) fn of(output: Option<&Output>) -> Self {
+        match output {
+            None | Some(Output::Success(_)) => Outcome::Success,
+            // Only protection errors carry a reason, see `crate::rejection`.
+            Some(Output::Failure(failure))
+                if failure
+                    .error
+                    .data
+                    .as_ref()
+                    .is_some_and(|data| data.get("reason").is_some()) =>
+            {
+                Outcome::Denied
+            }
+            Some(Output::Failure(_)) => Outcome::Error,
+        }
+    }
+}
+
+/// Replaces [`REDACTED_KEYS`] values with this.
+pub const REDACTED: &str = "[redacted]";
+
+/// Values of object keys containing any of these, ignoring case, are not logged.
+pub const REDACTED_KEYS: [&str; 3] = ["password", "secret", "token"];
+
+/// Methods all positional params of which are redacted, as some of them are secrets, and
+/// positional params have no keys to tell which ones.
+pub const REDACTED_METHOD_PREFIXES: [&str; 2] = ["auth_", "user_"];
+
+/// Replaces the values of keys that look like they hold secrets, at any depth.  Positional
+/// params have no keys, see [`redact_params`] for them.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = key.to_ascii_lowercase();
+                if REDACTED_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Same as [`redact`] for the params of a call to `method`, also replacing all positional params
+/// of the [`REDACTED_METHOD_PREFIXES`] methods.
+pub fn redact_params(method: &str, params: &mut Value) {
+    let has_secrets = REDACTED_METHOD_PREFIXES
+        .iter()
+        .any(|prefix| method.starts_with(prefix));
+    match params {
+        Value::Array(values) if has_secrets => {
+            for value in values {
+                *value = Value::String(REDACTED.to_owned());
+            }
+        }
+        params => redact(params),
+    }
+}
+
+fn redacted_json(value: &impl serde::Serialize) -> String {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    redact(&mut value);
+    value.to_string()
+}
+
+/// Log levels of individual methods.  Clones share the levels.
+#[derive(Clone, Debug, Default)]
+pub struct MethodLogLevels {
+    levels: Arc<RwLock<HashMap<String, LevelFilter>>>,
+}
+
+impl MethodLogLevels {
+    pub fn get(&self, method: &str) -> Option<LevelFilter> {
+        self.levels.read().unwrap().get(method).copied()
+    }
+
+    /// Sets the level of `method`, or, when `None`, returns it to sampling.
+    pub fn set(&self, method: &str, level: Option<LevelFilter>) {
+        let mut levels = self.levels.write().unwrap();
+        match level {
+            Some(level) => levels.insert(method.to_owned(), level),
+            None => levels.remove(method),
+        };
+    }
+
+    pub fn list(&self) -> BTreeMap<String, LevelFilter> {
+        self.levels
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(method, &level)| (method.clone(), level))
+            .collect()
+    }
+}
+
+/// Fractions of calls to log, between 0 and 1.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleRates {
+    pub success: f64,
+    pub denied: f64,
+    pub error: f64,
+}
+
+impl Default for SampleRates {
+    fn default() -> Self {
+        Self {
+            success: 0.01,
+            denied: 1.0,
+            error: 1.0,
+        }
+    }
+}
+
+impl SampleRates {
+    pub fn get(&self, outcome: Outcome) -> f64 {
+        match outcome {
+            Outcome::Success => self.success,
+            Outcome::Denied => self.denied,
+            Outcome::Error => self.error,
+        }
+    }
+
+    pub fn set(&mut self, outcome: Outcome, rate: f64) {
+        match outcome {
+            Outcome::Success => self.success = rate,
+            Outcome::Denied => self.denied = rate,
+            Outcome::Error => self.error = rate,
+        }
+    }
+}
+
+/// Logs a sample of the calls.  Put it in front of [`ProtectRpcMiddleware`], so that it sees
+/// the denied calls too.
+///
+/// [`ProtectRpcMiddleware`]: crate::middleware::ProtectRpcMiddleware
+#[derive(Clone, Default)]
+pub struct RequestLogMiddleware {
+    rates: SampleRates,
+    slow_calls: Option<SlowCalls>,
+    identities: Option<ProtectionHandle>,
+    levels: MethodLogLevels,
+}
+
+#[derive(Clone)]
+struct SlowCalls {
+    threshold: Duration,
+    state: ProtectionHandle,
+}
+
+impl RequestLogMiddleware {
+    pub fn new(rates: SampleRates) -> Self {
+        Self {
+            rates,
+            slow_calls: None,
+            identities: None,
+            levels: MethodLogLevels::default(),
+        }
+    }
+
+    /// Uses `levels` for the methods listed there, instead of sampling.
+    pub fn method_levels(mut self, levels: MethodLogLevels) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    /// Attaches the [`Identity`] of the caller to every line.
+    pub fn identities(mut self, state: ProtectionHandle) -> Self {
+        self.identities = Some(state);
+        self
+    }
+
+    /// Logs every call that takes longer than `threshold`, along with the role of the caller.
+    pub fn slow_calls(mut self, threshold: Duration, state: ProtectionHandle) -> Self {
+        self.slow_calls = Some(SlowCalls { threshold, state });
+        self
+    }
+}
+
+impl Middleware<RpcMeta> for RequestLogMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let (method, id, params) = match &call {
+            Call::MethodCall(MethodCall {
+                method, id, params, ..
+            }) => (method.clone(), Some(id.clone()), Some(params)),
+            Call::Notification(Notification { method, params, .. }) => {
+                (method.clone(), None, Some(params))
+            }
+            Call::Invalid { id } => ("-".to_owned(), Some(id.clone()), None),
+        };
+        let level = self.levels.get(&method);
+
+        let slow_calls = self
+            .slow_calls
+            .as_ref()
+            .filter(|_| level.is_none_or(|level| level >= LevelFilter::Warn))
+            .filter(|_| log::log_enabled!(target: SLOW_CALL_TARGET, log::Level::Warn));
+        let log_calls = level.is_none_or(|level| level >= LevelFilter::Info)
+            && log::log_enabled!(target: TARGET, log::Level::Info);
+        if slow_calls.is_none() && !log_calls {
+            return Either::Right(next(call, meta));
+        }
+
+        let params = params
+            .filter(|_| level >= Some(LevelFilter::Debug))
+            .map(|params| {
+                let mut params = serde_json::to_value(params).unwrap_or(Value::Null);
+                redact_params(&method, &mut params);
+                params.to_string()
+            });
+        let log_response = level >= Some(LevelFilter::Trace);
+        let peer_addr = meta.peer_addr;
+        let request_id = meta.request_id.clone();
+        let rates = self.rates;
+        // The role and identity have to be found before `meta` is handed over.
+        let slow_calls = slow_calls.map(|slow| (slow.threshold, slow.state.load().role(&meta)));
+        let identity = self.identities.as_ref().map_or_else(
+            || "-".to_owned(),
+            |state| Identity::of(state, &meta).to_string(),
+        );
+        let mut attributes = meta
+            .attributes
+            .iter()
+            .map(|(attribute, value)| format!(" {attribute}={value}"))
+            .collect::<String>();
+        // Calls made as another user show both.
+        if let Some(Ok(user)) = &meta.run_as {
+            attributes.push_str(&format!(" run_as={user}"));
+        }
+
+        let started = Instant::now();
+        let output = next(call, meta);
+
+        Either::Left(Box::pin(async move {
+            let output = output.await;
+            let duration = started.elapsed();
+            let id = id.as_ref().map_or_else(|| "-".to_owned(), format_id);
+            let peer = peer_addr.map_or_else(|| "-".to_owned(), |addr| addr.to_string());
+
+            let outcome = Outcome::of(output.as_ref());
+            // Methods with a level are always logged.
+            let rate = if level.is_some() {
+                1.0
+            } else {
+                rates.get(outcome)
+            };
+            if log_calls && (rate >= 1.0 || rand::random::<f64>() < rate) {
+                let mut details = String::new();
+                if let Some(params) = &params {
+                    details.push_str(&format!(" params={params}"));
+                }
+                if log_response {
+                    let response = output
+                        .as_ref()
+                        .map_or_else(|| "-".to_owned(), redacted_json);
+                    details.push_str(&format!(" response={response}"));
+                }
+                log::info!(
+                    target: TARGET,
+                    method = method.as_str(),
+                    identity = identity.as_str(),
+                    decision:% = outcome,
+                    request_id = request_id.as_str();
+                    "{outcome} method={method} id={id} identity={identity}{attributes} \
+                     peer={peer} request_id={request_id} duration={duration:?} sample_rate={rate}{details}",
+                );
+            }
+
+            if let Some((threshold, role)) = slow_calls {
+                if duration > threshold {
+                    log::warn!(
+                        target: SLOW_CALL_TARGET,
+                        "slow call method={method} id={id} role={role} peer={peer} \
+                         request_id={request_id} duration={duration:?}",
+                    );
+                }
+            }
+
+            output
+        }))
+    }
+}
+
+fn format_id(id: &Id) -> String {
+    match id {
+        Id::Null => "null".to_owned(),
+        Id::Num(id) => id.to_string(),
+        Id::Str(id) => format!("{id:?}"),
+    }
+}
+
+#[rpc(server)]
+pub trait LogLevelsRpc {
+    type Metadata;
+
+    /// Sets the log level of calls to `method`, one of `off`, `error`, `warn`, `info`, `debug`
+    /// and `trace`, or, when `null`, returns it to sampling.
+    #[rpc(meta, name = "admin_set_method_log_level")]
+    fn set_method_log_level(
+        &self,
+        meta: Self::Metadata,
+        method: String,
+        level: Option<String>,
+    ) -> RpcResult<()>;
+
+    #[rpc(name = "admin_method_log_levels")]
+    fn method_log_levels(&self) -> RpcResult<BTreeMap<String, String>>;
+}
+
+#[derive(Clone)]
+pub struct LogLevelsRpcImpl {
+    levels: MethodLogLevels,
+}
+
+impl LogLevelsRpcImpl {
+    pub fn new(levels: MethodLogLevels) -> Self {
+        Self { levels }
+    }
+}
+
+impl LogLevelsRpc for LogLevelsRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn set_method_log_level(
+        &self,
+        meta: RpcMeta,
+        method: String,
+        level: Option<String>,
+    ) -> RpcResult<()> {
+        let level = level
+            .map(|level| {
+                level.parse::<LevelFilter>().map_err(|_| {
+                    jsonrpc_core::Error::invalid_params(format!(
+                        "unknown log level {level:?}, expected one of off, error, warn, info, \
+                         debug and trace"
+                    ))
+                })
+            })
+            .transpose()?;
+        self.levels.set(&method, level);
+        log::info!(
+            "Log level of {method} set to {}, request_id={}",
+            level.map_or_else(
+                || "sampled".to_owned(),
+                |level| level.to_string().to_ascii_lowercase()
+            ),
+            meta.request_id,
+        );
+        Ok(())
+    }
+
+    fn method_log_levels(&self) -> RpcResult<BTreeMap<String, String>> {
+        Ok(self
+            .levels
+            .list()
+            .into_iter()
+            .map(|(method, level)| (method, level.to_string().to_ascii_lowercase()))
+            .collect())
+    }
+}
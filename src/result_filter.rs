@@ -0,0 +1,207 @@
+//! Removing or masking result fields that callers with a given role should not see.
+//!
+//! A [`ResultFilter`] such as `anonymous:status:/peers/*/address=mask` replaces the `address`
+//! of every peer in the result of `status` with [`MASKED`] for anonymous callers.  With
+//! `remove` instead of `mask`, the field is dropped altogether.  Paths are JSON pointers, where a
+//! `*` segment matches every array element or object member.  Results without the field are
+//! returned as they are, as are errors.
+//!
+//! For calls made as another user, with the `X-Run-As` header, the role of that user counts.
+
+use {
+    crate::{state::Role, RpcMeta},
+    futures_util::future::Either,
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall},
+            response::{Output, Response},
+        },
+    },
+    serde_json::Value,
+    std::{fmt, future::Future, pin::Pin, str::FromStr, sync::Arc},
+};
+
+/// Replaces masked values.
+pub const MASKED: &str = "****";
+
+/// What to do with a field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Remove,
+    Mask,
+}
+
+/// Callers with `role` get the result of `method` with the field at `path` removed or masked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResultFilter {
+    pub role: Role,
+    pub method: String,
+    /// Unescaped segments of the JSON pointer.
+    pub path: Vec<String>,
+    pub action: Action,
+}
+
+impl ResultFilter {
+    /// Applies the filter to `result`.
+    pub fn apply(&self, result: &mut Value) {
+        apply(result, &self.path, self.action);
+    }
+}
+
+fn apply(value: &mut Value, path: &[String], action: Action) {
+    match (path, action) {
+        ([], _) => (),
+        ([last], Action::Remove) => remove(value, last),
+        ([last], Action::Mask) => {
+            for child in children(value, last) {
+                *child = Value::String(MASKED.to_owned());
+            }
+        }
+        ([segment, rest @ ..], _) => {
+            for child in children(value, segment) {
+                apply(child, rest, action);
+            }
+        }
+    }
+}
+
+/// Values `segment` selects in `value`.
+fn children<'a>(value: &'a mut Value, segment: &str) -> Vec<&'a mut Value> {
+    match (value, segment) {
+        (Value::Object(object), "*") => object.values_mut().collect(),
+        (Value::Object(object), _) => object.get_mut(segment).into_iter().collect(),
+        (Value::Array(values), "*") => values.iter_mut().collect(),
+        (Value::Array(values), _) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| values.get_mut(i))
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn remove(value: &mut Value, segment: &str) {
+    match (value, segment) {
+        (Value::Object(object), "*") => object.clear(),
+        (Value::Object(object), _) => {
+            object.remove(segment);
+        }
+        (Value::Array(values), "*") => values.clear(),
+        (Value::Array(values), _) => {
+            if let Some(i) = segment.parse().ok().filter(|i| *i < values.len()) {
+                values.remove(i);
+            }
+        }
+        _ => (),
+    }
+}
+
+impl FromStr for ResultFilter {
+    type Err = String;
+
+    /// Parses `ROLE:METHOD:PATH=ACTION`, such as `anonymous:status:/peers/*/address=mask`.
+    /// `ACTION` is `remove` or `mask`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected ROLE:METHOD:PATH=ACTION, got \"{s}\"");
+        let (role, rest) = s.split_once(':').ok_or_else(expected)?;
+        let (method, rest) = rest.split_once(':').ok_or_else(expected)?;
+        let (path, action) = rest.rsplit_once('=').ok_or_else(expected)?;
+        if method.is_empty() {
+            return Err(expected());
+        }
+
+        let path = path
+            .strip_prefix('/')
+            .ok_or_else(|| format!("path \"{path}\" must be a JSON pointer, starting with `/`"))?
+            .split('/')
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        let action = match action {
+            "remove" => Action::Remove,
+            "mask" => Action::Mask,
+            _ => {
+                return Err(format!(
+                    "unknown action \"{action}\", expected \"remove\" or \"mask\""
+                ))
+            }
+        };
+
+        Ok(ResultFilter {
+            role: role.parse()?,
+            method: method.to_owned(),
+            path,
+            action,
+        })
+    }
+}
+
+impl fmt::Display for ResultFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:", self.role, self.method)?;
+        for segment in &self.path {
+            write!(f, "/{}", segment.replace('~', "~0").replace('/', "~1"))?;
+        }
+        let action = match self.action {
+            Action::Remove => "remove",
+            Action::Mask => "mask",
+        };
+        write!(f, "={action}")
+    }
+}
+
+/// Applies [`ResultFilter`]s to the results of successful calls.  Has to run after the
+/// protection middleware, which sets [`RpcMeta::caller`].  Calls without a caller are filtered
+/// as anonymous ones.
+#[derive(Clone, Debug, Default)]
+pub struct ResultFilterMiddleware {
+    filters: Arc<Vec<ResultFilter>>,
+}
+
+impl ResultFilterMiddleware {
+    pub fn new(filters: Vec<ResultFilter>) -> Self {
+        Self {
+            filters: Arc::new(filters),
+        }
+    }
+}
+
+impl Middleware<RpcMeta> for ResultFilterMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let role = meta
+            .caller
+            .as_ref()
+            .map_or(Role::Anonymous, |caller| caller.role);
+        let applicable = match &call {
+            Call::MethodCall(MethodCall { method, .. }) => self
+                .filters
+                .iter()
+                .filter(|filter| filter.role == role && filter.method == *method)
+                .cloned()
+                .collect::<Vec<_>>(),
+            Call::Notification(_) | Call::Invalid { .. } => Vec::new(),
+        };
+        if applicable.is_empty() {
+            return Either::Right(next(call, meta));
+        }
+
+        let output = next(call, meta);
+        Either::Left(Box::pin(async move {
+            let mut output = output.await?;
+            if let Output::Success(success) = &mut output {
+                for filter in &applicable {
+                    filter.apply(&mut success.result);
+                }
+            }
+            Some(output)
+        }))
+    }
+}
@@ -0,0 +1,93 @@
+//! A wrapper for credentials that keeps them out of logs and error messages.
+//!
+//! [`Secret`] prints as `****` with both `Debug` and `Display`, so that a credential stored in
+//! a struct that is logged, or formatted into an error, does not leak.  The value is only
+//! reachable through [`Secret::expose`], which makes every use easy to find.
+//!
+//! Secrets can be deserialized, as credentials arrive in requests, but are only serialized
+//! where a field explicitly opts in with [`Secret::serialize_exposed`].
+
+use {
+    serde::{Deserialize, Deserializer, Serializer},
+    std::{
+        fmt,
+        hash::{Hash, Hasher},
+    },
+};
+
+const MASK: &str = "****";
+
+#[derive(Clone, Default)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_exposed(self) -> T {
+        self.0
+    }
+}
+
+impl Secret<String> {
+    /// For `#[serde(serialize_with = "Secret::serialize_exposed")]` on fields that must be
+    /// sent, such as a token returned to the caller that logged in.
+    pub fn serialize_exposed<S: Serializer>(
+        secret: &Secret<String>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&secret.0)
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret({MASK})")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// Compares in time that depends only on the lengths, so that a caller guessing a token
+/// cannot tell how much of it was right.
+impl<T: AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (self.0.as_ref(), other.0.as_ref());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+impl<T: AsRef<[u8]>> Eq for Secret<T> {}
+
+impl<T: AsRef<[u8]>> Hash for Secret<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ref().hash(state);
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Self)
+    }
+}
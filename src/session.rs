@@ -0,0 +1,381 @@
+//! Short-lived session tokens, so that the admin token does not have to accompany every call.
+//!
+//! A session token is presented in the `X-Admin-Auth` header, just like the admin token.  It
+//! may be limited to a set of methods, and stops working once it expires.
+//!
+//! Every session comes with a refresh token, which can be exchanged once for a new session with
+//! the same scope, and a new refresh token.  Sessions started from the same login form a
+//! family.  When a refresh token is presented a second time, it must have leaked, so the whole
+//! family is ended.
+//!
+//! Anything that holds on to a session token, such as a subscription, can learn about sessions
+//! that end early through [`SessionStore::watch`].
+
+use {
+    crate::{secret::Secret, state::Role},
+    rand::Rng,
+    std::{
+        collections::{HashMap, HashSet},
+        fmt,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/// Lifetime of sessions created without an explicit one.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Longest lifetime a session may have.  Longer requests are shortened to this.
+pub const MAX_SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a refresh token may be used after it was issued.
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What a session allows, decided at login, and kept when it is refreshed.
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub role: Role,
+    /// Who logged in, when it was a user rather than a holder of the admin token.
+    pub user: Option<String>,
+    /// Protected methods the session may call.  `None` allows all of them.
+    pub methods: Option<HashSet<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub expires_at: Instant,
+    pub grant: Grant,
+    /// Sessions started from the same login share the family.
+    pub family: u64,
+}
+
+impl Session {
+    /// Whether the session may call `method`, or, when `None`, any method.
+    pub fn allows(&self, method: Option<&str>) -> bool {
+        if self.grant.role < Role::Admin {
+            return false;
+        }
+        match (&self.grant.methods, method) {
+            (None, _) => true,
+            (Some(methods), Some(method)) => methods.contains(method),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// A new session, as returned by [`SessionStore::issue`] and [`SessionStore::refresh`].
+#[derive(Clone, Debug)]
+pub struct Issued {
+    pub token: Secret<String>,
+    pub refresh_token: Secret<String>,
+    pub session: Session,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefreshError {
+    /// The refresh token was never issued, or has expired.
+    Unknown,
+    /// The refresh token was already used.  Its family has been ended.
+    Reused,
+}
+
+struct RefreshToken {
+    /// Session issued together with this refresh token.
+    session_token: String,
+    ttl: Duration,
+    grant: Grant,
+    family: u64,
+    expires_at: Instant,
+    /// Used tokens are kept until they expire, to detect reuse.
+    used: bool,
+}
+
+#[derive(Default)]
+struct Sessions {
+    sessions: HashMap<String, Session>,
+    refresh_tokens: HashMap<String, RefreshToken>,
+    next_family: u64,
+}
+
+impl Sessions {
+    fn prune(&mut self, now: Instant) {
+        self.sessions.retain(|_, session| session.expires_at > now);
+        self.refresh_tokens
+            .retain(|_, refresh| refresh.expires_at > now);
+    }
+
+    fn insert(&mut self, now: Instant, ttl: Duration, grant: Grant, family: u64) -> Issued {
+        let ttl = ttl.min(MAX_SESSION_TTL);
+        let session = Session {
+            expires_at: now + ttl,
+            grant: grant.clone(),
+            family,
+        };
+        let token = random_token();
+        let refresh_token = random_token();
+
+        self.sessions.insert(token.clone(), session.clone());
+        self.refresh_tokens.insert(
+            refresh_token.clone(),
+            RefreshToken {
+                session_token: token.clone(),
+                ttl,
+                grant,
+                family,
+                expires_at: now + REFRESH_TOKEN_TTL,
+                used: false,
+            },
+        );
+
+        Issued {
+            token: token.into(),
+            refresh_token: refresh_token.into(),
+            session,
+        }
+    }
+}
+
+fn random_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+type Watcher = Box<dyn Fn() + Send + Sync>;
+
+/// Sessions that have not expired yet, shared by every copy of the store.
+#[derive(Clone, Default)]
+pub struct SessionStore {
+    inner: Arc<Mutex<Sessions>>,
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+}
+
+impl SessionStore {
+    /// Calls `watcher` every time sessions end before they expire: when they are refreshed or
+    /// revoked, or when their family is ended.
+    pub fn watch<F>(&self, watcher: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.watchers.lock().unwrap().push(Box::new(watcher));
+    }
+
+    /// Called once the sessions are unlocked, as watchers are likely to look sessions up.
+    fn notify_watchers(&self) {
+        for watcher in self.watchers.lock().unwrap().iter() {
+            watcher();
+        }
+    }
+
+    /// Starts a session that lasts for `ttl`, capped at [`MAX_SESSION_TTL`], in a new family.
+    pub fn issue(&self, ttl: Duration, grant: Grant) -> Issued {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(now);
+
+        let family = inner.next_family;
+        inner.next_family += 1;
+        inner.insert(now, ttl, grant, family)
+    }
+
+    /// Exchanges `refresh_token` for a new session with the same lifetime and scope, ending the
+    /// session it was issued with.
+    pub fn refresh(&self, refresh_token: &str) -> Result<Issued, RefreshError> {
+        let result = self.refresh_locked(refresh_token);
+        if !matches!(result, Err(RefreshError::Unknown)) {
+            self.notify_watchers();
+        }
+        result
+    }
+
+    fn refresh_locked(&self, refresh_token: &str) -> Result<Issued, RefreshError> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.prune(now);
+
+        let Some(refresh) = inner.refresh_tokens.get_mut(refresh_token) else {
+            return Err(RefreshError::Unknown);
+        };
+
+        if refresh.used {
+            let family = refresh.family;
+            inner.sessions.retain(|_, session| session.family != family);
+            inner
+                .refresh_tokens
+                .retain(|_, refresh| refresh.family != family);
+            return Err(RefreshError::Reused);
+        }
+
+        refresh.used = true;
+        let (session_token, ttl, grant, family) = (
+            refresh.session_token.clone(),
+            refresh.ttl,
+            refresh.grant.clone(),
+            refresh.family,
+        );
+        inner.sessions.remove(&session_token);
+        Ok(inner.insert(now, ttl, grant, family))
+    }
+
+    /// The session `token` belongs to, if it has not expired.
+    pub fn get(&self, token: &str) -> Option<Session> {
+        let mut inner = self.inner.lock().unwrap();
+        let session = inner.sessions.get(token)?;
+        if session.expires_at > Instant::now() {
+            Some(session.clone())
+        } else {
+            inner.sessions.remove(token);
+            None
+        }
+    }
+
+    /// Ends the session `token` belongs to.  Returns `false` if there was none.
+    pub fn revoke(&self, token: &str) -> bool {
+        let revoked = self.inner.lock().unwrap().sessions.remove(token).is_some();
+        if revoked {
+            self.notify_watchers();
+        }
+        revoked
+    }
+
+    /// Ends all the sessions of `user`, and their refresh tokens, for when the user is removed,
+    /// or their password or role changes.  Returns the number of sessions ended.
+    pub fn revoke_user(&self, user: &str) -> usize {
+        let is_user = |grant: &Grant| grant.user.as_deref() == Some(user);
+        let revoked = {
+            let mut inner = self.inner.lock().unwrap();
+            let before = inner.sessions.len();
+            inner.sessions.retain(|_, session| !is_user(&session.grant));
+            inner
+                .refresh_tokens
+                .retain(|_, refresh| !is_user(&refresh.grant));
+            before - inner.sessions.len()
+        };
+        if revoked > 0 {
+            self.notify_watchers();
+        }
+        revoked
+    }
+
+    /// Number of sessions, including expired ones that were not noticed yet.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Tokens are secrets, so only their number is shown.
+        f.debug_struct("SessionStore")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(methods: Option<&[&str]>) -> Grant {
+        Grant {
+            role: Role::Admin,
+            user: None,
+            methods: methods.map(|methods| methods.iter().map(|m| m.to_string()).collect()),
+        }
+    }
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn refresh_tokens_replace_the_session_once() {
+        let store = SessionStore::default();
+        let first = store.issue(TTL, grant(Some(&["f"])));
+
+        let second = store.refresh(first.refresh_token.expose()).unwrap();
+        assert!(store.get(first.token.expose()).is_none());
+        let session = store.get(second.token.expose()).unwrap();
+        assert_eq!(session.family, first.session.family);
+        assert!(session.allows(Some("f")));
+        assert!(!session.allows(Some("g")));
+
+        let third = store.refresh(second.refresh_token.expose()).unwrap();
+        assert!(store.get(third.token.expose()).is_some());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn reused_refresh_tokens_end_the_family() {
+        let store = SessionStore::default();
+        let first = store.issue(TTL, grant(None));
+        let other = store.issue(TTL, grant(None));
+        let second = store.refresh(first.refresh_token.expose()).unwrap();
+
+        assert_eq!(
+            store.refresh(first.refresh_token.expose()).unwrap_err(),
+            RefreshError::Reused
+        );
+        assert!(store.get(second.token.expose()).is_none());
+        assert_eq!(
+            store.refresh(second.refresh_token.expose()).unwrap_err(),
+            RefreshError::Unknown
+        );
+
+        // Sessions from other logins are not affected.
+        assert!(store.get(other.token.expose()).is_some());
+        assert_ne!(other.session.family, first.session.family);
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let store = SessionStore::default();
+        assert_eq!(store.refresh("unknown").unwrap_err(), RefreshError::Unknown);
+
+        let issued = store.issue(Duration::ZERO, grant(None));
+        assert!(store.get(issued.token.expose()).is_none());
+        assert!(store.is_empty());
+
+        let issued = store.issue(MAX_SESSION_TTL * 2, grant(None));
+        assert!(issued.session.expires_at <= Instant::now() + MAX_SESSION_TTL);
+    }
+
+    #[test]
+    fn watchers_learn_about_sessions_ending_early() {
+        let store = SessionStore::default();
+        let notified = Arc::new(Mutex::new(0));
+        store.watch({
+            let notified = notified.clone();
+            move || *notified.lock().unwrap() += 1
+        });
+        let count = || *notified.lock().unwrap();
+
+        let first = store.issue(TTL, grant(None));
+        assert_eq!(count(), 0);
+        store.refresh(first.refresh_token.expose()).unwrap();
+        assert_eq!(count(), 1);
+        store.refresh(first.refresh_token.expose()).unwrap_err();
+        assert_eq!(count(), 2);
+        store.refresh("unknown").unwrap_err();
+        assert_eq!(count(), 2);
+
+        let second = store.issue(TTL, grant(None));
+        assert!(store.revoke(second.token.expose()));
+        assert!(!store.revoke(second.token.expose()));
+        assert_eq!(count(), 3);
+    }
+
+    #[test]
+    fn anonymous_grants_allow_nothing() {
+        let store = SessionStore::default();
+        let issued = store.issue(
+            TTL,
+            Grant {
+                role: Role::Anonymous,
+                ..grant(None)
+            },
+        );
+        assert!(!issued.session.allows(None));
+        assert!(!issued.session.allows(Some("f")));
+    }
+}
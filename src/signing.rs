@@ -0,0 +1,696 @@
+//! HMAC signatures over requests and responses.
+//!
+//! # Requests
+//!
+//! Instead of sending the admin token, a client that shares a signing key with the server can
+//! sign the request.  The signature is sent in three headers:
+//!
+//! * [`TIMESTAMP_HEADER`] - the signing time, as seconds since the Unix epoch.
+//! * [`NONCE_HEADER`] - a random string, unique for every request.
+//! * [`SIGNATURE_HEADER`] - hex encoded HMAC-SHA256 of the string returned by
+//!   [`string_to_sign`].
+//!
+//! The server rejects signatures that are too far from its own clock, and nonces it has already
+//! seen, so captured requests cannot be replayed.  [`RequestSigner`] produces all three headers.
+//!
+//! ## Key rotation
+//!
+//! Besides the key given to [`RequestVerifier::new`], the server may accept several keys, each
+//! with an id, and optionally a window of time it is valid in.  A signature made with one of
+//! them names it in the [`KEY_ID_HEADER`] header, and is only accepted if its timestamp is
+//! within the window of the key.  A new key can be added before clients switch to it, and the
+//! old one left valid until requests signed with it are no longer in flight.  [`load_keys`]
+//! reads the keys from a JSON file:
+//!
+//! ```json
+//! [
+//!     { "id": "2026-09", "key_file": "2026-09.key", "not_after": 1790812800 },
+//!     { "id": "2026-10", "key_file": "2026-10.key", "not_before": 1790726400 }
+//! ]
+//! ```
+//!
+//! Times are seconds since the Unix epoch.  Either end of the window may be omitted.
+//!
+//! # Responses
+//!
+//! When response signing is enabled the server computes an HMAC-SHA256 over the exact bytes of
+//! the HTTP response body and sends it, hex encoded, in the [`RESPONSE_SIGNATURE_HEADER`]
+//! header.
+
+use {
+    crate::{
+        diagnostic::{self, Diagnostic},
+        memory::{Component, MemoryBudget},
+        secret::Secret,
+        Error,
+    },
+    hmac::{Hmac, Mac},
+    hyper::HeaderMap,
+    rand::{distributions::Alphanumeric, Rng},
+    serde::Deserialize,
+    sha2::{Digest, Sha256},
+    std::{
+        collections::{HashMap, HashSet},
+        fs, io,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+pub const NONCE_HEADER: &str = "X-Signature-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Id of the key a request was signed with.  Only needed for keys added with
+/// [`RequestVerifier::keys`].
+pub const KEY_ID_HEADER: &str = "X-Signature-Key-Id";
+pub const RESPONSE_SIGNATURE_HEADER: &str = "X-Response-Signature";
+
+/// Signatures made further than this from the server clock are rejected.
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn new_mac(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size")
+}
+
+/// Returns the string covered by a request signature.
+///
+/// It consists of the following lines, separated by `\n`:
+///
+/// ```text
+/// POST
+/// <request path>
+/// <timestamp>
+/// <nonce>
+/// <hex encoded SHA-256 of the request body>
+/// ```
+pub fn string_to_sign(path: &str, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    let body_hash = hex::encode(Sha256::digest(body));
+    format!("POST\n{path}\n{timestamp}\n{nonce}\n{body_hash}")
+}
+
+/// A request signing key with an id, see [key rotation](self#key-rotation).
+#[derive(Clone, Debug)]
+pub struct SigningKey {
+    pub id: String,
+    pub key: Secret<Vec<u8>>,
+    /// Signatures with earlier timestamps are rejected.  In seconds since the Unix epoch.
+    pub not_before: Option<u64>,
+    /// Signatures with later timestamps are rejected.  In seconds since the Unix epoch.
+    pub not_after: Option<u64>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeysError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Failed to parse the keys: {0}")]
+    Parse(#[from] Diagnostic),
+
+    #[error("Key {id:?} {problem}")]
+    Invalid { id: String, problem: &'static str },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    id: String,
+    key_file: PathBuf,
+    #[serde(default)]
+    not_before: Option<u64>,
+    #[serde(default)]
+    not_after: Option<u64>,
+}
+
+/// Reads signing keys from the JSON file at `path`.  Each key is read from its `key_file`,
+/// relative to the directory of `path`, with leading and trailing whitespace ignored.
+pub fn load_keys(path: &Path) -> Result<Vec<SigningKey>, KeysError> {
+    let io_error = |path: &Path| {
+        let path = path.to_owned();
+        move |source| KeysError::Io { path, source }
+    };
+    let entries =
+        diagnostic::from_json::<Vec<KeyEntry>>(&fs::read_to_string(path).map_err(io_error(path))?)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut ids = HashSet::new();
+    entries
+        .into_iter()
+        .map(|entry| {
+            let invalid = |problem| {
+                Err(KeysError::Invalid {
+                    id: entry.id.clone(),
+                    problem,
+                })
+            };
+            if !ids.insert(entry.id.clone()) {
+                return invalid("is listed more than once");
+            }
+            if entry
+                .not_before
+                .zip(entry.not_after)
+                .is_some_and(|(from, to)| from > to)
+            {
+                return invalid("has `not_before` after `not_after`");
+            }
+
+            let key_file = dir.join(&entry.key_file);
+            let key = fs::read(&key_file).map_err(io_error(&key_file))?;
+            let key = key.trim_ascii();
+            if key.is_empty() {
+                return invalid("has an empty `key_file`");
+            }
+
+            Ok(SigningKey {
+                id: entry.id,
+                key: Secret::new(key.to_vec()),
+                not_before: entry.not_before,
+                not_after: entry.not_after,
+            })
+        })
+        .collect()
+}
+
+/// Header values that carry a request signature.
+#[derive(Clone, Debug)]
+pub struct SignatureHeaders {
+    pub timestamp: String,
+    pub nonce: String,
+    pub signature: String,
+    /// Value of the [`KEY_ID_HEADER`] header, if the key has an id.
+    pub key_id: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct RequestSigner {
+    mac: HmacSha256,
+    key_id: Option<String>,
+}
+
+impl RequestSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            mac: new_mac(key),
+            key_id: None,
+        }
+    }
+
+    /// Name the key in the [`KEY_ID_HEADER`] header, for servers that accept several keys.
+    pub fn key_id(mut self, id: impl Into<String>) -> Self {
+        self.key_id = Some(id.into());
+        self
+    }
+
+    /// Returns the hex encoded signature for a request with the given parts.
+    pub fn sign(&self, path: &str, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(string_to_sign(path, timestamp, nonce, body).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Signs a request sent now, with a fresh random nonce.
+    pub fn signature_headers(&self, path: &str, body: &[u8]) -> SignatureHeaders {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is after the Unix epoch")
+            .as_secs();
+        let nonce = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect::<String>();
+        let signature = self.sign(path, timestamp, &nonce, body);
+
+        SignatureHeaders {
+            timestamp: timestamp.to_string(),
+            nonce,
+            signature,
+            key_id: self.key_id.clone(),
+        }
+    }
+}
+
+/// A key that signatures name with the [`KEY_ID_HEADER`] header.
+#[derive(Clone)]
+struct VerificationKey {
+    mac: HmacSha256,
+    not_before: Option<u64>,
+    not_after: Option<u64>,
+}
+
+/// Checks request signatures on the server side.  Clones share the nonces of accepted
+/// requests, so that a request accepted by one is a replay for the others.
+#[derive(Clone)]
+pub struct RequestVerifier {
+    /// Checks signatures that do not name a key.
+    mac: Option<HmacSha256>,
+    keys: HashMap<String, VerificationKey>,
+    max_clock_skew: Duration,
+    /// Nonces of accepted requests, with the time after which they can be forgotten.
+    seen_nonces: Arc<Mutex<HashMap<String, u64>>>,
+    memory_budget: Option<MemoryBudget>,
+}
+
+/// Estimated memory used by an entry in the nonce table, besides the nonce itself.
+const NONCE_OVERHEAD: usize = 64;
+
+impl RequestVerifier {
+    pub fn new(key: &[u8]) -> Self {
+        Self::with_mac(Some(new_mac(key)))
+    }
+
+    /// Only accepts signatures that name one of `keys`.
+    pub fn from_keys(keys: impl IntoIterator<Item = SigningKey>) -> Self {
+        Self::with_mac(None).keys(keys)
+    }
+
+    fn with_mac(mac: Option<HmacSha256>) -> Self {
+        Self {
+            mac,
+            keys: HashMap::new(),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            seen_nonces: Arc::default(),
+            memory_budget: None,
+        }
+    }
+
+    /// Also accept signatures naming one of `keys`, within the validity window of the key.
+    /// A key replaces any key added before with the same id.
+    pub fn keys(mut self, keys: impl IntoIterator<Item = SigningKey>) -> Self {
+        self.keys.extend(keys.into_iter().map(|key| {
+            let verification_key = VerificationKey {
+                mac: new_mac(key.key.expose()),
+                not_before: key.not_before,
+                not_after: key.not_after,
+            };
+            (key.id, verification_key)
+        }));
+        self
+    }
+
+    /// Report the size of the nonce table to `budget`.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    pub fn max_clock_skew(mut self, max_clock_skew: Duration) -> Self {
+        self.max_clock_skew = max_clock_skew;
+        self
+    }
+
+    /// Checks the signature in `headers`.
+    ///
+    /// Returns `None` when the request is not signed at all.
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        path: &str,
+        body: &[u8],
+    ) -> Option<Result<(), Error>> {
+        let signature = headers.get(SIGNATURE_HEADER)?;
+        Some(self.verify_signature(headers, signature.as_bytes(), path, body))
+    }
+
+    fn verify_signature(
+        &self,
+        headers: &HeaderMap,
+        signature: &[u8],
+        path: &str,
+        body: &[u8],
+    ) -> Result<(), Error> {
+        let header = |name| {
+            headers
+                .get(name)
+                .ok_or(Error::RequestSignatureIncomplete)?
+                .to_str()
+                .map_err(|_| Error::RequestSignatureHeaderParserError)
+        };
+        let timestamp = header(TIMESTAMP_HEADER)?
+            .parse::<u64>()
+            .map_err(|_| Error::RequestSignatureHeaderParserError)?;
+        let nonce = header(NONCE_HEADER)?;
+        let signature =
+            hex::decode(signature).map_err(|_| Error::RequestSignatureHeaderParserError)?;
+
+        let (mac, not_before, not_after) = match headers.get(KEY_ID_HEADER) {
+            Some(key_id) => {
+                let key_id = key_id
+                    .to_str()
+                    .map_err(|_| Error::RequestSignatureHeaderParserError)?;
+                let key = self
+                    .keys
+                    .get(key_id)
+                    .ok_or(Error::RequestSignatureUnknownKey)?;
+                (&key.mac, key.not_before, key.not_after)
+            }
+            None => (
+                self.mac.as_ref().ok_or(Error::RequestSignatureUnknownKey)?,
+                None,
+                None,
+            ),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is after the Unix epoch")
+            .as_secs();
+        let max_skew = self.max_clock_skew.as_secs();
+        if timestamp.abs_diff(now) > max_skew {
+            return Err(Error::RequestSignatureExpired);
+        }
+
+        let mut mac = mac.clone();
+        mac.update(string_to_sign(path, timestamp, nonce, body).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| Error::RequestSignatureInvalid)?;
+
+        // The timestamp, rather than the current time, so that requests signed just before a
+        // key stopped being valid are still accepted.
+        if not_before.is_some_and(|from| timestamp < from)
+            || not_after.is_some_and(|to| timestamp > to)
+        {
+            return Err(Error::RequestSignatureKeyNotValid);
+        }
+
+        // Only requests with valid signatures get here, so the nonce table can only be filled by
+        // someone holding the key.  Entries are dropped once their signatures expire anyway.
+        let mut seen_nonces = self.seen_nonces.lock().unwrap();
+        seen_nonces.retain(|_, forget_after| *forget_after >= now);
+        let replayed = seen_nonces
+            .insert(nonce.to_owned(), timestamp + max_skew)
+            .is_some();
+
+        if let Some(budget) = &self.memory_budget {
+            let bytes = seen_nonces
+                .keys()
+                .map(|nonce| nonce.len() + NONCE_OVERHEAD)
+                .sum();
+            budget.set(Component::Nonces, bytes);
+        }
+
+        if replayed {
+            return Err(Error::RequestSignatureReplayed);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseSigner {
+    mac: HmacSha256,
+}
+
+impl ResponseSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { mac: new_mac(key) }
+    }
+
+    /// Returns the hex encoded signature of `body`.
+    pub fn sign(&self, body: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Checks `signature`, as sent in the [`RESPONSE_SIGNATURE_HEADER`] header, against `body`.
+    pub fn verify(&self, body: &[u8], signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+
+        let mut mac = self.mac.clone();
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, hyper::header::HeaderValue, std::env};
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn headers(signature: &SignatureHeaders) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let mut set = |name, value: &str| {
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+        };
+        set(TIMESTAMP_HEADER, &signature.timestamp);
+        set(NONCE_HEADER, &signature.nonce);
+        set(SIGNATURE_HEADER, &signature.signature);
+        if let Some(key_id) = &signature.key_id {
+            set(KEY_ID_HEADER, key_id);
+        }
+        headers
+    }
+
+    /// Headers of a request to `/` signed with `signer` at `timestamp`.
+    fn signed_at(signer: &RequestSigner, timestamp: u64, nonce: &str, body: &[u8]) -> HeaderMap {
+        headers(&SignatureHeaders {
+            timestamp: timestamp.to_string(),
+            nonce: nonce.to_owned(),
+            signature: signer.sign("/", timestamp, nonce, body),
+            key_id: signer.key_id.clone(),
+        })
+    }
+
+    fn key(id: &str, key: &[u8], not_before: Option<u64>, not_after: Option<u64>) -> SigningKey {
+        SigningKey {
+            id: id.to_owned(),
+            key: Secret::new(key.to_vec()),
+            not_before,
+            not_after,
+        }
+    }
+
+    #[test]
+    fn signed_requests_are_accepted_once() {
+        let verifier = RequestVerifier::new(b"key");
+        let headers = headers(&RequestSigner::new(b"key").signature_headers("/", b"body"));
+
+        assert!(matches!(
+            verifier.verify(&headers, "/", b"body"),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            verifier.clone().verify(&headers, "/", b"body"),
+            Some(Err(Error::RequestSignatureReplayed))
+        ));
+        assert!(verifier.verify(&HeaderMap::new(), "/", b"body").is_none());
+    }
+
+    #[test]
+    fn signatures_cover_the_path_and_the_body() {
+        let verifier = RequestVerifier::new(b"key");
+        let signer = RequestSigner::new(b"key");
+        let headers = signed_at(&signer, now(), "nonce", b"body");
+
+        for (path, body) in [("/", &b"other"[..]), ("/admin", b"body")] {
+            assert!(matches!(
+                verifier.verify(&headers, path, body),
+                Some(Err(Error::RequestSignatureInvalid))
+            ));
+        }
+        let other_key = signed_at(&RequestSigner::new(b"other"), now(), "nonce", b"body");
+        assert!(matches!(
+            verifier.verify(&other_key, "/", b"body"),
+            Some(Err(Error::RequestSignatureInvalid))
+        ));
+
+        // Rejected signatures do not use up the nonce.
+        assert!(matches!(
+            verifier.verify(&headers, "/", b"body"),
+            Some(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn signatures_far_from_the_server_clock_are_rejected() {
+        let verifier = RequestVerifier::new(b"key").max_clock_skew(Duration::from_secs(60));
+        let signer = RequestSigner::new(b"key");
+        for timestamp in [now() - 120, now() + 120] {
+            assert!(matches!(
+                verifier.verify(&signed_at(&signer, timestamp, "n", b""), "/", b""),
+                Some(Err(Error::RequestSignatureExpired))
+            ));
+        }
+        assert!(matches!(
+            verifier.verify(&signed_at(&signer, now() - 30, "n", b""), "/", b""),
+            Some(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn incomplete_or_malformed_signatures_are_rejected() {
+        let verifier = RequestVerifier::new(b"key");
+        let complete = signed_at(&RequestSigner::new(b"key"), now(), "nonce", b"");
+
+        for missing in [TIMESTAMP_HEADER, NONCE_HEADER] {
+            let mut headers = complete.clone();
+            headers.remove(missing);
+            assert!(matches!(
+                verifier.verify(&headers, "/", b""),
+                Some(Err(Error::RequestSignatureIncomplete))
+            ));
+        }
+        for (name, value) in [
+            (TIMESTAMP_HEADER, "yesterday"),
+            (SIGNATURE_HEADER, "not hex"),
+        ] {
+            let mut headers = complete.clone();
+            headers.insert(name, HeaderValue::from_static(value));
+            assert!(matches!(
+                verifier.verify(&headers, "/", b""),
+                Some(Err(Error::RequestSignatureHeaderParserError))
+            ));
+        }
+    }
+
+    #[test]
+    fn named_keys_are_only_valid_within_their_window() {
+        let now = now();
+        let verifier = RequestVerifier::from_keys([
+            key("old", b"old key", None, Some(now - 10)),
+            key("new", b"new key", Some(now - 10), None),
+        ]);
+        let old = RequestSigner::new(b"old key").key_id("old");
+        let new = RequestSigner::new(b"new key").key_id("new");
+
+        assert!(matches!(
+            verifier.verify(&signed_at(&old, now, "1", b""), "/", b""),
+            Some(Err(Error::RequestSignatureKeyNotValid))
+        ));
+        assert!(matches!(
+            verifier.verify(&signed_at(&old, now - 20, "2", b""), "/", b""),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            verifier.verify(&signed_at(&new, now, "3", b""), "/", b""),
+            Some(Ok(()))
+        ));
+
+        // A named key does not verify signatures naming another one.
+        let misnamed = RequestSigner::new(b"new key").key_id("old");
+        assert!(matches!(
+            verifier.verify(&signed_at(&misnamed, now - 20, "4", b""), "/", b""),
+            Some(Err(Error::RequestSignatureInvalid))
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let verifier = RequestVerifier::from_keys([key("a", b"key", None, None)]);
+        let unnamed = RequestSigner::new(b"key");
+        let unknown = RequestSigner::new(b"key").key_id("b");
+        for signer in [unnamed, unknown] {
+            assert!(matches!(
+                verifier.verify(&signed_at(&signer, now(), "n", b""), "/", b""),
+                Some(Err(Error::RequestSignatureUnknownKey))
+            ));
+        }
+    }
+
+    /// Writes `files` to a new directory, and loads the keys listed in `keys.json`.
+    fn load(name: &str, files: &[(&str, &str)]) -> Result<Vec<SigningKey>, KeysError> {
+        let dir = env::temp_dir().join(format!("signing-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        let keys = load_keys(&dir.join("keys.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        keys
+    }
+
+    #[test]
+    fn keys_are_loaded_relative_to_the_key_list() {
+        let keys = load(
+            "valid",
+            &[
+                (
+                    "keys.json",
+                    r#"[{"id": "a", "key_file": "a.key", "not_before": 1, "not_after": 2}]"#,
+                ),
+                ("a.key", " secret\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].id, "a");
+        assert_eq!(keys[0].key.expose(), b"secret");
+        assert_eq!((keys[0].not_before, keys[0].not_after), (Some(1), Some(2)));
+    }
+
+    #[test]
+    fn invalid_key_lists_are_rejected() {
+        let problem =
+            |keys: &str, key: &str| match load("invalid", &[("keys.json", keys), ("a.key", key)]) {
+                Err(KeysError::Invalid { problem, .. }) => problem,
+                other => panic!("unexpected result: {other:?}"),
+            };
+        assert_eq!(
+            problem(
+                r#"[{"id": "a", "key_file": "a.key"}, {"id": "a", "key_file": "a.key"}]"#,
+                "secret"
+            ),
+            "is listed more than once"
+        );
+        assert_eq!(
+            problem(
+                r#"[{"id": "a", "key_file": "a.key", "not_before": 2, "not_after": 1}]"#,
+                "secret"
+            ),
+            "has `not_before` after `not_after`"
+        );
+        assert_eq!(
+            problem(r#"[{"id": "a", "key_file": "a.key"}]"#, " \n"),
+            "has an empty `key_file`"
+        );
+
+        assert!(matches!(
+            load(
+                "missing",
+                &[("keys.json", r#"[{"id": "a", "key_file": "a.key"}]"#)]
+            ),
+            Err(KeysError::Io { .. })
+        ));
+        assert!(matches!(
+            load(
+                "unknown",
+                &[("keys.json", r#"[{"id": "a", "key": "secret"}]"#)]
+            ),
+            Err(KeysError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn response_signatures_cover_the_exact_body() {
+        let signer = ResponseSigner::new(b"key");
+        let signature = signer.sign(b"{\"result\":1}");
+
+        assert!(signer.verify(b"{\"result\":1}", &signature));
+        assert!(signer.verify(b"{\"result\":1}", &signature.to_uppercase()));
+        assert!(!signer.verify(b"{\"result\": 1}", &signature));
+        assert!(!signer.verify(b"{\"result\":2}", &signature));
+        assert!(!ResponseSigner::new(b"other key").verify(b"{\"result\":1}", &signature));
+    }
+
+    #[test]
+    fn malformed_response_signatures_are_rejected() {
+        let signer = ResponseSigner::new(b"key");
+        let signature = signer.sign(b"body");
+        assert!(!signer.verify(b"body", ""));
+        assert!(!signer.verify(b"body", "not hex"));
+        assert!(!signer.verify(b"body", &signature[..signature.len() - 2]));
+    }
+}
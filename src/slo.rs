@@ -0,0 +1,419 @@
+//! Alerts on service level objectives that are being missed.
+//!
+//! An [`Slo`] covers a group of methods, and sets an availability objective, a latency
+//! objective, or both.  [`SloMonitor`] counts the calls that miss each objective, and
+//! [`SloMonitor::run`] periodically compares how fast the error budget, the fraction of calls
+//! an objective allows to miss it, is being spent.  Following the usual multi-window approach,
+//! an alert fires when the budget is spent too fast over both a long window and a short one,
+//! see [`BURN_RATE_ALERTS`].  The short window makes the alert resolve soon after the problem
+//! does.
+//!
+//! Calls rejected by the protection rules are the caller's doing, so they are not counted.
+//!
+//! Objectives are read from a JSON file:
+//!
+//! ```json
+//! [
+//!     {
+//!         "name": "reads",
+//!         "methods": ["get_balance", "get_block"],
+//!         "availability": 0.999,
+//!         "latency": { "threshold_ms": 200, "objective": 0.99 }
+//!     }
+//! ]
+//! ```
+
+use {
+    crate::{
+        diagnostic::{self, Diagnostic},
+        request_log::Outcome,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{HashMap, HashSet, VecDeque},
+        fmt, fs, io,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+    thiserror::Error,
+};
+
+pub const TARGET: &str = "jsonrpc_protection::slo";
+
+/// How often burn rates are checked.
+const INTERVAL: Duration = Duration::from_secs(30);
+
+/// Calls are counted in buckets this long.
+const BUCKET: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum SloError {
+    #[error("Failed to read the objectives: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to parse the objectives: {0}")]
+    Parse(#[from] Diagnostic),
+
+    #[error("Objective {name:?} {problem}")]
+    Invalid { name: String, problem: &'static str },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Slo {
+    pub name: String,
+    pub methods: HashSet<String>,
+    /// Fraction of calls that must not fail.
+    #[serde(default)]
+    pub availability: Option<f64>,
+    #[serde(default)]
+    pub latency: Option<LatencyObjective>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyObjective {
+    pub threshold_ms: u64,
+    /// Fraction of calls that must be answered within `threshold_ms`.
+    pub objective: f64,
+}
+
+impl Slo {
+    fn validate(&self) -> Result<(), SloError> {
+        let invalid = |problem| {
+            Err(SloError::Invalid {
+                name: self.name.clone(),
+                problem,
+            })
+        };
+        let in_range = |objective: f64| objective > 0.0 && objective < 1.0;
+
+        if self.methods.is_empty() {
+            return invalid("has no methods");
+        }
+        if self.availability.is_none() && self.latency.is_none() {
+            return invalid("sets neither an availability nor a latency objective");
+        }
+        if self
+            .availability
+            .is_some_and(|objective| !in_range(objective))
+        {
+            return invalid("has an availability objective outside of (0, 1)");
+        }
+        if self
+            .latency
+            .is_some_and(|latency| !in_range(latency.objective))
+        {
+            return invalid("has a latency objective outside of (0, 1)");
+        }
+        Ok(())
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Slo>, SloError> {
+    let slos = diagnostic::from_json::<Vec<Slo>>(&fs::read_to_string(path)?)?;
+    for slo in &slos {
+        slo.validate()?;
+    }
+    Ok(slos)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Objective {
+    Availability,
+    Latency,
+}
+
+impl fmt::Display for Objective {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Objective::Availability => "availability",
+            Objective::Latency => "latency",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The whole budget would be gone within days.  Someone should look now.
+    Page,
+    /// The budget is spent faster than it should be, but there is time.
+    Ticket,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Page => "page",
+            Severity::Ticket => "ticket",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BurnRateAlert {
+    pub severity: Severity,
+    pub long_window: Duration,
+    pub short_window: Duration,
+    /// How many times faster than allowed the budget must be spent, over both windows.
+    pub burn_rate: f64,
+}
+
+/// For a 30 day budget, a `Page` fires when 2% of it is spent within an hour, and a `Ticket`
+/// when 5% of it is spent within 6 hours.
+pub const BURN_RATE_ALERTS: [BurnRateAlert; 2] = [
+    BurnRateAlert {
+        severity: Severity::Page,
+        long_window: Duration::from_secs(60 * 60),
+        short_window: Duration::from_secs(5 * 60),
+        burn_rate: 14.4,
+    },
+    BurnRateAlert {
+        severity: Severity::Ticket,
+        long_window: Duration::from_secs(6 * 60 * 60),
+        short_window: Duration::from_secs(30 * 60),
+        burn_rate: 6.0,
+    },
+];
+
+/// A change in whether an alert is firing.
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub slo: String,
+    pub objective: Objective,
+    pub severity: Severity,
+    pub firing: bool,
+    pub long_burn_rate: f64,
+    pub short_burn_rate: f64,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    /// Number of [`BUCKET`]s since the monitor was created.
+    index: u64,
+    calls: u64,
+    failed: u64,
+    slow: u64,
+}
+
+/// Buckets of one [`Slo`], covering the longest window, oldest first.
+#[derive(Default)]
+struct History {
+    buckets: VecDeque<Bucket>,
+}
+
+impl History {
+    fn current(&mut self, index: u64) -> &mut Bucket {
+        if self
+            .buckets
+            .back()
+            .is_none_or(|bucket| bucket.index != index)
+        {
+            self.buckets.push_back(Bucket {
+                index,
+                ..Bucket::default()
+            });
+        }
+        let kept = longest_window_buckets();
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.index + kept <= index)
+        {
+            self.buckets.pop_front();
+        }
+        self.buckets.back_mut().unwrap()
+    }
+
+    /// Fraction of the calls in the last `window` that missed `objective`.
+    fn miss_ratio(&self, index: u64, window: Duration, objective: Objective) -> f64 {
+        let first = (index + 1).saturating_sub(buckets_in(window));
+        let (calls, missed) = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.index >= first)
+            .fold((0, 0), |(calls, missed), bucket| {
+                let bucket_missed = match objective {
+                    Objective::Availability => bucket.failed,
+                    Objective::Latency => bucket.slow,
+                };
+                (calls + bucket.calls, missed + bucket_missed)
+            });
+        if calls == 0 {
+            0.0
+        } else {
+            missed as f64 / calls as f64
+        }
+    }
+}
+
+fn buckets_in(window: Duration) -> u64 {
+    window.as_secs().div_ceil(BUCKET.as_secs())
+}
+
+fn longest_window_buckets() -> u64 {
+    BURN_RATE_ALERTS
+        .iter()
+        .map(|alert| buckets_in(alert.long_window))
+        .max()
+        .unwrap_or(1)
+}
+
+/// Clones share the counters.
+#[derive(Clone)]
+pub struct SloMonitor {
+    started: Instant,
+    slos: Arc<[Slo]>,
+    /// Indices into `slos` of the objectives every method is covered by.
+    by_method: Arc<HashMap<String, Vec<usize>>>,
+    histories: Arc<Mutex<Vec<History>>>,
+    firing: Arc<Mutex<HashSet<(usize, Objective, Severity)>>>,
+    on_alert: Option<Arc<dyn Fn(Alert) + Send + Sync>>,
+}
+
+impl SloMonitor {
+    pub fn new(slos: Vec<Slo>) -> Self {
+        let mut by_method = HashMap::<String, Vec<usize>>::new();
+        for (index, slo) in slos.iter().enumerate() {
+            for method in &slo.methods {
+                by_method.entry(method.clone()).or_default().push(index);
+            }
+        }
+        Self {
+            started: Instant::now(),
+            histories: Arc::new(Mutex::new(
+                slos.iter().map(|_| History::default()).collect(),
+            )),
+            slos: slos.into(),
+            by_method: Arc::new(by_method),
+            firing: Arc::default(),
+            on_alert: None,
+        }
+    }
+
+    /// Calls `f` whenever an alert starts or stops firing, after it is logged.
+    pub fn on_alert<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Alert) + Send + Sync + 'static,
+    {
+        self.on_alert = Some(Arc::new(f));
+        self
+    }
+
+    /// Records that a call to `method` ended with `outcome` after `latency`.
+    pub fn record(&self, method: &str, outcome: Outcome, latency: Duration) {
+        if outcome == Outcome::Denied {
+            return;
+        }
+        let Some(indices) = self.by_method.get(method) else {
+            return;
+        };
+
+        let index = self.bucket_index(Instant::now());
+        let mut histories = self.histories.lock().unwrap();
+        for &slo in indices {
+            let bucket = histories[slo].current(index);
+            bucket.calls += 1;
+            if outcome == Outcome::Error {
+                bucket.failed += 1;
+            }
+            if let Some(objective) = self.slos[slo].latency {
+                if latency > Duration::from_millis(objective.threshold_ms) {
+                    bucket.slow += 1;
+                }
+            }
+        }
+    }
+
+    fn bucket_index(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / BUCKET.as_secs()
+    }
+
+    /// Checks the burn rates every [`INTERVAL`], forever.
+    pub async fn run(self) {
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            for alert in self.evaluate(Instant::now()) {
+                if alert.firing {
+                    log::warn!(
+                        target: TARGET,
+                        "SLO alert firing: slo={} objective={} severity={} \
+                         long_burn_rate={:.1} short_burn_rate={:.1}",
+                        alert.slo,
+                        alert.objective,
+                        alert.severity,
+                        alert.long_burn_rate,
+                        alert.short_burn_rate,
+                    );
+                } else {
+                    log::info!(
+                        target: TARGET,
+                        "SLO alert resolved: slo={} objective={} severity={}",
+                        alert.slo,
+                        alert.objective,
+                        alert.severity,
+                    );
+                }
+                if let Some(on_alert) = &self.on_alert {
+                    on_alert(alert);
+                }
+            }
+        }
+    }
+
+    /// Alerts that started or stopped firing since the last evaluation.
+    pub fn evaluate(&self, now: Instant) -> Vec<Alert> {
+        let index = self.bucket_index(now);
+        let histories = self.histories.lock().unwrap();
+        let mut firing = self.firing.lock().unwrap();
+
+        let mut alerts = vec![];
+        for (slo_index, (slo, history)) in self.slos.iter().zip(histories.iter()).enumerate() {
+            let objectives = [
+                (Objective::Availability, slo.availability),
+                (
+                    Objective::Latency,
+                    slo.latency.map(|latency| latency.objective),
+                ),
+            ];
+            for (objective, target) in objectives {
+                let Some(target) = target else {
+                    continue;
+                };
+                let budget = 1.0 - target;
+
+                for rule in &BURN_RATE_ALERTS {
+                    let long_burn_rate =
+                        history.miss_ratio(index, rule.long_window, objective) / budget;
+                    let short_burn_rate =
+                        history.miss_ratio(index, rule.short_window, objective) / budget;
+                    let is_firing =
+                        long_burn_rate >= rule.burn_rate && short_burn_rate >= rule.burn_rate;
+
+                    let key = (slo_index, objective, rule.severity);
+                    let was_firing = firing.contains(&key);
+                    if is_firing == was_firing {
+                        continue;
+                    }
+                    if is_firing {
+                        firing.insert(key);
+                    } else {
+                        firing.remove(&key);
+                    }
+                    alerts.push(Alert {
+                        slo: slo.name.clone(),
+                        objective,
+                        severity: rule.severity,
+                        firing: is_firing,
+                        long_burn_rate,
+                        short_burn_rate,
+                    });
+                }
+            }
+        }
+        alerts
+    }
+}
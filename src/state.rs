@@ -0,0 +1,964 @@
+//! Protection rules and credentials shared between the middleware and anything that needs to
+//! change them while the server is running.
+
+use {
+    crate::{
+        break_glass::{BreakGlass, Check},
+        constraints::{self, OwnershipRule, ParamConstraint},
+        rejection::{Reason, Rejection},
+        secret::Secret,
+        session::{Session, SessionStore},
+        users::UserStore,
+        RpcMeta,
+    },
+    arc_swap::{ArcSwap, Guard},
+    jsonrpc_core::{
+        types::request::{Call, MethodCall, Notification},
+        Id, Params, Version,
+    },
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::HashSet,
+        fmt,
+        str::FromStr,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// Everything the middleware needs to decide whether a call is allowed.
+#[derive(Clone, Debug)]
+pub struct ProtectionState {
+    /// Names of the methods that require admin credentials.
+    pub protected: HashSet<String>,
+    /// Expected value of the `X-Admin-Auth` header.
+    pub admin_token: Secret<String>,
+    /// Protected methods that callers connecting from a loopback address may call without
+    /// credentials, for local operational tooling.  Only transports that set
+    /// [`RpcMeta::peer_addr`] are affected.
+    pub loopback_methods: HashSet<String>,
+    /// Session tokens accepted in place of the admin token.  Copies of the state share the
+    /// same sessions.
+    pub sessions: SessionStore,
+    /// Users admins may act as, with the [`RUN_AS_HEADER`].  Copies of the state share the
+    /// same users.
+    ///
+    /// [`RUN_AS_HEADER`]: crate::RUN_AS_HEADER
+    pub users: UserStore,
+    /// Emergency tokens accepted in place of the admin token.  Copies of the state share the
+    /// same activations.
+    pub break_glass: BreakGlass,
+    /// Limits on the parameters callers with a given role may pass, checked once the call is
+    /// otherwise allowed.
+    pub constraints: Vec<ParamConstraint>,
+    /// Parameters that have to match attributes of the caller, checked along with the
+    /// [`Self::constraints`].
+    pub ownership: Vec<OwnershipRule>,
+    /// Different rules for some of the callers, for trying them out before applying them to
+    /// everyone.
+    pub canary: Option<Canary>,
+}
+
+/// Rules applied to [`Self::percent`] of the callers, in place of the loopback methods,
+/// constraints and ownership rules of the [`ProtectionState`].
+///
+/// Callers are picked by a hash of who they authenticated as: the user, for sessions started by
+/// a user login, and the admin otherwise, whichever admin credentials are used.  Callers
+/// without valid credentials are picked by a hash of their IP address, so that they can not
+/// choose their rules by making up tokens.  Either way, a caller gets the same rules on every
+/// call, and on every instance.  Calls with neither get the usual rules.
+#[derive(Clone, Debug, Default)]
+pub struct Canary {
+    pub percent: u8,
+    pub loopback_methods: HashSet<String>,
+    pub constraints: Vec<ParamConstraint>,
+    pub ownership: Vec<OwnershipRule>,
+}
+
+/// Which rules a call is checked against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleSet {
+    Stable,
+    Canary,
+}
+
+impl fmt::Display for RuleSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RuleSet::Stable => "stable",
+            RuleSet::Canary => "canary",
+        })
+    }
+}
+
+/// What [`ProtectionState::check_call`] decides for a call, and which rules decide it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Decision {
+    pub allowed: bool,
+    /// Who the caller is taken to be, as shown by [`Caller`]'s `Display`.
+    pub caller: String,
+    pub rule_set: RuleSet,
+    /// Why the method may be called at all: it is not protected, it is a loopback method and
+    /// the call comes from a loopback address, or the caller has to present credentials.
+    pub access: String,
+    /// Constraints and ownership rules for the call, as written in a [`crate::policy::Policy`].
+    pub rules: Vec<String>,
+    /// Why the call is denied.
+    pub reason: Option<Reason>,
+    pub message: Option<String>,
+}
+
+/// The rules that [`RuleSet`] stands for.
+struct Rules<'a> {
+    loopback_methods: &'a HashSet<String>,
+    constraints: &'a [ParamConstraint],
+    ownership: &'a [OwnershipRule],
+}
+
+fn is_loopback(meta: &RpcMeta) -> bool {
+    meta.peer_addr
+        .is_some_and(|addr| addr.ip().to_canonical().is_loopback())
+}
+
+impl ProtectionState {
+    /// Which rules calls with the given `meta` are checked against.
+    pub fn rule_set(&self, meta: &RpcMeta) -> RuleSet {
+        if self.canary.is_none() {
+            return RuleSet::Stable;
+        }
+        self.rule_set_of(&self.own_caller(meta), meta)
+    }
+
+    /// Same as [`Self::rule_set`], for a caller already identified as `caller`.
+    fn rule_set_of(&self, caller: &Caller, meta: &RpcMeta) -> RuleSet {
+        let Some(canary) = &self.canary else {
+            return RuleSet::Stable;
+        };
+        // Callers acting as another user are picked by who they are.
+        let caller = caller.run_by.as_deref().unwrap_or(caller);
+        let mut hasher = Sha256::new();
+        match (&caller.user, caller.role, meta.peer_addr) {
+            (Some(user), _, _) => hasher.update(format!("user {user}").as_bytes()),
+            (None, Role::Admin, _) => hasher.update(b"admin"),
+            (None, Role::Anonymous, Some(addr)) => {
+                hasher.update(addr.ip().to_canonical().to_string().as_bytes())
+            }
+            (None, Role::Anonymous, None) => return RuleSet::Stable,
+        }
+        let hash = hasher.finalize();
+        if u16::from_be_bytes([hash[0], hash[1]]) % 100 < u16::from(canary.percent) {
+            RuleSet::Canary
+        } else {
+            RuleSet::Stable
+        }
+    }
+
+    fn rules(&self, rule_set: RuleSet) -> Rules<'_> {
+        match (rule_set, &self.canary) {
+            (RuleSet::Canary, Some(canary)) => Rules {
+                loopback_methods: &canary.loopback_methods,
+                constraints: &canary.constraints,
+                ownership: &canary.ownership,
+            },
+            _ => Rules {
+                loopback_methods: &self.loopback_methods,
+                constraints: &self.constraints,
+                ownership: &self.ownership,
+            },
+        }
+    }
+
+    /// Checks whether `call` may be executed for a caller with the given `meta`.
+    ///
+    /// Invalid calls are allowed: jsonrpc-core answers them with an Invalid Request error
+    /// without executing anything.  Transports that forward requests to another server have to
+    /// reject them, see [`crate::layer`].
+    ///
+    /// Returns the caller the call is allowed for, see [`RpcMeta::caller`].
+    ///
+    /// An emergency token is activated by the first call to a protected method it is allowed.
+    /// Every other check here only looks the token up.
+    pub fn check_call(&self, call: &Call, meta: &RpcMeta) -> Result<Caller, Rejection> {
+        let caller = self.evaluate(call, meta)?;
+        match call {
+            Call::MethodCall(MethodCall { method, .. })
+            | Call::Notification(Notification { method, .. })
+                if self.protected.contains(method) =>
+            {
+                self.activate_emergency_token(meta)?;
+            }
+            _ => (),
+        }
+        Ok(caller)
+    }
+
+    /// Same as [`Self::check_call`], without activating emergency tokens.
+    fn evaluate(&self, call: &Call, meta: &RpcMeta) -> Result<Caller, Rejection> {
+        let caller = self.caller(meta);
+        let (method, params) = match call {
+            Call::MethodCall(MethodCall { method, params, .. })
+            | Call::Notification(Notification { method, params, .. }) => (method, params),
+            Call::Invalid { .. } => return Ok(caller),
+        };
+
+        let rules = self.rules(self.rule_set_of(&caller, meta));
+        self.check_access(method, meta, rules.loopback_methods)?;
+        constraints::check(rules.constraints, caller.role, method, params)?;
+        constraints::check_ownership(
+            rules.ownership,
+            caller.user.as_deref(),
+            &meta.attributes,
+            method,
+            params,
+        )?;
+        Ok(caller)
+    }
+
+    /// Same as [`Self::check_call`] for a call to `method` with `params`, explaining the
+    /// outcome.
+    pub fn decide(&self, method: &str, params: &Params, meta: &RpcMeta) -> Decision {
+        let call = Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: method.to_owned(),
+            params: params.clone(),
+            id: Id::Null,
+        });
+        let result = self.evaluate(&call, meta);
+
+        let caller = self.caller(meta);
+        let rule_set = self.rule_set_of(&caller, meta);
+        let rules = self.rules(rule_set);
+        let access = if !self.protected.contains(method) {
+            format!("{method} is not protected")
+        } else if is_loopback(meta) && rules.loopback_methods.contains(method) {
+            format!("loopback_methods: {method}")
+        } else {
+            format!("{method} is protected, credentials are required")
+        };
+        let constraints = rules
+            .constraints
+            .iter()
+            .filter(|constraint| constraint.role == caller.role && constraint.method == method)
+            .map(|constraint| format!("param_constraints: {constraint}"));
+        let ownership = rules
+            .ownership
+            .iter()
+            .filter(|rule| rule.method == method)
+            .map(|rule| format!("owned_params: {rule}"));
+
+        let (reason, message) = match result {
+            Ok(_) => (None, None),
+            Err(rejection) => (Some(rejection.reason), Some(rejection.message)),
+        };
+        Decision {
+            allowed: reason.is_none(),
+            caller: caller.to_string(),
+            rule_set,
+            access,
+            rules: constraints.chain(ownership).collect(),
+            reason,
+            message,
+        }
+    }
+
+    /// Checks whether a caller with the given `meta` may call `method` at all.
+    fn check_access(
+        &self,
+        method: &str,
+        meta: &RpcMeta,
+        loopback_methods: &HashSet<String>,
+    ) -> Result<(), Rejection> {
+        if !self.protected.contains(method) {
+            return Ok(());
+        }
+
+        if is_loopback(meta) && loopback_methods.contains(method) {
+            return Ok(());
+        }
+
+        self.check_credentials(meta, Some(method))
+            .map_err(|rejection| rejection.required_role(Role::Admin))
+    }
+
+    /// Checks that `meta` carries credentials that allow calls to all protected methods.
+    pub fn authorize(&self, meta: &RpcMeta) -> Result<(), Rejection> {
+        self.check_credentials(meta, None)
+            .map_err(|rejection| rejection.required_role(Role::Admin))
+    }
+
+    /// Same as [`Self::authorize`], ignoring the `X-Run-As` header.
+    pub fn authenticate(&self, meta: &RpcMeta) -> Result<(), Rejection> {
+        self.check_own_credentials(meta, None)
+            .map_err(|rejection| rejection.required_role(Role::Admin))
+    }
+
+    /// Checks the credentials in `meta` for calls to `method`, or to any method when `None`.
+    ///
+    /// With an `X-Run-As` header, the call is checked as if made by the named user instead,
+    /// provided the credentials allow calls to all protected methods.
+    fn check_credentials(&self, meta: &RpcMeta, method: Option<&str>) -> Result<(), Rejection> {
+        if meta.run_as.is_none() {
+            return self.check_own_credentials(meta, method);
+        }
+        match self.run_as(meta)? {
+            Role::Admin => Ok(()),
+            Role::Anonymous => Err(Rejection::new(
+                Reason::OutOfScope,
+                "X-Run-As user may not call this method",
+            )),
+        }
+    }
+
+    /// Role of the user named in the `X-Run-As` header of `meta`, if the caller may act as
+    /// them.
+    fn run_as(&self, meta: &RpcMeta) -> Result<Role, Rejection> {
+        let user = match &meta.run_as {
+            Some(Ok(user)) => user,
+            Some(Err(error)) => return Err(error.into()),
+            None => unreachable!("Only called for requests with an X-Run-As header"),
+        };
+        if let Err(rejection) = self.check_own_credentials(meta, None) {
+            return Err(match rejection.reason {
+                Reason::OutOfScope => Rejection::new(
+                    Reason::RunAsDenied,
+                    "Only credentials that allow all methods may use X-Run-As",
+                ),
+                _ => rejection,
+            });
+        }
+        self.users
+            .role(user)
+            .ok_or_else(|| Rejection::new(Reason::RunAsDenied, "X-Run-As user does not exist"))
+    }
+
+    fn check_own_credentials(&self, meta: &RpcMeta, method: Option<&str>) -> Result<(), Rejection> {
+        match &meta.request_signature {
+            Some(Ok(())) => return Ok(()),
+            Some(Err(error)) => return Err(error.into()),
+            None => (),
+        }
+
+        let Some(auth) = &meta.auth else {
+            return Err(Rejection::new(
+                Reason::CredentialsRequired,
+                "X-Admin-Auth header required",
+            ));
+        };
+
+        let auth = match auth {
+            Ok(auth) => auth,
+            Err(error) => return Err(error.into()),
+        };
+
+        if *auth == self.admin_token {
+            return Ok(());
+        }
+
+        match self.break_glass.peek(auth.expose()) {
+            Check::Active => return Ok(()),
+            Check::Expired => {
+                return Err(Rejection::new(
+                    Reason::InvalidToken,
+                    "Emergency token has expired",
+                ))
+            }
+            Check::NotEmergencyToken => (),
+        }
+
+        match self.sessions.get(auth.expose()) {
+            Some(session) if session.allows(method) => Ok(()),
+            Some(_) => Err(Rejection::new(
+                Reason::OutOfScope,
+                "Session token does not allow calling this method",
+            )),
+            None => Err(Rejection::new(
+                Reason::InvalidToken,
+                "X-Admin-Auth value is not valid",
+            )),
+        }
+    }
+
+    /// Whether `meta` carries an active emergency token.
+    pub fn is_emergency(&self, meta: &RpcMeta) -> bool {
+        match &meta.auth {
+            Some(Ok(auth)) => self.break_glass.peek(auth.expose()) == Check::Active,
+            _ => false,
+        }
+    }
+
+    /// Activates the emergency token in `meta`, if it carries one that was not used yet.
+    /// Request signatures take precedence over `X-Admin-Auth`, so a signed request does not
+    /// activate anything.
+    fn activate_emergency_token(&self, meta: &RpcMeta) -> Result<(), Rejection> {
+        let (None, Some(Ok(auth))) = (&meta.request_signature, &meta.auth) else {
+            return Ok(());
+        };
+        match self.break_glass.check(auth.expose(), meta) {
+            // The token expired since it was looked up.
+            Check::Expired => Err(Rejection::new(
+                Reason::InvalidToken,
+                "Emergency token has expired",
+            )),
+            Check::Active | Check::NotEmergencyToken => Ok(()),
+        }
+    }
+
+    /// The session of the token in `meta`, if it is a session token.
+    pub fn session(&self, meta: &RpcMeta) -> Option<Session> {
+        match &meta.auth {
+            Some(Ok(auth)) => self.sessions.get(auth.expose()),
+            _ => None,
+        }
+    }
+
+    /// Role of a caller with the given `meta`.  Sessions have the role they were granted, even
+    /// when limited to some methods.
+    pub fn role(&self, meta: &RpcMeta) -> Role {
+        self.caller(meta).role
+    }
+
+    /// Who is making calls with the given `meta`.  For calls made as another user, the user,
+    /// with the actual caller in [`Caller::run_by`].
+    pub fn caller(&self, meta: &RpcMeta) -> Caller {
+        let caller = self.own_caller(meta);
+        match &meta.run_as {
+            Some(Ok(user)) => match self.run_as(meta) {
+                Ok(role) => Caller {
+                    role,
+                    user: Some(user.clone()),
+                    run_by: Some(Box::new(caller)),
+                },
+                Err(_) => caller,
+            },
+            _ => caller,
+        }
+    }
+
+    fn own_caller(&self, meta: &RpcMeta) -> Caller {
+        if let Some(session) = self.session(meta) {
+            Caller {
+                role: session.grant.role,
+                user: session.grant.user,
+                run_by: None,
+            }
+        } else if self.authenticate(meta).is_ok() {
+            Caller {
+                role: Role::Admin,
+                user: None,
+                run_by: None,
+            }
+        } else {
+            Caller {
+                role: Role::Anonymous,
+                user: None,
+                run_by: None,
+            }
+        }
+    }
+}
+
+/// The caller of a method, as handlers see it in [`RpcMeta::caller`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Caller {
+    pub role: Role,
+    /// The user that logged in, for sessions started with a username and password.  `None`
+    /// for holders of the admin token or a signing key.
+    pub user: Option<String>,
+    /// The actual caller, when the call is made as `user` with the `X-Run-As` header.
+    pub run_by: Option<Box<Caller>>,
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{} {user}", self.role)?,
+            None => write!(f, "{}", self.role)?,
+        }
+        if let Some(run_by) = &self.run_by {
+            write!(f, " (run by {run_by})")?;
+        }
+        Ok(())
+    }
+}
+
+/// What a caller is allowed to do, as decided by the credentials it presents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// No valid credentials.  Only unprotected methods may be called.
+    Anonymous,
+    /// Valid admin token, session token or request signature.  Protected methods may be
+    /// called, within the scope of the session.
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Anonymous => "anonymous",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anonymous" => Ok(Role::Anonymous),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "unknown role \"{s}\", expected \"anonymous\" or \"admin\""
+            )),
+        }
+    }
+}
+
+/// A cheaply cloneable handle to the current [`ProtectionState`].
+///
+/// Readers get a consistent snapshot via [`ProtectionHandle::load`], while writers replace the
+/// whole state atomically.  Calls that are already in flight keep using the snapshot they
+/// started with.
+#[derive(Clone)]
+pub struct ProtectionHandle {
+    state: Arc<ArcSwap<ProtectionState>>,
+    watchers: Arc<Mutex<Vec<Watcher>>>,
+}
+
+type Watcher = Box<dyn Fn(&ProtectionState) + Send + Sync>;
+
+impl ProtectionHandle {
+    pub fn new(state: ProtectionState) -> Self {
+        Self {
+            state: Arc::new(ArcSwap::from_pointee(state)),
+            watchers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Calls `watcher` with the new state every time the state is replaced.
+    pub fn watch<F>(&self, watcher: F)
+    where
+        F: Fn(&ProtectionState) + Send + Sync + 'static,
+    {
+        self.watchers.lock().unwrap().push(Box::new(watcher));
+    }
+
+    fn notify_watchers(&self) {
+        let state = self.load_full();
+        for watcher in self.watchers.lock().unwrap().iter() {
+            watcher(&state);
+        }
+    }
+
+    /// Returns the current state.  The guard is cheap to obtain, but should not be held across
+    /// `await` points; use [`ProtectionHandle::load_full`] for that.
+    pub fn load(&self) -> Guard<Arc<ProtectionState>> {
+        self.state.load()
+    }
+
+    pub fn load_full(&self) -> Arc<ProtectionState> {
+        self.state.load_full()
+    }
+
+    pub fn store(&self, state: ProtectionState) {
+        self.state.store(Arc::new(state));
+        self.notify_watchers();
+    }
+
+    /// Applies `f` to a copy of the current state and publishes the result.
+    ///
+    /// `f` may be called more than once if another writer races with this one.
+    pub fn update<F>(&self, mut f: F)
+    where
+        F: FnMut(&mut ProtectionState),
+    {
+        self.state.rcu(|current| {
+            let mut next = ProtectionState::clone(current);
+            f(&mut next);
+            next
+        });
+        self.notify_watchers();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::session::Grant,
+        jsonrpc_core::Params,
+        sha2::{Digest, Sha256},
+        std::{
+            net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+            sync::atomic::{AtomicUsize, Ordering},
+            time::Duration,
+        },
+    };
+
+    /// `f` is protected, with `root` as the admin token.
+    pub(crate) fn state() -> ProtectionState {
+        ProtectionState {
+            protected: ["f".to_owned()].into(),
+            admin_token: "root".into(),
+            loopback_methods: Default::default(),
+            sessions: Default::default(),
+            users: Default::default(),
+            break_glass: Default::default(),
+            constraints: Default::default(),
+            ownership: Default::default(),
+            canary: Default::default(),
+        }
+    }
+
+    /// Metadata of a request with `auth` in the `X-Admin-Auth` header.
+    pub(crate) fn meta(auth: Option<&str>) -> RpcMeta {
+        let mut meta = RpcMeta::from_headers(|_| None);
+        meta.auth = auth.map(|auth| Ok(auth.into()));
+        meta
+    }
+
+    pub(crate) fn call(method: &str) -> Call {
+        Call::MethodCall(MethodCall {
+            jsonrpc: Some(Version::V2),
+            method: method.to_owned(),
+            params: Params::Array(vec![]),
+            id: Id::Num(1),
+        })
+    }
+
+    /// [`meta`], for a request from `10.0.0.<ip>`.
+    pub(crate) fn from(ip: u8, auth: Option<&str>) -> RpcMeta {
+        let mut meta = meta(auth);
+        meta.peer_addr = Some(SocketAddr::from((Ipv4Addr::new(10, 0, 0, ip), 1)));
+        meta
+    }
+
+    /// Token of a new admin session of `user`, or started with the admin token for `None`.
+    pub(crate) fn session(state: &ProtectionState, user: Option<&str>) -> String {
+        let issued = state.sessions.issue(
+            Duration::from_secs(60),
+            Grant {
+                role: Role::Admin,
+                user: user.map(str::to_owned),
+                methods: None,
+            },
+        );
+        issued.token.expose().clone()
+    }
+
+    fn reason<T>(result: Result<T, Rejection>) -> Option<Reason> {
+        result.err().map(|rejection| rejection.reason)
+    }
+
+    #[test]
+    fn unprotected_methods_need_no_credentials() {
+        let state = state();
+        assert_eq!(reason(state.check_call(&call("g"), &meta(None))), None);
+        assert_eq!(reason(state.check_call(&call("g"), &meta(Some("x")))), None);
+    }
+
+    #[test]
+    fn protected_methods_need_the_admin_token() {
+        let state = state();
+        assert_eq!(
+            reason(state.check_call(&call("f"), &meta(None))),
+            Some(Reason::CredentialsRequired)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &meta(Some("roo")))),
+            Some(Reason::InvalidToken)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &meta(Some("root ")))),
+            Some(Reason::InvalidToken)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &meta(Some("root")))),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_admin_auth_is_rejected() {
+        let mut meta = meta(None);
+        meta.auth = Some(Err(crate::Error::AdminAuthHeaderParserError));
+        assert_eq!(
+            reason(state().check_call(&call("f"), &meta)),
+            Some(Reason::MalformedCredentials)
+        );
+    }
+
+    #[test]
+    fn notifications_are_checked_like_calls() {
+        let notification = Call::Notification(Notification {
+            jsonrpc: Some(Version::V2),
+            method: "f".to_owned(),
+            params: Params::None,
+        });
+        assert_eq!(
+            reason(state().check_call(&notification, &meta(None))),
+            Some(Reason::CredentialsRequired)
+        );
+    }
+
+    #[test]
+    fn loopback_methods_need_a_loopback_peer() {
+        let mut state = state();
+        state.protected.insert("h".to_owned());
+        state.loopback_methods.insert("f".to_owned());
+
+        let from = |ip: std::net::IpAddr| {
+            let mut meta = meta(None);
+            meta.peer_addr = Some(SocketAddr::new(ip, 1234));
+            meta
+        };
+        let local = from(Ipv4Addr::LOCALHOST.into());
+        let mapped = from(Ipv4Addr::LOCALHOST.to_ipv6_mapped().into());
+        let remote = from(Ipv4Addr::new(192, 0, 2, 1).into());
+
+        assert_eq!(reason(state.check_call(&call("f"), &local)), None);
+        assert_eq!(reason(state.check_call(&call("f"), &mapped)), None);
+        assert_eq!(
+            reason(state.check_call(&call("f"), &from(Ipv6Addr::LOCALHOST.into()))),
+            None
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &remote)),
+            Some(Reason::CredentialsRequired)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("h"), &local)),
+            Some(Reason::CredentialsRequired)
+        );
+        // Without a peer address the transport can not tell.
+        assert_eq!(
+            reason(state.check_call(&call("f"), &meta(None))),
+            Some(Reason::CredentialsRequired)
+        );
+    }
+
+    #[test]
+    fn allowed_calls_come_with_their_caller() {
+        let state = state();
+        let caller = |method, auth| state.check_call(&call(method), &meta(auth)).unwrap();
+        assert_eq!(caller("f", Some("root")).role, Role::Admin);
+        assert_eq!(caller("g", Some("root")).role, Role::Admin);
+        assert_eq!(caller("g", None).role, Role::Anonymous);
+        assert_eq!(caller("g", Some("x")).role, Role::Anonymous);
+    }
+
+    #[test]
+    fn only_admin_credentials_may_run_as_users() {
+        let state = state();
+        state.users.add("alice", "password", Role::Admin).unwrap();
+
+        let run_as = |auth, user: &str| {
+            let mut meta = meta(auth);
+            meta.run_as = Some(Ok(user.to_owned()));
+            meta
+        };
+        assert_eq!(
+            reason(state.check_call(&call("f"), &run_as(None, "alice"))),
+            Some(Reason::CredentialsRequired)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &run_as(Some("x"), "alice"))),
+            Some(Reason::InvalidToken)
+        );
+        assert_eq!(
+            reason(state.check_call(&call("f"), &run_as(Some("root"), "bob"))),
+            Some(Reason::RunAsDenied)
+        );
+        assert_eq!(
+            state
+                .check_call(&call("f"), &run_as(Some("root"), "alice"))
+                .unwrap()
+                .to_string(),
+            "admin alice (run by admin)"
+        );
+
+        state.users.set_role("alice", Role::Anonymous).unwrap();
+        assert_eq!(
+            reason(state.check_call(&call("f"), &run_as(Some("root"), "alice"))),
+            Some(Reason::OutOfScope)
+        );
+        // Unprotected methods do not care who the call is made as.
+        assert_eq!(
+            reason(state.check_call(&call("g"), &run_as(Some("root"), "alice"))),
+            None
+        );
+    }
+
+    /// State accepting the emergency token `break`, for `duration`, and the number of times it
+    /// was activated.
+    pub(crate) fn with_emergency_token(duration: Duration) -> (ProtectionState, Arc<AtomicUsize>) {
+        let activations = Arc::new(AtomicUsize::new(0));
+        let hash = Sha256::digest(b"break").into();
+        let break_glass = BreakGlass::new([hash], duration).on_activation({
+            let activations = activations.clone();
+            move |_| {
+                activations.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let state = ProtectionState {
+            break_glass,
+            ..state()
+        };
+        (state, activations)
+    }
+
+    #[test]
+    fn emergency_tokens_are_activated_by_protected_calls() {
+        let (state, activations) = with_emergency_token(Duration::from_secs(60));
+        let emergency = meta(Some("break"));
+
+        // Looking the caller up, explaining a decision or calling an unprotected method does not
+        // start the clock.
+        assert_eq!(state.role(&emergency), Role::Admin);
+        assert!(state.is_emergency(&emergency));
+        assert!(state.decide("f", &Params::None, &emergency).allowed);
+        assert_eq!(reason(state.check_call(&call("g"), &emergency)), None);
+        assert_eq!(activations.load(Ordering::Relaxed), 0);
+
+        assert_eq!(reason(state.check_call(&call("f"), &emergency)), None);
+        assert_eq!(reason(state.check_call(&call("f"), &emergency)), None);
+        assert_eq!(activations.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn expired_emergency_tokens_are_rejected() {
+        let (state, activations) = with_emergency_token(Duration::ZERO);
+        let emergency = meta(Some("break"));
+
+        assert_eq!(reason(state.check_call(&call("f"), &emergency)), None);
+        assert_eq!(
+            reason(state.check_call(&call("f"), &emergency)),
+            Some(Reason::InvalidToken)
+        );
+        assert!(!state.is_emergency(&emergency));
+        assert_eq!(state.role(&emergency), Role::Anonymous);
+        assert_eq!(activations.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn loaded_snapshots_do_not_change() {
+        let handle = ProtectionHandle::new(state());
+        let before = handle.load_full();
+        handle.update(|state| {
+            state.protected.insert("g".to_owned());
+        });
+        handle.store(ProtectionState {
+            admin_token: "new".into(),
+            ..state()
+        });
+
+        assert!(!before.protected.contains("g"));
+        assert_eq!(before.admin_token, "root".into());
+        assert!(!handle.load().protected.contains("g"));
+        assert_eq!(handle.load().admin_token, "new".into());
+    }
+
+    #[test]
+    fn copies_of_a_handle_share_the_state() {
+        let handle = ProtectionHandle::new(state());
+        let copy = handle.clone();
+        copy.update(|state| state.protected.clear());
+        assert_eq!(
+            reason(handle.load().check_call(&call("f"), &meta(None))),
+            None
+        );
+    }
+
+    #[test]
+    fn watchers_see_every_new_state() {
+        let handle = ProtectionHandle::new(state());
+        let seen = Arc::new(Mutex::new(vec![]));
+        handle.watch({
+            let seen = seen.clone();
+            move |state| seen.lock().unwrap().push(state.protected.len())
+        });
+
+        handle.update(|state| {
+            state.protected.insert("g".to_owned());
+        });
+        handle.store(state());
+        assert_eq!(*seen.lock().unwrap(), [2, 1]);
+    }
+
+    /// [`state`], with a canary for `percent` of the callers.
+    fn with_canary(percent: u8) -> ProtectionState {
+        ProtectionState {
+            canary: Some(Canary {
+                percent,
+                ..Default::default()
+            }),
+            ..state()
+        }
+    }
+
+    #[test]
+    fn canary_callers_are_picked_by_principal() {
+        let state = with_canary(50);
+
+        // Made up tokens get the rules of their address, whatever they are.
+        for ip in 0..20 {
+            let rule_set = state.rule_set(&from(ip, None));
+            for token in 0..20 {
+                let made_up = from(ip, Some(&format!("token {token}")));
+                assert_eq!(state.rule_set(&made_up), rule_set);
+            }
+        }
+
+        // Any credential of the admin gets the rules of the admin, wherever it comes from.
+        let admin = state.rule_set(&from(1, Some("root")));
+        let mut signed = from(2, None);
+        signed.request_signature = Some(Ok(()));
+        let mut run_as = from(3, Some("root"));
+        run_as.run_as = Some(Ok("alice".to_owned()));
+        for meta in [
+            from(4, Some(&session(&state, None))),
+            signed,
+            run_as,
+            meta(Some("root")),
+        ] {
+            assert_eq!(state.rule_set(&meta), admin);
+        }
+
+        // Users keep their rules across sessions.
+        for user in 0..20 {
+            let user = format!("user{user}");
+            assert_eq!(
+                state.rule_set(&from(1, Some(&session(&state, Some(&user))))),
+                state.rule_set(&from(2, Some(&session(&state, Some(&user))))),
+            );
+        }
+    }
+
+    #[test]
+    fn canary_percent_is_respected() {
+        let never = with_canary(0);
+        let always = with_canary(100);
+        for ip in 0..20 {
+            assert_eq!(never.rule_set(&from(ip, None)), RuleSet::Stable);
+            assert_eq!(always.rule_set(&from(ip, None)), RuleSet::Canary);
+        }
+        assert_eq!(always.rule_set(&meta(Some("root"))), RuleSet::Canary);
+        // Callers without credentials or an address get the usual rules.
+        assert_eq!(always.rule_set(&meta(Some("made up"))), RuleSet::Stable);
+        assert_eq!(state().rule_set(&from(1, Some("root"))), RuleSet::Stable);
+
+        let half = with_canary(50);
+        let canaries = (0..=255)
+            .filter(|&ip| half.rule_set(&from(ip, None)) == RuleSet::Canary)
+            .count();
+        assert!((80..=176).contains(&canaries), "{canaries}");
+    }
+}
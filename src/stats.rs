@@ -0,0 +1,416 @@
+//! Counters for dashboards that do not scrape Prometheus, served by the protected
+//! `admin_stats` method.
+//!
+//! Calls are counted by [`StatsMiddleware`], which should be the outermost middleware, so that
+//! it sees every call.  It also records how long every method takes into a [`Histogram`], so
+//! that slow admin methods stand out from fast public ones.  Finally, it counts calls by
+//! [`Outcome`] and [`Identity`], so that unauthorized attempts can be charted directly.
+//! Requests rejected by the transport before reaching the handler, because of rate limits or
+//! overload, are counted by the transport.
+
+use {
+    crate::{
+        histogram::{Histogram, Percentiles},
+        memory::MemoryBudget,
+        priority::PriorityScheduler,
+        rate_limit::RateLimiter,
+        rejection::Reason,
+        request_log::Outcome,
+        slo::SloMonitor,
+        state::{ProtectionHandle, RuleSet},
+        RpcMeta,
+    },
+    futures_util::{future::Either, FutureExt},
+    jsonrpc_core::{
+        middleware::Middleware,
+        types::{
+            request::{Call, MethodCall, Notification},
+            response::{Output, Response},
+        },
+        Result,
+    },
+    jsonrpc_derive::rpc,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
+};
+
+/// Method names are chosen by callers, so only this many are counted separately.  Calls to
+/// any other method are counted under [`OTHER_METHODS`].
+pub const MAX_METHODS: usize = 1000;
+pub const OTHER_METHODS: &str = "(other)";
+
+/// What kind of credentials a caller presented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// No valid credentials.
+    Anonymous,
+    /// A session token, see [`crate::session`].
+    Session,
+    /// The admin token, or a request signature.
+    Admin,
+    /// An active emergency token, see [`crate::break_glass`].
+    Emergency,
+}
+
+impl Identity {
+    /// The credentials in `meta`, regardless of any user the call is made as.
+    pub fn of(state: &ProtectionHandle, meta: &RpcMeta) -> Self {
+        let state = state.load();
+        if state.session(meta).is_some() {
+            Identity::Session
+        } else if state.is_emergency(meta) {
+            Identity::Emergency
+        } else if state.authenticate(meta).is_ok() {
+            Identity::Admin
+        } else {
+            Identity::Anonymous
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Identity::Anonymous => "anonymous",
+            Identity::Session => "session",
+            Identity::Admin => "admin",
+            Identity::Emergency => "emergency",
+        })
+    }
+}
+
+#[derive(Default)]
+struct MethodStats {
+    calls: u64,
+    latency: Histogram,
+}
+
+struct Counters {
+    started: Instant,
+    methods: Mutex<HashMap<String, MethodStats>>,
+    denials: Mutex<HashMap<Reason, u64>>,
+    outcomes: Mutex<HashMap<(Outcome, Identity), u64>>,
+    rule_sets: Mutex<HashMap<(RuleSet, Outcome), u64>>,
+    deprecated_calls: Mutex<HashMap<(String, String), u64>>,
+}
+
+/// Clones share the counters.
+#[derive(Clone)]
+pub struct Stats {
+    counters: Arc<Counters>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::new(Counters {
+                started: Instant::now(),
+                methods: Mutex::default(),
+                denials: Mutex::default(),
+                outcomes: Mutex::default(),
+                rule_sets: Mutex::default(),
+                deprecated_calls: Mutex::default(),
+            }),
+        }
+    }
+
+    pub fn record_call(&self, method: &str) {
+        self.with_method(method, |stats| stats.calls += 1);
+    }
+
+    /// Records that a call to `method` took `duration`, from the time it reached the
+    /// middleware, until its response was ready.
+    pub fn record_latency(&self, method: &str, duration: Duration) {
+        self.with_method(method, |stats| stats.latency.record(duration));
+    }
+
+    pub fn record_outcome(&self, outcome: Outcome, identity: Identity) {
+        *self
+            .counters
+            .outcomes
+            .lock()
+            .unwrap()
+            .entry((outcome, identity))
+            .or_default() += 1;
+    }
+
+    /// Records the `outcome` of a call checked against `rule_set`, see [`crate::policy`].
+    pub fn record_rule_set(&self, rule_set: RuleSet, outcome: Outcome) {
+        *self
+            .counters
+            .rule_sets
+            .lock()
+            .unwrap()
+            .entry((rule_set, outcome))
+            .or_default() += 1;
+    }
+
+    /// Records a call to the deprecated `method` by `caller`, see [`crate::deprecation`].
+    pub fn record_deprecated_call(&self, method: &str, caller: &str) {
+        *self
+            .counters
+            .deprecated_calls
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), caller.to_owned()))
+            .or_default() += 1;
+    }
+
+    fn with_method(&self, method: &str, f: impl FnOnce(&mut MethodStats)) {
+        let mut methods = self.counters.methods.lock().unwrap();
+        if let Some(stats) = methods.get_mut(method) {
+            return f(stats);
+        }
+        let key = if methods.len() < MAX_METHODS {
+            method
+        } else {
+            OTHER_METHODS
+        };
+        f(methods.entry(key.to_owned()).or_default());
+    }
+
+    pub fn record_denial(&self, reason: Reason) {
+        *self
+            .counters
+            .denials
+            .lock()
+            .unwrap()
+            .entry(reason)
+            .or_default() += 1;
+    }
+}
+
+/// Counts calls by method, outcome and identity, and rejections by reason, into [`Stats`].
+#[derive(Clone)]
+pub struct StatsMiddleware {
+    stats: Stats,
+    state: ProtectionHandle,
+    slo: Option<SloMonitor>,
+}
+
+impl StatsMiddleware {
+    pub fn new(stats: Stats, state: ProtectionHandle) -> Self {
+        Self {
+            stats,
+            state,
+            slo: None,
+        }
+    }
+
+    /// Also feeds every call to `monitor`, see [`crate::slo`].
+    pub fn slo_monitor(mut self, monitor: SloMonitor) -> Self {
+        self.slo = Some(monitor);
+        self
+    }
+}
+
+impl Middleware<RpcMeta> for StatsMiddleware {
+    type Future = Pin<Box<dyn Future<Output = Option<Response>> + Send + 'static>>;
+    type CallFuture = Pin<Box<dyn Future<Output = Option<Output>> + Send + 'static>>;
+
+    fn on_call<F, X>(&self, call: Call, meta: RpcMeta, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, RpcMeta) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let method = match &call {
+            Call::MethodCall(MethodCall { method, .. })
+            | Call::Notification(Notification { method, .. }) => Some(method.clone()),
+            Call::Invalid { .. } => None,
+        };
+        if let Some(method) = &method {
+            self.stats.record_call(method);
+        }
+
+        let identity = Identity::of(&self.state, &meta);
+        let rule_set = {
+            let state = self.state.load();
+            state.canary.is_some().then(|| state.rule_set(&meta))
+        };
+        let stats = self.stats.clone();
+        let slo = self.slo.clone();
+        let started = Instant::now();
+        Either::Left(Box::pin(next(call, meta).map(move |output| {
+            let latency = started.elapsed();
+            let outcome = Outcome::of(output.as_ref());
+            if let Some(method) = &method {
+                stats.record_latency(method, latency);
+                if let Some(slo) = &slo {
+                    slo.record(method, outcome, latency);
+                }
+            }
+            stats.record_outcome(outcome, identity);
+            if let Some(rule_set) = rule_set {
+                stats.record_rule_set(rule_set, outcome);
+            }
+
+            if let Some(Output::Failure(failure)) = &output {
+                // Only protection errors carry a reason, see `crate::rejection`.
+                let reason = failure
+                    .error
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get("reason"))
+                    .and_then(|reason| serde_json::from_value(reason.clone()).ok());
+                if let Some(reason) = reason {
+                    stats.record_denial(reason);
+                }
+            }
+            output
+        })))
+    }
+}
+
+/// Result of `admin_stats`.  Fields for features that are not enabled are omitted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StatsReport {
+    /// In seconds.
+    pub uptime: u64,
+    pub calls: BTreeMap<String, u64>,
+    /// Handler latency of every method, in microseconds.
+    pub latency: BTreeMap<String, Percentiles>,
+    pub denials: BTreeMap<Reason, u64>,
+    /// Calls by outcome, then by identity of the caller.  Outcomes and identities without calls
+    /// are omitted.
+    pub outcomes: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Calls by the rules they were checked against, then by outcome, while there is a
+    /// canary, see [`crate::policy`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rule_sets: BTreeMap<String, BTreeMap<String, u64>>,
+    /// Calls to deprecated methods, by method, then by caller, see [`crate::deprecation`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deprecated_calls: BTreeMap<String, BTreeMap<String, u64>>,
+    pub sessions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit_buckets: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_flight: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued: Option<usize>,
+    /// In bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_used: Option<usize>,
+}
+
+#[rpc(server)]
+pub trait StatsRpc {
+    type Metadata;
+
+    #[rpc(name = "admin_stats")]
+    fn stats(&self) -> Result<StatsReport>;
+}
+
+#[derive(Clone)]
+pub struct StatsRpcImpl {
+    stats: Stats,
+    state: ProtectionHandle,
+    rate_limiter: Option<RateLimiter>,
+    scheduler: Option<PriorityScheduler>,
+    memory_budget: Option<MemoryBudget>,
+}
+
+impl StatsRpcImpl {
+    pub fn new(stats: Stats, state: ProtectionHandle) -> Self {
+        Self {
+            stats,
+            state,
+            rate_limiter: None,
+            scheduler: None,
+            memory_budget: None,
+        }
+    }
+
+    /// Report the number of rate limit buckets in use.
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Report the number of requests in flight and waiting.
+    pub fn priority_scheduler(mut self, scheduler: PriorityScheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Report the memory in use.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+}
+
+impl StatsRpc for StatsRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn stats(&self) -> Result<StatsReport> {
+        let counters = &self.stats.counters;
+        let methods = counters.methods.lock().unwrap();
+        Ok(StatsReport {
+            uptime: counters.started.elapsed().as_secs(),
+            calls: methods
+                .iter()
+                .map(|(method, stats)| (method.clone(), stats.calls))
+                .collect(),
+            latency: methods
+                .iter()
+                .filter(|(_, stats)| stats.latency.count() > 0)
+                .map(|(method, stats)| (method.clone(), stats.latency.percentiles()))
+                .collect(),
+            denials: counters
+                .denials
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&reason, &count)| (reason, count))
+                .collect(),
+            outcomes: counters.outcomes.lock().unwrap().iter().fold(
+                BTreeMap::new(),
+                |mut outcomes, ((outcome, identity), &count)| {
+                    outcomes
+                        .entry(outcome.to_string())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(identity.to_string(), count);
+                    outcomes
+                },
+            ),
+            rule_sets: counters.rule_sets.lock().unwrap().iter().fold(
+                BTreeMap::new(),
+                |mut rule_sets, ((rule_set, outcome), &count)| {
+                    rule_sets
+                        .entry(rule_set.to_string())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(outcome.to_string(), count);
+                    rule_sets
+                },
+            ),
+            deprecated_calls: counters.deprecated_calls.lock().unwrap().iter().fold(
+                BTreeMap::new(),
+                |mut deprecated_calls, ((method, caller), &count)| {
+                    deprecated_calls
+                        .entry(method.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(caller.clone(), count);
+                    deprecated_calls
+                },
+            ),
+            sessions: self.state.load().sessions.len(),
+            rate_limit_buckets: self.rate_limiter.as_ref().map(RateLimiter::buckets),
+            in_flight: self.scheduler.as_ref().map(PriorityScheduler::in_flight),
+            queued: self.scheduler.as_ref().map(PriorityScheduler::queued),
+            memory_used: self.memory_budget.as_ref().map(MemoryBudget::used),
+        })
+    }
+}
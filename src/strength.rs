@@ -0,0 +1,78 @@
+//! Rejecting weak passwords and tokens when they are set.
+//!
+//! Entropy is estimated from the character classes a secret uses: lowercase and uppercase
+//! letters, digits, and everything else.  Runs of the same character count once, so that
+//! `aaaaaaaa` is not mistaken for a strong secret.  This is crude, but catches the secrets an
+//! attacker would try first.
+
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug)]
+pub struct StrengthPolicy {
+    /// In characters.
+    pub min_length: usize,
+    /// In bits, see [`entropy_bits`].
+    pub min_entropy_bits: f64,
+}
+
+impl Default for StrengthPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 12,
+            min_entropy_bits: 50.0,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum WeakSecret {
+    #[error("must be at least {min} characters long, not {actual}")]
+    TooShort { min: usize, actual: usize },
+
+    #[error("is too predictable, use more characters, or a mix of letters, digits and symbols")]
+    TooPredictable,
+}
+
+impl StrengthPolicy {
+    pub fn check(&self, secret: &str) -> Result<(), WeakSecret> {
+        let length = secret.chars().count();
+        if length < self.min_length {
+            return Err(WeakSecret::TooShort {
+                min: self.min_length,
+                actual: length,
+            });
+        }
+        if entropy_bits(secret) < self.min_entropy_bits {
+            return Err(WeakSecret::TooPredictable);
+        }
+        Ok(())
+    }
+}
+
+/// Estimated entropy of `secret`, in bits.
+pub fn entropy_bits(secret: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut other) = (false, false, false, false);
+    let mut length = 0u32;
+    let mut previous = None;
+    for c in secret.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            _ => other = true,
+        }
+        if previous != Some(c) {
+            length += 1;
+        }
+        previous = Some(c);
+    }
+
+    let pool = [(lower, 26), (upper, 26), (digit, 10), (other, 33)]
+        .into_iter()
+        .filter_map(|(used, size)| used.then_some(size))
+        .sum::<u32>();
+    if pool == 0 {
+        return 0.0;
+    }
+    f64::from(length) * f64::from(pool).log2()
+}
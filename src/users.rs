@@ -0,0 +1,685 @@
+//! User accounts, so that admin calls can be attributed to a person rather than to whoever
+//! knows the shared admin token.
+//!
+//! Users log in with `auth_login`, passing their username and password, and get a
+//! [`session`](crate::session) token for the role of their account.  Passwords are stored as
+//! argon2 hashes, in a JSON file that is rewritten on every change.  Changing the password or
+//! the role of a user, or removing them, through [`UsersRpc`] ends their sessions.
+//!
+//! With a [`LockoutPolicy`], accounts are locked for a while after too many failed logins in a
+//! row, no matter where the attempts come from.  Failures are only counted for existing
+//! users, and are forgotten on restart.
+
+use {
+    crate::{
+        rejection::{Reason, Rejection},
+        secret::Secret,
+        session::SessionStore,
+        state::Role,
+        strength::{StrengthPolicy, WeakSecret},
+        RpcMeta,
+    },
+    argon2::{
+        password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+        Argon2,
+    },
+    jsonrpc_core::{BoxFuture, Result as RpcResult},
+    jsonrpc_derive::rpc,
+    rand::Rng,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt, fs, io,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex, RwLock},
+        time::{Duration, Instant},
+    },
+    thiserror::Error,
+};
+
+#[derive(Error, Debug)]
+pub enum UserError {
+    #[error("user \"{0}\" already exists")]
+    Exists(String),
+
+    #[error("there is no user \"{0}\"")]
+    NotFound(String),
+
+    #[error(
+        "usernames must be non-empty, and contain only ASCII letters, digits, '.', '-' and '_'"
+    )]
+    InvalidUsername,
+
+    #[error("the password {0}")]
+    WeakPassword(WeakSecret),
+
+    #[error("failed to hash the password: {0}")]
+    Hash(String),
+
+    #[error("failed to save users to {}: {err}", path.display())]
+    Save { path: PathBuf, err: io::Error },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+    /// In the PHC string format.
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// Locks an account for `duration` after `max_failures` failed logins in a row.
+#[derive(Clone, Copy, Debug)]
+pub struct LockoutPolicy {
+    pub max_failures: u32,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginError {
+    /// The username or the password is wrong.
+    Invalid,
+    /// Too many logins failed, the account may be used again after the given time.
+    Locked { retry_after: Duration },
+}
+
+#[derive(Default)]
+struct Failures {
+    /// Failed logins since the last successful one, or since the last lockout.
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Users known to the server, shared by every copy of the store.
+#[derive(Clone, Default)]
+pub struct UserStore {
+    users: Arc<RwLock<BTreeMap<String, User>>>,
+    /// Where changes are saved.  Kept in memory only when `None`.
+    path: Option<PathBuf>,
+    lockout: Option<LockoutPolicy>,
+    failures: Arc<Mutex<HashMap<String, Failures>>>,
+    /// New passwords must satisfy it.  Any password is accepted when `None`.
+    strength: Option<StrengthPolicy>,
+}
+
+impl UserStore {
+    /// Loads users from `path`, where changes are saved from then on.  Starts with no users
+    /// if the file does not exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let users = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Self {
+            users: Arc::new(RwLock::new(users)),
+            path: Some(path.to_owned()),
+            ..Self::default()
+        })
+    }
+
+    /// Reject new passwords that do not satisfy `policy`.  Existing ones keep working.
+    pub fn strength_policy(mut self, policy: StrengthPolicy) -> Self {
+        self.strength = Some(policy);
+        self
+    }
+
+    fn check_strength(&self, password: &str) -> Result<(), UserError> {
+        match &self.strength {
+            Some(policy) => policy.check(password).map_err(UserError::WeakPassword),
+            None => Ok(()),
+        }
+    }
+
+    /// Lock accounts according to `policy` in [`Self::login`].
+    pub fn lockout(mut self, policy: LockoutPolicy) -> Self {
+        self.lockout = Some(policy);
+        self
+    }
+
+    pub fn add(&self, username: &str, password: &str, role: Role) -> Result<(), UserError> {
+        if username.is_empty()
+            || !username
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+        {
+            return Err(UserError::InvalidUsername);
+        }
+
+        self.check_strength(password)?;
+        let password_hash = hash_password(password)?;
+        self.change(|users| {
+            if users.contains_key(username) {
+                return Err(UserError::Exists(username.to_owned()));
+            }
+            users.insert(
+                username.to_owned(),
+                User {
+                    password_hash,
+                    role,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, username: &str) -> Result<(), UserError> {
+        self.change(|users| match users.remove(username) {
+            Some(_) => Ok(()),
+            None => Err(UserError::NotFound(username.to_owned())),
+        })?;
+        self.failures.lock().unwrap().remove(username);
+        Ok(())
+    }
+
+    /// Lets `username` log in again right away, and forgets their failed logins.
+    pub fn unlock(&self, username: &str) -> Result<(), UserError> {
+        if self.role(username).is_none() {
+            return Err(UserError::NotFound(username.to_owned()));
+        }
+        self.failures.lock().unwrap().remove(username);
+        Ok(())
+    }
+
+    /// Whether `username` may not log in at the moment.
+    pub fn is_locked(&self, username: &str) -> bool {
+        self.locked_for(username, Instant::now()).is_some()
+    }
+
+    fn locked_for(&self, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures.get(username)?.locked_until?;
+        (locked_until > now).then(|| locked_until - now)
+    }
+
+    /// Role of `username`, if `password` is theirs, and the account is not locked.
+    pub fn login(&self, username: &str, password: &str) -> Result<Role, LoginError> {
+        if let Some(retry_after) = self.locked_for(username, Instant::now()) {
+            return Err(LoginError::Locked { retry_after });
+        }
+
+        if let Some(role) = self.verify(username, password) {
+            self.failures.lock().unwrap().remove(username);
+            return Ok(role);
+        }
+
+        let Some(policy) = self.lockout else {
+            return Err(LoginError::Invalid);
+        };
+        if self.role(username).is_none() {
+            return Err(LoginError::Invalid);
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        let user = failures.entry(username.to_owned()).or_default();
+        user.count += 1;
+        if user.count < policy.max_failures {
+            return Err(LoginError::Invalid);
+        }
+
+        log::warn!(
+            "User {username} is locked for {}s after {} failed logins",
+            policy.duration.as_secs(),
+            user.count,
+        );
+        user.count = 0;
+        user.locked_until = Some(Instant::now() + policy.duration);
+        Err(LoginError::Locked {
+            retry_after: policy.duration,
+        })
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<(), UserError> {
+        self.check_strength(password)?;
+        let password_hash = hash_password(password)?;
+        self.change(|users| match users.get_mut(username) {
+            Some(user) => {
+                user.password_hash = password_hash;
+                Ok(())
+            }
+            None => Err(UserError::NotFound(username.to_owned())),
+        })
+    }
+
+    pub fn set_role(&self, username: &str, role: Role) -> Result<(), UserError> {
+        self.change(|users| match users.get_mut(username) {
+            Some(user) => {
+                user.role = role;
+                Ok(())
+            }
+            None => Err(UserError::NotFound(username.to_owned())),
+        })
+    }
+
+    /// Usernames and roles of all users.
+    pub fn list(&self) -> Vec<(String, Role)> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(username, user)| (username.clone(), user.role))
+            .collect()
+    }
+
+    /// Role of `username`, if there is such a user.
+    pub fn role(&self, username: &str) -> Option<Role> {
+        self.users
+            .read()
+            .unwrap()
+            .get(username)
+            .map(|user| user.role)
+    }
+
+    /// Role of `username`, if `password` is theirs.  Ignores lockouts.
+    pub fn verify(&self, username: &str, password: &str) -> Option<Role> {
+        let user = self.users.read().unwrap().get(username).cloned();
+
+        // Unknown users take as long as wrong passwords, so that usernames can not be probed.
+        let (password_hash, role) = match &user {
+            Some(user) => (user.password_hash.as_str(), Some(user.role)),
+            None => (UNKNOWN_USER_HASH, None),
+        };
+
+        let hash = PasswordHash::new(password_hash).ok()?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()?;
+        role
+    }
+
+    /// Applies `f` to the users, and saves the result.  Nothing changes if either fails.
+    fn change<F>(&self, f: F) -> Result<(), UserError>
+    where
+        F: FnOnce(&mut BTreeMap<String, User>) -> Result<(), UserError>,
+    {
+        let mut users = self.users.write().unwrap();
+        let mut changed = users.clone();
+        f(&mut changed)?;
+
+        if let Some(path) = &self.path {
+            save(path, &changed).map_err(|err| UserError::Save {
+                path: path.clone(),
+                err,
+            })?;
+        }
+        *users = changed;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UserStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Password hashes are secrets too, so only the number of users is shown.
+        f.debug_struct("UserStore")
+            .field("len", &self.users.read().unwrap().len())
+            .finish()
+    }
+}
+
+/// Hash of a password nobody knows, checked for unknown users.
+const UNKNOWN_USER_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$\
+    9Dq6Vmc2z1yqrhW8vVevDeyEqN8a0OyIiWfN6KhWpQo";
+
+fn hash_password(password: &str) -> Result<String, UserError> {
+    let salt = SaltString::encode_b64(&rand::thread_rng().gen::<[u8; 16]>())
+        .map_err(|err| UserError::Hash(err.to_string()))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| UserError::Hash(err.to_string()))
+}
+
+/// Writes `users` next to `path`, and moves them into place, so that a crash never leaves a
+/// partial file behind.
+fn save(path: &Path, users: &BTreeMap<String, User>) -> io::Result<()> {
+    let content = serde_json::to_vec_pretty(users).expect("users always serialize");
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(&tmp)?, &content)?;
+    fs::rename(&tmp, path)
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub role: Role,
+    /// Too many logins failed recently, see `user_unlock`.
+    pub locked: bool,
+}
+
+/// Protected methods for managing users.
+#[rpc(server)]
+pub trait UsersRpc {
+    type Metadata;
+
+    #[rpc(meta, name = "user_add")]
+    fn add(
+        &self,
+        meta: Self::Metadata,
+        username: String,
+        password: Secret<String>,
+        role: Role,
+    ) -> BoxFuture<RpcResult<()>>;
+
+    #[rpc(meta, name = "user_remove")]
+    fn remove(&self, meta: Self::Metadata, username: String) -> RpcResult<()>;
+
+    #[rpc(meta, name = "user_set_password")]
+    fn set_password(
+        &self,
+        meta: Self::Metadata,
+        username: String,
+        password: Secret<String>,
+    ) -> BoxFuture<RpcResult<()>>;
+
+    #[rpc(meta, name = "user_set_role")]
+    fn set_role(&self, meta: Self::Metadata, username: String, role: Role) -> RpcResult<()>;
+
+    /// Lets a locked user log in again right away.
+    #[rpc(meta, name = "user_unlock")]
+    fn unlock(&self, meta: Self::Metadata, username: String) -> RpcResult<()>;
+
+    #[rpc(name = "user_list")]
+    fn list(&self) -> RpcResult<Vec<UserInfo>>;
+}
+
+#[derive(Clone)]
+pub struct UsersRpcImpl {
+    users: UserStore,
+    /// Where sessions of changed users are ended.
+    sessions: SessionStore,
+}
+
+impl UsersRpcImpl {
+    pub fn new(users: UserStore, sessions: SessionStore) -> Self {
+        Self { users, sessions }
+    }
+
+    /// Makes `username` log in again, with their new password or role.
+    fn end_sessions(&self, username: &str, meta: &RpcMeta) {
+        let revoked = self.sessions.revoke_user(username);
+        if revoked > 0 {
+            log::info!(
+                "Ended {revoked} sessions of user {username}, request_id={}",
+                meta.request_id
+            );
+        }
+    }
+}
+
+/// Runs `f`, which hashes a password, on a blocking thread, as argon2 takes a while on purpose.
+async fn hashing<F>(f: F, meta: &RpcMeta) -> RpcResult<()>
+where
+    F: FnOnce() -> Result<(), UserError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| jsonrpc_core::Error::internal_error())?
+        .map_err(|err| to_rpc_error(err, meta))
+}
+
+fn to_rpc_error(err: UserError, meta: &RpcMeta) -> jsonrpc_core::Error {
+    let mut error = match err {
+        UserError::Hash(_) | UserError::Save { .. } => {
+            log::error!("User change failed: {err}, request_id={}", meta.request_id);
+            jsonrpc_core::Error::internal_error()
+        }
+        err => jsonrpc_core::Error::invalid_params(err.to_string()),
+    };
+    error.data = Some(serde_json::json!({ "request_id": meta.request_id }));
+    error
+}
+
+impl UsersRpc for UsersRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn add(
+        &self,
+        meta: RpcMeta,
+        username: String,
+        password: Secret<String>,
+        role: Role,
+    ) -> BoxFuture<RpcResult<()>> {
+        let users = self.users.clone();
+        Box::pin(async move {
+            let add = {
+                let username = username.clone();
+                move || users.add(&username, password.expose(), role)
+            };
+            hashing(add, &meta).await?;
+            log::info!(
+                "User {username} added with role {role}, request_id={}",
+                meta.request_id
+            );
+            Ok(())
+        })
+    }
+
+    fn remove(&self, meta: RpcMeta, username: String) -> RpcResult<()> {
+        self.users
+            .remove(&username)
+            .map_err(|err| to_rpc_error(err, &meta))?;
+        log::info!("User {username} removed, request_id={}", meta.request_id);
+        self.end_sessions(&username, &meta);
+        Ok(())
+    }
+
+    fn set_password(
+        &self,
+        meta: RpcMeta,
+        username: String,
+        password: Secret<String>,
+    ) -> BoxFuture<RpcResult<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let set_password = {
+                let (users, username) = (this.users.clone(), username.clone());
+                move || users.set_password(&username, password.expose())
+            };
+            hashing(set_password, &meta).await?;
+            log::info!(
+                "Password of user {username} changed, request_id={}",
+                meta.request_id
+            );
+            this.end_sessions(&username, &meta);
+            Ok(())
+        })
+    }
+
+    fn set_role(&self, meta: RpcMeta, username: String, role: Role) -> RpcResult<()> {
+        self.users
+            .set_role(&username, role)
+            .map_err(|err| to_rpc_error(err, &meta))?;
+        log::info!(
+            "User {username} now has role {role}, request_id={}",
+            meta.request_id
+        );
+        self.end_sessions(&username, &meta);
+        Ok(())
+    }
+
+    fn unlock(&self, meta: RpcMeta, username: String) -> RpcResult<()> {
+        self.users
+            .unlock(&username)
+            .map_err(|err| to_rpc_error(err, &meta))?;
+        log::info!("User {username} unlocked, request_id={}", meta.request_id);
+        Ok(())
+    }
+
+    fn list(&self) -> RpcResult<Vec<UserInfo>> {
+        Ok(self
+            .users
+            .list()
+            .into_iter()
+            .map(|(username, role)| UserInfo {
+                locked: self.users.is_locked(&username),
+                username,
+                role,
+            })
+            .collect())
+    }
+}
+
+/// Rejection for a failed `auth_login` with a username and password.
+pub fn invalid_login() -> Rejection {
+    Rejection::new(Reason::InvalidToken, "Username or password is not valid")
+}
+
+impl From<LoginError> for Rejection {
+    fn from(error: LoginError) -> Self {
+        match error {
+            LoginError::Invalid => invalid_login(),
+            LoginError::Locked { retry_after } => Rejection::new(
+                Reason::AccountLocked,
+                "Account is locked after too many failed logins",
+            )
+            .retry_after(retry_after),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{session::Grant, state::tests::meta},
+        jsonrpc_core::{MetaIoHandler, Value},
+        serde_json::json,
+        std::env,
+    };
+
+    #[test]
+    fn usernames_are_checked() {
+        let users = UserStore::default();
+        for username in ["", "a b", "ä", "a/b"] {
+            assert!(matches!(
+                users.add(username, "secret", Role::Admin),
+                Err(UserError::InvalidUsername)
+            ));
+        }
+        users.add("alice.b-c_1", "secret", Role::Admin).unwrap();
+        assert!(matches!(
+            users.add("alice.b-c_1", "other", Role::Anonymous),
+            Err(UserError::Exists(_))
+        ));
+        assert!(matches!(users.remove("bob"), Err(UserError::NotFound(_))));
+        assert!(matches!(
+            users.set_role("bob", Role::Admin),
+            Err(UserError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn logins_need_the_password_of_the_user() {
+        let users = UserStore::default();
+        users.add("alice", "secret", Role::Admin).unwrap();
+
+        assert_eq!(users.login("alice", "secret"), Ok(Role::Admin));
+        assert_eq!(users.login("alice", "Secret"), Err(LoginError::Invalid));
+        assert_eq!(users.login("bob", "secret"), Err(LoginError::Invalid));
+
+        users.set_role("alice", Role::Anonymous).unwrap();
+        assert_eq!(users.verify("alice", "secret"), Some(Role::Anonymous));
+        users.remove("alice").unwrap();
+        assert_eq!(users.verify("alice", "secret"), None);
+    }
+
+    #[test]
+    fn accounts_are_locked_after_failed_logins() {
+        let users = UserStore::default().lockout(LockoutPolicy {
+            max_failures: 2,
+            duration: Duration::from_secs(60),
+        });
+        users.add("alice", "secret", Role::Admin).unwrap();
+
+        assert_eq!(users.login("alice", "wrong"), Err(LoginError::Invalid));
+        assert!(matches!(
+            users.login("alice", "wrong"),
+            Err(LoginError::Locked { .. })
+        ));
+        assert!(users.is_locked("alice"));
+        assert!(matches!(
+            users.login("alice", "secret"),
+            Err(LoginError::Locked { .. })
+        ));
+
+        users.unlock("alice").unwrap();
+        assert_eq!(users.login("alice", "secret"), Ok(Role::Admin));
+
+        // Unknown users are never locked, so that they can not be told apart.
+        for _ in 0..3 {
+            assert_eq!(users.login("bob", "wrong"), Err(LoginError::Invalid));
+        }
+    }
+
+    #[test]
+    fn changes_are_saved() {
+        let path = env::temp_dir().join(format!("users-{}.json", std::process::id()));
+        let users = UserStore::load(&path).unwrap();
+        assert!(users.list().is_empty());
+        users.add("alice", "secret", Role::Admin).unwrap();
+
+        let loaded = UserStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.list(), [("alice".to_owned(), Role::Admin)]);
+        assert_eq!(loaded.verify("alice", "secret"), Some(Role::Admin));
+    }
+
+    #[tokio::test]
+    async fn changes_to_a_user_end_their_sessions() {
+        let users = UserStore::default();
+        users.add("alice", "secret", Role::Admin).unwrap();
+        users.add("bob", "secret", Role::Admin).unwrap();
+        let sessions = SessionStore::default();
+        let mut io = MetaIoHandler::default();
+        io.extend_with(UsersRpcImpl::new(users.clone(), sessions.clone()).to_delegate());
+
+        let login = |user: &str| {
+            let grant = Grant {
+                role: Role::Admin,
+                user: Some(user.to_owned()),
+                methods: None,
+            };
+            sessions.issue(Duration::from_secs(60), grant)
+        };
+        let call = |method: &str, params: Value| {
+            let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+            let response = io.handle_request(&request.to_string(), meta(Some("root")));
+            let method = method.to_owned();
+            async move {
+                let response = serde_json::from_str::<Value>(&response.await.unwrap()).unwrap();
+                assert_eq!(response["result"], Value::Null, "{method}: {response}");
+            }
+        };
+
+        let bob = login("bob");
+        for (method, params) in [
+            ("user_set_password", json!(["alice", "new secret"])),
+            ("user_set_role", json!(["alice", "anonymous"])),
+            ("user_remove", json!(["alice"])),
+        ] {
+            let alice = login("alice");
+            call(method, params).await;
+            assert!(sessions.get(alice.token.expose()).is_none(), "{method}");
+            assert_eq!(
+                sessions.refresh(alice.refresh_token.expose()).unwrap_err(),
+                crate::session::RefreshError::Unknown
+            );
+        }
+        assert!(sessions.get(bob.token.expose()).is_some());
+
+        call("user_add", json!(["carol", "secret", "admin"])).await;
+        assert_eq!(users.verify("carol", "secret"), Some(Role::Admin));
+    }
+}
@@ -0,0 +1,81 @@
+//! Versions of the API served side by side, each on its own path, such as `/v1` and `/v2`, so
+//! that breaking changes to methods can be rolled out gradually.
+//!
+//! Every version has its own methods, but they all go through the same middleware, so the
+//! protection rules for a method name apply in every version that has it.  Clients that
+//! support several versions ask the server which one to use with `rpc_negotiate_version`:
+//!
+//! ```json
+//! {"jsonrpc": "2.0", "id": 1, "method": "rpc_negotiate_version", "params": [["v2", "v1"]]}
+//! ```
+//!
+//! and get the newest version both sides support, with the path it is served on.
+
+use {
+    crate::RpcMeta,
+    jsonrpc_core::{Error as JsonRpcError, Result as RpcResult},
+    jsonrpc_derive::rpc,
+    serde::{Deserialize, Serialize},
+};
+
+/// A version of the API and the path it is served on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiVersion {
+    pub version: String,
+    pub path: String,
+}
+
+impl ApiVersion {
+    /// Version `version`, served on `/{version}`.
+    pub fn new(version: impl Into<String>) -> Self {
+        let version = version.into();
+        Self {
+            path: format!("/{version}"),
+            version,
+        }
+    }
+}
+
+#[rpc(server)]
+pub trait VersionRpc {
+    type Metadata;
+
+    /// Versions the server has, oldest first.
+    #[rpc(name = "rpc_versions")]
+    fn versions(&self) -> RpcResult<Vec<ApiVersion>>;
+
+    /// The newest version the server has out of the `supported` ones.
+    #[rpc(name = "rpc_negotiate_version")]
+    fn negotiate(&self, supported: Vec<String>) -> RpcResult<ApiVersion>;
+}
+
+#[derive(Clone)]
+pub struct VersionRpcImpl {
+    versions: Vec<ApiVersion>,
+}
+
+impl VersionRpcImpl {
+    /// `versions` are the versions the server has, oldest first.
+    pub fn new(versions: Vec<ApiVersion>) -> Self {
+        Self { versions }
+    }
+}
+
+impl VersionRpc for VersionRpcImpl {
+    type Metadata = RpcMeta;
+
+    fn versions(&self) -> RpcResult<Vec<ApiVersion>> {
+        Ok(self.versions.clone())
+    }
+
+    fn negotiate(&self, supported: Vec<String>) -> RpcResult<ApiVersion> {
+        self.versions
+            .iter()
+            .rev()
+            .find(|version| supported.contains(&version.version))
+            .cloned()
+            .ok_or_else(|| {
+                JsonRpcError::invalid_params("None of the versions is served, see rpc_versions")
+            })
+    }
+}
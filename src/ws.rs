@@ -0,0 +1,49 @@
+//! WebSocket transport.  Unlike HTTP, it supports subscriptions, see [`crate::pubsub`].
+//!
+//! `jsonrpc-ws-server` does not expose the handshake headers, so the admin token is passed as
+//! a subprotocol instead: `Sec-WebSocket-Protocol: x-admin-auth.<token>`.  Browsers can not set
+//! arbitrary headers on a WebSocket either, so this is the common workaround.
+
+use {
+    crate::RpcMeta,
+    jsonrpc_core::{MetaIoHandler, Middleware},
+    jsonrpc_pubsub::Session,
+    jsonrpc_ws_server::{tokio::runtime::Handle, Error, RequestContext, Server, ServerBuilder},
+    std::{net::SocketAddr, sync::Arc},
+};
+
+/// Subprotocol prefix carrying the admin token.
+pub const ADMIN_AUTH_PROTOCOL_PREFIX: &str = "x-admin-auth.";
+
+/// Metadata shared by all the calls made over one connection.
+pub fn extract_meta(context: &RequestContext) -> RpcMeta {
+    let auth = context
+        .protocols
+        .iter()
+        .find_map(|protocol| protocol.strip_prefix(ADMIN_AUTH_PROTOCOL_PREFIX));
+
+    let mut meta = RpcMeta::from_headers(|name| match name {
+        "X-Admin-Auth" => auth.map(str::as_bytes),
+        _ => None,
+    });
+    meta.session = Some(Arc::new(Session::new(context.sender())));
+    meta
+}
+
+/// Starts serving `io` on `addr`.  Connections are handled on a separate thread, while calls
+/// are executed on `executor`.
+pub fn start<S>(
+    addr: &SocketAddr,
+    io: MetaIoHandler<RpcMeta, S>,
+    executor: Handle,
+) -> Result<Server, Box<Error>>
+where
+    S: Middleware<RpcMeta>,
+    S::Future: Unpin,
+    S::CallFuture: Unpin,
+{
+    ServerBuilder::with_meta_extractor(io, extract_meta)
+        .event_loop_executor(executor)
+        .start(addr)
+        .map_err(Box::new)
+}